@@ -0,0 +1,36 @@
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    passkey TEXT,
+    eakey TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS user_property (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    instance_status TEXT NOT NULL DEFAULT 'inactive',
+    instance_usage DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    api_key_active BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS instances (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    instances_count INTEGER NOT NULL DEFAULT 0,
+    expected_consumption DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    instances_overall_consumption DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS billing (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    amount_in_wallet DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    amount_spent DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    total_amount_spent DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    average_hourly_consumption DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
\ No newline at end of file
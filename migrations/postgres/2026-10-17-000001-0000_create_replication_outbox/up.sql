@@ -0,0 +1,2 @@
+-- The replication outbox lives only on the SQLite side; this version is
+-- kept so both backends report the same schema version.
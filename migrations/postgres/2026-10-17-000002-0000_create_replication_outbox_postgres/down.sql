@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS billing_outbox ON billing;
+DROP TRIGGER IF EXISTS instances_outbox ON instances;
+DROP TRIGGER IF EXISTS user_property_outbox ON user_property;
+DROP TRIGGER IF EXISTS users_outbox ON users;
+DROP FUNCTION IF EXISTS record_replication_outbox();
+DROP TABLE IF EXISTS replication_outbox;
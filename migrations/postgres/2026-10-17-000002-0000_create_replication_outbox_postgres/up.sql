@@ -0,0 +1,43 @@
+-- Postgres keeps its own outbox so writes made while it is the primary
+-- flow back to the SQLite fallback. Rows written by the replication worker
+-- itself set `orsta.replicating` and are not recorded, to avoid echoes.
+CREATE TABLE IF NOT EXISTS replication_outbox (
+    id SERIAL PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    row_id INTEGER NOT NULL,
+    op TEXT NOT NULL CHECK (op IN ('upsert', 'delete')),
+    created_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::BIGINT),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at BIGINT NOT NULL DEFAULT 0,
+    last_error TEXT
+);
+
+CREATE OR REPLACE FUNCTION record_replication_outbox() RETURNS trigger AS $$
+BEGIN
+    IF current_setting('orsta.replicating', true) = 'on' THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO replication_outbox (table_name, row_id, op) VALUES (TG_TABLE_NAME, OLD.id, 'delete');
+    ELSE
+        INSERT INTO replication_outbox (table_name, row_id, op) VALUES (TG_TABLE_NAME, NEW.id, 'upsert');
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS users_outbox ON users;
+CREATE TRIGGER users_outbox AFTER INSERT OR UPDATE OR DELETE ON users
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS user_property_outbox ON user_property;
+CREATE TRIGGER user_property_outbox AFTER INSERT OR UPDATE OR DELETE ON user_property
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS instances_outbox ON instances;
+CREATE TRIGGER instances_outbox AFTER INSERT OR UPDATE OR DELETE ON instances
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS billing_outbox ON billing;
+CREATE TRIGGER billing_outbox AFTER INSERT OR UPDATE OR DELETE ON billing
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS refresh_tokens_outbox ON refresh_tokens;
+DROP TRIGGER IF EXISTS sessions_outbox ON sessions;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS sessions;
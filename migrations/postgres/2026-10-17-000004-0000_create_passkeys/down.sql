@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS passkeys_outbox ON passkeys;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS passkey TEXT;
+ALTER TABLE users DROP COLUMN IF EXISTS require_passkey;
+DROP TABLE IF EXISTS auth_challenges;
+DROP TABLE IF EXISTS passkeys;
@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS passkeys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    credential_id TEXT NOT NULL UNIQUE,
+    public_key TEXT NOT NULL,
+    alg INTEGER NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    last_used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_user_id ON passkeys (user_id);
+
+CREATE TABLE IF NOT EXISTS auth_challenges (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER,
+    purpose TEXT NOT NULL,
+    challenge TEXT NOT NULL UNIQUE,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS require_passkey BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users DROP COLUMN IF EXISTS passkey;
+
+DROP TRIGGER IF EXISTS passkeys_outbox ON passkeys;
+CREATE TRIGGER passkeys_outbox AFTER INSERT OR UPDATE OR DELETE ON passkeys
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS recovery_codes_outbox ON recovery_codes;
+DROP TRIGGER IF EXISTS totp_credentials_outbox ON totp_credentials;
+ALTER TABLE auth_challenges DROP COLUMN IF EXISTS attempts;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS totp_credentials;
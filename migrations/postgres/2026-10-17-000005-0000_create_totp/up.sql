@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS totp_credentials (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL UNIQUE,
+    secret TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    enabled_at BIGINT,
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL UNIQUE,
+    created_at BIGINT NOT NULL,
+    used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id ON recovery_codes (user_id);
+
+ALTER TABLE auth_challenges ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
+
+DROP TRIGGER IF EXISTS totp_credentials_outbox ON totp_credentials;
+CREATE TRIGGER totp_credentials_outbox AFTER INSERT OR UPDATE OR DELETE ON totp_credentials
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS recovery_codes_outbox ON recovery_codes;
+CREATE TRIGGER recovery_codes_outbox AFTER INSERT OR UPDATE OR DELETE ON recovery_codes
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS audit_log_outbox ON audit_log;
+DROP TABLE IF EXISTS audit_log;
+DROP TABLE IF EXISTS auth_throttles;
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS auth_throttles (
+    id SERIAL PRIMARY KEY,
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at BIGINT NOT NULL,
+    locked_until BIGINT,
+    UNIQUE (scope, subject)
+);
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id SERIAL PRIMARY KEY,
+    actor_id INTEGER,
+    user_id INTEGER,
+    action TEXT NOT NULL,
+    detail TEXT NOT NULL DEFAULT '{}',
+    ip TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_id ON audit_log (user_id);
+
+DROP TRIGGER IF EXISTS audit_log_outbox ON audit_log;
+CREATE TRIGGER audit_log_outbox AFTER INSERT OR UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
@@ -0,0 +1,2 @@
+DROP TRIGGER IF EXISTS api_keys_outbox ON api_keys;
+DROP TABLE IF EXISTS api_keys;
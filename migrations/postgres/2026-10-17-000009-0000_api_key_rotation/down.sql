@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS api_keys_previous_key_hash;
+ALTER TABLE api_keys DROP COLUMN IF EXISTS rotated_at;
+ALTER TABLE api_keys DROP COLUMN IF EXISTS previous_expires_at;
+ALTER TABLE api_keys DROP COLUMN IF EXISTS previous_prefix;
+ALTER TABLE api_keys DROP COLUMN IF EXISTS previous_key_hash;
@@ -0,0 +1,6 @@
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS previous_key_hash TEXT;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS previous_prefix TEXT;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS previous_expires_at BIGINT;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS rotated_at BIGINT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_keys_previous_key_hash ON api_keys (previous_key_hash);
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_at BIGINT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_reason TEXT;
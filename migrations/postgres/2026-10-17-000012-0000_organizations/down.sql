@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS org_invitations_outbox ON org_invitations;
+DROP TRIGGER IF EXISTS org_members_outbox ON org_members;
+DROP TRIGGER IF EXISTS organizations_outbox ON organizations;
+ALTER TABLE sessions DROP COLUMN IF EXISTS active_org_id;
+ALTER TABLE billing DROP COLUMN IF EXISTS org_id;
+ALTER TABLE instances DROP COLUMN IF EXISTS org_id;
+DROP TABLE IF EXISTS org_invitations;
+DROP TABLE IF EXISTS org_members;
+DROP TABLE IF EXISTS organizations;
@@ -0,0 +1,58 @@
+CREATE TABLE IF NOT EXISTS organizations (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    personal_user_id INTEGER UNIQUE,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (personal_user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS org_members (
+    id SERIAL PRIMARY KEY,
+    org_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    UNIQUE (org_id, user_id),
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS org_members_user_id ON org_members (user_id);
+
+CREATE TABLE IF NOT EXISTS org_invitations (
+    id SERIAL PRIMARY KEY,
+    org_id INTEGER NOT NULL,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL,
+    invited_by INTEGER,
+    jti TEXT NOT NULL UNIQUE,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    accepted_at BIGINT,
+    accepted_by INTEGER,
+    revoked_at BIGINT,
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (invited_by) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (accepted_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS org_invitations_org_id ON org_invitations (org_id);
+
+ALTER TABLE instances ADD COLUMN IF NOT EXISTS org_id INTEGER REFERENCES organizations (id) ON DELETE CASCADE;
+ALTER TABLE billing ADD COLUMN IF NOT EXISTS org_id INTEGER REFERENCES organizations (id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS instances_org_id ON instances (org_id);
+CREATE UNIQUE INDEX IF NOT EXISTS billing_org_id ON billing (org_id);
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS active_org_id INTEGER REFERENCES organizations (id) ON DELETE SET NULL;
+
+DROP TRIGGER IF EXISTS organizations_outbox ON organizations;
+CREATE TRIGGER organizations_outbox AFTER INSERT OR UPDATE OR DELETE ON organizations
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS org_members_outbox ON org_members;
+CREATE TRIGGER org_members_outbox AFTER INSERT OR UPDATE OR DELETE ON org_members
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+DROP TRIGGER IF EXISTS org_invitations_outbox ON org_invitations;
+CREATE TRIGGER org_invitations_outbox AFTER INSERT OR UPDATE OR DELETE ON org_invitations
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
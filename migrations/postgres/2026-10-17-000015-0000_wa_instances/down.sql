@@ -0,0 +1,2 @@
+DROP TRIGGER IF EXISTS wa_instances_outbox ON wa_instances;
+DROP TABLE IF EXISTS wa_instances;
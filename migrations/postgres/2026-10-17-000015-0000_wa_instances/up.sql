@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS wa_instances (
+    id SERIAL PRIMARY KEY,
+    org_id INTEGER NOT NULL,
+    created_by INTEGER,
+    name TEXT NOT NULL,
+    phone_number TEXT UNIQUE,
+    status TEXT NOT NULL,
+    config TEXT NOT NULL DEFAULT '{}',
+    created_at BIGINT NOT NULL,
+    started_at BIGINT,
+    stopped_at BIGINT,
+    run_secs BIGINT NOT NULL DEFAULT 0,
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS wa_instances_org_id ON wa_instances (org_id);
+
+DROP TRIGGER IF EXISTS wa_instances_outbox ON wa_instances;
+CREATE TRIGGER wa_instances_outbox AFTER INSERT OR UPDATE OR DELETE ON wa_instances
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
@@ -0,0 +1,7 @@
+ALTER TABLE user_property ADD COLUMN IF NOT EXISTS instance_status TEXT NOT NULL DEFAULT 'inactive';
+DROP TRIGGER IF EXISTS wa_instance_events_outbox ON wa_instance_events;
+DROP TABLE IF EXISTS wa_instance_events;
+DELETE FROM wa_instances WHERE status = 'deleted';
+UPDATE wa_instances SET status = 'running'
+WHERE status IN ('provisioning', 'awaiting_pairing', 'connecting', 'online', 'disconnected');
+UPDATE wa_instances SET status = 'stopped' WHERE status <> 'running';
@@ -0,0 +1,27 @@
+-- Instances now follow a state machine. A started instance used to be
+-- 'running'; it is now provisioning until its session reports otherwise.
+UPDATE wa_instances SET status = 'provisioning' WHERE status = 'running';
+
+-- Every status change of an instance, with why and by whom.
+CREATE TABLE IF NOT EXISTS wa_instance_events (
+    id SERIAL PRIMARY KEY,
+    instance_id INTEGER NOT NULL REFERENCES wa_instances (id) ON DELETE CASCADE,
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    actor_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS wa_instance_events_instance_id ON wa_instance_events (instance_id);
+
+DROP TRIGGER IF EXISTS wa_instance_events_outbox ON wa_instance_events;
+CREATE TRIGGER wa_instance_events_outbox AFTER INSERT OR UPDATE OR DELETE ON wa_instance_events
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+-- Existing instances start their history where they are.
+INSERT INTO wa_instance_events (instance_id, from_status, to_status, reason, actor_id, created_at)
+SELECT id, NULL, status, 'migrated', NULL, EXTRACT(EPOCH FROM now())::BIGINT FROM wa_instances ORDER BY id;
+
+-- Superseded by the status of each instance.
+ALTER TABLE user_property DROP COLUMN IF EXISTS instance_status;
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS wa_instances_node_id;
+ALTER TABLE wa_instances DROP COLUMN IF EXISTS node_id;
+DROP TRIGGER IF EXISTS nodes_outbox ON nodes;
+DROP TABLE IF EXISTS nodes;
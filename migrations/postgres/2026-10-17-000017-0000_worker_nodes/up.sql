@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS nodes (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    region TEXT,
+    labels TEXT NOT NULL DEFAULT '{}',
+    token_prefix TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    capacity INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL DEFAULT 'offline',
+    agent_version TEXT,
+    created_at BIGINT NOT NULL,
+    connected_at BIGINT,
+    last_seen_at BIGINT
+);
+
+DROP TRIGGER IF EXISTS nodes_outbox ON nodes;
+CREATE TRIGGER nodes_outbox AFTER INSERT OR UPDATE OR DELETE ON nodes
+    FOR EACH ROW EXECUTE FUNCTION record_replication_outbox();
+
+ALTER TABLE wa_instances ADD COLUMN IF NOT EXISTS node_id INTEGER REFERENCES nodes (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS wa_instances_node_id ON wa_instances (node_id);
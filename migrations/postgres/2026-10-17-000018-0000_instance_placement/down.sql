@@ -0,0 +1,5 @@
+ALTER TABLE wa_instances DROP COLUMN IF EXISTS placed_at;
+ALTER TABLE wa_instances DROP COLUMN IF EXISTS placement;
+ALTER TABLE wa_instances DROP COLUMN IF EXISTS affinity;
+ALTER TABLE wa_instances DROP COLUMN IF EXISTS region;
+ALTER TABLE nodes DROP COLUMN IF EXISTS draining;
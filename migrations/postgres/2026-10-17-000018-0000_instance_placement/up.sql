@@ -0,0 +1,13 @@
+-- A draining node takes no new instances; the scheduler moves the ones it
+-- has to other nodes.
+ALTER TABLE nodes ADD COLUMN IF NOT EXISTS draining BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Where an instance may run: a region and labels its node must carry
+-- (a JSON object of strings).
+ALTER TABLE wa_instances ADD COLUMN IF NOT EXISTS region TEXT;
+ALTER TABLE wa_instances ADD COLUMN IF NOT EXISTS affinity TEXT NOT NULL DEFAULT '{}';
+
+-- The scheduler's last decision about the instance, in words, and when it
+-- was made.
+ALTER TABLE wa_instances ADD COLUMN IF NOT EXISTS placement TEXT;
+ALTER TABLE wa_instances ADD COLUMN IF NOT EXISTS placed_at BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE replication_outbox DROP COLUMN requeues;
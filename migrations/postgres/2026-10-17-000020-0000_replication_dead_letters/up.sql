@@ -0,0 +1,11 @@
+-- Outbox entries the worker cannot replay at all (a table it does not
+-- mirror), set aside so they don't hold up the queue behind them.
+CREATE TABLE IF NOT EXISTS replication_dead_letters (
+    id SERIAL PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    row_id INTEGER NOT NULL,
+    op TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    error TEXT NOT NULL,
+    dead_at BIGINT NOT NULL DEFAULT (extract(epoch FROM now())::BIGINT)
+);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS billing;
+DROP TABLE IF EXISTS instances;
+DROP TABLE IF EXISTS user_property;
+DROP TABLE IF EXISTS users;
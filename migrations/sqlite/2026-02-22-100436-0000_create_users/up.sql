@@ -12,6 +12,7 @@ CREATE TABLE IF NOT EXISTS user_property (
     user_id INTEGER NOT NULL,
     instance_status TEXT NOT NULL DEFAULT 'inactive',
     instance_usage REAL NOT NULL DEFAULT 0.0,
+    api_key_active INTEGER NOT NULL DEFAULT 0,
     FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
 );
 
@@ -0,0 +1,13 @@
+DROP TRIGGER IF EXISTS billing_outbox_delete;
+DROP TRIGGER IF EXISTS billing_outbox_update;
+DROP TRIGGER IF EXISTS billing_outbox_insert;
+DROP TRIGGER IF EXISTS instances_outbox_delete;
+DROP TRIGGER IF EXISTS instances_outbox_update;
+DROP TRIGGER IF EXISTS instances_outbox_insert;
+DROP TRIGGER IF EXISTS user_property_outbox_delete;
+DROP TRIGGER IF EXISTS user_property_outbox_update;
+DROP TRIGGER IF EXISTS user_property_outbox_insert;
+DROP TRIGGER IF EXISTS users_outbox_delete;
+DROP TRIGGER IF EXISTS users_outbox_update;
+DROP TRIGGER IF EXISTS users_outbox_insert;
+DROP TABLE IF EXISTS replication_outbox;
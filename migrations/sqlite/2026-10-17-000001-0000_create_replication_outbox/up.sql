@@ -0,0 +1,78 @@
+-- Every mutation of a replicated table is recorded here by the triggers
+-- below and replayed to Postgres in `id` order by the replication worker.
+CREATE TABLE IF NOT EXISTS replication_outbox (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    table_name TEXT NOT NULL,
+    row_id INTEGER NOT NULL,
+    op TEXT NOT NULL CHECK (op IN ('upsert', 'delete')),
+    created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT
+);
+
+CREATE TRIGGER IF NOT EXISTS users_outbox_insert AFTER INSERT ON users
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('users', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS users_outbox_update AFTER UPDATE ON users
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('users', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS users_outbox_delete AFTER DELETE ON users
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('users', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS user_property_outbox_insert AFTER INSERT ON user_property
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('user_property', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS user_property_outbox_update AFTER UPDATE ON user_property
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('user_property', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS user_property_outbox_delete AFTER DELETE ON user_property
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('user_property', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS instances_outbox_insert AFTER INSERT ON instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('instances', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS instances_outbox_update AFTER UPDATE ON instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('instances', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS instances_outbox_delete AFTER DELETE ON instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('instances', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS billing_outbox_insert AFTER INSERT ON billing
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('billing', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS billing_outbox_update AFTER UPDATE ON billing
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('billing', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS billing_outbox_delete AFTER DELETE ON billing
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('billing', OLD.id, 'delete');
+END;
+
+-- Backfill rows written before the outbox existed so the mirror converges.
+INSERT INTO replication_outbox (table_name, row_id, op) SELECT 'users', id, 'upsert' FROM users ORDER BY id;
+INSERT INTO replication_outbox (table_name, row_id, op) SELECT 'user_property', id, 'upsert' FROM user_property ORDER BY id;
+INSERT INTO replication_outbox (table_name, row_id, op) SELECT 'instances', id, 'upsert' FROM instances ORDER BY id;
+INSERT INTO replication_outbox (table_name, row_id, op) SELECT 'billing', id, 'upsert' FROM billing ORDER BY id;
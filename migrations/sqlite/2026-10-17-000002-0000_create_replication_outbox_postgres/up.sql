@@ -0,0 +1,2 @@
+-- Postgres-only: adds the outbox that replicates Postgres writes back to
+-- SQLite. Kept so both backends report the same schema version.
@@ -0,0 +1,8 @@
+DROP TRIGGER IF EXISTS refresh_tokens_outbox_delete;
+DROP TRIGGER IF EXISTS refresh_tokens_outbox_update;
+DROP TRIGGER IF EXISTS refresh_tokens_outbox_insert;
+DROP TRIGGER IF EXISTS sessions_outbox_delete;
+DROP TRIGGER IF EXISTS sessions_outbox_update;
+DROP TRIGGER IF EXISTS sessions_outbox_insert;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS sessions;
@@ -0,0 +1,61 @@
+-- One row per login. Access tokens carry the session id and are rejected
+-- once the session is revoked or expired.
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked_at INTEGER,
+    revoked_reason TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
+
+-- Rotating refresh tokens, stored as SHA-256 hashes. Every token issued for
+-- a session is kept until it expires so that presenting an already used one
+-- can be detected and the whole session revoked.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    session_id INTEGER NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY (session_id) REFERENCES sessions (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_session_id ON refresh_tokens (session_id);
+
+CREATE TRIGGER IF NOT EXISTS sessions_outbox_insert AFTER INSERT ON sessions
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('sessions', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS sessions_outbox_update AFTER UPDATE ON sessions
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('sessions', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS sessions_outbox_delete AFTER DELETE ON sessions
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('sessions', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS refresh_tokens_outbox_insert AFTER INSERT ON refresh_tokens
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('refresh_tokens', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS refresh_tokens_outbox_update AFTER UPDATE ON refresh_tokens
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('refresh_tokens', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS refresh_tokens_outbox_delete AFTER DELETE ON refresh_tokens
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('refresh_tokens', OLD.id, 'delete');
+END;
@@ -0,0 +1,7 @@
+DROP TRIGGER IF EXISTS passkeys_outbox_delete;
+DROP TRIGGER IF EXISTS passkeys_outbox_update;
+DROP TRIGGER IF EXISTS passkeys_outbox_insert;
+ALTER TABLE users ADD COLUMN passkey TEXT;
+ALTER TABLE users DROP COLUMN require_passkey;
+DROP TABLE IF EXISTS auth_challenges;
+DROP TABLE IF EXISTS passkeys;
@@ -0,0 +1,48 @@
+-- WebAuthn credentials. `public_key` is the credential's COSE_Key as
+-- base64url; `credential_id` is base64url as well.
+CREATE TABLE IF NOT EXISTS passkeys (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    credential_id TEXT NOT NULL UNIQUE,
+    public_key TEXT NOT NULL,
+    alg INTEGER NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_user_id ON passkeys (user_id);
+
+-- Single-use challenges for in-flight WebAuthn ceremonies and second-factor
+-- logins. Short-lived, so deliberately not replicated.
+CREATE TABLE IF NOT EXISTS auth_challenges (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    purpose TEXT NOT NULL,
+    challenge TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+ALTER TABLE users ADD COLUMN require_passkey INTEGER NOT NULL DEFAULT 0;
+
+-- Replaced by the passkeys table; the old column was never verified.
+ALTER TABLE users DROP COLUMN passkey;
+
+CREATE TRIGGER IF NOT EXISTS passkeys_outbox_insert AFTER INSERT ON passkeys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('passkeys', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS passkeys_outbox_update AFTER UPDATE ON passkeys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('passkeys', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS passkeys_outbox_delete AFTER DELETE ON passkeys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('passkeys', OLD.id, 'delete');
+END;
@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS recovery_codes_outbox_delete;
+DROP TRIGGER IF EXISTS recovery_codes_outbox_update;
+DROP TRIGGER IF EXISTS recovery_codes_outbox_insert;
+DROP TRIGGER IF EXISTS totp_credentials_outbox_delete;
+DROP TRIGGER IF EXISTS totp_credentials_outbox_update;
+DROP TRIGGER IF EXISTS totp_credentials_outbox_insert;
+ALTER TABLE auth_challenges DROP COLUMN attempts;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS totp_credentials;
@@ -0,0 +1,57 @@
+-- TOTP authenticator per user. `enabled_at` stays NULL until the user has
+-- proved the enrollment with a valid code. `last_used_step` is the latest
+-- accepted 30-second time step, so a code cannot be replayed.
+CREATE TABLE IF NOT EXISTS totp_credentials (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL UNIQUE,
+    secret TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    enabled_at INTEGER,
+    last_used_step INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- Single-use recovery codes, stored as SHA-256 hex.
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id ON recovery_codes (user_id);
+
+-- Wrong second-factor codes entered against an mfa_token.
+ALTER TABLE auth_challenges ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS totp_credentials_outbox_insert AFTER INSERT ON totp_credentials
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('totp_credentials', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS totp_credentials_outbox_update AFTER UPDATE ON totp_credentials
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('totp_credentials', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS totp_credentials_outbox_delete AFTER DELETE ON totp_credentials
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('totp_credentials', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS recovery_codes_outbox_insert AFTER INSERT ON recovery_codes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('recovery_codes', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS recovery_codes_outbox_update AFTER UPDATE ON recovery_codes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('recovery_codes', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS recovery_codes_outbox_delete AFTER DELETE ON recovery_codes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('recovery_codes', OLD.id, 'delete');
+END;
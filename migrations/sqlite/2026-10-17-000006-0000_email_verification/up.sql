@@ -0,0 +1,18 @@
+ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+
+-- One row per emailed verification or password-reset link. The link is a
+-- signed token naming `jti`; marking the row used makes it single-use.
+-- Short-lived, so deliberately not replicated.
+CREATE TABLE IF NOT EXISTS email_tokens (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    purpose TEXT NOT NULL,
+    jti TEXT NOT NULL UNIQUE,
+    email TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS email_tokens_user_id ON email_tokens (user_id);
@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS audit_log_outbox_delete;
+DROP TRIGGER IF EXISTS audit_log_outbox_update;
+DROP TRIGGER IF EXISTS audit_log_outbox_insert;
+DROP TABLE IF EXISTS audit_log;
+DROP TABLE IF EXISTS auth_throttles;
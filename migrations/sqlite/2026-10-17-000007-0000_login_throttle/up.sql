@@ -0,0 +1,42 @@
+-- Failed authentication attempts per account or client IP. A row outlives
+-- restarts so lockouts do; `locked_until` is set once the failures exceed
+-- the free allowance.
+CREATE TABLE IF NOT EXISTS auth_throttles (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at INTEGER NOT NULL,
+    locked_until INTEGER,
+    UNIQUE (scope, subject)
+);
+
+-- Security-relevant events. `user_id` is the account affected and
+-- `actor_id` whoever caused the event, when known. Rows are kept when the
+-- users they name are deleted.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    actor_id INTEGER,
+    user_id INTEGER,
+    action TEXT NOT NULL,
+    detail TEXT NOT NULL DEFAULT '{}',
+    ip TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_id ON audit_log (user_id);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_outbox_insert AFTER INSERT ON audit_log
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('audit_log', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_outbox_update AFTER UPDATE ON audit_log
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('audit_log', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_outbox_delete AFTER DELETE ON audit_log
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('audit_log', OLD.id, 'delete');
+END;
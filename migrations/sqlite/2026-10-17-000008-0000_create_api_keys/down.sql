@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS api_keys_outbox_delete;
+DROP TRIGGER IF EXISTS api_keys_outbox_update;
+DROP TRIGGER IF EXISTS api_keys_outbox_insert;
+DROP TABLE IF EXISTS api_keys;
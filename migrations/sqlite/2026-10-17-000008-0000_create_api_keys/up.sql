@@ -0,0 +1,36 @@
+-- Named API keys. Only a SHA-256 of each key is stored; `prefix` is its
+-- first characters, shown so users can tell keys apart. `scopes` and
+-- `allowed_ips` are space-separated; no `allowed_ips` means any address.
+-- The users.eakey values are moved here at startup and then retired.
+CREATE TABLE IF NOT EXISTS api_keys (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    allowed_ips TEXT,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    last_used_at INTEGER,
+    last_used_ip TEXT,
+    revoked_at INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS api_keys_user_id ON api_keys (user_id);
+
+CREATE TRIGGER IF NOT EXISTS api_keys_outbox_insert AFTER INSERT ON api_keys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('api_keys', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS api_keys_outbox_update AFTER UPDATE ON api_keys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('api_keys', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS api_keys_outbox_delete AFTER DELETE ON api_keys
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('api_keys', OLD.id, 'delete');
+END;
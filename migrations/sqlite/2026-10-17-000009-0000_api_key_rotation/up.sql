@@ -0,0 +1,9 @@
+-- Rotating a key keeps its id, name and scopes. The replaced key's hash
+-- moves to `previous_key_hash` and keeps working until
+-- `previous_expires_at`, so integrations can switch over without downtime.
+ALTER TABLE api_keys ADD COLUMN previous_key_hash TEXT;
+ALTER TABLE api_keys ADD COLUMN previous_prefix TEXT;
+ALTER TABLE api_keys ADD COLUMN previous_expires_at INTEGER;
+ALTER TABLE api_keys ADD COLUMN rotated_at INTEGER;
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_keys_previous_key_hash ON api_keys (previous_key_hash);
@@ -0,0 +1,5 @@
+-- `role` is one of user, support, admin. A suspended account cannot log
+-- in or use its API keys; `suspended_reason` is shown to operators only.
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN suspended_at INTEGER;
+ALTER TABLE users ADD COLUMN suspended_reason TEXT;
@@ -0,0 +1,3 @@
+-- Sessions opened by staff to act as the user. They have no refresh token
+-- and end when the impersonation token expires.
+ALTER TABLE sessions ADD COLUMN impersonator_id INTEGER REFERENCES users (id) ON DELETE SET NULL;
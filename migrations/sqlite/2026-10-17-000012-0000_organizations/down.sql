@@ -0,0 +1,17 @@
+DROP TRIGGER IF EXISTS org_invitations_outbox_delete;
+DROP TRIGGER IF EXISTS org_invitations_outbox_update;
+DROP TRIGGER IF EXISTS org_invitations_outbox_insert;
+DROP TRIGGER IF EXISTS org_members_outbox_delete;
+DROP TRIGGER IF EXISTS org_members_outbox_update;
+DROP TRIGGER IF EXISTS org_members_outbox_insert;
+DROP TRIGGER IF EXISTS organizations_outbox_delete;
+DROP TRIGGER IF EXISTS organizations_outbox_update;
+DROP TRIGGER IF EXISTS organizations_outbox_insert;
+ALTER TABLE sessions DROP COLUMN active_org_id;
+DROP INDEX IF EXISTS billing_org_id;
+DROP INDEX IF EXISTS instances_org_id;
+ALTER TABLE billing DROP COLUMN org_id;
+ALTER TABLE instances DROP COLUMN org_id;
+DROP TABLE IF EXISTS org_invitations;
+DROP TABLE IF EXISTS org_members;
+DROP TABLE IF EXISTS organizations;
@@ -0,0 +1,99 @@
+-- Organizations own instances and a wallet (`billing`). Every user has a
+-- personal organization, marked by `personal_user_id`; users created before
+-- organizations existed are given one at startup.
+CREATE TABLE IF NOT EXISTS organizations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    personal_user_id INTEGER UNIQUE,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (personal_user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- `role` is the member's role in the organization: owner, admin or member.
+CREATE TABLE IF NOT EXISTS org_members (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    org_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (org_id, user_id),
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS org_members_user_id ON org_members (user_id);
+
+-- Emailed invitations. The link is a signed token naming `jti`.
+CREATE TABLE IF NOT EXISTS org_invitations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    org_id INTEGER NOT NULL,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL,
+    invited_by INTEGER,
+    jti TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    accepted_at INTEGER,
+    accepted_by INTEGER,
+    revoked_at INTEGER,
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (invited_by) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (accepted_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS org_invitations_org_id ON org_invitations (org_id);
+
+-- `user_id` stays as the member who created the row.
+ALTER TABLE instances ADD COLUMN org_id INTEGER REFERENCES organizations (id) ON DELETE CASCADE;
+ALTER TABLE billing ADD COLUMN org_id INTEGER REFERENCES organizations (id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS instances_org_id ON instances (org_id);
+CREATE UNIQUE INDEX IF NOT EXISTS billing_org_id ON billing (org_id);
+
+-- The organization the session's access tokens act in; the personal one
+-- when NULL.
+ALTER TABLE sessions ADD COLUMN active_org_id INTEGER REFERENCES organizations (id) ON DELETE SET NULL;
+
+CREATE TRIGGER IF NOT EXISTS organizations_outbox_insert AFTER INSERT ON organizations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('organizations', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS organizations_outbox_update AFTER UPDATE ON organizations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('organizations', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS organizations_outbox_delete AFTER DELETE ON organizations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('organizations', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_members_outbox_insert AFTER INSERT ON org_members
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_members', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_members_outbox_update AFTER UPDATE ON org_members
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_members', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_members_outbox_delete AFTER DELETE ON org_members
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_members', OLD.id, 'delete');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_invitations_outbox_insert AFTER INSERT ON org_invitations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_invitations', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_invitations_outbox_update AFTER UPDATE ON org_invitations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_invitations', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS org_invitations_outbox_delete AFTER DELETE ON org_invitations
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('org_invitations', OLD.id, 'delete');
+END;
@@ -0,0 +1,3 @@
+-- When an account the user asked to delete will be removed; NULL unless a
+-- deletion is pending. Until then the user can log in and cancel it.
+ALTER TABLE users ADD COLUMN deletion_due_at INTEGER;
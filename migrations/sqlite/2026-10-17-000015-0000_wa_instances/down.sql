@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS wa_instances_outbox_delete;
+DROP TRIGGER IF EXISTS wa_instances_outbox_update;
+DROP TRIGGER IF EXISTS wa_instances_outbox_insert;
+DROP TABLE IF EXISTS wa_instances;
@@ -0,0 +1,35 @@
+-- One row per WhatsApp instance. The per-organization counters in
+-- `instances` are derived from these. `config` is a JSON object, and
+-- `run_secs` the running time of earlier runs, excluding the current one.
+CREATE TABLE IF NOT EXISTS wa_instances (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    org_id INTEGER NOT NULL,
+    created_by INTEGER,
+    name TEXT NOT NULL,
+    phone_number TEXT UNIQUE,
+    status TEXT NOT NULL,
+    config TEXT NOT NULL DEFAULT '{}',
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    stopped_at INTEGER,
+    run_secs INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (org_id) REFERENCES organizations (id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS wa_instances_org_id ON wa_instances (org_id);
+
+CREATE TRIGGER IF NOT EXISTS wa_instances_outbox_insert AFTER INSERT ON wa_instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instances', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS wa_instances_outbox_update AFTER UPDATE ON wa_instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instances', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS wa_instances_outbox_delete AFTER DELETE ON wa_instances
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instances', OLD.id, 'delete');
+END;
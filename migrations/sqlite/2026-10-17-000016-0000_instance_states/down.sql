@@ -0,0 +1,9 @@
+ALTER TABLE user_property ADD COLUMN instance_status TEXT NOT NULL DEFAULT 'inactive';
+DROP TRIGGER IF EXISTS wa_instance_events_outbox_delete;
+DROP TRIGGER IF EXISTS wa_instance_events_outbox_update;
+DROP TRIGGER IF EXISTS wa_instance_events_outbox_insert;
+DROP TABLE IF EXISTS wa_instance_events;
+DELETE FROM wa_instances WHERE status = 'deleted';
+UPDATE wa_instances SET status = 'running'
+WHERE status IN ('provisioning', 'awaiting_pairing', 'connecting', 'online', 'disconnected');
+UPDATE wa_instances SET status = 'stopped' WHERE status <> 'running';
@@ -0,0 +1,40 @@
+-- Instances now follow a state machine. A started instance used to be
+-- 'running'; it is now provisioning until its session reports otherwise.
+UPDATE wa_instances SET status = 'provisioning' WHERE status = 'running';
+
+-- Every status change of an instance, with why and by whom.
+CREATE TABLE IF NOT EXISTS wa_instance_events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    instance_id INTEGER NOT NULL,
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    actor_id INTEGER,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (instance_id) REFERENCES wa_instances (id) ON DELETE CASCADE,
+    FOREIGN KEY (actor_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS wa_instance_events_instance_id ON wa_instance_events (instance_id);
+
+CREATE TRIGGER IF NOT EXISTS wa_instance_events_outbox_insert AFTER INSERT ON wa_instance_events
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instance_events', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS wa_instance_events_outbox_update AFTER UPDATE ON wa_instance_events
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instance_events', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS wa_instance_events_outbox_delete AFTER DELETE ON wa_instance_events
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('wa_instance_events', OLD.id, 'delete');
+END;
+
+-- Existing instances start their history where they are.
+INSERT INTO wa_instance_events (instance_id, from_status, to_status, reason, actor_id, created_at)
+SELECT id, NULL, status, 'migrated', NULL, CAST(strftime('%s', 'now') AS INTEGER) FROM wa_instances ORDER BY id;
+
+-- Superseded by the status of each instance.
+ALTER TABLE user_property DROP COLUMN instance_status;
@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS wa_instances_node_id;
+ALTER TABLE wa_instances DROP COLUMN node_id;
+DROP TRIGGER IF EXISTS nodes_outbox_delete;
+DROP TRIGGER IF EXISTS nodes_outbox_update;
+DROP TRIGGER IF EXISTS nodes_outbox_insert;
+DROP TABLE IF EXISTS nodes;
@@ -0,0 +1,38 @@
+-- Machines that host instances. Each runs an agent that connects with the
+-- node's token, of which only the SHA-256 and a short prefix are kept.
+-- `labels` is a JSON object of strings; `capacity` is how many active
+-- instances the agent last said it can host.
+CREATE TABLE IF NOT EXISTS nodes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    region TEXT,
+    labels TEXT NOT NULL DEFAULT '{}',
+    token_prefix TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    capacity INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL DEFAULT 'offline',
+    agent_version TEXT,
+    created_at INTEGER NOT NULL,
+    connected_at INTEGER,
+    last_seen_at INTEGER
+);
+
+CREATE TRIGGER IF NOT EXISTS nodes_outbox_insert AFTER INSERT ON nodes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('nodes', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS nodes_outbox_update AFTER UPDATE ON nodes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('nodes', NEW.id, 'upsert');
+END;
+
+CREATE TRIGGER IF NOT EXISTS nodes_outbox_delete AFTER DELETE ON nodes
+BEGIN
+    INSERT INTO replication_outbox (table_name, row_id, op) VALUES ('nodes', OLD.id, 'delete');
+END;
+
+-- The node an instance runs on, or last ran on.
+ALTER TABLE wa_instances ADD COLUMN node_id INTEGER REFERENCES nodes (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS wa_instances_node_id ON wa_instances (node_id);
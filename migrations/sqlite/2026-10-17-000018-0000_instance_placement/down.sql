@@ -0,0 +1,5 @@
+ALTER TABLE wa_instances DROP COLUMN placed_at;
+ALTER TABLE wa_instances DROP COLUMN placement;
+ALTER TABLE wa_instances DROP COLUMN affinity;
+ALTER TABLE wa_instances DROP COLUMN region;
+ALTER TABLE nodes DROP COLUMN draining;
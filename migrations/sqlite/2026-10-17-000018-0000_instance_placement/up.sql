@@ -0,0 +1,13 @@
+-- A draining node takes no new instances; the scheduler moves the ones it
+-- has to other nodes.
+ALTER TABLE nodes ADD COLUMN draining INTEGER NOT NULL DEFAULT 0;
+
+-- Where an instance may run: a region and labels its node must carry
+-- (a JSON object of strings).
+ALTER TABLE wa_instances ADD COLUMN region TEXT;
+ALTER TABLE wa_instances ADD COLUMN affinity TEXT NOT NULL DEFAULT '{}';
+
+-- The scheduler's last decision about the instance, in words, and when it
+-- was made.
+ALTER TABLE wa_instances ADD COLUMN placement TEXT;
+ALTER TABLE wa_instances ADD COLUMN placed_at INTEGER;
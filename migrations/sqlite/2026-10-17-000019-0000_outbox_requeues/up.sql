@@ -0,0 +1,3 @@
+-- How often an entry was moved to the back of the queue to wait for rows it
+-- refers to. Kept apart from `attempts`, which drives the retry backoff.
+ALTER TABLE replication_outbox ADD COLUMN requeues INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- Outbox entries the worker cannot replay at all (a table it does not
+-- mirror), set aside so they don't hold up the queue behind them.
+CREATE TABLE IF NOT EXISTS replication_dead_letters (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    table_name TEXT NOT NULL,
+    row_id INTEGER NOT NULL,
+    op TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    dead_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
@@ -0,0 +1,381 @@
+//! Account self-service: data export and deletion.
+//!
+//! Deleting an account is not immediate. The request schedules it
+//! [`deletion_grace_secs`] ahead in `users.deletion_due_at`, during which
+//! the user can still log in and cancel. [`spawn`] runs the purge that
+//! removes accounts whose time has come; their sessions, keys, credentials,
+//! personal organization and its instances and wallet go with them through
+//! `ON DELETE CASCADE`. Shared organizations outlive the account: they pass
+//! to a remaining owner, or to the longest-standing member if there is none,
+//! and are only deleted when nobody else belongs to them.
+
+use crate::{
+    audit,
+    mailer::Email,
+    org::OrgRole,
+    session,
+    sql::{
+        DbConn, Orchestrator,
+        api_key::ApiKey,
+        audit::AuditEntry,
+        billing::Billing,
+        instance::Instance,
+        org::Organization,
+        passkey::Passkey,
+        session::Session,
+        transaction,
+        user::User,
+        user_property::UserProperty,
+        wa_instance::{WaInstance, WaInstanceEvent},
+        with_conn,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Cooling-off period before a requested deletion happens, unless
+/// `ACCOUNT_DELETION_GRACE_SECS` says otherwise (seconds).
+pub const DEFAULT_DELETION_GRACE_SECS: i64 = 14 * 24 * 60 * 60;
+
+pub fn deletion_grace_secs() -> i64 {
+    std::env::var("ACCOUNT_DELETION_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&s: &i64| s >= 0)
+        .unwrap_or(DEFAULT_DELETION_GRACE_SECS)
+}
+
+/// Everything stored about `uid`, as one JSON document. Secrets (password
+/// and key hashes, TOTP secrets) are left out. `None` if there is no such
+/// user.
+pub async fn export(db: &mut DbConn, uid: i32) -> QueryResult<Option<serde_json::Value>> {
+    use crate::schema::{
+        api_keys::dsl as k, audit_log::dsl as a, billing::dsl as b, instances::dsl as i, passkeys::dsl as p,
+        sessions::dsl as s, user_property::dsl as up, users::dsl as u, wa_instance_events::dsl as we,
+        wa_instances::dsl as w,
+    };
+
+    let Some(user) = with_conn!(*db, |c| {
+        u::users.find(uid).select(User::as_select()).first(c).await.optional()
+    })?
+    else {
+        return Ok(None);
+    };
+    let property: Vec<UserProperty> = with_conn!(*db, |c| {
+        up::user_property.filter(up::user_id.eq(uid)).select(UserProperty::as_select()).load(c).await
+    })?;
+    let instances: Vec<Instance> = with_conn!(*db, |c| {
+        i::instances.filter(i::user_id.eq(uid)).order(i::id.asc()).select(Instance::as_select()).load(c).await
+    })?;
+    let billing: Vec<Billing> = with_conn!(*db, |c| {
+        b::billing.filter(b::user_id.eq(uid)).order(b::id.asc()).select(Billing::as_select()).load(c).await
+    })?;
+    let sessions: Vec<Session> = with_conn!(*db, |c| {
+        s::sessions.filter(s::user_id.eq(uid)).order(s::id.asc()).select(Session::as_select()).load(c).await
+    })?;
+    let keys: Vec<ApiKey> = with_conn!(*db, |c| {
+        k::api_keys.filter(k::user_id.eq(uid)).order(k::id.asc()).select(ApiKey::as_select()).load(c).await
+    })?;
+    let passkeys: Vec<Passkey> = with_conn!(*db, |c| {
+        p::passkeys.filter(p::user_id.eq(uid)).order(p::id.asc()).select(Passkey::as_select()).load(c).await
+    })?;
+    let history: Vec<AuditEntry> = with_conn!(*db, |c| {
+        a::audit_log.filter(a::user_id.eq(uid)).order(a::id.asc()).select(AuditEntry::as_select()).load(c).await
+    })?;
+    let organizations = crate::org::memberships(db, uid).await?;
+    // WhatsApp instances they created, and all those of their personal
+    // organization, with every status change each went through.
+    let personal = crate::org::personal_id(db, uid).await?.unwrap_or(0);
+    let wa_instances: Vec<WaInstance> = with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::created_by.eq(uid).or(w::org_id.eq(personal)))
+            .order(w::id.asc())
+            .select(WaInstance::as_select())
+            .load(c)
+            .await
+    })?;
+    let wa_ids: Vec<i32> = wa_instances.iter().map(|w| w.id).collect();
+    let wa_events: Vec<WaInstanceEvent> = with_conn!(*db, |c| {
+        we::wa_instance_events
+            .filter(we::instance_id.eq_any(&wa_ids))
+            .order(we::id.asc())
+            .select(WaInstanceEvent::as_select())
+            .load(c)
+            .await
+    })?;
+
+    Ok(Some(serde_json::json!({
+        "exported_at": chrono::Utc::now().timestamp(),
+        "user": {
+            "id": user.id,
+            "username": user.username,
+            "email": user.email,
+            "email_verified_at": user.email_verified_at,
+            "role": user.role,
+            "require_passkey": user.require_passkey,
+            "suspended_at": user.suspended_at,
+            "suspended_reason": user.suspended_reason,
+            "deletion_due_at": user.deletion_due_at,
+        },
+        "user_property": property,
+        "organizations": organizations
+            .iter()
+            .map(|(o, m)| serde_json::json!({
+                "id": o.id,
+                "name": o.name,
+                "personal": o.personal_user_id.is_some(),
+                "role": m.role,
+                "joined_at": m.created_at,
+            }))
+            .collect::<Vec<_>>(),
+        "instances": instances,
+        "wa_instances": wa_instances,
+        "wa_instance_events": wa_events,
+        "billing": billing,
+        "sessions": sessions,
+        "api_keys": keys
+            .iter()
+            .map(|k| serde_json::json!({
+                "id": k.id,
+                "name": k.name,
+                "prefix": k.prefix,
+                "scopes": k.scopes,
+                "allowed_ips": k.allowed_ips,
+                "created_at": k.created_at,
+                "expires_at": k.expires_at,
+                "last_used_at": k.last_used_at,
+                "last_used_ip": k.last_used_ip,
+                "revoked_at": k.revoked_at,
+            }))
+            .collect::<Vec<_>>(),
+        "passkeys": passkeys
+            .iter()
+            .map(|p| serde_json::json!({
+                "id": p.id,
+                "name": p.name,
+                "created_at": p.created_at,
+                "last_used_at": p.last_used_at,
+            }))
+            .collect::<Vec<_>>(),
+        "history": history
+            .iter()
+            .map(|e| serde_json::json!({
+                "action": e.action,
+                "actor_id": e.actor_id,
+                "detail": serde_json::from_str::<serde_json::Value>(&e.detail).unwrap_or_default(),
+                "ip": e.ip,
+                "created_at": e.created_at,
+            }))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+/// Shared organizations that would be left without an owner: `uid` is
+/// their only owner and others belong to them. Deletion waits until
+/// ownership has been passed on.
+pub async fn sole_owned(db: &mut DbConn, uid: i32) -> QueryResult<Vec<Organization>> {
+    use crate::schema::{org_members::dsl as m, organizations::dsl as o};
+
+    let owned: Vec<Organization> = with_conn!(*db, |c| {
+        o::organizations
+            .inner_join(m::org_members)
+            .filter(m::user_id.eq(uid))
+            .filter(m::role.eq(OrgRole::Owner.as_str()))
+            .filter(o::personal_user_id.is_null())
+            .select(Organization::as_select())
+            .load(c)
+            .await
+    })?;
+
+    let mut blocking = Vec::new();
+    for org in owned {
+        let others: Vec<String> = with_conn!(*db, |c| {
+            m::org_members
+                .filter(m::org_id.eq(org.id))
+                .filter(m::user_id.ne(uid))
+                .select(m::role)
+                .load(c)
+                .await
+        })?;
+        if !others.is_empty() && !others.iter().any(|r| OrgRole::of(r) == OrgRole::Owner) {
+            blocking.push(org);
+        }
+    }
+    Ok(blocking)
+}
+
+/// Schedule `uid` for deletion at `due`, or cancel with `None`. Returns
+/// `false` if nothing changed.
+pub async fn set_deletion(db: &mut DbConn, uid: i32, due: Option<i64>) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+
+    let n = with_conn!(*db, |c| {
+        match due {
+            Some(_) => {
+                diesel::update(users.find(uid).filter(deletion_due_at.is_null()))
+                    .set(deletion_due_at.eq(due))
+                    .execute(c)
+                    .await
+            }
+            None => {
+                diesel::update(users.find(uid).filter(deletion_due_at.is_not_null()))
+                    .set(deletion_due_at.eq(None::<i64>))
+                    .execute(c)
+                    .await
+            }
+        }
+    })?;
+    Ok(n > 0)
+}
+
+/// What became of the shared organizations of a deleted account.
+#[derive(Debug, Default)]
+pub struct Handover {
+    /// Passed to another member, with the rows the user created in them.
+    pub transferred: Vec<i32>,
+    /// Deleted for having no other members.
+    pub deleted: Vec<i32>,
+}
+
+/// Delete `uid` now, handing over its shared organizations first. Returns
+/// `None` if the user no longer exists.
+pub async fn delete(db: &mut DbConn, uid: i32) -> QueryResult<Option<Handover>> {
+    use crate::schema::{
+        billing::dsl as b, instances::dsl as i, org_members::dsl as m, organizations::dsl as o, users::dsl as u,
+    };
+
+    // Close open sockets; the rows go with the user below.
+    session::revoke_all(db, uid, None, "account_deleted").await?;
+
+    transaction!(*db, |c| {
+        let exists: i64 = u::users.find(uid).count().get_result(c).await?;
+        if exists == 0 {
+            return Ok(None);
+        }
+
+        let shared: Vec<i32> = o::organizations
+            .inner_join(m::org_members)
+            .filter(m::user_id.eq(uid))
+            .filter(o::personal_user_id.is_null())
+            .select(o::id)
+            .load(c)
+            .await?;
+
+        let mut handover = Handover::default();
+        for org in shared {
+            let others: Vec<(i32, String)> = m::org_members
+                .filter(m::org_id.eq(org))
+                .filter(m::user_id.ne(uid))
+                .order(m::id.asc())
+                .select((m::user_id, m::role))
+                .load(c)
+                .await?;
+            let Some(&(first, _)) = others.as_slice().first() else {
+                diesel::delete(o::organizations.find(org)).execute(c).await?;
+                handover.deleted.push(org);
+                continue;
+            };
+            let heir = match others.iter().find(|(_, r)| OrgRole::of(r) == OrgRole::Owner) {
+                Some(&(owner, _)) => owner,
+                None => {
+                    diesel::update(m::org_members.filter(m::org_id.eq(org)).filter(m::user_id.eq(first)))
+                        .set(m::role.eq(OrgRole::Owner.as_str()))
+                        .execute(c)
+                        .await?;
+                    first
+                }
+            };
+            // The wallet and instances were created by someone; keep them
+            // from cascading away with that someone.
+            diesel::update(b::billing.filter(b::org_id.eq(org)).filter(b::user_id.eq(uid)))
+                .set(b::user_id.eq(heir))
+                .execute(c)
+                .await?;
+            diesel::update(i::instances.filter(i::org_id.eq(org)).filter(i::user_id.eq(uid)))
+                .set(i::user_id.eq(heir))
+                .execute(c)
+                .await?;
+            handover.transferred.push(org);
+        }
+
+        diesel::delete(u::users.find(uid)).execute(c).await?;
+        Ok(Some(handover))
+    })
+}
+
+/// Delete every account whose deletion is due. Returns how many were.
+pub async fn purge(orch: &Orchestrator) -> Result<usize, String> {
+    use crate::schema::users::dsl::*;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let now = chrono::Utc::now().timestamp();
+    let due: QueryResult<Vec<i32>> = with_conn!(db, |c| {
+        users
+            .filter(deletion_due_at.le(now))
+            .select(id)
+            .load(c)
+            .await
+    });
+
+    let mut deleted = 0;
+    for uid in due.map_err(|e| e.to_string())? {
+        match delete(&mut db, uid).await {
+            Ok(Some(handover)) => {
+                audit::record(
+                    &mut db,
+                    audit::ACCOUNT_DELETED,
+                    Some(uid),
+                    None,
+                    None,
+                    serde_json::json!({
+                        "orgs_transferred": handover.transferred,
+                        "orgs_deleted": handover.deleted,
+                    }),
+                )
+                .await;
+                info!(user_id = uid, "Account deleted.");
+                deleted += 1;
+            }
+            Ok(None) => {}
+            Err(e) => warn!(user_id = uid, "Could not delete account: {}", e),
+        }
+    }
+    Ok(deleted)
+}
+
+/// Start the purge of due deletions, every `ACCOUNT_PURGE_INTERVAL_SECS`
+/// (default 3600; `0` turns it off).
+pub fn spawn(orch: Arc<Orchestrator>) {
+    let interval: u64 = std::env::var("ACCOUNT_PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+    if interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(interval)).await;
+            if let Err(e) = purge(&orch).await {
+                warn!("Account purge skipped: {}", e);
+            }
+        }
+    });
+}
+
+/// A notice to `to` that something changed on `username`'s account.
+pub fn notice(to: &str, username: &str, subject: &str, what: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body: format!(
+            "Hi {},\n\n{}\n\nIf this wasn't you, reset your password straight away and contact support.\n",
+            username, what
+        ),
+    }
+}
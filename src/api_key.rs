@@ -0,0 +1,579 @@
+//! Named, scoped API keys.
+//!
+//! A user can hold several keys, each limited to a set of scopes and
+//! optionally to an expiry date and a list of client addresses. A key is
+//! shown once when created; only its SHA-256 and a short prefix are kept.
+//! Keys only work while the account's API access is enabled through
+//! billing (`user_property.api_key_active`).
+//!
+//! A key can be rotated: it gets a new value under the same id, name and
+//! scopes, and the old value keeps working for a grace period so
+//! integrations can switch over. Rotations are written to the audit log and
+//! announced in-process (see [`rotations`]) so the user's open WebSockets
+//! can be told. Revocations and switched-off API access are announced too
+//! (see [`disabled`]), so WebSockets opened with a key that no longer works
+//! are closed.
+//!
+//! Keys replace the single `users.eakey` of each account: at startup,
+//! [`import_legacy`] turns every remaining eakey into a key named
+//! "Default" with all scopes and retires the column value.
+
+use crate::{
+    audit,
+    auth::Claims,
+    sql::{
+        DbConn, Orchestrator,
+        api_key::{ApiKey, NewApiKey},
+        transaction, with_conn,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+use tracing::info;
+use validator::ValidationError;
+
+pub const INSTANCES_READ: &str = "instances:read";
+pub const INSTANCES_WRITE: &str = "instances:write";
+pub const MESSAGES_SEND: &str = "messages:send";
+pub const BILLING_READ: &str = "billing:read";
+
+/// Every scope a key can be granted.
+pub const SCOPES: [&str; 4] = [INSTANCES_READ, INSTANCES_WRITE, MESSAGES_SEND, BILLING_READ];
+
+/// Keys a user may hold at once, revoked ones excluded.
+pub const MAX_KEYS_PER_USER: i64 = 25;
+
+/// Prefix of every generated key, so leaked keys are easy to recognise.
+const KEY_PREFIX: &str = "ok_";
+
+/// Characters of a key kept in clear (`ok_` plus 8).
+const VISIBLE_CHARS: usize = 11;
+
+/// How often `last_used_at` is bumped for a key in active use.
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// How long a replaced key keeps working when the request does not say,
+/// unless `API_KEY_ROTATION_GRACE_SECS` is set (seconds).
+pub const DEFAULT_ROTATION_GRACE_SECS: i64 = 24 * 60 * 60;
+
+/// Longest grace period a rotation may ask for (seconds).
+pub const MAX_ROTATION_GRACE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Marks a `users.eakey` value that is no longer a key.
+const RETIRED_EAKEY: &str = "retired:";
+
+/// A request authenticated with an API key.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub key_id: i32,
+    pub user_id: i32,
+    pub username: String,
+    pub scopes: Vec<String>,
+    /// SHA-256 of the value presented, current or replaced.
+    pub key_hash: String,
+    /// When that value stops working on its own: the key's expiry, or the
+    /// end of the grace period if it was replaced by a rotation.
+    pub valid_until: Option<i64>,
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    /// Claims for code paths shared with session logins. API key callers
+    /// have no session, so `sid` is `None`.
+    pub fn claims(&self) -> Claims {
+        let now = chrono::Utc::now().timestamp() as usize;
+        Claims {
+            sub: self.user_id.to_string(),
+            username: self.username.clone(),
+            exp: now + crate::auth::ACCESS_TOKEN_EXPIRY_SECS as usize,
+            iat: now,
+            sid: None,
+            // Keys never carry staff privileges.
+            role: crate::auth::Role::User,
+            act: None,
+            org: None,
+        }
+    }
+}
+
+/// Why a key was refused.
+#[derive(Debug)]
+pub enum Rejection {
+    /// No such key. Counts as a failed attempt.
+    Unknown,
+    /// Revoked or expired.
+    Inactive,
+    /// The account's API access is switched off, or the account suspended.
+    Disabled,
+    /// Not allowed from the client's address.
+    IpNotAllowed,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Rejection {
+    fn from(e: diesel::result::Error) -> Self {
+        Rejection::Db(e)
+    }
+}
+
+/// A key given a new value, as announced to open WebSockets.
+#[derive(Debug, Clone)]
+pub struct Rotated {
+    pub user_id: i32,
+    pub key_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub previous_prefix: Option<String>,
+    /// When the old value stops working; `None` if it already has.
+    pub previous_expires_at: Option<i64>,
+}
+
+static ROTATIONS: LazyLock<broadcast::Sender<Rotated>> =
+    LazyLock::new(|| broadcast::channel(256).0);
+
+/// Subscribe to the keys rotated by this process from now on.
+pub fn rotations() -> broadcast::Receiver<Rotated> {
+    ROTATIONS.subscribe()
+}
+
+static DISABLED: LazyLock<broadcast::Sender<i32>> = LazyLock::new(|| broadcast::channel(256).0);
+
+/// Subscribe to the ids of users some of whose keys this process revoked
+/// or switched off from now on.
+pub fn disabled() -> broadcast::Receiver<i32> {
+    DISABLED.subscribe()
+}
+
+/// Tell open WebSockets of `uid` to check their keys again: some were
+/// revoked, or the account can no longer use them.
+pub fn announce_disabled(uid: i32) {
+    // No receivers just means no open sockets.
+    let _ = DISABLED.send(uid);
+}
+
+/// Grace period used when a rotation does not ask for one.
+pub fn default_grace_secs() -> i64 {
+    std::env::var("API_KEY_ROTATION_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_ROTATION_GRACE_SECS)
+        .clamp(0, MAX_ROTATION_GRACE_SECS)
+}
+
+/// What a user asks for when creating a key.
+pub struct Spec {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<i64>,
+    pub allowed_ips: Vec<String>,
+}
+
+/// Validation rule for requested scopes: at least one, all known.
+pub fn validate_scopes(scopes: &[String]) -> Result<(), ValidationError> {
+    if scopes.is_empty() {
+        return Err(ValidationError::new("required").with_message("At least one scope is required".into()));
+    }
+    if let Some(s) = scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return Err(ValidationError::new("scope").with_message(format!("Unknown scope '{}'", s).into()));
+    }
+    Ok(())
+}
+
+/// Validation rule for allowed addresses: IPs or CIDR ranges.
+pub fn validate_ranges(ranges: &[String]) -> Result<(), ValidationError> {
+    if let Some(r) = ranges.iter().find(|r| parse_range(r).is_none()) {
+        return Err(ValidationError::new("ip_range")
+            .with_message(format!("Invalid IP address or range '{}'", r).into()));
+    }
+    Ok(())
+}
+
+/// Validation rule for an expiry: in the future.
+pub fn validate_expiry(expires_at: i64) -> Result<(), ValidationError> {
+    if expires_at <= chrono::Utc::now().timestamp() {
+        return Err(ValidationError::new("expiry").with_message("Must be in the future".into()));
+    }
+    Ok(())
+}
+
+/// Create a key for `uid`. Returns the stored row and the key itself, which
+/// is not kept and cannot be shown again. `None` if the user already holds
+/// [`MAX_KEYS_PER_USER`] keys.
+pub async fn create(db: &mut DbConn, uid: i32, spec: &Spec) -> QueryResult<Option<(ApiKey, String)>> {
+    use crate::schema::api_keys::dsl::*;
+
+    let key = generate_key();
+    let mut granted = spec.scopes.clone();
+    granted.sort();
+    granted.dedup();
+    let row = NewApiKey {
+        user_id: uid,
+        name: spec.name.trim().to_string(),
+        prefix: key[..VISIBLE_CHARS].to_string(),
+        key_hash: hash_key(&key),
+        scopes: granted.join(" "),
+        allowed_ips: (!spec.allowed_ips.is_empty()).then(|| spec.allowed_ips.join(" ")),
+        created_at: chrono::Utc::now().timestamp(),
+        expires_at: spec.expires_at,
+    };
+
+    let created: Option<ApiKey> = transaction!(*db, |c| {
+        let held: i64 = api_keys
+            .filter(user_id.eq(uid))
+            .filter(revoked_at.is_null())
+            .count()
+            .get_result(c)
+            .await?;
+        if held >= MAX_KEYS_PER_USER {
+            return Ok(None);
+        }
+        diesel::insert_into(api_keys)
+            .values(&row)
+            .returning(ApiKey::as_returning())
+            .get_result(c)
+            .await
+            .map(Some)
+    })?;
+
+    Ok(created.map(|k| (k, key)))
+}
+
+/// Keys of `uid` that have not been revoked, newest first.
+pub async fn list(db: &mut DbConn, uid: i32) -> QueryResult<Vec<ApiKey>> {
+    use crate::schema::api_keys::dsl::*;
+
+    with_conn!(*db, |c| {
+        api_keys
+            .filter(user_id.eq(uid))
+            .filter(revoked_at.is_null())
+            .order(id.desc())
+            .select(ApiKey::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Revoke key `key_id` of `uid`. Returns `false` if there is no such
+/// active key.
+pub async fn revoke(db: &mut DbConn, uid: i32, key_id: i32) -> QueryResult<bool> {
+    use crate::schema::api_keys::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let n = with_conn!(*db, |c| {
+        diesel::update(api_keys.find(key_id).filter(user_id.eq(uid)).filter(revoked_at.is_null()))
+            .set(revoked_at.eq(now))
+            .execute(c)
+            .await
+    })?;
+    if n > 0 {
+        announce_disabled(uid);
+    }
+    Ok(n > 0)
+}
+
+/// Revoke every active key of `uid`. Returns how many were revoked.
+pub async fn revoke_all(db: &mut DbConn, uid: i32) -> QueryResult<usize> {
+    use crate::schema::api_keys::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let n = with_conn!(*db, |c| {
+        diesel::update(api_keys.filter(user_id.eq(uid)).filter(revoked_at.is_null()))
+            .set(revoked_at.eq(now))
+            .execute(c)
+            .await
+    })?;
+    if n > 0 {
+        announce_disabled(uid);
+    }
+    Ok(n)
+}
+
+/// Give key `key_id` of `uid` a new value. The current value keeps working
+/// for `grace_secs` more seconds, or stops at once if that is 0; a value
+/// replaced by an earlier rotation stops now. Returns the updated row and
+/// the new key, or `None` if there is no such active key.
+pub async fn rotate(
+    db: &mut DbConn,
+    uid: i32,
+    key_id: i32,
+    grace_secs: i64,
+    ip: Option<&str>,
+) -> QueryResult<Option<(ApiKey, String)>> {
+    use crate::schema::api_keys::dsl::*;
+
+    let key = generate_key();
+    let (new_hash, new_prefix) = (hash_key(&key), key[..VISIBLE_CHARS].to_string());
+    let now = chrono::Utc::now().timestamp();
+    let rotated: Option<ApiKey> = transaction!(*db, |c| {
+        let current: Option<ApiKey> = api_keys
+            .find(key_id)
+            .filter(user_id.eq(uid))
+            .filter(revoked_at.is_null())
+            .select(ApiKey::as_select())
+            .first(c)
+            .await
+            .optional()?;
+        let Some(current) = current.filter(|k| k.expires_at.is_none_or(|e| e > now)) else {
+            return Ok(None);
+        };
+
+        let overlap = grace_secs > 0;
+        diesel::update(api_keys.find(current.id))
+            .set((
+                key_hash.eq(&new_hash),
+                prefix.eq(&new_prefix),
+                previous_key_hash.eq(overlap.then_some(&current.key_hash)),
+                previous_prefix.eq(Some(&current.prefix)),
+                previous_expires_at.eq(overlap.then_some(now + grace_secs)),
+                rotated_at.eq(now),
+            ))
+            .returning(ApiKey::as_returning())
+            .get_result(c)
+            .await
+            .map(Some)
+    })?;
+    let Some(row) = rotated else {
+        return Ok(None);
+    };
+
+    audit::record(
+        db,
+        audit::API_KEY_ROTATED,
+        Some(uid),
+        Some(uid),
+        ip,
+        serde_json::json!({
+            "key_id": row.id,
+            "name": row.name,
+            "prefix": row.prefix,
+            "previous_prefix": row.previous_prefix,
+            "previous_expires_at": row.previous_expires_at,
+        }),
+    )
+    .await;
+    // No receivers just means no open sockets.
+    let _ = ROTATIONS.send(Rotated {
+        user_id: uid,
+        key_id: row.id,
+        name: row.name.clone(),
+        prefix: row.prefix.clone(),
+        previous_prefix: row.previous_prefix.clone(),
+        previous_expires_at: row.previous_expires_at,
+    });
+
+    Ok(Some((row, key)))
+}
+
+/// Check `key` presented from `ip` and record its use. A value replaced by
+/// a rotation is accepted until its grace period ends.
+pub async fn authenticate(db: &mut DbConn, key: &str, ip: &str) -> Result<Principal, Rejection> {
+    use crate::schema::api_keys::dsl as k;
+    use crate::schema::{user_property::dsl as prop, users::dsl as u};
+
+    let hashed = hash_key(key);
+    let found: Option<(ApiKey, String, bool, Option<i64>)> = with_conn!(*db, |c| {
+        k::api_keys
+            .inner_join(u::users)
+            .inner_join(prop::user_property.on(prop::user_id.eq(k::user_id)))
+            .filter(k::key_hash.eq(&hashed).or(k::previous_key_hash.eq(&hashed)))
+            .select((ApiKey::as_select(), u::username, prop::api_key_active, u::suspended_at))
+            .first(c)
+            .await
+            .optional()
+    })?;
+    let Some((row, name, active, suspended_at)) = found else {
+        return Err(Rejection::Unknown);
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let valid_until = usable(&row, &hashed, active, suspended_at, now)?;
+    if let Some(ranges) = &row.allowed_ips
+        && !ip_allowed(ranges, ip)
+    {
+        return Err(Rejection::IpNotAllowed);
+    }
+
+    if row.last_used_at.is_none_or(|t| t <= now - TOUCH_INTERVAL_SECS) {
+        with_conn!(*db, |c| {
+            diesel::update(k::api_keys.find(row.id))
+                .set((k::last_used_at.eq(now), k::last_used_ip.eq(ip)))
+                .execute(c)
+                .await
+        })?;
+    }
+
+    Ok(Principal {
+        key_id: row.id,
+        user_id: row.user_id,
+        username: name,
+        scopes: row.scopes.split_whitespace().map(str::to_string).collect(),
+        key_hash: hashed,
+        valid_until,
+    })
+}
+
+/// Check again that the key `principal` was authenticated with still
+/// works, for connections that outlive a single request. Returns when the
+/// value stops working on its own, as in [`Principal::valid_until`].
+pub async fn recheck(db: &mut DbConn, principal: &Principal) -> Result<Option<i64>, Rejection> {
+    use crate::schema::api_keys::dsl as k;
+    use crate::schema::{user_property::dsl as prop, users::dsl as u};
+
+    let found: Option<(ApiKey, bool, Option<i64>)> = with_conn!(*db, |c| {
+        k::api_keys
+            .find(principal.key_id)
+            .inner_join(u::users)
+            .inner_join(prop::user_property.on(prop::user_id.eq(k::user_id)))
+            .select((ApiKey::as_select(), prop::api_key_active, u::suspended_at))
+            .first(c)
+            .await
+            .optional()
+    })?;
+    let Some((row, active, suspended_at)) = found else {
+        return Err(Rejection::Unknown);
+    };
+    // Rotated twice since: the value is neither current nor previous.
+    if row.key_hash != principal.key_hash && row.previous_key_hash.as_ref() != Some(&principal.key_hash) {
+        return Err(Rejection::Inactive);
+    }
+    usable(&row, &principal.key_hash, active, suspended_at, chrono::Utc::now().timestamp())
+}
+
+/// The state checks shared by [`authenticate`] and [`recheck`] for key
+/// `row` presented as `hashed`: not revoked or expired, a replaced value
+/// still in its grace period, API access on and the account not
+/// suspended. Returns when the value stops working on its own.
+fn usable(row: &ApiKey, hashed: &str, active: bool, suspended_at: Option<i64>, now: i64) -> Result<Option<i64>, Rejection> {
+    if row.revoked_at.is_some() || row.expires_at.is_some_and(|e| e <= now) {
+        return Err(Rejection::Inactive);
+    }
+    let replaced = row.key_hash != hashed;
+    if replaced && row.previous_expires_at.is_none_or(|e| e <= now) {
+        return Err(Rejection::Inactive);
+    }
+    if !active || suspended_at.is_some() {
+        return Err(Rejection::Disabled);
+    }
+    let grace_ends = if replaced { row.previous_expires_at } else { None };
+    Ok(match (row.expires_at, grace_ends) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    })
+}
+
+/// Value stored in `users.eakey` for accounts whose key lives in
+/// `api_keys`. The column is unique, so each value is distinct.
+pub fn retired_eakey() -> String {
+    format!("{}{}", RETIRED_EAKEY, uuid::Uuid::new_v4())
+}
+
+/// Move every remaining `users.eakey` into `api_keys` as a key named
+/// "Default" with all scopes, so existing integrations keep working, and
+/// retire the plaintext value. Returns how many keys were imported.
+pub async fn import_legacy(orch: &Orchestrator) -> Result<usize, String> {
+    use crate::schema::api_keys::dsl as k;
+    use crate::schema::users::dsl as u;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let legacy: QueryResult<Vec<(i32, String)>> = with_conn!(db, |c| {
+        u::users
+            .filter(u::eakey.not_like(format!("{}%", RETIRED_EAKEY)))
+            .select((u::id, u::eakey))
+            .load(c)
+            .await
+    });
+    let legacy = legacy.map_err(|e| e.to_string())?;
+
+    let now = chrono::Utc::now().timestamp();
+    for (uid, old) in &legacy {
+        let row = NewApiKey {
+            user_id: *uid,
+            name: "Default".to_string(),
+            prefix: old.chars().take(VISIBLE_CHARS - KEY_PREFIX.len()).collect(),
+            key_hash: hash_key(old),
+            scopes: SCOPES.join(" "),
+            allowed_ips: None,
+            created_at: now,
+            expires_at: None,
+        };
+        let retired = retired_eakey();
+        let moved: QueryResult<()> = transaction!(db, |c| {
+            diesel::insert_into(k::api_keys).values(&row).execute(c).await?;
+            diesel::update(u::users.find(*uid).filter(u::eakey.eq(old)))
+                .set(u::eakey.eq(&retired))
+                .execute(c)
+                .await?;
+            Ok::<_, diesel::result::Error>(())
+        });
+        moved.map_err(|e| format!("user {}: {}", uid, e))?;
+    }
+
+    if !legacy.is_empty() {
+        info!("Moved {} legacy API key(s) to hashed storage.", legacy.len());
+    }
+    Ok(legacy.len())
+}
+
+/// SHA-256 hex of a key. Keys are high-entropy random values, so a fast
+/// unsalted hash is sufficient.
+pub(crate) fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// `ok_` followed by 32 random bytes in hex.
+fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::random();
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", KEY_PREFIX, hex)
+}
+
+/// Whether `ip` falls in any of the space-separated `ranges`.
+fn ip_allowed(ranges: &str, ip: &str) -> bool {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return false;
+    };
+    ranges
+        .split_whitespace()
+        .filter_map(parse_range)
+        .any(|(net, len)| in_range(ip, net, len))
+}
+
+/// `203.0.113.7`, `203.0.113.0/24` or `2001:db8::/32`.
+pub(crate) fn parse_range(range: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = match range.split_once('/') {
+        Some((a, l)) => (a.parse::<IpAddr>().ok()?, Some(l.parse::<u8>().ok()?)),
+        None => (range.parse::<IpAddr>().ok()?, None),
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let len = len.unwrap_or(max);
+    (len <= max).then_some((addr, len))
+}
+
+pub(crate) fn in_range(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    let (ip, net, bits) = match (ip, net) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+        // An IPv4 client seen through an IPv6 socket.
+        (IpAddr::V6(a), IpAddr::V4(b)) => match a.to_ipv4_mapped() {
+            Some(a) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+            None => return false,
+        },
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    let mask = u128::MAX << (bits - len as u32);
+    let mask = if bits == 32 { mask & 0xffff_ffff } else { mask };
+    ip & mask == net & mask
+}
@@ -0,0 +1,63 @@
+//! Audit log of security-relevant events.
+//!
+//! Entries are only ever appended. They name the affected account and,
+//! when known, the user who caused the event, and carry event-specific
+//! fields as a JSON object.
+
+use crate::sql::{DbConn, audit::NewAuditEntry, with_conn};
+use diesel_async::RunQueryDsl;
+use tracing::warn;
+
+pub const LOCKOUT_STARTED: &str = "lockout.started";
+pub const LOCKOUT_CLEARED: &str = "lockout.cleared";
+pub const API_KEY_ROTATED: &str = "api_key.rotated";
+pub const API_KEY_DEACTIVATED: &str = "api_key.deactivated";
+pub const ACCOUNT_SUSPENDED: &str = "account.suspended";
+pub const ACCOUNT_UNSUSPENDED: &str = "account.unsuspended";
+pub const ROLE_CHANGED: &str = "role.changed";
+pub const WALLET_ADJUSTED: &str = "wallet.adjusted";
+pub const IMPERSONATION_STARTED: &str = "impersonation.started";
+pub const IMPERSONATED_REQUEST: &str = "impersonation.request";
+pub const ORG_MEMBER_ADDED: &str = "org.member_added";
+pub const ORG_MEMBER_REMOVED: &str = "org.member_removed";
+pub const ORG_ROLE_CHANGED: &str = "org.role_changed";
+pub const ORG_DELETED: &str = "org.deleted";
+pub const PASSWORD_CHANGED: &str = "account.password_changed";
+pub const EMAIL_CHANGED: &str = "account.email_changed";
+pub const ACCOUNT_DELETION_SCHEDULED: &str = "account.deletion_scheduled";
+pub const ACCOUNT_DELETION_CANCELLED: &str = "account.deletion_cancelled";
+pub const ACCOUNT_DELETED: &str = "account.deleted";
+pub const INSTANCE_SUSPENDED: &str = "instance.suspended";
+pub const INSTANCE_UNSUSPENDED: &str = "instance.unsuspended";
+pub const NODE_CREATED: &str = "node.created";
+pub const NODE_REMOVED: &str = "node.removed";
+pub const NODE_DRAINED: &str = "node.drained";
+pub const NODE_UNDRAINED: &str = "node.undrained";
+
+/// Append an entry. Failures are logged rather than returned: a missing
+/// audit row should not undo the action it describes.
+pub async fn record(
+    db: &mut DbConn,
+    action: &str,
+    user_id: Option<i32>,
+    actor_id: Option<i32>,
+    ip: Option<&str>,
+    detail: serde_json::Value,
+) {
+    use crate::schema::audit_log::dsl;
+
+    let entry = NewAuditEntry {
+        actor_id,
+        user_id,
+        action: action.to_string(),
+        detail: detail.to_string(),
+        ip: ip.map(str::to_string),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let written = with_conn!(*db, |c| {
+        diesel::insert_into(dsl::audit_log).values(&entry).execute(c).await
+    });
+    if let Err(e) = written {
+        warn!(action = entry.action, "Could not write audit entry: {}", e);
+    }
+}
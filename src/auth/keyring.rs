@@ -0,0 +1,336 @@
+//! JWT signing key ring.
+//!
+//! Tokens are signed with the single *active* key and carry its id in the
+//! `kid` header. Verification looks the key up by `kid`, so keys can be
+//! rotated without logging everyone out: the previous key stays in the ring
+//! with a `retired_at` timestamp and keeps verifying tokens for
+//! `JWT_KEY_GRACE_SECS` (default 3600) afterwards.
+//!
+//! Keys come from a JSON manifest named by `JWT_KEYS_FILE`; paths in it are
+//! relative to the manifest:
+//!
+//! ```json
+//! {
+//!   "active": "2026-10",
+//!   "keys": [
+//!     { "kid": "2026-10", "alg": "EdDSA", "private_key": "2026-10.pem", "public_key": "2026-10.pub.pem" },
+//!     { "kid": "2026-07", "alg": "RS256", "public_key": "2026-07.pub.pem", "retired_at": 1790812800 },
+//!     { "kid": "legacy", "alg": "HS256", "secret_file": "legacy.secret", "retired_at": 1790812800 }
+//!   ]
+//! }
+//! ```
+//!
+//! Without a manifest, `JWT_SECRET` is used as a single HS256 key. With
+//! neither, startup fails unless `DEBUG_MODE` is on.
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+    errors::{Error, ErrorKind},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use simple_asn1::ASN1Block;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::{env, fs};
+use tracing::{info, warn};
+
+use super::Claims;
+
+const DEV_SECRET: &str = "orsta_default_secret_CHANGE_ME";
+
+static RING: OnceLock<KeyRing> = OnceLock::new();
+
+struct KeyRing {
+    active: usize,
+    keys: Vec<Key>,
+    grace_secs: i64,
+}
+
+struct Key {
+    kid: String,
+    alg: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    retired_at: Option<i64>,
+    /// Public JWK; `None` for HMAC keys, which must never be published.
+    jwk: Option<Value>,
+}
+
+impl Key {
+    fn accepts_at(&self, now: i64, grace_secs: i64) -> bool {
+        self.retired_at.is_none_or(|t| now <= t + grace_secs)
+    }
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    active: String,
+    keys: Vec<KeySpec>,
+}
+
+#[derive(Deserialize)]
+struct KeySpec {
+    kid: String,
+    alg: String,
+    private_key: Option<PathBuf>,
+    public_key: Option<PathBuf>,
+    secret_file: Option<PathBuf>,
+    retired_at: Option<i64>,
+}
+
+/// Load the key ring. Panics on any misconfiguration, and when no key is
+/// configured outside debug mode.
+pub fn init(debug_mode: bool) {
+    let grace_secs = env::var("JWT_KEY_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+
+    let (active, keys) = match env::var("JWT_KEYS_FILE").ok().filter(|s| !s.is_empty()) {
+        Some(path) => load_manifest(Path::new(&path)),
+        None => {
+            let secret = match env::var("JWT_SECRET").ok().filter(|s| !s.is_empty()) {
+                Some(s) => s,
+                None if debug_mode => {
+                    warn!("No JWT_KEYS_FILE or JWT_SECRET set — signing tokens with the insecure development secret.");
+                    DEV_SECRET.to_string()
+                }
+                None => panic!("No JWT signing key configured. Set JWT_KEYS_FILE or JWT_SECRET (or DEBUG_MODE=true for development)."),
+            };
+            (0, vec![hmac_key(secret_kid(secret.as_bytes()), secret.into_bytes(), None)])
+        }
+    };
+
+    info!(
+        "JWT key ring loaded: signing with '{}' ({:?}), {} key(s) total.",
+        keys[active].kid,
+        keys[active].alg,
+        keys.len()
+    );
+
+    let _ = RING.set(KeyRing {
+        active,
+        keys,
+        grace_secs,
+    });
+}
+
+fn ring() -> &'static KeyRing {
+    RING.get().expect("auth::keyring::init must run before tokens are issued")
+}
+
+/// Sign `claims` with the active key, naming it in the `kid` header.
+pub fn sign<T: Serialize>(claims: &T) -> Result<String, Error> {
+    let ring = ring();
+    let key = &ring.keys[ring.active];
+    let mut header = Header::new(key.alg);
+    header.kid = Some(key.kid.clone());
+    let encoding = key.encoding.as_ref().expect("active key has signing material");
+    encode(&header, claims, encoding)
+}
+
+/// Verify an access token against the key named by its `kid`, provided
+/// that key is current or still inside its grace window.
+pub fn verify(token: &str) -> Result<Claims, Error> {
+    decode_with(token, None)
+}
+
+/// Verify a token issued for `audience` (e.g. an emailed link). Tokens
+/// carrying an `aud` are never accepted by [`verify`], and vice versa.
+pub fn verify_for<T: DeserializeOwned>(token: &str, audience: &str) -> Result<T, Error> {
+    decode_with(token, Some(audience))
+}
+
+fn decode_with<T: DeserializeOwned>(token: &str, audience: Option<&str>) -> Result<T, Error> {
+    let ring = ring();
+    let kid = decode_header(token)?
+        .kid
+        .ok_or_else(|| Error::from(ErrorKind::InvalidToken))?;
+    let now = chrono::Utc::now().timestamp();
+    let key = ring
+        .keys
+        .iter()
+        .find(|k| k.kid == kid && k.accepts_at(now, ring.grace_secs))
+        .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
+
+    // Pin the algorithm to the key's own, never the header's.
+    // Without an expected audience, any token carrying `aud` is rejected.
+    let mut validation = Validation::new(key.alg);
+    if let Some(aud) = audience {
+        validation.set_audience(&[aud]);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+    }
+    let data = decode::<T>(token, &key.decoding, &validation)?;
+    Ok(data.claims)
+}
+
+/// Public keys that currently verify tokens, as a JWK Set.
+pub fn jwks() -> Value {
+    let ring = ring();
+    let now = chrono::Utc::now().timestamp();
+    let keys: Vec<&Value> = ring
+        .keys
+        .iter()
+        .filter(|k| k.accepts_at(now, ring.grace_secs))
+        .filter_map(|k| k.jwk.as_ref())
+        .collect();
+    json!({ "keys": keys })
+}
+
+// ---------------------------------------------------------------------------
+// Loading
+// ---------------------------------------------------------------------------
+
+fn load_manifest(path: &Path) -> (usize, Vec<Key>) {
+    let raw = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Cannot read JWT_KEYS_FILE {}: {}", path.display(), e));
+    let manifest: Manifest = serde_json::from_str(&raw)
+        .unwrap_or_else(|e| panic!("Invalid JWT key manifest {}: {}", path.display(), e));
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut keys = Vec::new();
+    for spec in manifest.keys {
+        if keys.iter().any(|k: &Key| k.kid == spec.kid) {
+            panic!("Duplicate JWT key id '{}'", spec.kid);
+        }
+        keys.push(load_key(spec, base));
+    }
+
+    let active = keys
+        .iter()
+        .position(|k| k.kid == manifest.active)
+        .unwrap_or_else(|| panic!("Active JWT key '{}' is not in the manifest", manifest.active));
+    if keys[active].retired_at.is_some() {
+        panic!("Active JWT key '{}' is marked as retired", manifest.active);
+    }
+    if keys[active].encoding.is_none() {
+        panic!("Active JWT key '{}' has no private key or secret", manifest.active);
+    }
+
+    (active, keys)
+}
+
+fn load_key(spec: KeySpec, base: &Path) -> Key {
+    let kid = spec.kid;
+    let read = |p: &PathBuf| {
+        let p = base.join(p);
+        fs::read(&p).unwrap_or_else(|e| panic!("JWT key '{}': cannot read {}: {}", kid, p.display(), e))
+    };
+    let bad = |what: &str, e: Error| -> ! { panic!("JWT key '{}': invalid {}: {}", kid, what, e) };
+
+    match spec.alg.as_str() {
+        "HS256" => {
+            let path = spec
+                .secret_file
+                .as_ref()
+                .unwrap_or_else(|| panic!("JWT key '{}': HS256 keys need `secret_file`", kid));
+            let secret = read(path).trim_ascii().to_vec();
+            if secret.is_empty() {
+                panic!("JWT key '{}': secret file is empty", kid);
+            }
+            hmac_key(kid.clone(), secret, spec.retired_at)
+        }
+        "RS256" | "EdDSA" => {
+            let alg = if spec.alg == "RS256" { Algorithm::RS256 } else { Algorithm::EdDSA };
+            let public_pem = read(
+                spec.public_key
+                    .as_ref()
+                    .unwrap_or_else(|| panic!("JWT key '{}': {} keys need `public_key`", kid, spec.alg)),
+            );
+
+            let (decoding, jwk) = match alg {
+                Algorithm::RS256 => (
+                    DecodingKey::from_rsa_pem(&public_pem).unwrap_or_else(|e| bad("public key", e)),
+                    rsa_jwk(&kid, &public_pem),
+                ),
+                _ => (
+                    DecodingKey::from_ed_pem(&public_pem).unwrap_or_else(|e| bad("public key", e)),
+                    ed25519_jwk(&kid, &public_pem),
+                ),
+            };
+            let encoding = spec.private_key.as_ref().map(|p| {
+                let pem = read(p);
+                match alg {
+                    Algorithm::RS256 => EncodingKey::from_rsa_pem(&pem),
+                    _ => EncodingKey::from_ed_pem(&pem),
+                }
+                .unwrap_or_else(|e| bad("private key", e))
+            });
+
+            Key {
+                jwk: Some(jwk.unwrap_or_else(|| panic!("JWT key '{}': public key is not a {} SPKI PEM", kid, spec.alg))),
+                kid,
+                alg,
+                encoding,
+                decoding,
+                retired_at: spec.retired_at,
+            }
+        }
+        other => panic!("JWT key '{}': unsupported alg '{}' (expected HS256, RS256 or EdDSA)", kid, other),
+    }
+}
+
+fn hmac_key(kid: String, secret: Vec<u8>, retired_at: Option<i64>) -> Key {
+    Key {
+        kid,
+        alg: Algorithm::HS256,
+        encoding: Some(EncodingKey::from_secret(&secret)),
+        decoding: DecodingKey::from_secret(&secret),
+        retired_at,
+        jwk: None,
+    }
+}
+
+/// Stable, non-reversible id for a bare `JWT_SECRET`, so changing the secret
+/// also changes the `kid`.
+fn secret_kid(secret: &[u8]) -> String {
+    Sha256::digest(secret)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The `subjectPublicKey` bit string of a PEM-encoded SubjectPublicKeyInfo.
+fn spki_key_bits(pem: &[u8]) -> Option<Vec<u8>> {
+    let der = pem::parse(pem).ok()?;
+    match simple_asn1::from_der(der.contents()).ok()?.first()? {
+        ASN1Block::Sequence(_, parts) => match parts.get(1)? {
+            ASN1Block::BitString(_, _, bits) => Some(bits.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn rsa_jwk(kid: &str, pem: &[u8]) -> Option<Value> {
+    let bits = spki_key_bits(pem)?;
+    let ASN1Block::Sequence(_, parts) = simple_asn1::from_der(&bits).ok()?.into_iter().next()? else {
+        return None;
+    };
+    let [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] = parts.as_slice() else {
+        return None;
+    };
+    Some(json!({
+        "kty": "RSA",
+        "use": "sig",
+        "alg": "RS256",
+        "kid": kid,
+        "n": URL_SAFE_NO_PAD.encode(n.to_bytes_be().1),
+        "e": URL_SAFE_NO_PAD.encode(e.to_bytes_be().1),
+    }))
+}
+
+fn ed25519_jwk(kid: &str, pem: &[u8]) -> Option<Value> {
+    let bits = spki_key_bits(pem).filter(|b| b.len() == 32)?;
+    Some(json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "use": "sig",
+        "alg": "EdDSA",
+        "kid": kid,
+        "x": URL_SAFE_NO_PAD.encode(bits),
+    }))
+}
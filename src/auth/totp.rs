@@ -0,0 +1,78 @@
+//! RFC 6238 time-based one-time passwords, as produced by Google
+//! Authenticator, 1Password, Aegis and friends: HMAC-SHA1, 6 digits,
+//! 30-second steps.
+
+use data_encoding::BASE32_NOPAD;
+use ring::hmac;
+
+pub const STEP_SECS: i64 = 30;
+const DIGITS: usize = 6;
+/// Codes from one step either side of the current one are accepted, to
+/// absorb clock drift and slow typing.
+const SKEW_STEPS: i64 = 1;
+
+/// A new 160-bit shared secret, base32 without padding.
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::random();
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// `otpauth://` URI for enrolling `secret`, to be rendered as a QR code.
+pub fn provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// Issuer shown in authenticator apps: `TOTP_ISSUER`, default `Orsta`.
+pub fn issuer() -> String {
+    std::env::var("TOTP_ISSUER")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "Orsta".to_string())
+}
+
+/// The time step `code` is valid for at `now`, if it is valid and later
+/// than `after_step` (the last step accepted, so codes cannot be replayed).
+pub fn verify(secret: &str, code: &str, now: i64, after_step: i64) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP_SECS;
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|&step| step > after_step)
+        .find(|&step| constant_time_eq(code_at(&key, step).as_bytes(), code.as_bytes()))
+}
+
+fn code_at(key: &[u8], step: i64) -> String {
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key),
+        &(step as u64).to_be_bytes(),
+    );
+    let mac = tag.as_ref();
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
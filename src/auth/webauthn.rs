@@ -0,0 +1,427 @@
+//! Minimal WebAuthn relying party.
+//!
+//! Builds the options for `navigator.credentials.create()` / `.get()` and
+//! verifies what the browser sends back: client data (type, challenge,
+//! origin), authenticator data (RP id hash, user presence/verification,
+//! signature counter) and, for assertions, the signature against the stored
+//! COSE public key. Attestation statements are not verified — we ask for
+//! `"attestation": "none"` and only need the credential's public key.
+//!
+//! Binary fields travel as base64url strings in both directions.
+//!
+//! | Variable           | Default                 |
+//! |--------------------|-------------------------|
+//! | `WEBAUTHN_RP_ID`   | `localhost`             |
+//! | `WEBAUTHN_RP_NAME` | `Orsta`                 |
+//! | `WEBAUTHN_ORIGINS` | `http://localhost:3000` |
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ciborium::Value as Cbor;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Cursor;
+use std::sync::OnceLock;
+
+/// COSE algorithm identifiers we accept, in order of preference.
+pub const ES256: i32 = -7;
+pub const EDDSA: i32 = -8;
+pub const RS256: i32 = -257;
+
+/// How long a ceremony may take (seconds).
+pub const CEREMONY_TIMEOUT_SECS: i64 = 300;
+
+const FLAG_UP: u8 = 0x01;
+const FLAG_UV: u8 = 0x04;
+const FLAG_AT: u8 = 0x40;
+
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origins: Vec<String>,
+}
+
+/// Relying party settings, read from the environment on first use.
+pub fn rp() -> &'static RelyingParty {
+    static RP: OnceLock<RelyingParty> = OnceLock::new();
+    RP.get_or_init(|| {
+        let var = |k: &str, d: &str| std::env::var(k).ok().filter(|v| !v.is_empty()).unwrap_or_else(|| d.to_string());
+        RelyingParty {
+            id: var("WEBAUTHN_RP_ID", "localhost"),
+            name: var("WEBAUTHN_RP_NAME", "Orsta"),
+            origins: var("WEBAUTHN_ORIGINS", "http://localhost:3000")
+                .split(',')
+                .map(|o| o.trim().trim_end_matches('/').to_string())
+                .filter(|o| !o.is_empty())
+                .collect(),
+        }
+    })
+}
+
+#[derive(Debug)]
+pub struct WebauthnError(pub &'static str);
+
+impl fmt::Display for WebauthnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+type Result<T> = std::result::Result<T, WebauthnError>;
+
+// ---------------------------------------------------------------------------
+// Wire types (PublicKeyCredential as JSON)
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+// ---------------------------------------------------------------------------
+// Options
+// ---------------------------------------------------------------------------
+
+/// A fresh random challenge, base64url.
+pub fn new_challenge() -> String {
+    let bytes: [u8; 32] = rand::random();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Options for `navigator.credentials.create()`.
+pub fn creation_options(challenge: &str, user_id: i32, username: &str, exclude: &[String]) -> Value {
+    let rp = rp();
+    let params: Vec<Value> = [ES256, EDDSA, RS256]
+        .iter()
+        .map(|alg| json!({ "type": "public-key", "alg": alg }))
+        .collect();
+    json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rp": { "id": rp.id, "name": rp.name },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user_id.to_be_bytes()),
+                "name": username,
+                "displayName": username,
+            },
+            "pubKeyCredParams": params,
+            "excludeCredentials": descriptors(exclude),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred",
+            },
+            "attestation": "none",
+            "timeout": CEREMONY_TIMEOUT_SECS * 1000,
+        }
+    })
+}
+
+/// Options for `navigator.credentials.get()`. An empty `allow` list lets
+/// the authenticator offer any discoverable credential for this RP.
+pub fn request_options(challenge: &str, allow: &[String], user_verification: &str) -> Value {
+    json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rpId": rp().id,
+            "allowCredentials": descriptors(allow),
+            "userVerification": user_verification,
+            "timeout": CEREMONY_TIMEOUT_SECS * 1000,
+        }
+    })
+}
+
+fn descriptors(ids: &[String]) -> Vec<Value> {
+    ids.iter()
+        .map(|id| json!({ "type": "public-key", "id": id }))
+        .collect()
+}
+
+// ---------------------------------------------------------------------------
+// Verification
+// ---------------------------------------------------------------------------
+
+/// The challenge a response claims to answer, so the caller can look up
+/// the ceremony before verifying the rest.
+pub fn challenge_of(client_data_json: &str) -> Result<String> {
+    Ok(parse_client_data(client_data_json)?.1.challenge)
+}
+
+pub struct VerifiedRegistration {
+    pub credential_id: String,
+    /// COSE_Key, base64url.
+    pub public_key: String,
+    pub alg: i32,
+    pub sign_count: u32,
+}
+
+/// Check a `navigator.credentials.create()` response against `challenge`.
+pub fn verify_registration(
+    cred: &RegistrationCredential,
+    challenge: &str,
+    require_uv: bool,
+) -> Result<VerifiedRegistration> {
+    let (_, client) = parse_client_data(&cred.response.client_data_json)?;
+    check_client_data(&client, "webauthn.create", challenge)?;
+
+    let att_bytes = b64(&cred.response.attestation_object)?;
+    let att: Cbor = ciborium::from_reader(att_bytes.as_slice()).map_err(|_| WebauthnError("Malformed attestation object"))?;
+    let auth_data = cbor_text_key(&att, "authData")
+        .and_then(|v| v.as_bytes())
+        .ok_or(WebauthnError("Attestation object has no authData"))?;
+
+    let parsed = parse_auth_data(auth_data)?;
+    check_auth_data(&parsed, require_uv)?;
+
+    let (credential_id, cose_key) = parsed
+        .attested
+        .ok_or(WebauthnError("No attested credential data"))?;
+    if URL_SAFE_NO_PAD.encode(&credential_id) != cred.id {
+        return Err(WebauthnError("Credential id mismatch"));
+    }
+
+    let key: Cbor = ciborium::from_reader(cose_key.as_slice()).map_err(|_| WebauthnError("Malformed credential public key"))?;
+    let alg = cose_alg(&key)?;
+    // Reject keys we could not verify with later.
+    CoseKey::parse(&key, alg)?;
+
+    Ok(VerifiedRegistration {
+        credential_id: cred.id.clone(),
+        public_key: URL_SAFE_NO_PAD.encode(&cose_key),
+        alg,
+        sign_count: parsed.sign_count,
+    })
+}
+
+pub struct VerifiedAssertion {
+    pub sign_count: u32,
+}
+
+/// Check a `navigator.credentials.get()` response against `challenge` and
+/// the stored credential.
+pub fn verify_assertion(
+    cred: &AssertionCredential,
+    challenge: &str,
+    public_key: &str,
+    alg: i32,
+    stored_sign_count: i64,
+    require_uv: bool,
+) -> Result<VerifiedAssertion> {
+    let (client_raw, client) = parse_client_data(&cred.response.client_data_json)?;
+    check_client_data(&client, "webauthn.get", challenge)?;
+
+    let auth_data = b64(&cred.response.authenticator_data)?;
+    let parsed = parse_auth_data(&auth_data)?;
+    check_auth_data(&parsed, require_uv)?;
+
+    let key: Cbor = ciborium::from_reader(b64(public_key)?.as_slice()).map_err(|_| WebauthnError("Stored key is corrupt"))?;
+    let mut signed = auth_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_raw));
+    CoseKey::parse(&key, alg)?.verify(&signed, &b64(&cred.response.signature)?)?;
+
+    // Authenticators that keep a counter must move it forward; going back
+    // suggests a cloned credential.
+    let new_count = parsed.sign_count as i64;
+    if (new_count != 0 || stored_sign_count != 0) && new_count <= stored_sign_count {
+        return Err(WebauthnError("Signature counter did not increase"));
+    }
+
+    Ok(VerifiedAssertion {
+        sign_count: parsed.sign_count,
+    })
+}
+
+fn b64(s: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .map_err(|_| WebauthnError("Invalid base64url"))
+}
+
+fn parse_client_data(encoded: &str) -> Result<(Vec<u8>, ClientData)> {
+    let raw = b64(encoded)?;
+    let data = serde_json::from_slice(&raw).map_err(|_| WebauthnError("Malformed clientDataJSON"))?;
+    Ok((raw, data))
+}
+
+fn check_client_data(client: &ClientData, kind: &str, challenge: &str) -> Result<()> {
+    if client.kind != kind {
+        return Err(WebauthnError("Wrong ceremony type"));
+    }
+    if client.challenge.trim_end_matches('=') != challenge {
+        return Err(WebauthnError("Challenge mismatch"));
+    }
+    if !rp().origins.contains(&client.origin) {
+        return Err(WebauthnError("Origin not allowed"));
+    }
+    Ok(())
+}
+
+struct AuthData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and COSE public key, present at registration.
+    attested: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn parse_auth_data(b: &[u8]) -> Result<AuthData> {
+    let short = WebauthnError("Truncated authenticator data");
+    if b.len() < 37 {
+        return Err(short);
+    }
+    let flags = b[32];
+    let mut parsed = AuthData {
+        rp_id_hash: b[..32].try_into().unwrap(),
+        flags,
+        sign_count: u32::from_be_bytes(b[33..37].try_into().unwrap()),
+        attested: None,
+    };
+
+    if flags & FLAG_AT != 0 {
+        // aaguid (16) | credential id length (2) | credential id | COSE key
+        let rest = b.get(37 + 16..).ok_or(WebauthnError("Truncated authenticator data"))?;
+        if rest.len() < 2 {
+            return Err(short);
+        }
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let id = rest.get(2..2 + len).ok_or(WebauthnError("Truncated authenticator data"))?;
+        let key_bytes = &rest[2 + len..];
+        let mut cursor = Cursor::new(key_bytes);
+        let _: Cbor = ciborium::from_reader(&mut cursor).map_err(|_| WebauthnError("Malformed credential public key"))?;
+        let key_len = cursor.position() as usize;
+        parsed.attested = Some((id.to_vec(), key_bytes[..key_len].to_vec()));
+    }
+
+    Ok(parsed)
+}
+
+fn check_auth_data(data: &AuthData, require_uv: bool) -> Result<()> {
+    if data.rp_id_hash[..] != Sha256::digest(rp().id.as_bytes())[..] {
+        return Err(WebauthnError("RP id mismatch"));
+    }
+    if data.flags & FLAG_UP == 0 {
+        return Err(WebauthnError("User presence not asserted"));
+    }
+    if require_uv && data.flags & FLAG_UV == 0 {
+        return Err(WebauthnError("User verification required"));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// COSE keys
+// ---------------------------------------------------------------------------
+
+enum CoseKey {
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl CoseKey {
+    fn parse(key: &Cbor, alg: i32) -> Result<Self> {
+        let bytes = |label: i64| {
+            cose_int_key(key, label)
+                .and_then(|v| v.as_bytes())
+                .cloned()
+                .ok_or(WebauthnError("Incomplete credential public key"))
+        };
+        let int = |label: i64| cose_int_key(key, label).and_then(cbor_i64);
+        let kty = int(1).ok_or(WebauthnError("Credential public key has no kty"))?;
+
+        match (alg, kty) {
+            (ES256, 2) if int(-1) == Some(1) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(WebauthnError("Invalid P-256 key"));
+                }
+                Ok(CoseKey::Es256([&[4u8][..], &x, &y].concat()))
+            }
+            (EDDSA, 1) if int(-1) == Some(6) => {
+                let x = bytes(-2)?;
+                if x.len() != 32 {
+                    return Err(WebauthnError("Invalid Ed25519 key"));
+                }
+                Ok(CoseKey::Ed25519(x))
+            }
+            (RS256, 3) => Ok(CoseKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            _ => Err(WebauthnError("Unsupported credential algorithm")),
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> Result<()> {
+        let ok = match self {
+            CoseKey::Es256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, sig)
+                .is_ok(),
+            CoseKey::Ed25519(x) => UnparsedPublicKey::new(&signature::ED25519, x)
+                .verify(message, sig)
+                .is_ok(),
+            CoseKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+        };
+        if ok { Ok(()) } else { Err(WebauthnError("Invalid signature")) }
+    }
+}
+
+fn cose_alg(key: &Cbor) -> Result<i32> {
+    cose_int_key(key, 3)
+        .and_then(cbor_i64)
+        .and_then(|a| i32::try_from(a).ok())
+        .ok_or(WebauthnError("Credential public key has no alg"))
+}
+
+fn cbor_i64(v: &Cbor) -> Option<i64> {
+    v.as_integer().and_then(|i| i64::try_from(i).ok())
+}
+
+fn cose_int_key(map: &Cbor, label: i64) -> Option<&Cbor> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| cbor_i64(k) == Some(label))
+        .map(|(_, v)| v)
+}
+
+fn cbor_text_key<'a>(map: &'a Cbor, label: &str) -> Option<&'a Cbor> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(label))
+        .map(|(_, v)| v)
+}
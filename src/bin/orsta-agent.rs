@@ -0,0 +1,210 @@
+//! Reference worker agent.
+//!
+//! Connects to an Orsta-Client server as one node and simulates the
+//! WhatsApp sessions of the instances placed on it: each started instance
+//! reports `awaiting_pairing`, `connecting` and then `online`, a step
+//! apart. It is meant for development and tests, and as a template for
+//! real agents.
+//!
+//! ```bash
+//! ORSTA_NODE_TOKEN=on_... orsta-agent --url ws://localhost:3000/agent --capacity 10
+//! ```
+//!
+//! | Flag         | Variable               | Default                     |
+//! | ------------ | ---------------------- | --------------------------- |
+//! | `--url`      | `ORSTA_AGENT_URL`      | `ws://localhost:3000/agent` |
+//! | `--token`    | `ORSTA_NODE_TOKEN`     | required                    |
+//! | `--capacity` | `ORSTA_AGENT_CAPACITY` | `10`                        |
+//! | `--step-ms`  | `ORSTA_AGENT_STEP_MS`  | `500`                       |
+//!
+//! An instance's `config` can steer the simulation with a `simulate` key:
+//! `"fail"` reports `failed` instead of pairing, `"pairing"` stays at
+//! `awaiting_pairing`, and `"crash"` goes online and then fails.
+//!
+//! The agent reconnects with a growing delay when the connection drops,
+//! and keeps its simulated sessions running meanwhile.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest, http::HeaderValue};
+use tracing::{info, warn};
+
+struct Options {
+    url: String,
+    token: String,
+    capacity: i32,
+    step: Duration,
+}
+
+fn options() -> Result<Options, String> {
+    let mut flags: HashMap<String, String> = HashMap::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let name = flag.strip_prefix("--").ok_or_else(|| format!("Unexpected argument '{}'", flag))?;
+        let value = args.next().ok_or_else(|| format!("Missing value for --{}", name))?;
+        flags.insert(name.to_string(), value);
+    }
+    let get = |flag: &str, var: &str| flags.get(flag).cloned().or_else(|| std::env::var(var).ok());
+    let number = |flag: &str, var: &str, default: u64| match get(flag, var) {
+        Some(v) => v.parse::<u64>().map_err(|_| format!("--{} must be a number", flag)),
+        None => Ok(default),
+    };
+    Ok(Options {
+        url: get("url", "ORSTA_AGENT_URL").unwrap_or_else(|| "ws://localhost:3000/agent".to_string()),
+        token: get("token", "ORSTA_NODE_TOKEN").ok_or("A node token is required (--token or ORSTA_NODE_TOKEN)")?,
+        capacity: number("capacity", "ORSTA_AGENT_CAPACITY", 10)? as i32,
+        step: Duration::from_millis(number("step-ms", "ORSTA_AGENT_STEP_MS", 500)?),
+    })
+}
+
+/// The simulated sessions, by instance id.
+type Sessions = HashMap<i32, JoinHandle<()>>;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt().with_target(false).with_ansi(std::io::stdout().is_terminal()).init();
+    let opts = match options() {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Status reports from the sessions, sent whenever connected.
+    let (reports, mut outbox) = mpsc::unbounded_channel::<Value>();
+    let mut sessions = Sessions::new();
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        match run(&opts, &reports, &mut outbox, &mut sessions).await {
+            Ok(()) => {
+                info!("Disconnected.");
+                delay = Duration::from_secs(1);
+            }
+            Err(e) => warn!("Connection failed: {}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = tokio::signal::ctrl_c() => return,
+        }
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+}
+
+/// One connection, from registering until it closes.
+async fn run(
+    opts: &Options,
+    reports: &mpsc::UnboundedSender<Value>,
+    outbox: &mut mpsc::UnboundedReceiver<Value>,
+    sessions: &mut Sessions,
+) -> Result<(), String> {
+    let mut request = opts.url.as_str().into_client_request().map_err(|e| e.to_string())?;
+    let bearer = HeaderValue::from_str(&format!("Bearer {}", opts.token)).map_err(|e| e.to_string())?;
+    request.headers_mut().insert("Authorization", bearer);
+    let (socket, _) = tokio_tungstenite::connect_async(request).await.map_err(|e| e.to_string())?;
+    let (mut sink, mut stream) = socket.split();
+
+    sessions.retain(|_, s| !s.is_finished());
+    let running: Vec<i32> = sessions.keys().copied().collect();
+    let hello = json!({
+        "action": "register",
+        "payload": {"capacity": opts.capacity, "version": env!("CARGO_PKG_VERSION"), "instances": running},
+    });
+    sink.send(Message::text(hello.to_string())).await.map_err(|e| e.to_string())?;
+
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(15));
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(frame))) => {
+                        info!("Closed by server: {}", frame.map(|f| f.reason.to_string()).unwrap_or_default());
+                        return Ok(());
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.to_string()),
+                    None => return Ok(()),
+                };
+                let Ok(msg) = serde_json::from_str::<Value>(&text) else { continue };
+                let payload = &msg["payload"];
+                match msg["action"].as_str().unwrap_or_default() {
+                    "registered" => {
+                        let secs = msg["data"]["heartbeat_secs"].as_u64().unwrap_or(15).max(1);
+                        info!(node_id = %msg["data"]["node_id"], "Registered; heartbeat every {}s.", secs);
+                        heartbeat = tokio::time::interval(Duration::from_secs(secs));
+                    }
+                    "start" => {
+                        let Some(id) = payload["id"].as_i64().map(|i| i as i32) else { continue };
+                        if sessions.get(&id).is_some_and(|s| !s.is_finished()) {
+                            continue;
+                        }
+                        info!(instance_id = id, "Starting.");
+                        let mode = payload["config"]["simulate"].as_str().unwrap_or_default().to_string();
+                        sessions.insert(id, tokio::spawn(simulate(id, mode, opts.step, reports.clone())));
+                    }
+                    "stop" => {
+                        let Some(id) = payload["id"].as_i64().map(|i| i as i32) else { continue };
+                        if let Some(session) = sessions.remove(&id) {
+                            info!(instance_id = id, "Stopping.");
+                            session.abort();
+                        }
+                    }
+                    _ => {
+                        if let Some(error) = msg["error"].as_str() {
+                            warn!("{}: {}", msg["action"].as_str().unwrap_or("error"), error);
+                        }
+                    }
+                }
+            }
+            Some(report) = outbox.recv() => {
+                sink.send(Message::text(report.to_string())).await.map_err(|e| e.to_string())?;
+            }
+            _ = heartbeat.tick() => {
+                sessions.retain(|_, s| !s.is_finished());
+                let running: Vec<i32> = sessions.keys().copied().collect();
+                let beat = json!({"action": "heartbeat", "payload": {"instances": running}});
+                sink.send(Message::text(beat.to_string())).await.map_err(|e| e.to_string())?;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                let _ = sink.send(Message::Close(None)).await;
+                std::process::exit(0);
+            }
+        }
+    }
+}
+
+/// Walk a simulated session through pairing to online, a `step` apart.
+async fn simulate(id: i32, mode: String, step: Duration, reports: mpsc::UnboundedSender<Value>) {
+    let report = |status: &str, reason: &str| {
+        let _ = reports.send(json!({
+            "action": "status",
+            "payload": {"id": id, "status": status, "reason": reason},
+        }));
+    };
+    tokio::time::sleep(step).await;
+    if mode == "fail" {
+        report("failed", "Simulated failure");
+        return;
+    }
+    report("awaiting_pairing", "Pairing code shown");
+    if mode == "pairing" {
+        return std::future::pending().await;
+    }
+    tokio::time::sleep(step).await;
+    report("connecting", "Paired");
+    tokio::time::sleep(step).await;
+    report("online", "Connected");
+    if mode == "crash" {
+        tokio::time::sleep(step).await;
+        report("failed", "Simulated crash");
+        return;
+    }
+    std::future::pending().await
+}
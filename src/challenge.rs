@@ -0,0 +1,128 @@
+//! Single-use challenges for multi-step logins.
+//!
+//! Each in-flight WebAuthn ceremony stores its challenge here, and so does
+//! a password login that still needs a second factor (the `mfa_token` handed
+//! to the client). Only a SHA-256 of the value is kept. A challenge is
+//! deleted when it is taken, so it can be answered once, and expires after
+//! a few minutes either way.
+
+use crate::sql::{
+    DbConn,
+    passkey::{AuthChallenge, NewAuthChallenge},
+    with_conn,
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use sha2::{Digest, Sha256};
+
+pub const PASSKEY_REGISTER: &str = "passkey_register";
+pub const PASSKEY_LOGIN: &str = "passkey_login";
+pub const MFA: &str = "mfa";
+
+/// How long a password login may wait for its second factor (seconds).
+pub const MFA_TOKEN_EXPIRY_SECS: i64 = 300;
+
+fn digest(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Store `value` as a challenge for `purpose`, valid for `ttl_secs`.
+pub async fn issue(
+    db: &mut DbConn,
+    user_id: Option<i32>,
+    purpose: &str,
+    value: &str,
+    ttl_secs: i64,
+) -> QueryResult<()> {
+    use crate::schema::auth_challenges::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        // Abandoned ceremonies are cleaned up as new ones start.
+        diesel::delete(dsl::auth_challenges.filter(dsl::expires_at.le(now)))
+            .execute(c)
+            .await?;
+
+        diesel::insert_into(dsl::auth_challenges)
+            .values(&NewAuthChallenge {
+                user_id,
+                purpose: purpose.to_string(),
+                challenge: digest(value),
+                created_at: now,
+                expires_at: now + ttl_secs,
+            })
+            .execute(c)
+            .await
+    })?;
+    Ok(())
+}
+
+/// Start a second-factor login for `user_id` and return its token.
+pub async fn issue_mfa_token(db: &mut DbConn, user_id: i32) -> QueryResult<String> {
+    let token = crate::auth::generate_refresh_token();
+    issue(db, Some(user_id), MFA, &token, MFA_TOKEN_EXPIRY_SECS).await?;
+    Ok(token)
+}
+
+/// Look up an unexpired challenge without consuming it.
+pub async fn peek(db: &mut DbConn, value: &str, purpose: &str) -> QueryResult<Option<AuthChallenge>> {
+    use crate::schema::auth_challenges::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    let hashed = digest(value);
+    with_conn!(*db, |c| {
+        dsl::auth_challenges
+            .filter(dsl::challenge.eq(&hashed))
+            .filter(dsl::purpose.eq(purpose))
+            .filter(dsl::expires_at.gt(now))
+            .select(AuthChallenge::as_select())
+            .first(c)
+            .await
+            .optional()
+    })
+}
+
+/// Consume a challenge. Returns `None` if it is unknown, was already used,
+/// was issued for another purpose, or has expired.
+pub async fn take(db: &mut DbConn, value: &str, purpose: &str) -> QueryResult<Option<AuthChallenge>> {
+    use crate::schema::auth_challenges::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    let hashed = digest(value);
+    let taken: Option<AuthChallenge> = with_conn!(*db, |c| {
+        diesel::delete(
+            dsl::auth_challenges
+                .filter(dsl::challenge.eq(&hashed))
+                .filter(dsl::purpose.eq(purpose)),
+        )
+        .returning(AuthChallenge::as_returning())
+        .get_result(c)
+        .await
+        .optional()
+    })?;
+    Ok(taken.filter(|t| t.expires_at > now))
+}
+
+/// Count a wrong answer against a challenge, discarding it once it has
+/// been answered wrongly `max_attempts` times.
+pub async fn record_failure(db: &mut DbConn, value: &str, purpose: &str, max_attempts: i32) -> QueryResult<()> {
+    use crate::schema::auth_challenges::dsl;
+
+    let hashed = digest(value);
+    let mine = dsl::auth_challenges
+        .filter(dsl::challenge.eq(&hashed))
+        .filter(dsl::purpose.eq(purpose));
+    with_conn!(*db, |c| {
+        diesel::update(mine)
+            .set(dsl::attempts.eq(dsl::attempts + 1))
+            .execute(c)
+            .await?;
+        diesel::delete(mine.filter(dsl::attempts.ge(max_attempts)))
+            .execute(c)
+            .await
+    })?;
+    Ok(())
+}
@@ -0,0 +1,219 @@
+//! Signed, single-use links for email verification, email changes and
+//! password reset.
+//!
+//! A link carries a JWT signed by the key ring whose audience is the link's
+//! purpose, so it is never accepted as an access token or for the other
+//! purpose. Its `jti` names a row in `email_tokens`: redeeming the link marks
+//! the row used, and sending a new link for the same purpose retires the
+//! older ones. The row also records the address the link went to, so a link
+//! stops working if the account's email changes in the meantime.
+
+use crate::{
+    auth::keyring,
+    mailer::{Email, Mailer},
+    sql::{
+        DbConn,
+        email_token::{EmailToken, NewEmailToken},
+        transaction, with_conn,
+        user::User,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    VerifyEmail,
+    /// Confirms a new address; the link is sent to that address.
+    ChangeEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "verify_email",
+            Purpose::ChangeEmail => "change_email",
+            Purpose::ResetPassword => "reset_password",
+        }
+    }
+
+    fn audience(self) -> String {
+        format!("orsta:{}", self.as_str())
+    }
+
+    /// How long a link stays valid (seconds).
+    fn expiry_secs(self) -> i64 {
+        match self {
+            Purpose::VerifyEmail | Purpose::ChangeEmail => 24 * 60 * 60,
+            Purpose::ResetPassword => 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LinkClaims {
+    sub: String,
+    aud: String,
+    jti: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Why a link was refused.
+#[derive(Debug)]
+pub enum RedeemError {
+    /// Malformed, forged, expired, already used or superseded.
+    Invalid,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for RedeemError {
+    fn from(e: diesel::result::Error) -> Self {
+        RedeemError::Db(e)
+    }
+}
+
+/// Create a link token for `user`, retiring earlier unused ones for the
+/// same purpose. The link is bound to `user.email`, so for
+/// [`Purpose::ChangeEmail`] pass the user with the new address.
+pub async fn issue(db: &mut DbConn, user: &User, purpose: Purpose) -> Result<String, String> {
+    use crate::schema::email_tokens::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = LinkClaims {
+        sub: user.id.to_string(),
+        aud: purpose.audience(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        iat: now,
+        exp: now + purpose.expiry_secs(),
+    };
+    let token = keyring::sign(&claims).map_err(|e| e.to_string())?;
+
+    let row = NewEmailToken {
+        user_id: user.id,
+        purpose: purpose.as_str().to_string(),
+        jti: claims.jti.clone(),
+        email: user.email.clone(),
+        created_at: now,
+        expires_at: claims.exp,
+    };
+    let stored: QueryResult<()> = transaction!(*db, |c| {
+        diesel::update(
+            dsl::email_tokens
+                .filter(dsl::user_id.eq(row.user_id))
+                .filter(dsl::purpose.eq(&row.purpose))
+                .filter(dsl::used_at.is_null()),
+        )
+        .set(dsl::used_at.eq(now))
+        .execute(c)
+        .await?;
+        // Expired rows are of no further use.
+        diesel::delete(dsl::email_tokens.filter(dsl::expires_at.le(now)))
+            .execute(c)
+            .await?;
+        diesel::insert_into(dsl::email_tokens)
+            .values(&row)
+            .execute(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(())
+    });
+    stored.map_err(|e| e.to_string())?;
+
+    Ok(token)
+}
+
+/// Check a link token and mark it used. Returns the ledger row, which names
+/// the user and the address the link was sent to.
+pub async fn redeem(db: &mut DbConn, token: &str, purpose: Purpose) -> Result<EmailToken, RedeemError> {
+    use crate::schema::email_tokens::dsl;
+
+    let claims: LinkClaims =
+        keyring::verify_for(token, &purpose.audience()).map_err(|_| RedeemError::Invalid)?;
+    let uid: i32 = claims.sub.parse().map_err(|_| RedeemError::Invalid)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let row: Option<EmailToken> = with_conn!(*db, |c| {
+        diesel::update(
+            dsl::email_tokens
+                .filter(dsl::jti.eq(&claims.jti))
+                .filter(dsl::user_id.eq(uid))
+                .filter(dsl::purpose.eq(purpose.as_str()))
+                .filter(dsl::used_at.is_null())
+                .filter(dsl::expires_at.gt(now)),
+        )
+        .set(dsl::used_at.eq(now))
+        .returning(EmailToken::as_returning())
+        .get_result(c)
+        .await
+        .optional()
+    })?;
+
+    row.ok_or(RedeemError::Invalid)
+}
+
+/// The email carrying a link for `purpose`.
+pub fn link_email(user: &User, purpose: Purpose, token: &str) -> Email {
+    let base = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let base = base.trim_end_matches('/');
+    match purpose {
+        Purpose::VerifyEmail => Email {
+            to: user.email.clone(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nPlease confirm your email address for Orsta by opening this link:\n\n{}/verify-email?token={}\n\nThe link is valid for 24 hours. If you did not create an account, you can ignore this email.\n",
+                user.username, base, token
+            ),
+        },
+        Purpose::ChangeEmail => Email {
+            to: user.email.clone(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Hi {},\n\nTo use this address for your Orsta account from now on, open this link:\n\n{}/confirm-email?token={}\n\nThe link is valid for 24 hours. Until then your account keeps its current address. If you did not ask for this, you can ignore this email.\n",
+                user.username, base, token
+            ),
+        },
+        Purpose::ResetPassword => Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password of your Orsta account. To choose a new one, open this link:\n\n{}/reset-password?token={}\n\nThe link is valid for 1 hour and can be used once. If this wasn't you, you can ignore this email; your password has not been changed.\n",
+                user.username, base, token
+            ),
+        },
+    }
+}
+
+/// Issue a link for `user` and mail it, without holding up the caller.
+/// Failures are logged.
+pub fn send_in_background(
+    orch: Arc<crate::sql::Orchestrator>,
+    mailer: Arc<dyn Mailer>,
+    user: User,
+    purpose: Purpose,
+) {
+    tokio::spawn(async move {
+        let mut db = match orch.conn().await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(user_id = user.id, "Could not send {} email: {}", purpose.as_str(), e);
+                return;
+            }
+        };
+        let token = match issue(&mut db, &user, purpose).await {
+            Ok(t) => t,
+            Err(e) => {
+                warn!(user_id = user.id, "Could not issue {} link: {}", purpose.as_str(), e);
+                return;
+            }
+        };
+        drop(db);
+
+        if let Err(e) = mailer.send(&link_email(&user, purpose, &token)).await {
+            warn!(user_id = user.id, "Could not send {} email: {}", purpose.as_str(), e);
+        }
+    });
+}
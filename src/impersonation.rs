@@ -0,0 +1,97 @@
+//! Staff impersonation.
+//!
+//! An admin can mint a short-lived token to act as a user and see what
+//! they see. The token names the admin in its `act` claim and belongs to a
+//! session of the user marked with the admin's id, so the user can find it
+//! in their session list and end it. Endpoints that charge money, show
+//! secrets or change account security refuse it (see
+//! [`AccountOwner`](crate::auth::AccountOwner)), and [`track`] writes every
+//! request made with it to the audit log.
+
+use crate::{
+    audit,
+    auth::{Role, extract_token, validate_token},
+    session::ClientInfo,
+    sql::{Orchestrator, with_conn},
+};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// How long an impersonation token lasts when the admin does not say
+/// (seconds).
+pub const DEFAULT_IMPERSONATION_SECS: i64 = 15 * 60;
+
+/// Longest impersonation an admin may ask for (seconds).
+pub const MAX_IMPERSONATION_SECS: i64 = 60 * 60;
+
+/// Middleware: audit every request made with an impersonation token, and
+/// refuse it once the acting staff member is no longer an admin.
+pub async fn track(
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let claims = extract_token(request.headers()).and_then(|t| validate_token(&t).ok());
+    let Some((claims, admin_id)) = claims.and_then(|c| c.impersonator().map(|a| (c, a))) else {
+        return next.run(request).await;
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"})))
+                .into_response();
+        }
+    };
+    let still_admin: QueryResult<Option<(String, Option<i64>)>> = with_conn!(db, |c| {
+        use crate::schema::users::dsl::*;
+        users.find(admin_id).select((role, suspended_at)).first(c).await.optional()
+    });
+    // Hand the connection back while the request runs.
+    drop(db);
+    match still_admin {
+        Ok(Some((r, None))) if Role::of(&r) == Role::Admin => {}
+        Ok(_) => {
+            return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Impersonation ended"}))).into_response();
+        }
+        Err(_) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"})))
+                .into_response();
+        }
+    }
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let ip = ClientInfo::new(request.headers(), peer).ip;
+    let response = next.run(request).await;
+
+    if let Ok(mut db) = orch.conn().await {
+        audit::record(
+            &mut db,
+            audit::IMPERSONATED_REQUEST,
+            claims.sub.parse().ok(),
+            Some(admin_id),
+            ip.as_deref(),
+            serde_json::json!({
+                "sid": claims.sid,
+                "method": method,
+                "path": path,
+                "status": response.status().as_u16(),
+            }),
+        )
+        .await;
+    } else {
+        tracing::warn!(admin_id, path, "Could not audit an impersonated request: database unavailable.");
+    }
+    response
+}
@@ -0,0 +1,617 @@
+//! WhatsApp instances.
+//!
+//! Each instance is a `wa_instances` row owned by an organization: one
+//! WhatsApp number with its name, configuration and [`Status`]. Any member
+//! of the organization may manage its instances.
+//!
+//! Statuses change only through [`apply`], which checks the change against
+//! the state machine (see [`Status::next`]) and records it in the instance's
+//! event history (`wa_instance_events`) with a reason, who asked for it and
+//! when. Deleting an instance moves it to [`Status::Deleted`], which is
+//! final; the row and its history are kept, but it no longer counts towards
+//! the organization's instances and its phone number is free again.
+//!
+//! The organization's `instances` row holds counters derived from these
+//! rows and is brought up to date in the same transaction as every change:
+//! `instances_count` is the number of instances, `expected_consumption` the
+//! hourly cost of the active ones, and `instances_overall_consumption` the
+//! cost of every finished run, which is booked when the run ends so it
+//! survives the instance. A run lasts from the moment an instance becomes
+//! active until it stops, fails, is suspended or deleted, or is restarted.
+//! Running time is priced at `INSTANCE_HOURLY_RATE` per hour (default `0`,
+//! free).
+//!
+//! Each run is placed on a worker node when it starts, as far as one has
+//! room, in the instance's region and with its labels (see
+//! [`crate::scheduler`]).
+//!
+//! Changes are announced on a broadcast channel (see [`changes`]) so open
+//! WebSockets of the organization's members, and the nodes running the
+//! instances, can follow them.
+
+use crate::sql::{
+    DbConn,
+    instance::NewInstance,
+    transaction, with_conn,
+    wa_instance::{NewWaInstance, NewWaInstanceEvent, WaInstance, WaInstanceEvent},
+};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+use validator::ValidationError;
+
+/// Most instances one organization may hold, deleted ones aside.
+pub const MAX_INSTANCES_PER_ORG: i64 = 100;
+
+/// Largest configuration object accepted (bytes of JSON).
+pub const MAX_CONFIG_BYTES: usize = 16 * 1024;
+
+/// Longest reason accepted for a status change.
+pub const MAX_REASON_LEN: u64 = 500;
+
+/// Price of one hour of running time, from `INSTANCE_HOURLY_RATE`.
+pub fn hourly_rate() -> f64 {
+    std::env::var("INSTANCE_HOURLY_RATE")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|r| r.is_finite() && *r >= 0.0)
+        .unwrap_or(0.0)
+}
+
+// ---------------------------------------------------------------------------
+// State machine
+// ---------------------------------------------------------------------------
+
+/// Where an instance is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Started; its session is being set up.
+    Provisioning,
+    /// Waiting for the phone to scan the pairing code.
+    AwaitingPairing,
+    /// Paired and connecting to WhatsApp.
+    Connecting,
+    Online,
+    /// Lost its connection; expected to reconnect.
+    Disconnected,
+    /// Taken offline by an operator; only they can lift it.
+    Suspended,
+    Stopped,
+    /// Gave up; can be started again.
+    Failed,
+    /// Gone for good.
+    Deleted,
+}
+
+use Status::*;
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Provisioning => "provisioning",
+            AwaitingPairing => "awaiting_pairing",
+            Connecting => "connecting",
+            Online => "online",
+            Disconnected => "disconnected",
+            Suspended => "suspended",
+            Stopped => "stopped",
+            Failed => "failed",
+            Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Status> {
+        [Provisioning, AwaitingPairing, Connecting, Online, Disconnected, Suspended, Stopped, Failed, Deleted]
+            .into_iter()
+            .find(|s| s.as_str() == value)
+    }
+
+    /// The status stored in a row. Values this build does not know are
+    /// treated as failed, which can only be left by starting over.
+    pub fn of(stored: &str) -> Status {
+        Status::parse(stored).unwrap_or(Failed)
+    }
+
+    /// Whether the instance is running, whatever the state of its session.
+    pub fn is_active(self) -> bool {
+        matches!(self, Provisioning | AwaitingPairing | Connecting | Online | Disconnected)
+    }
+
+    /// Whether an instance's session may report this status itself (see
+    /// [`Op::Report`]); the others follow from what members and operators
+    /// ask for.
+    pub fn is_reportable(self) -> bool {
+        matches!(self, AwaitingPairing | Connecting | Online | Disconnected | Failed)
+    }
+
+    /// The statuses an instance may move to from this one.
+    pub fn next(self) -> &'static [Status] {
+        match self {
+            // Every active status may become disconnected: a process can
+            // crash before its session ever connected, and again while down.
+            Provisioning => {
+                &[AwaitingPairing, Connecting, Disconnected, Failed, Provisioning, Stopped, Suspended, Deleted]
+            }
+            AwaitingPairing => &[Connecting, Disconnected, Failed, Provisioning, Stopped, Suspended, Deleted],
+            Connecting => &[Online, AwaitingPairing, Disconnected, Failed, Provisioning, Stopped, Suspended, Deleted],
+            Online => &[Disconnected, AwaitingPairing, Failed, Provisioning, Stopped, Suspended, Deleted],
+            Disconnected => {
+                &[Connecting, Online, AwaitingPairing, Disconnected, Failed, Provisioning, Stopped, Suspended, Deleted]
+            }
+            Stopped => &[Provisioning, Suspended, Deleted],
+            Failed => &[Provisioning, Stopped, Suspended, Deleted],
+            // Not deleted while suspended: that would free its number.
+            Suspended => &[Stopped],
+            Deleted => &[],
+        }
+    }
+
+    pub fn can_become(self, to: Status) -> bool {
+        self.next().contains(&to)
+    }
+}
+
+/// Stored names of the active statuses, for queries.
+pub(crate) const ACTIVE: [&str; 5] = ["provisioning", "awaiting_pairing", "connecting", "online", "disconnected"];
+
+/// Validation rule for statuses reported by an instance's session.
+pub fn validate_reported_status(value: &str) -> Result<(), ValidationError> {
+    match Status::parse(value) {
+        Some(s) if s.is_reportable() => Ok(()),
+        _ => Err(ValidationError::new("status").with_message(
+            "Must be one of awaiting_pairing, connecting, online, disconnected or failed".into(),
+        )),
+    }
+}
+
+/// Something asked of an existing instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Start a stopped or failed instance.
+    Start,
+    /// Stop an active or failed instance.
+    Stop,
+    /// End the current run of an active instance and start a new one.
+    Restart,
+    /// Delete an instance, ending its run if it has one.
+    Delete,
+    /// Take an instance offline until an operator lifts it.
+    Suspend,
+    /// Lift a suspension, leaving the instance stopped.
+    Unsuspend,
+    /// Record a status reported by the instance's session.
+    Report(Status),
+    /// End the current run of an active instance and start a new one on
+    /// whichever node the scheduler picks.
+    Reschedule,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Start => "start",
+            Op::Stop => "stop",
+            Op::Restart => "restart",
+            Op::Delete => "delete",
+            Op::Suspend => "suspend",
+            Op::Unsuspend => "unsuspend",
+            Op::Report(_) => "report",
+            Op::Reschedule => "reschedule",
+        }
+    }
+
+    /// Where the operation takes an instance that is `from`, if it applies.
+    fn target(self, from: Status) -> Option<Status> {
+        let to = match self {
+            Op::Start if !from.is_active() => Provisioning,
+            Op::Restart | Op::Reschedule if from.is_active() => Provisioning,
+            // Only an operator ends a suspension.
+            Op::Stop if from != Suspended => Stopped,
+            Op::Unsuspend if from == Suspended => Stopped,
+            Op::Delete => Deleted,
+            Op::Suspend => Suspended,
+            Op::Report(to) if to.is_reportable() => to,
+            _ => return None,
+        };
+        from.can_become(to).then_some(to)
+    }
+
+    /// The reason recorded when none is given.
+    fn default_reason(self) -> String {
+        match self {
+            Op::Report(to) => format!("Reported {}", to.as_str()),
+            op => format!("{} requested", op.name()),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Errors and notifications
+// ---------------------------------------------------------------------------
+
+/// Why an instance operation was refused.
+#[derive(Debug)]
+pub enum Error {
+    /// No such instance in the organization.
+    NotFound,
+    /// The state machine does not allow the operation from `from`.
+    IllegalTransition { from: Status, op: Op },
+    /// The organization already holds [`MAX_INSTANCES_PER_ORG`] instances.
+    LimitReached,
+    /// The phone number belongs to another instance.
+    PhoneTaken,
+    Db(DieselError),
+}
+
+impl Error {
+    /// What went wrong, in words for the client.
+    pub fn describe(&self) -> String {
+        match self {
+            Error::NotFound => "Instance not found".to_string(),
+            Error::IllegalTransition { from, op: Op::Report(to) } => {
+                format!("Cannot move an instance from '{}' to '{}'", from.as_str(), to.as_str())
+            }
+            Error::IllegalTransition { from, op } => {
+                format!("Cannot {} an instance that is '{}'", op.name(), from.as_str())
+            }
+            Error::LimitReached => format!("An organization can hold at most {} instances", MAX_INSTANCES_PER_ORG),
+            Error::PhoneTaken => "Phone number is already in use".to_string(),
+            Error::Db(_) => "Instance operation failed".to_string(),
+        }
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(e: DieselError) -> Self {
+        Error::Db(e)
+    }
+}
+
+/// A change to an instance, as announced to WebSockets.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub org_id: i32,
+    /// The instance after the change.
+    pub instance: WaInstance,
+    /// The history entry the change wrote.
+    pub event: WaInstanceEvent,
+    /// The node it was placed on before the change.
+    pub previous_node_id: Option<i32>,
+}
+
+static CHANGES: LazyLock<broadcast::Sender<Change>> = LazyLock::new(|| broadcast::channel(256).0);
+
+/// Subscribe to the instance changes made by this process from now on.
+pub fn changes() -> broadcast::Receiver<Change> {
+    CHANGES.subscribe()
+}
+
+fn announce(instance: &WaInstance, event: &WaInstanceEvent, previous_node_id: Option<i32>) {
+    let _ = CHANGES.send(Change {
+        org_id: instance.org_id,
+        instance: instance.clone(),
+        event: event.clone(),
+        previous_node_id,
+    });
+}
+
+// ---------------------------------------------------------------------------
+// Creation rules and presentation
+// ---------------------------------------------------------------------------
+
+/// What a member asks for when creating an instance.
+pub struct Spec {
+    pub name: String,
+    pub phone_number: Option<String>,
+    pub config: Option<Value>,
+    /// Region its nodes must be in.
+    pub region: Option<String>,
+    /// Labels its nodes must carry.
+    pub affinity: Option<Value>,
+}
+
+/// Validation rule for phone numbers: E.164, a `+` and 8 to 15 digits.
+pub fn validate_phone_number(number: &str) -> Result<(), ValidationError> {
+    let valid = number
+        .strip_prefix('+')
+        .is_some_and(|d| (8..=15).contains(&d.len()) && d.bytes().all(|b| b.is_ascii_digit()) && !d.starts_with('0'));
+    if !valid {
+        return Err(ValidationError::new("phone_number")
+            .with_message("Must be in international format, e.g. +15551234567".into()));
+    }
+    Ok(())
+}
+
+/// Validation rule for configurations: a JSON object of at most
+/// [`MAX_CONFIG_BYTES`].
+pub fn validate_config(config: &Value) -> Result<(), ValidationError> {
+    if !config.is_object() {
+        return Err(ValidationError::new("config_object").with_message("Must be an object".into()));
+    }
+    if config.to_string().len() > MAX_CONFIG_BYTES {
+        return Err(ValidationError::new("config_size")
+            .with_message(format!("Must be at most {} bytes", MAX_CONFIG_BYTES).into()));
+    }
+    Ok(())
+}
+
+/// How an instance is shown to clients.
+pub fn view(instance: &WaInstance) -> Value {
+    let now = chrono::Utc::now().timestamp();
+    let status = Status::of(&instance.status);
+    let current = match (status.is_active(), instance.started_at) {
+        (true, Some(started)) => (now - started).max(0),
+        _ => 0,
+    };
+    serde_json::json!({
+        "id": instance.id,
+        "org_id": instance.org_id,
+        "name": instance.name,
+        "phone_number": instance.phone_number,
+        "status": status,
+        "config": serde_json::from_str::<Value>(&instance.config).unwrap_or(Value::Null),
+        "created_by": instance.created_by,
+        "created_at": instance.created_at,
+        "started_at": instance.started_at,
+        "stopped_at": instance.stopped_at,
+        "run_secs": instance.run_secs + current,
+        "region": instance.region,
+        "affinity": serde_json::from_str::<Value>(&instance.affinity).unwrap_or(Value::Null),
+        "node_id": instance.node_id,
+        "placement": instance.placement,
+        "placed_at": instance.placed_at,
+    })
+}
+
+/// How a history entry is shown to clients.
+pub fn event_view(event: &WaInstanceEvent) -> Value {
+    serde_json::json!({
+        "id": event.id,
+        "instance_id": event.instance_id,
+        "from": event.from_status,
+        "to": event.to_status,
+        "reason": event.reason,
+        "actor_id": event.actor_id,
+        "at": event.created_at,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Queries
+// ---------------------------------------------------------------------------
+
+/// Bring the `instances` counters of `$org` up to date inside a
+/// transaction on `$c`, booking `$hours` of finished running time. Creates
+/// the row, attributed to `$actor`, if the organization has none yet and
+/// the change has an actor.
+macro_rules! sync_counters {
+    ($c:ident, $org:expr, $actor:expr, $hours:expr) => {{
+        use crate::schema::{instances::dsl as i, wa_instances::dsl as w};
+
+        let org: i32 = $org;
+        let actor: Option<i32> = $actor;
+        let rate = hourly_rate();
+        let count: i64 = w::wa_instances
+            .filter(w::org_id.eq(org))
+            .filter(w::status.ne(Deleted.as_str()))
+            .count()
+            .get_result($c)
+            .await?;
+        let active: i64 = w::wa_instances
+            .filter(w::org_id.eq(org))
+            .filter(w::status.eq_any(ACTIVE))
+            .count()
+            .get_result($c)
+            .await?;
+        let booked: f64 = $hours * rate;
+        let updated = diesel::update(i::instances.filter(i::org_id.eq(org)))
+            .set((
+                i::instances_count.eq(count as i32),
+                i::expected_consumption.eq(active as f64 * rate),
+                i::instances_overall_consumption.eq(i::instances_overall_consumption + booked),
+            ))
+            .execute($c)
+            .await?;
+        if let (0, Some(actor)) = (updated, actor) {
+            diesel::insert_into(i::instances)
+                .values(&NewInstance {
+                    user_id: actor,
+                    instances_count: count as i32,
+                    expected_consumption: active as f64 * rate,
+                    instances_overall_consumption: booked,
+                    org_id: Some(org),
+                })
+                .execute($c)
+                .await?;
+        }
+    }};
+}
+
+/// Instances of organization `org_id`, oldest first, without deleted ones.
+pub async fn list(db: &mut DbConn, org_id: i32) -> QueryResult<Vec<WaInstance>> {
+    use crate::schema::wa_instances::dsl;
+
+    with_conn!(*db, |c| {
+        dsl::wa_instances
+            .filter(dsl::org_id.eq(org_id))
+            .filter(dsl::status.ne(Deleted.as_str()))
+            .order(dsl::id.asc())
+            .select(WaInstance::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Instance `id` of organization `org_id`, deleted or not.
+pub async fn get(db: &mut DbConn, org_id: i32, id: i32) -> Result<WaInstance, Error> {
+    use crate::schema::wa_instances::dsl;
+
+    let row: Option<WaInstance> = with_conn!(*db, |c| {
+        dsl::wa_instances
+            .find(id)
+            .filter(dsl::org_id.eq(org_id))
+            .select(WaInstance::as_select())
+            .first(c)
+            .await
+            .optional()
+    })?;
+    row.ok_or(Error::NotFound)
+}
+
+/// History of instance `id` of organization `org_id`, oldest first.
+pub async fn events(db: &mut DbConn, org_id: i32, id: i32) -> Result<Vec<WaInstanceEvent>, Error> {
+    use crate::schema::wa_instance_events::dsl;
+
+    get(db, org_id, id).await?;
+    let rows = with_conn!(*db, |c| {
+        dsl::wa_instance_events
+            .filter(dsl::instance_id.eq(id))
+            .order(dsl::id.asc())
+            .select(WaInstanceEvent::as_select())
+            .load(c)
+            .await
+    })?;
+    Ok(rows)
+}
+
+/// Create a stopped instance in `org_id` on behalf of member `actor`.
+pub async fn create(db: &mut DbConn, org_id: i32, actor: i32, spec: &Spec) -> Result<WaInstance, Error> {
+    use crate::schema::{wa_instance_events::dsl as e, wa_instances::dsl};
+
+    let now = chrono::Utc::now().timestamp();
+    let row = NewWaInstance {
+        org_id,
+        created_by: Some(actor),
+        name: spec.name.trim().to_string(),
+        phone_number: spec.phone_number.clone(),
+        status: Stopped.as_str().to_string(),
+        config: spec.config.clone().unwrap_or_else(|| serde_json::json!({})).to_string(),
+        created_at: now,
+        region: spec.region.clone(),
+        affinity: spec.affinity.clone().unwrap_or_else(|| serde_json::json!({})).to_string(),
+    };
+
+    let created: Result<Option<(WaInstance, WaInstanceEvent)>, DieselError> = transaction!(*db, |c| {
+        let held: i64 = dsl::wa_instances
+            .filter(dsl::org_id.eq(org_id))
+            .filter(dsl::status.ne(Deleted.as_str()))
+            .count()
+            .get_result(c)
+            .await?;
+        if held >= MAX_INSTANCES_PER_ORG {
+            return Ok(None);
+        }
+        let instance = diesel::insert_into(dsl::wa_instances)
+            .values(&row)
+            .returning(WaInstance::as_returning())
+            .get_result(c)
+            .await?;
+        let event = diesel::insert_into(e::wa_instance_events)
+            .values(&NewWaInstanceEvent {
+                instance_id: instance.id,
+                from_status: None,
+                to_status: row.status.clone(),
+                reason: "created".to_string(),
+                actor_id: Some(actor),
+                created_at: now,
+            })
+            .returning(WaInstanceEvent::as_returning())
+            .get_result(c)
+            .await?;
+        sync_counters!(c, org_id, Some(actor), 0.0);
+        Ok::<_, DieselError>(Some((instance, event)))
+    });
+
+    match created {
+        Ok(Some((instance, event))) => {
+            announce(&instance, &event, None);
+            Ok(instance)
+        }
+        Ok(None) => Err(Error::LimitReached),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(Error::PhoneTaken),
+        Err(e) => Err(Error::Db(e)),
+    }
+}
+
+/// Apply `op` to instance `id`, recording `reason` (or a default one) and
+/// `actor` in its history. `org_id` limits the instance to one
+/// organization; operators pass `None`. Returns the instance after the
+/// change.
+pub async fn apply(
+    db: &mut DbConn,
+    org_id: Option<i32>,
+    actor: Option<i32>,
+    id: i32,
+    op: Op,
+    reason: Option<&str>,
+) -> Result<WaInstance, Error> {
+    use crate::schema::{wa_instance_events::dsl as e, wa_instances::dsl};
+
+    let now = chrono::Utc::now().timestamp();
+    let reason = reason.map(str::trim).filter(|r| !r.is_empty()).map_or_else(|| op.default_reason(), str::to_string);
+    let outcome: Result<(WaInstance, WaInstanceEvent, Option<i32>), Error> = transaction!(*db, |c| {
+        let mut query = dsl::wa_instances.find(id).select(WaInstance::as_select()).into_boxed();
+        if let Some(org_id) = org_id {
+            query = query.filter(dsl::org_id.eq(org_id));
+        }
+        let Some(current) = query.first(c).await.optional()? else {
+            return Ok(Err(Error::NotFound));
+        };
+
+        let from = Status::of(&current.status);
+        let Some(to) = op.target(from) else {
+            return Ok(Err(Error::IllegalTransition { from, op }));
+        };
+
+        // A run ends when the instance stops being active, or restarts.
+        let restarts = matches!(op, Op::Restart | Op::Reschedule);
+        let ends_run = from.is_active() && (!to.is_active() || restarts);
+        let starts_run = to.is_active() && (!from.is_active() || restarts);
+        let ran = match (ends_run, current.started_at) {
+            (true, Some(started)) => (now - started).max(0),
+            _ => 0,
+        };
+
+        let mut next = current.clone();
+        next.status = to.as_str().to_string();
+        next.run_secs += ran;
+        if ends_run {
+            next.stopped_at = Some(now);
+        }
+        if starts_run {
+            next.started_at = Some(now);
+            let decision = crate::scheduler::place!(c, &current);
+            next.node_id = decision.node_id;
+            next.placement = Some(decision.reason);
+            next.placed_at = Some(now);
+        }
+        if to == Deleted {
+            // Let the number be used again.
+            next.phone_number = None;
+        }
+        diesel::update(dsl::wa_instances.find(id)).set(&next).execute(c).await?;
+        let event = diesel::insert_into(e::wa_instance_events)
+            .values(&NewWaInstanceEvent {
+                instance_id: id,
+                from_status: Some(current.status.clone()),
+                to_status: next.status.clone(),
+                reason: reason.clone(),
+                actor_id: actor,
+                created_at: now,
+            })
+            .returning(WaInstanceEvent::as_returning())
+            .get_result(c)
+            .await?;
+        sync_counters!(c, current.org_id, actor, ran as f64 / 3600.0);
+        Ok::<_, DieselError>(Ok((next, event, current.node_id)))
+    })?;
+
+    let (instance, event, previous_node_id) = outcome?;
+    announce(&instance, &event, previous_node_id);
+    Ok(instance)
+}
@@ -0,0 +1,403 @@
+//! Local node: instances run as child processes of this server.
+//!
+//! For single-box deployments, setting `LOCAL_NODE` makes this process a
+//! worker node of that name (registered on first start) whose [`Worker`]
+//! runs each instance placed on it as a child process, instead of handing
+//! it to a remote agent. The scheduler treats it like any other node.
+//!
+//! The command comes from a template (see [`Config`]): `LOCAL_NODE_COMMAND`,
+//! or the template an instance names in its `config.command`. Templates are
+//! split on whitespace into the program and its arguments, with `{id}` and
+//! `{phone_number}` filled in; no shell is involved. The process also gets
+//! `ORSTA_INSTANCE_ID`, `ORSTA_PHONE_NUMBER` and `ORSTA_INSTANCE_CONFIG`.
+//!
+//! A process reports how its session is doing by printing lines such as
+//! `::status online Connected` on stdout. Everything it prints is appended
+//! to `instance-<id>.log` in `LOCAL_NODE_LOG_DIR`. Stopping an instance
+//! closes the process's stdin and kills it if it has not exited after
+//! [`STOP_GRACE`].
+//!
+//! A process that exits without being asked to is started again after a
+//! delay that doubles with each consecutive crash, and the instance is
+//! reported `disconnected` meanwhile. After `LOCAL_NODE_MAX_RESTARTS`
+//! consecutive crashes, or when the command cannot be started at all, the
+//! instance is reported `failed`. A run that lasted [`STABLE_AFTER`] resets
+//! the count.
+
+use crate::{
+    instance::{self, Op, Status},
+    node::{self, Command, Worker},
+    sql::Orchestrator,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How long a stopped process has to exit after its stdin is closed.
+pub const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a process must run for its crashes to be forgotten.
+pub const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Longest delay before a crashed process is started again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Prefix of the stdout lines that report a status.
+const STATUS_PREFIX: &str = "::status ";
+
+/// The local node's settings, read from the environment.
+///
+/// | Variable                  | Default          |
+/// |---------------------------|------------------|
+/// | `LOCAL_NODE`              | unset (off)      |
+/// | `LOCAL_NODE_REGION`       | none             |
+/// | `LOCAL_NODE_CAPACITY`     | 10               |
+/// | `LOCAL_NODE_COMMAND`      | none             |
+/// | `LOCAL_NODE_COMMAND_<NAME>` | none           |
+/// | `LOCAL_NODE_LOG_DIR`      | `instance-logs`  |
+/// | `LOCAL_NODE_BACKOFF_MS`   | 1000             |
+/// | `LOCAL_NODE_MAX_RESTARTS` | 5                |
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Name of the node this process acts as.
+    pub name: String,
+    /// Region given to the node when it is first registered.
+    pub region: Option<String>,
+    pub capacity: i32,
+    /// Template run for instances that name none.
+    pub command: Option<String>,
+    pub log_dir: PathBuf,
+    /// Delay before the first restart of a crashed process.
+    pub backoff: Duration,
+    /// Consecutive crashes after which an instance is given up on.
+    pub max_restarts: u32,
+}
+
+impl Config {
+    /// The settings, or `None` when `LOCAL_NODE` is not set.
+    pub fn from_env() -> Option<Config> {
+        let name = std::env::var("LOCAL_NODE").ok().filter(|n| !n.trim().is_empty())?;
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.trim().is_empty());
+        Some(Config {
+            name: name.trim().to_string(),
+            region: var("LOCAL_NODE_REGION"),
+            capacity: var("LOCAL_NODE_CAPACITY").and_then(|v| v.parse().ok()).unwrap_or(10),
+            command: var("LOCAL_NODE_COMMAND"),
+            log_dir: var("LOCAL_NODE_LOG_DIR").unwrap_or_else(|| "instance-logs".to_string()).into(),
+            backoff: Duration::from_millis(var("LOCAL_NODE_BACKOFF_MS").and_then(|v| v.parse().ok()).unwrap_or(1000)),
+            max_restarts: var("LOCAL_NODE_MAX_RESTARTS").and_then(|v| v.parse().ok()).unwrap_or(5),
+        })
+    }
+
+    /// The template for an instance with `config`: the one its `command`
+    /// names (`LOCAL_NODE_COMMAND_<NAME>`, upper-cased with `-` as `_`),
+    /// or the default.
+    fn template(&self, config: &Value) -> Result<String, String> {
+        match config.get("command") {
+            None | Some(Value::Null) => {
+                self.command.clone().ok_or_else(|| "No command template is configured".to_string())
+            }
+            Some(Value::String(name))
+                if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) =>
+            {
+                let key = format!("LOCAL_NODE_COMMAND_{}", name.to_ascii_uppercase().replace('-', "_"));
+                std::env::var(key)
+                    .ok()
+                    .filter(|t| !t.trim().is_empty())
+                    .ok_or_else(|| format!("Unknown command template '{}'", name))
+            }
+            Some(_) => Err("'command' must name a command template".to_string()),
+        }
+    }
+}
+
+/// The program and arguments `template` makes for an instance.
+fn argv(template: &str, id: i32, phone_number: Option<&str>) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|word| word.replace("{id}", &id.to_string()).replace("{phone_number}", phone_number.unwrap_or("")))
+        .collect()
+}
+
+/// How a process ended, in words.
+fn describe(status: &ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("was killed by signal {}", signal);
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "exited".to_string(),
+    }
+}
+
+/// The delay before restart number `crashes` (from 1).
+fn backoff(base: Duration, crashes: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(crashes.saturating_sub(1))).min(MAX_BACKOFF)
+}
+
+// ---------------------------------------------------------------------------
+// Worker
+// ---------------------------------------------------------------------------
+
+/// Commands for the local node, handled by [`manage`].
+struct LocalWorker(mpsc::UnboundedSender<Command>);
+
+impl Worker for LocalWorker {
+    fn deliver(&self, command: Command) -> bool {
+        self.0.send(command).is_ok()
+    }
+}
+
+/// A supervised instance: its task and how to ask it to stop.
+struct Supervised {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+/// Register the local node, if `LOCAL_NODE` is set, and start running the
+/// instances placed on it. Call after [`node::spawn`].
+pub async fn spawn(orch: Arc<Orchestrator>) -> Result<(), String> {
+    let Some(config) = Config::from_env() else {
+        return Ok(());
+    };
+    for value in std::iter::once(&config.name).chain(&config.region) {
+        node::validate_name(value).map_err(|_| format!("'{}' is not a valid node name or region", value))?;
+    }
+    if !(1..=node::MAX_CAPACITY).contains(&config.capacity) {
+        return Err(format!("LOCAL_NODE_CAPACITY must be between 1 and {}", node::MAX_CAPACITY));
+    }
+    tokio::fs::create_dir_all(&config.log_dir)
+        .await
+        .map_err(|e| format!("Could not create {}: {}", config.log_dir.display(), e))?;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let local = match node::find(&mut db, &config.name).await.map_err(|e| e.to_string())? {
+        Some(n) => n,
+        None => {
+            let spec = node::Spec { name: config.name.clone(), region: config.region.clone(), labels: None };
+            // Nothing connects with its token; it is not kept.
+            let (created, _) = node::create(&mut db, &spec).await.map_err(|e| e.describe())?;
+            info!(node_id = created.id, name = created.name, "Registered the local node.");
+            created
+        }
+    };
+
+    let (commands, queue) = mpsc::unbounded_channel();
+    if !node::attach(local.id, Arc::new(LocalWorker(commands))) {
+        return Err(format!("Node '{}' already has a worker", local.name));
+    }
+    node::register(&mut db, local.id, config.capacity, Some(env!("CARGO_PKG_VERSION")))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(manage(orch, local.id, config, queue));
+    crate::scheduler::wake();
+    Ok(())
+}
+
+/// Start and stop processes as commands arrive, and keep the node alive:
+/// every heartbeat interval it is marked seen and brought in line with the
+/// instances that should run on it, like a remote agent's heartbeat.
+async fn manage(orch: Arc<Orchestrator>, node_id: i32, config: Config, mut queue: mpsc::UnboundedReceiver<Command>) {
+    let config = Arc::new(config);
+    let mut running: HashMap<i32, Supervised> = HashMap::new();
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(node::heartbeat_secs()));
+
+    loop {
+        let commands = tokio::select! {
+            Some(command) = queue.recv() => vec![command],
+            _ = heartbeat.tick() => {
+                running.retain(|_, s| !s.task.is_finished());
+                let ids: Vec<i32> = running.keys().copied().collect();
+                match catch_up(&orch, node_id, &ids).await {
+                    Ok(commands) => commands,
+                    Err(e) => {
+                        warn!(node_id, "Local node heartbeat failed: {}", e);
+                        vec![]
+                    }
+                }
+            }
+        };
+        for command in commands {
+            match command {
+                Command::Start { id, phone_number, config: instance_config } => {
+                    if running.get(&id).is_some_and(|s| !s.task.is_finished()) {
+                        continue;
+                    }
+                    let (stop, stopped) = oneshot::channel();
+                    let run = Run { id, phone_number, config: instance_config };
+                    let task = tokio::spawn(supervise(Arc::clone(&orch), Arc::clone(&config), run, stopped));
+                    running.insert(id, Supervised { stop, task });
+                }
+                Command::Stop { id } => {
+                    if let Some(s) = running.remove(&id) {
+                        let _ = s.stop.send(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn catch_up(orch: &Orchestrator, node_id: i32, running: &[i32]) -> Result<Vec<Command>, String> {
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    node::seen(&mut db, node_id).await.map_err(|e| e.to_string())?;
+    node::reconcile(&mut db, node_id, running).await.map_err(|e| e.to_string())
+}
+
+// ---------------------------------------------------------------------------
+// Supervision
+// ---------------------------------------------------------------------------
+
+/// What a process is started for.
+struct Run {
+    id: i32,
+    phone_number: Option<String>,
+    config: Value,
+}
+
+/// Record `status` for instance `id` as reported by its process. Moves
+/// the state machine does not allow, such as `online` straight from
+/// `provisioning`, are left out.
+async fn report(orch: &Orchestrator, id: i32, status: Status, reason: &str) {
+    let mut db = match orch.conn().await {
+        Ok(db) => db,
+        Err(e) => {
+            warn!(instance_id = id, "Could not record process status: {}", e);
+            return;
+        }
+    };
+    match instance::apply(&mut db, None, None, id, Op::Report(status), Some(reason)).await {
+        Ok(_) => {}
+        Err(e @ instance::Error::IllegalTransition { .. }) => debug!(instance_id = id, "{}", e.describe()),
+        Err(instance::Error::Db(e)) => warn!(instance_id = id, "Could not record process status: {}", e),
+        Err(e) => debug!(instance_id = id, "{}", e.describe()),
+    }
+}
+
+/// Run an instance's process until it is stopped or given up on,
+/// restarting it when it crashes.
+async fn supervise(orch: Arc<Orchestrator>, config: Arc<Config>, run: Run, mut stopped: oneshot::Receiver<()>) {
+    let id = run.id;
+    let template = match config.template(&run.config) {
+        Ok(t) => t,
+        Err(reason) => return report(&orch, id, Status::Failed, &reason).await,
+    };
+    let args = argv(&template, id, run.phone_number.as_deref());
+    let Some((program, rest)) = args.split_first() else {
+        return report(&orch, id, Status::Failed, "The command template is empty").await;
+    };
+    let log_path = config.log_dir.join(format!("instance-{}.log", id));
+    let mut crashes = 0u32;
+
+    loop {
+        let spawned = tokio::process::Command::new(program)
+            .args(rest)
+            .env("ORSTA_INSTANCE_ID", id.to_string())
+            .env("ORSTA_PHONE_NUMBER", run.phone_number.as_deref().unwrap_or(""))
+            .env("ORSTA_INSTANCE_CONFIG", run.config.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match spawned {
+            Ok(c) => c,
+            Err(e) => {
+                let reason = format!("Could not start '{}': {}", program, e);
+                return report(&orch, id, Status::Failed, &reason).await;
+            }
+        };
+        info!(instance_id = id, pid = child.id(), "Process started.");
+        let started = Instant::now();
+
+        let log = match tokio::fs::OpenOptions::new().create(true).append(true).open(&log_path).await {
+            Ok(f) => Some(Arc::new(Mutex::new(f))),
+            Err(e) => {
+                warn!(instance_id = id, "Could not open {}: {}", log_path.display(), e);
+                None
+            }
+        };
+        let stdin = child.stdin.take();
+        let out = child.stdout.take().map(|s| tokio::spawn(capture(s, "stdout", log.clone(), Some((orch.clone(), id)))));
+        let err = child.stderr.take().map(|s| tokio::spawn(capture(s, "stderr", log.clone(), None)));
+
+        let exited = tokio::select! {
+            status = child.wait() => status,
+            _ = &mut stopped => {
+                // Closing stdin asks it to stop; a process that ignores it is killed.
+                drop(stdin);
+                if tokio::time::timeout(STOP_GRACE, child.wait()).await.is_err() {
+                    let _ = child.kill().await;
+                }
+                info!(instance_id = id, "Process stopped.");
+                return;
+            }
+        };
+        // Let the last lines, and any status they report, land first.
+        for reader in [out, err].into_iter().flatten() {
+            let _ = reader.await;
+        }
+
+        let how = match &exited {
+            Ok(status) => describe(status),
+            Err(e) => format!("could not be waited for ({})", e),
+        };
+        if started.elapsed() >= STABLE_AFTER {
+            crashes = 0;
+        }
+        crashes += 1;
+        if crashes > config.max_restarts {
+            warn!(instance_id = id, crashes, "Process {}; giving up.", how);
+            let reason = format!("Process {}; gave up after {} restarts", how, config.max_restarts);
+            return report(&orch, id, Status::Failed, &reason).await;
+        }
+        let delay = backoff(config.backoff, crashes);
+        warn!(instance_id = id, crashes, "Process {}; restarting in {:?}.", how, delay);
+        let reason = format!("Process {}; restarting in {} ms", how, delay.as_millis());
+        report(&orch, id, Status::Disconnected, &reason).await;
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = &mut stopped => return,
+        }
+    }
+}
+
+/// Copy a process's output to its log, one line at a time. With `reports`,
+/// status lines are recorded for the instance.
+async fn capture(
+    stream: impl AsyncRead + Unpin,
+    name: &'static str,
+    log: Option<Arc<Mutex<tokio::fs::File>>>,
+    reports: Option<(Arc<Orchestrator>, i32)>,
+) {
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(log) = &log {
+            let entry = format!("{} {} {}\n", chrono::Utc::now().to_rfc3339(), name, line);
+            let _ = log.lock().await.write_all(entry.as_bytes()).await;
+        }
+        let Some((orch, id)) = &reports else {
+            continue;
+        };
+        let Some(rest) = line.strip_prefix(STATUS_PREFIX) else {
+            continue;
+        };
+        let (status, reason) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+        match Status::parse(status).filter(|s| s.is_reportable()) {
+            Some(s) => report(orch, *id, s, reason.trim()).await,
+            None => debug!(instance_id = id, "Ignored unknown status '{}'.", status),
+        }
+    }
+}
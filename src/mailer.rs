@@ -0,0 +1,172 @@
+//! Outgoing email abstraction.
+//!
+//! Account emails (address verification, password reset) go through a
+//! [`Mailer`] registered as an Axum [`Extension`](axum::Extension) in
+//! `main.rs`, the same way as [`PaymentProvider`](crate::payment::PaymentProvider).
+//!
+//! ## Development / testing
+//! Without `SMTP_HOST`, mail is not sent: [`LogMailer`] writes each message
+//! as an `.eml` file to `MAIL_DIR` if set, or logs it otherwise, so links
+//! can be copied out of the log.
+//!
+//! ## Production
+//! Set `SMTP_HOST` (and usually `SMTP_USERNAME`, `SMTP_PASSWORD`) to send
+//! through [`SmtpMailer`], or implement [`Mailer`] for another service.
+
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+// ---------------------------------------------------------------------------
+// Mailer trait & associated types
+// ---------------------------------------------------------------------------
+
+/// A plain-text email to one recipient.
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Implement this trait for each mail backend and register it via
+/// `app.layer(Extension(Arc::new(MyMailer) as Arc<dyn Mailer>))`.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+}
+
+/// Pick a mailer from the environment: SMTP if `SMTP_HOST` is set, the
+/// log/file mailer otherwise.
+pub fn from_env(debug_mode: bool) -> Arc<dyn Mailer> {
+    if std::env::var("SMTP_HOST").is_ok_and(|h| !h.is_empty()) {
+        let mailer = SmtpMailer::from_env();
+        info!("Sending mail through SMTP server {}.", mailer.host);
+        return Arc::new(mailer);
+    }
+
+    let dir = std::env::var("MAIL_DIR").ok().filter(|d| !d.is_empty()).map(PathBuf::from);
+    if !debug_mode {
+        warn!("SMTP_HOST is not set — emails will be written to the log or MAIL_DIR instead of being sent.");
+    }
+    Arc::new(LogMailer { dir })
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, String> {
+    let to: Mailbox = email.to.parse().map_err(|e| format!("Invalid recipient: {}", e))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())
+        .map_err(|e| e.to_string())
+}
+
+fn from_address() -> Mailbox {
+    let from = std::env::var("MAIL_FROM").unwrap_or_else(|_| "Orsta <no-reply@localhost>".to_string());
+    from.parse()
+        .unwrap_or_else(|e| panic!("Invalid MAIL_FROM '{}': {}", from, e))
+}
+
+// ---------------------------------------------------------------------------
+// SMTP
+// ---------------------------------------------------------------------------
+
+/// Sends through an SMTP relay.
+///
+/// | Variable        | Default    | Description                                   |
+/// |-----------------|------------|-----------------------------------------------|
+/// | `SMTP_HOST`     | —          | Relay host name                               |
+/// | `SMTP_PORT`     | per mode   | 587 for `starttls`, 465 for `tls`, 25 for `none` |
+/// | `SMTP_TLS`      | `starttls` | `starttls`, `tls` (implicit) or `none`        |
+/// | `SMTP_USERNAME` | —          | Login, if the relay requires one              |
+/// | `SMTP_PASSWORD` | —          |                                               |
+/// | `MAIL_FROM`     | `Orsta <no-reply@localhost>` | Sender address              |
+pub struct SmtpMailer {
+    host: String,
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    /// Panics on invalid configuration, like the other startup settings.
+    pub fn from_env() -> Self {
+        let host = std::env::var("SMTP_HOST").expect("SMTP_HOST must be set");
+        let mode = std::env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+
+        let mut builder = match mode.as_str() {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host),
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&host),
+            "none" => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host)),
+            other => panic!("Invalid SMTP_TLS '{}': expected 'starttls', 'tls' or 'none'", other),
+        }
+        .unwrap_or_else(|e| panic!("Invalid SMTP configuration for {}: {}", host, e));
+
+        if let Some(port) = std::env::var("SMTP_PORT").ok().and_then(|p| p.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let Ok(user) = std::env::var("SMTP_USERNAME") {
+            let password = std::env::var("SMTP_PASSWORD").unwrap_or_default();
+            builder = builder.credentials(Credentials::new(user, password));
+        }
+
+        Self {
+            host,
+            from: from_address(),
+            transport: builder.build(),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+        Box::pin(async move {
+            let message = build_message(&self.from, email)?;
+            self.transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Log / file mailer — development and testing only.
+// ---------------------------------------------------------------------------
+
+/// Writes each message to `dir` as an `.eml` file, or logs it when no
+/// directory is configured. Nothing leaves the machine.
+pub struct LogMailer {
+    pub dir: Option<PathBuf>,
+}
+
+impl Mailer for LogMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+        Box::pin(async move {
+            let Some(dir) = &self.dir else {
+                info!(to = email.to, subject = email.subject, "Email not sent (no SMTP_HOST):\n{}", email.body);
+                return Ok(());
+            };
+
+            let message = build_message(&from_address(), email)?;
+            tokio::fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+            let path = dir.join(format!(
+                "{}-{}.eml",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                uuid::Uuid::new_v4()
+            ));
+            tokio::fs::write(&path, message.formatted())
+                .await
+                .map_err(|e| e.to_string())?;
+            info!(to = email.to, subject = email.subject, "Email written to {}.", path.display());
+            Ok(())
+        })
+    }
+}
@@ -1,15 +1,30 @@
+mod account;
+mod api_key;
+mod audit;
 mod auth;
+mod challenge;
+mod email_token;
+mod impersonation;
+mod instance;
+mod local_node;
 mod logger;
+mod mailer;
+mod mfa;
+mod node;
+mod org;
 mod payment;
 mod route;
+mod scheduler;
 mod schema;
+mod session;
 mod sql;
+mod throttle;
+mod validate;
 
 use axum::Extension;
 use payment::DummyPaymentProvider;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep};
 use tracing::info;
 
 #[tokio::main]
@@ -22,19 +37,43 @@ async fn main() {
 
     logger::init(debug_mode);
 
-    let orchestrator = Arc::new(Mutex::new(sql::Orchestrator::init().await));
+    let orchestrator = Arc::new(sql::Orchestrator::init().await);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("reconcile") {
+        std::process::exit(reconcile(&orchestrator, &args[1..]).await);
+    }
+    if args.first().map(String::as_str) == Some("set-role") {
+        std::process::exit(set_role(&orchestrator, &args[1..]).await);
+    }
+
+    auth::keyring::init(debug_mode);
+
+    if let Err(e) = api_key::import_legacy(&orchestrator).await {
+        panic!("Could not move legacy API keys to hashed storage: {}", e);
+    }
+
+    if let Err(e) = org::migrate_personal(&orchestrator).await {
+        panic!("Could not create personal organizations: {}", e);
+    }
+
+    if let Err(e) = validate::fold_emails(&orchestrator).await {
+        panic!("Could not fold stored email addresses: {}", e);
+    }
 
     info!("Orchestrator initialized. Ready to execute queries.");
 
-    let sync_orch = Arc::clone(&orchestrator);
-    tokio::spawn(async move {
-        loop {
-            sleep(Duration::from_secs(5)).await;
-            let mut orch = sync_orch.lock().await;
-            let ping = diesel::sql_query("SELECT 1");
-            let _ = orch.sync_write(ping).await;
-        }
-    });
+    sql::replication::spawn(Arc::clone(&orchestrator));
+    sql::supervisor::spawn(Arc::clone(&orchestrator));
+    sql::reconcile::spawn(Arc::clone(&orchestrator));
+    account::spawn(Arc::clone(&orchestrator));
+    if let Err(e) = node::spawn(Arc::clone(&orchestrator)).await {
+        panic!("Could not reset node statuses: {}", e);
+    }
+    if let Err(e) = local_node::spawn(Arc::clone(&orchestrator)).await {
+        panic!("Could not start the local node: {}", e);
+    }
+    scheduler::spawn(Arc::clone(&orchestrator));
 
     let app = route::start_client_api_service(Arc::clone(&orchestrator));
 
@@ -49,7 +88,9 @@ async fn main() {
         panic!("No PaymentProvider configured. Set DUMMY_PAYMENT_MODE=true for development or implement a real provider.");
     };
 
-    let app = app.layer(Extension(payment_provider));
+    let mailer = mailer::from_env(debug_mode);
+
+    let app = app.layer(Extension(payment_provider)).layer(Extension(mailer));
 
     let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
     let addr = format!("0.0.0.0:{}", port);
@@ -57,7 +98,7 @@ async fn main() {
 
     info!("WebSocket service started on {}", addr);
 
-    axum::serve(listener, app)
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
@@ -65,6 +106,122 @@ async fn main() {
     info!("Server shut down cleanly.");
 }
 
+/// `Orsta-Client reconcile [--repair sqlite-to-postgres|postgres-to-sqlite]`
+///
+/// Prints the divergence report as JSON and, with `--repair`, copies the
+/// named side over the other. Exits non-zero if divergence remains.
+async fn reconcile(orch: &sql::Orchestrator, args: &[String]) -> i32 {
+    let direction = match args {
+        [] => None,
+        [flag, dir] if flag == "--repair" => match sql::reconcile::parse_direction(dir) {
+            Some(d) => Some(d),
+            None => {
+                eprintln!("Unknown direction '{}': expected sqlite-to-postgres or postgres-to-sqlite.", dir);
+                return 2;
+            }
+        },
+        _ => {
+            eprintln!("Usage: Orsta-Client reconcile [--repair sqlite-to-postgres|postgres-to-sqlite]");
+            return 2;
+        }
+    };
+
+    if orch.pg.is_none() {
+        eprintln!("POSTGRES_DATABASE_URL is not set; nothing to reconcile.");
+        return 2;
+    }
+
+    let mut report = match sql::reconcile::check(orch).await {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Reconcile failed: {}", e);
+            return 1;
+        }
+    };
+    report.log();
+
+    if let Some(from) = direction
+        && report.divergent() > 0
+    {
+        match sql::reconcile::repair(orch, &report, from).await {
+            Ok(n) => info!("Repaired {} rows from {}.", n, from),
+            Err(e) => {
+                eprintln!("Repair failed: {}", e);
+                return 1;
+            }
+        }
+        report = match sql::reconcile::check(orch).await {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Reconcile failed: {}", e);
+                return 1;
+            }
+        };
+        report.log();
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    if report.divergent() == 0 { 0 } else { 1 }
+}
+
+/// `Orsta-Client set-role <email> <user|support|admin>`
+///
+/// Assigns a role from the command line, e.g. to appoint the first admin.
+async fn set_role(orch: &sql::Orchestrator, args: &[String]) -> i32 {
+    use crate::schema::users::dsl::*;
+    use diesel::prelude::*;
+    use diesel_async::RunQueryDsl;
+
+    let [address, name] = args else {
+        eprintln!("Usage: Orsta-Client set-role <email> <user|support|admin>");
+        return 2;
+    };
+    let Some(new_role) = auth::Role::parse(name) else {
+        eprintln!("Unknown role '{}': expected user, support or admin.", name);
+        return 2;
+    };
+
+    let address = validate::normalize_email(address);
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Database unavailable: {}", e);
+            return 1;
+        }
+    };
+    let updated: QueryResult<Vec<i32>> = sql::with_conn!(db, |c| {
+        diesel::update(users.filter(email.eq(&address)))
+            .set(role.eq(new_role.as_str()))
+            .returning(id)
+            .get_results(c)
+            .await
+    });
+    let uid = match updated.as_deref() {
+        Ok([uid]) => *uid,
+        Ok(_) => {
+            eprintln!("No user with email '{}'.", address);
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Could not set role: {}", e);
+            return 1;
+        }
+    };
+
+    audit::record(
+        &mut db,
+        audit::ROLE_CHANGED,
+        Some(uid),
+        None,
+        None,
+        serde_json::json!({"to": new_role, "via": "cli"}),
+    )
+    .await;
+    println!("User {} is now {}.", uid, new_role.as_str());
+    0
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
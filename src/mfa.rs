@@ -0,0 +1,281 @@
+//! Second factors for password logins: TOTP authenticators and recovery
+//! codes (passkeys live in `route::passkey`).
+//!
+//! Enrollment is two-step: `begin_totp` stores a pending secret and
+//! `confirm_totp` enables it once the user proves their app produces valid
+//! codes, returning a fresh set of recovery codes. Each recovery code works
+//! once and only its SHA-256 is stored.
+
+use crate::{
+    auth::totp,
+    sql::{
+        DbConn,
+        totp::{NewRecoveryCode, NewTotpCredential, TotpCredential},
+        transaction, with_conn,
+        user::User,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Recovery codes issued at a time.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Wrong codes allowed against one `mfa_token` before it is discarded.
+pub const MAX_CODE_ATTEMPTS: i32 = 5;
+
+/// Which kind of code was accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Totp,
+    RecoveryCode,
+}
+
+/// Second factors that can complete a password login for `user`; empty if
+/// the password alone is enough. A user who requires a passkey has asked
+/// for nothing weaker, so their TOTP and recovery codes do not count.
+pub async fn methods(db: &mut DbConn, user: &User) -> QueryResult<Vec<&'static str>> {
+    if user.require_passkey {
+        return Ok(vec!["passkey"]);
+    }
+    let mut methods = Vec::new();
+    if totp_enabled(db, user.id).await? {
+        methods.extend(["totp", "recovery_code"]);
+    }
+    Ok(methods)
+}
+
+pub async fn totp_enabled(db: &mut DbConn, uid: i32) -> QueryResult<bool> {
+    use crate::schema::totp_credentials::dsl::*;
+
+    let n: i64 = with_conn!(*db, |c| {
+        totp_credentials
+            .filter(user_id.eq(uid))
+            .filter(enabled_at.is_not_null())
+            .count()
+            .get_result(c)
+            .await
+    })?;
+    Ok(n > 0)
+}
+
+pub async fn recovery_codes_remaining(db: &mut DbConn, uid: i32) -> QueryResult<i64> {
+    use crate::schema::recovery_codes::dsl::*;
+
+    with_conn!(*db, |c| {
+        recovery_codes
+            .filter(user_id.eq(uid))
+            .filter(used_at.is_null())
+            .count()
+            .get_result(c)
+            .await
+    })
+}
+
+/// Start TOTP enrollment, replacing any unconfirmed secret. Returns `None`
+/// if TOTP is already enabled.
+pub async fn begin_totp(db: &mut DbConn, uid: i32) -> QueryResult<Option<String>> {
+    use crate::schema::totp_credentials::dsl::*;
+
+    let new_secret = totp::generate_secret();
+    let now = chrono::Utc::now().timestamp();
+    transaction!(*db, |c| {
+        let existing: Option<TotpCredential> = totp_credentials
+            .filter(user_id.eq(uid))
+            .select(TotpCredential::as_select())
+            .first(c)
+            .await
+            .optional()?;
+        match existing {
+            Some(t) if t.enabled_at.is_some() => return Ok(None),
+            Some(t) => {
+                diesel::delete(totp_credentials.find(t.id)).execute(c).await?;
+            }
+            None => {}
+        }
+        diesel::insert_into(totp_credentials)
+            .values(&NewTotpCredential {
+                user_id: uid,
+                secret: new_secret.clone(),
+                created_at: now,
+            })
+            .execute(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(Some(new_secret))
+    })
+}
+
+/// Enable the pending TOTP secret if `code` is valid for it. Returns the
+/// new recovery codes, or `None` if there is nothing pending or the code is
+/// wrong.
+pub async fn confirm_totp(db: &mut DbConn, uid: i32, code: &str) -> QueryResult<Option<Vec<String>>> {
+    use crate::schema::recovery_codes::dsl as rc;
+    use crate::schema::totp_credentials::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let pending: Option<TotpCredential> = with_conn!(*db, |c| {
+        totp_credentials
+            .filter(user_id.eq(uid))
+            .filter(enabled_at.is_null())
+            .select(TotpCredential::as_select())
+            .first(c)
+            .await
+            .optional()
+    })?;
+    let Some(pending) = pending else {
+        return Ok(None);
+    };
+    let Some(step) = totp::verify(&pending.secret, code, now, pending.last_used_step) else {
+        return Ok(None);
+    };
+
+    let codes = new_recovery_codes();
+    let rows = recovery_code_rows(uid, &codes, now);
+    let enabled = transaction!(*db, |c| {
+        let n = diesel::update(totp_credentials.find(pending.id).filter(enabled_at.is_null()))
+            .set((enabled_at.eq(now), last_used_step.eq(step)))
+            .execute(c)
+            .await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        diesel::delete(rc::recovery_codes.filter(rc::user_id.eq(uid)))
+            .execute(c)
+            .await?;
+        for row in &rows {
+            diesel::insert_into(rc::recovery_codes).values(row).execute(c).await?;
+        }
+        Ok::<_, diesel::result::Error>(true)
+    })?;
+
+    Ok(enabled.then_some(codes))
+}
+
+/// Check a TOTP or recovery code for `uid`, consuming it if valid.
+pub async fn verify_code(db: &mut DbConn, uid: i32, code: &str) -> QueryResult<Option<Method>> {
+    let now = chrono::Utc::now().timestamp();
+
+    {
+        use crate::schema::totp_credentials::dsl::*;
+
+        let cred: Option<TotpCredential> = with_conn!(*db, |c| {
+            totp_credentials
+                .filter(user_id.eq(uid))
+                .filter(enabled_at.is_not_null())
+                .select(TotpCredential::as_select())
+                .first(c)
+                .await
+                .optional()
+        })?;
+        let Some(cred) = cred else {
+            return Ok(None);
+        };
+
+        if let Some(step) = totp::verify(&cred.secret, code, now, cred.last_used_step) {
+            // Claim the step so the same code cannot be used twice, even by
+            // two concurrent requests.
+            let claimed = with_conn!(*db, |c| {
+                diesel::update(totp_credentials.find(cred.id).filter(last_used_step.lt(step)))
+                    .set(last_used_step.eq(step))
+                    .execute(c)
+                    .await
+            })?;
+            return Ok((claimed > 0).then_some(Method::Totp));
+        }
+    }
+
+    use crate::schema::recovery_codes::dsl::*;
+
+    let hashed = hash_recovery_code(code);
+    let used = with_conn!(*db, |c| {
+        diesel::update(
+            recovery_codes
+                .filter(user_id.eq(uid))
+                .filter(code_hash.eq(&hashed))
+                .filter(used_at.is_null()),
+        )
+        .set(used_at.eq(now))
+        .execute(c)
+        .await
+    })?;
+    Ok((used > 0).then_some(Method::RecoveryCode))
+}
+
+/// Invalidate all recovery codes of `uid` and issue a new set.
+pub async fn regenerate_recovery_codes(db: &mut DbConn, uid: i32) -> QueryResult<Vec<String>> {
+    use crate::schema::recovery_codes::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let codes = new_recovery_codes();
+    let rows = recovery_code_rows(uid, &codes, now);
+    transaction!(*db, |c| {
+        diesel::delete(recovery_codes.filter(user_id.eq(uid)))
+            .execute(c)
+            .await?;
+        for row in &rows {
+            diesel::insert_into(recovery_codes).values(row).execute(c).await?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })?;
+    Ok(codes)
+}
+
+/// Remove the TOTP authenticator and recovery codes of `uid`.
+pub async fn disable_totp(db: &mut DbConn, uid: i32) -> QueryResult<()> {
+    use crate::schema::{recovery_codes::dsl as rc, totp_credentials::dsl as tc};
+
+    transaction!(*db, |c| {
+        diesel::delete(rc::recovery_codes.filter(rc::user_id.eq(uid)))
+            .execute(c)
+            .await?;
+        diesel::delete(tc::totp_credentials.filter(tc::user_id.eq(uid)))
+            .execute(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(())
+    })
+}
+
+/// Codes look like `k3vd-9wqa-mt2x-p7hc`: 16 characters (about 79 bits)
+/// from a lowercase alphabet without look-alike characters.
+fn new_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: Vec<char> = (0..16)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            chars
+                .chunks(4)
+                .map(|g| g.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+fn recovery_code_rows(uid: i32, codes: &[String], now: i64) -> Vec<NewRecoveryCode> {
+    codes
+        .iter()
+        .map(|c| NewRecoveryCode {
+            user_id: uid,
+            code_hash: hash_recovery_code(c),
+            created_at: now,
+        })
+        .collect()
+}
+
+/// Hash of a recovery code, ignoring case, spaces and dashes.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
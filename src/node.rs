@@ -0,0 +1,524 @@
+//! Worker nodes.
+//!
+//! Instances run on nodes: machines registered by an operator, each with a
+//! name, an optional region and labels. Registering a node yields a token,
+//! shown once, with which the node's agent connects to `/agent` (see
+//! [`crate::route::agent`]). On connecting the agent says how many active
+//! instances it can host and which it is running; the node is `online`
+//! until it disconnects or misses [`MISSED_HEARTBEATS`] heartbeats. An
+//! operator may drain a node to move its instances elsewhere.
+//!
+//! Whatever runs a node's instances is a [`Worker`], attached to the node
+//! while it is online. The scheduler decides which node runs an instance
+//! (see [`crate::scheduler`]), and changes to placed instances become
+//! [`Command`]s for the workers of their nodes (see [`spawn`]). Workers
+//! report how their instances are doing through [`instance::apply`], like
+//! any other session.
+//!
+//! Each heartbeat lists the instances the agent is running, and the node's
+//! worker is told to start or stop whatever differs from what should run
+//! there, so commands lost on the way are made up for.
+
+use crate::{
+    api_key::hash_key,
+    instance::{self, Change, Status},
+    scheduler,
+    sql::{
+        DbConn, Orchestrator,
+        node::{NewNode, Node},
+        wa_instance::WaInstance,
+        with_conn,
+    },
+};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use validator::ValidationError;
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Most active instances one node may offer to host.
+pub const MAX_CAPACITY: i32 = 10_000;
+
+/// Heartbeats a node may miss before it is taken offline.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// Prefix of every node token.
+const TOKEN_PREFIX: &str = "on_";
+
+/// Characters of a token kept in clear (`on_` plus 8).
+const VISIBLE_CHARS: usize = 11;
+
+/// How often agents send a heartbeat, from `NODE_HEARTBEAT_SECS`
+/// (seconds, default 15).
+pub fn heartbeat_secs() -> u64 {
+    std::env::var("NODE_HEARTBEAT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|s| *s > 0)
+        .unwrap_or(15)
+}
+
+/// How long a node may go unheard before it counts as lost (seconds).
+pub fn heartbeat_allowance() -> u64 {
+    heartbeat_secs() * MISSED_HEARTBEATS as u64
+}
+
+/// The time (Unix seconds) from which a node must have been heard to
+/// count as alive.
+pub fn heard_since() -> i64 {
+    chrono::Utc::now().timestamp() - heartbeat_allowance() as i64
+}
+
+// ---------------------------------------------------------------------------
+// Workers and commands
+// ---------------------------------------------------------------------------
+
+/// What a node is told to do with one of its instances. Starting an
+/// instance that is already running does nothing, as does stopping one
+/// that is not.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", content = "payload", rename_all = "snake_case")]
+pub enum Command {
+    Start { id: i32, phone_number: Option<String>, config: Value },
+    Stop { id: i32 },
+}
+
+impl Command {
+    pub fn start(instance: &WaInstance) -> Command {
+        Command::Start {
+            id: instance.id,
+            phone_number: instance.phone_number.clone(),
+            config: serde_json::from_str(&instance.config).unwrap_or_else(|_| serde_json::json!({})),
+        }
+    }
+}
+
+/// Runs the instances placed on one node.
+pub trait Worker: Send + Sync {
+    /// Hand over `command`. Returns `false` if the worker has gone away.
+    fn deliver(&self, command: Command) -> bool;
+}
+
+static WORKERS: LazyLock<Mutex<HashMap<i32, Arc<dyn Worker>>>> = LazyLock::new(Default::default);
+
+/// Make `worker` run the instances of node `node_id`. Returns `false`,
+/// leaving things as they are, if the node already has one.
+pub fn attach(node_id: i32, worker: Arc<dyn Worker>) -> bool {
+    let mut workers = WORKERS.lock().unwrap();
+    if workers.contains_key(&node_id) {
+        return false;
+    }
+    workers.insert(node_id, worker);
+    true
+}
+
+pub fn detach(node_id: i32) {
+    WORKERS.lock().unwrap().remove(&node_id);
+}
+
+pub fn is_attached(node_id: i32) -> bool {
+    WORKERS.lock().unwrap().contains_key(&node_id)
+}
+
+/// Hand `command` to the worker of node `node_id`, if it has one.
+pub fn deliver(node_id: i32, command: Command) -> bool {
+    let worker = WORKERS.lock().unwrap().get(&node_id).cloned();
+    worker.is_some_and(|w| w.deliver(command))
+}
+
+static REMOVALS: LazyLock<broadcast::Sender<i32>> = LazyLock::new(|| broadcast::channel(64).0);
+
+/// Subscribe to the ids of nodes removed by this process from now on.
+pub fn removals() -> broadcast::Receiver<i32> {
+    REMOVALS.subscribe()
+}
+
+/// The commands a change to an instance calls for on the node it is
+/// placed on: a start for a new run, preceded by a stop when the run
+/// replaces another, and a stop when it stops being active.
+pub fn commands_for(change: &Change) -> Vec<Command> {
+    let to = Status::of(&change.instance.status);
+    let from = change.event.from_status.as_deref().map(Status::of);
+    let was_active = from.is_some_and(Status::is_active);
+    match to {
+        Status::Provisioning if was_active => {
+            vec![Command::Stop { id: change.instance.id }, Command::start(&change.instance)]
+        }
+        Status::Provisioning => vec![Command::start(&change.instance)],
+        _ if to.is_active() || from.is_none() => vec![],
+        _ => vec![Command::Stop { id: change.instance.id }],
+    }
+}
+
+/// Mark every node offline, as no worker is attached yet, then pass the
+/// commands for instance changes on to the workers of their nodes. Commands
+/// missed while lagging are made up for by the next heartbeats.
+pub async fn spawn(orch: Arc<Orchestrator>) -> Result<(), String> {
+    use crate::schema::nodes::dsl;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    with_conn!(db, |c| {
+        diesel::update(dsl::nodes.filter(dsl::status.ne(OFFLINE)))
+            .set(dsl::status.eq(OFFLINE))
+            .execute(c)
+            .await
+    })
+    .map_err(|e| e.to_string())?;
+
+    let mut changes = instance::changes();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(change) => {
+                    // Stops go to the node the run was on, starts to the
+                    // one it is placed on now.
+                    for command in commands_for(&change) {
+                        let target = match command {
+                            Command::Stop { .. } => change.previous_node_id,
+                            Command::Start { .. } => change.instance.node_id,
+                        };
+                        if let Some(node_id) = target {
+                            deliver(node_id, command);
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Missed {} instance changes; nodes catch up on their next heartbeat.", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Registry
+// ---------------------------------------------------------------------------
+
+/// Why a node operation was refused.
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    /// Another node has the name.
+    NameTaken,
+    /// Active instances still run on the node.
+    Busy(i64),
+    Db(DieselError),
+}
+
+impl Error {
+    pub fn describe(&self) -> String {
+        match self {
+            Error::NotFound => "Node not found".to_string(),
+            Error::NameTaken => "A node with this name already exists".to_string(),
+            Error::Busy(n) => format!("{} active instances still run on this node", n),
+            Error::Db(_) => "Node operation failed".to_string(),
+        }
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(e: DieselError) -> Self {
+        Error::Db(e)
+    }
+}
+
+/// What an operator asks for when registering a node.
+pub struct Spec {
+    pub name: String,
+    pub region: Option<String>,
+    pub labels: Option<Value>,
+}
+
+fn is_identifier(s: &str, max: usize) -> bool {
+    (1..=max).contains(&s.len())
+        && s.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Validation rule for node names and regions: up to 63 ASCII letters,
+/// digits, `-`, `_` or `.`, starting with a letter or digit.
+pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+    if !is_identifier(name, 63) {
+        return Err(ValidationError::new("node_name")
+            .with_message("Must be 1 to 63 letters, digits, '-', '_' or '.', starting with a letter or digit".into()));
+    }
+    Ok(())
+}
+
+/// Validation rule for labels: an object of up to 32 names, each following
+/// [`validate_name`], with string values of up to 63 characters.
+pub fn validate_labels(labels: &Value) -> Result<(), ValidationError> {
+    let Some(map) = labels.as_object() else {
+        return Err(ValidationError::new("labels_object").with_message("Must be an object".into()));
+    };
+    if map.len() > 32 {
+        return Err(ValidationError::new("labels_count").with_message("Must have at most 32 labels".into()));
+    }
+    for (name, value) in map {
+        if !is_identifier(name, 63) {
+            return Err(ValidationError::new("label_name").with_message(format!("Invalid label name '{}'", name).into()));
+        }
+        if value.as_str().is_none_or(|v| v.len() > 63) {
+            return Err(ValidationError::new("label_value")
+                .with_message(format!("Label '{}' must be a string of at most 63 characters", name).into()));
+        }
+    }
+    Ok(())
+}
+
+/// How a node is shown to operators, with `used` the active instances it
+/// hosts.
+pub fn view(node: &Node, used: i64) -> Value {
+    serde_json::json!({
+        "id": node.id,
+        "name": node.name,
+        "region": node.region,
+        "labels": serde_json::from_str::<Value>(&node.labels).unwrap_or(Value::Null),
+        "token_prefix": node.token_prefix,
+        "status": node.status,
+        "capacity": node.capacity,
+        "used": used,
+        "agent_version": node.agent_version,
+        "created_at": node.created_at,
+        "connected_at": node.connected_at,
+        "last_seen_at": node.last_seen_at,
+        "draining": node.draining,
+    })
+}
+
+/// Register a node. Returns the stored row and its token, which is not
+/// kept and cannot be shown again.
+pub async fn create(db: &mut DbConn, spec: &Spec) -> Result<(Node, String), Error> {
+    use crate::schema::nodes::dsl;
+
+    let bytes: [u8; 32] = rand::random();
+    let token = format!("{}{}", TOKEN_PREFIX, bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let row = NewNode {
+        name: spec.name.clone(),
+        region: spec.region.clone(),
+        labels: spec.labels.clone().unwrap_or_else(|| serde_json::json!({})).to_string(),
+        token_prefix: token[..VISIBLE_CHARS].to_string(),
+        token_hash: hash_key(&token),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let created = with_conn!(*db, |c| {
+        diesel::insert_into(dsl::nodes)
+            .values(&row)
+            .returning(Node::as_returning())
+            .get_result(c)
+            .await
+    });
+    match created {
+        Ok(node) => Ok((node, token)),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(Error::NameTaken),
+        Err(e) => Err(Error::Db(e)),
+    }
+}
+
+/// Every node, oldest first, with the active instances each hosts.
+pub async fn list(db: &mut DbConn) -> QueryResult<Vec<(Node, i64)>> {
+    use crate::schema::nodes::dsl;
+
+    let nodes: Vec<Node> = with_conn!(*db, |c| {
+        dsl::nodes.order(dsl::id.asc()).select(Node::as_select()).load(c).await
+    })?;
+    let used = usage(db).await?;
+    Ok(nodes
+        .into_iter()
+        .map(|n| {
+            let count = used.get(&n.id).copied().unwrap_or(0);
+            (n, count)
+        })
+        .collect())
+}
+
+/// Active instances per node.
+pub async fn usage(db: &mut DbConn) -> QueryResult<HashMap<i32, i64>> {
+    use crate::schema::wa_instances::dsl as w;
+
+    let rows: Vec<(Option<i32>, i64)> = with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.is_not_null())
+            .filter(w::status.eq_any(instance::ACTIVE))
+            .group_by(w::node_id)
+            .select((w::node_id, diesel::dsl::count_star()))
+            .load(c)
+            .await
+    })?;
+    Ok(rows.into_iter().filter_map(|(node, n)| Some((node?, n))).collect())
+}
+
+pub async fn get(db: &mut DbConn, id: i32) -> Result<Node, Error> {
+    use crate::schema::nodes::dsl;
+
+    let row: Option<Node> = with_conn!(*db, |c| {
+        dsl::nodes.find(id).select(Node::as_select()).first(c).await.optional()
+    })?;
+    row.ok_or(Error::NotFound)
+}
+
+/// The node named `name`, if any.
+pub async fn find(db: &mut DbConn, name: &str) -> QueryResult<Option<Node>> {
+    use crate::schema::nodes::dsl;
+
+    with_conn!(*db, |c| {
+        dsl::nodes.filter(dsl::name.eq(name)).select(Node::as_select()).first(c).await.optional()
+    })
+}
+
+/// Instances placed on node `id`, deleted ones aside, oldest first.
+pub async fn hosted(db: &mut DbConn, id: i32) -> QueryResult<Vec<WaInstance>> {
+    use crate::schema::wa_instances::dsl as w;
+
+    with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.eq(id))
+            .filter(w::status.ne(Status::Deleted.as_str()))
+            .order(w::id.asc())
+            .select(WaInstance::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Remove node `id`, disconnecting its agent. Refused while active
+/// instances run on it; the others are left unplaced.
+pub async fn remove(db: &mut DbConn, id: i32) -> Result<Node, Error> {
+    use crate::schema::{nodes::dsl, wa_instances::dsl as w};
+
+    let node = get(db, id).await?;
+    let active: i64 = with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.eq(id))
+            .filter(w::status.eq_any(instance::ACTIVE))
+            .count()
+            .get_result(c)
+            .await
+    })?;
+    if active > 0 {
+        return Err(Error::Busy(active));
+    }
+    with_conn!(*db, |c| diesel::delete(dsl::nodes.find(id)).execute(c).await)?;
+    let _ = REMOVALS.send(id);
+    Ok(node)
+}
+
+/// Start or stop draining node `id`. A draining node takes no new
+/// instances, and the scheduler moves its active ones elsewhere as other
+/// nodes have room.
+pub async fn set_draining(db: &mut DbConn, id: i32, draining: bool) -> Result<Node, Error> {
+    use crate::schema::nodes::dsl;
+
+    let updated: Option<Node> = with_conn!(*db, |c| {
+        diesel::update(dsl::nodes.find(id))
+            .set(dsl::draining.eq(draining))
+            .returning(Node::as_returning())
+            .get_result(c)
+            .await
+            .optional()
+    })?;
+    let node = updated.ok_or(Error::NotFound)?;
+    scheduler::wake();
+    Ok(node)
+}
+
+/// The node whose token is `token`, if any.
+pub async fn authenticate(db: &mut DbConn, token: &str) -> QueryResult<Option<Node>> {
+    use crate::schema::nodes::dsl;
+
+    let hashed = hash_key(token);
+    with_conn!(*db, |c| {
+        dsl::nodes
+            .filter(dsl::token_hash.eq(&hashed))
+            .select(Node::as_select())
+            .first(c)
+            .await
+            .optional()
+    })
+}
+
+/// Record that node `id`'s agent connected offering `capacity`.
+pub async fn register(db: &mut DbConn, id: i32, capacity: i32, version: Option<&str>) -> QueryResult<()> {
+    use crate::schema::nodes::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        diesel::update(dsl::nodes.find(id))
+            .set((
+                dsl::status.eq(ONLINE),
+                dsl::capacity.eq(capacity),
+                dsl::agent_version.eq(version),
+                dsl::connected_at.eq(now),
+                dsl::last_seen_at.eq(now),
+            ))
+            .execute(c)
+            .await
+    })?;
+    info!(node_id = id, capacity, "Node online.");
+    Ok(())
+}
+
+/// Record a heartbeat of node `id`.
+pub async fn seen(db: &mut DbConn, id: i32) -> QueryResult<()> {
+    use crate::schema::nodes::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        diesel::update(dsl::nodes.find(id)).set(dsl::last_seen_at.eq(now)).execute(c).await
+    })?;
+    Ok(())
+}
+
+/// Record that node `id`'s agent went away.
+pub async fn disconnect(db: &mut DbConn, id: i32) -> QueryResult<()> {
+    use crate::schema::nodes::dsl;
+
+    with_conn!(*db, |c| {
+        diesel::update(dsl::nodes.find(id)).set(dsl::status.eq(OFFLINE)).execute(c).await
+    })?;
+    info!(node_id = id, "Node offline.");
+    Ok(())
+}
+
+/// The commands that bring node `id` from running `running` to running
+/// exactly its active instances.
+pub async fn reconcile(db: &mut DbConn, id: i32, running: &[i32]) -> QueryResult<Vec<Command>> {
+    use crate::schema::wa_instances::dsl as w;
+
+    let wanted: Vec<WaInstance> = with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.eq(id))
+            .filter(w::status.eq_any(instance::ACTIVE))
+            .order(w::id.asc())
+            .select(WaInstance::as_select())
+            .load(c)
+            .await
+    })?;
+    let mut commands: Vec<Command> = running
+        .iter()
+        .filter(|r| !wanted.iter().any(|i| i.id == **r))
+        .map(|&r| Command::Stop { id: r })
+        .collect();
+    commands.extend(wanted.iter().filter(|i| !running.contains(&i.id)).map(Command::start));
+    Ok(commands)
+}
+
+/// Whether instance `instance_id` is placed on node `id`.
+pub async fn hosts(db: &mut DbConn, id: i32, instance_id: i32) -> QueryResult<bool> {
+    use crate::schema::wa_instances::dsl as w;
+
+    let placed: Option<Option<i32>> = with_conn!(*db, |c| {
+        w::wa_instances.find(instance_id).select(w::node_id).first(c).await.optional()
+    })?;
+    Ok(placed.flatten() == Some(id))
+}
@@ -0,0 +1,433 @@
+//! Organizations and team membership.
+//!
+//! Instances and the wallet (`billing`) belong to an organization rather
+//! than to a user. Every user has a personal organization they own, created
+//! at signup; users from before organizations existed are given one at
+//! startup by [`migrate_personal`]. Further organizations have members with
+//! a per-organization [`OrgRole`] and grow through emailed invitations.
+//!
+//! Access tokens name the organization they act in (the `org` claim), which
+//! is the personal one after login; `/orgs/{id}/switch` issues a token for
+//! another one and remembers the choice on the session, so refreshes keep
+//! it. Membership is checked against the database on every use, so a removed
+//! member loses access straight away.
+
+use crate::{
+    auth::keyring,
+    mailer::Email,
+    sql::{
+        DbConn, Orchestrator,
+        billing::NewBilling,
+        org::{NewOrgInvitation, NewOrgMember, NewOrganization, OrgInvitation, OrgMember, Organization},
+        transaction, with_conn,
+        user::User,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+use validator::ValidationError;
+
+/// How long an invitation link stays valid (seconds).
+pub const INVITATION_EXPIRY_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Longest organization name accepted.
+pub const MAX_NAME_LEN: usize = 100;
+
+/// Audience of invitation tokens, so they are accepted for nothing else.
+const INVITATION_AUDIENCE: &str = "orsta:org_invitation";
+
+/// What a member may do in an organization. Ordered: each role can do
+/// everything the ones before it can.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    /// Can see the organization, its instances and its wallet.
+    #[default]
+    Member,
+    /// Can also spend from the wallet, rename the organization, invite and
+    /// remove members.
+    Admin,
+    /// Can also appoint owners and delete the organization.
+    Owner,
+}
+
+impl OrgRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<OrgRole> {
+        match value {
+            "member" => Some(OrgRole::Member),
+            "admin" => Some(OrgRole::Admin),
+            "owner" => Some(OrgRole::Owner),
+            _ => None,
+        }
+    }
+
+    /// Role stored in `org_members.role`. Anything unrecognised grants the
+    /// least.
+    pub fn of(stored: &str) -> OrgRole {
+        OrgRole::parse(stored).unwrap_or_default()
+    }
+}
+
+/// An organization together with the caller's role in it.
+#[derive(Debug, Clone)]
+pub struct Membership {
+    pub org: Organization,
+    pub role: OrgRole,
+}
+
+/// The organization `uid` acts in: `org_id` if they are a member of it, or
+/// their personal organization when `org_id` is `None`. `None` if they are
+/// not a member.
+pub async fn resolve(db: &mut DbConn, uid: i32, org_id: Option<i32>) -> QueryResult<Option<Membership>> {
+    use crate::schema::{org_members::dsl as m, organizations::dsl as o};
+
+    let row: Option<(Organization, String)> = with_conn!(*db, |c| {
+        let query = o::organizations
+            .inner_join(m::org_members)
+            .filter(m::user_id.eq(uid))
+            .select((Organization::as_select(), m::role))
+            .into_boxed();
+        let query = match org_id {
+            Some(id) => query.filter(o::id.eq(id)),
+            None => query.filter(o::personal_user_id.eq(uid)),
+        };
+        query.first(c).await.optional()
+    })?;
+    Ok(row.map(|(org, role)| Membership { org, role: OrgRole::of(&role) }))
+}
+
+/// Id of the personal organization of `uid`.
+pub async fn personal_id(db: &mut DbConn, uid: i32) -> QueryResult<Option<i32>> {
+    use crate::schema::organizations::dsl::*;
+
+    with_conn!(*db, |c| {
+        organizations
+            .filter(personal_user_id.eq(uid))
+            .select(id)
+            .first(c)
+            .await
+            .optional()
+    })
+}
+
+/// Every organization `uid` belongs to with their membership, personal one
+/// first.
+pub async fn memberships(db: &mut DbConn, uid: i32) -> QueryResult<Vec<(Organization, OrgMember)>> {
+    use crate::schema::{org_members::dsl as m, organizations::dsl as o};
+
+    with_conn!(*db, |c| {
+        o::organizations
+            .inner_join(m::org_members)
+            .filter(m::user_id.eq(uid))
+            .order((o::personal_user_id.is_null().asc(), o::id.asc()))
+            .select((Organization::as_select(), OrgMember::as_select()))
+            .load(c)
+            .await
+    })
+}
+
+/// Give every user without a personal organization one, owning the
+/// instances and wallet rows they created. Returns how many were created.
+pub async fn migrate_personal(orch: &Orchestrator) -> Result<usize, String> {
+    use crate::schema::{
+        billing::dsl as b, instances::dsl as i, org_members::dsl as m, organizations::dsl as o, users::dsl as u,
+    };
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let users: QueryResult<Vec<(i32, String)>> =
+        with_conn!(db, |c| u::users.select((u::id, u::username)).load(c).await);
+    let users = users.map_err(|e| e.to_string())?;
+    let covered: QueryResult<Vec<Option<i32>>> = with_conn!(db, |c| {
+        o::organizations
+            .filter(o::personal_user_id.is_not_null())
+            .select(o::personal_user_id)
+            .load(c)
+            .await
+    });
+    let covered: HashSet<i32> = covered.map_err(|e| e.to_string())?.into_iter().flatten().collect();
+
+    let now = chrono::Utc::now().timestamp();
+    let mut created = 0;
+    for (uid, name) in users.into_iter().filter(|(uid, _)| !covered.contains(uid)) {
+        let moved: QueryResult<()> = transaction!(db, |c| {
+            let org: Organization = diesel::insert_into(o::organizations)
+                .values(&NewOrganization { name: name.clone(), personal_user_id: Some(uid), created_at: now })
+                .returning(Organization::as_returning())
+                .get_result(c)
+                .await?;
+            diesel::insert_into(m::org_members)
+                .values(&NewOrgMember {
+                    org_id: org.id,
+                    user_id: uid,
+                    role: OrgRole::Owner.as_str().to_string(),
+                    created_at: now,
+                })
+                .execute(c)
+                .await?;
+            diesel::update(i::instances.filter(i::user_id.eq(uid)).filter(i::org_id.is_null()))
+                .set(i::org_id.eq(org.id))
+                .execute(c)
+                .await?;
+            // One wallet per organization; should there be several rows, the
+            // first one becomes it.
+            let wallet: Option<i32> = b::billing
+                .filter(b::user_id.eq(uid))
+                .filter(b::org_id.is_null())
+                .order(b::id.asc())
+                .select(b::id)
+                .first(c)
+                .await
+                .optional()?;
+            match wallet {
+                Some(wallet) => {
+                    diesel::update(b::billing.find(wallet))
+                        .set(b::org_id.eq(org.id))
+                        .execute(c)
+                        .await?;
+                }
+                // Never had one: start it empty, as at signup.
+                None => {
+                    diesel::insert_into(b::billing)
+                        .values(&NewBilling {
+                            user_id: uid,
+                            amount_in_wallet: 0.0,
+                            amount_spent: 0.0,
+                            total_amount_spent: 0.0,
+                            average_hourly_consumption: 0.0,
+                            org_id: Some(org.id),
+                        })
+                        .execute(c)
+                        .await?;
+                }
+            }
+            Ok::<_, diesel::result::Error>(())
+        });
+        moved.map_err(|e| format!("user {}: {}", uid, e))?;
+        created += 1;
+    }
+
+    if created > 0 {
+        info!("Created personal organizations for {} existing user(s).", created);
+    }
+    Ok(created)
+}
+
+/// Validation rule for organization names, which are stored trimmed.
+pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ValidationError::new("required").with_message("Name is required".into()));
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(ValidationError::new("length")
+            .with_message(format!("Must be at most {} characters", MAX_NAME_LEN).into()));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Invitations
+// ---------------------------------------------------------------------------
+
+#[derive(Serialize, Deserialize)]
+struct InvitationClaims {
+    /// Organization id (as string).
+    sub: String,
+    aud: String,
+    jti: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Why an invitation could not be accepted.
+#[derive(Debug)]
+pub enum AcceptError {
+    /// Malformed, forged, expired, revoked or already accepted.
+    Invalid,
+    /// Sent to another address than the accepting account's.
+    WrongRecipient,
+    AlreadyMember,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for AcceptError {
+    fn from(e: diesel::result::Error) -> Self {
+        AcceptError::Db(e)
+    }
+}
+
+/// Invite `email` to `org_id` with `role`, replacing any pending invitation
+/// of the same address. Returns the invitation and its token.
+pub async fn invite(
+    db: &mut DbConn,
+    org_id: i32,
+    email: &str,
+    role: OrgRole,
+    invited_by: i32,
+) -> Result<(OrgInvitation, String), String> {
+    use crate::schema::org_invitations::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = InvitationClaims {
+        sub: org_id.to_string(),
+        aud: INVITATION_AUDIENCE.to_string(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        iat: now,
+        exp: now + INVITATION_EXPIRY_SECS,
+    };
+    let token = keyring::sign(&claims).map_err(|e| e.to_string())?;
+
+    let row = NewOrgInvitation {
+        org_id,
+        email: email.to_lowercase(),
+        role: role.as_str().to_string(),
+        invited_by: Some(invited_by),
+        jti: claims.jti,
+        created_at: now,
+        expires_at: claims.exp,
+    };
+    let stored: QueryResult<OrgInvitation> = transaction!(*db, |c| {
+        diesel::update(
+            dsl::org_invitations
+                .filter(dsl::org_id.eq(org_id))
+                .filter(dsl::email.eq(&row.email))
+                .filter(dsl::accepted_at.is_null())
+                .filter(dsl::revoked_at.is_null()),
+        )
+        .set(dsl::revoked_at.eq(now))
+        .execute(c)
+        .await?;
+        diesel::insert_into(dsl::org_invitations)
+            .values(&row)
+            .returning(OrgInvitation::as_returning())
+            .get_result(c)
+            .await
+    });
+
+    Ok((stored.map_err(|e| e.to_string())?, token))
+}
+
+/// Invitations of `org_id` that can still be accepted, newest first.
+pub async fn pending_invitations(db: &mut DbConn, org_id: i32) -> QueryResult<Vec<OrgInvitation>> {
+    use crate::schema::org_invitations::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        dsl::org_invitations
+            .filter(dsl::org_id.eq(org_id))
+            .filter(dsl::accepted_at.is_null())
+            .filter(dsl::revoked_at.is_null())
+            .filter(dsl::expires_at.gt(now))
+            .order(dsl::id.desc())
+            .select(OrgInvitation::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Withdraw a pending invitation of `org_id`. Returns whether there was one.
+pub async fn revoke_invitation(db: &mut DbConn, org_id: i32, invitation_id: i32) -> QueryResult<bool> {
+    use crate::schema::org_invitations::dsl;
+
+    let now = chrono::Utc::now().timestamp();
+    let n = with_conn!(*db, |c| {
+        diesel::update(
+            dsl::org_invitations
+                .find(invitation_id)
+                .filter(dsl::org_id.eq(org_id))
+                .filter(dsl::accepted_at.is_null())
+                .filter(dsl::revoked_at.is_null()),
+        )
+        .set(dsl::revoked_at.eq(now))
+        .execute(c)
+        .await
+    })?;
+    Ok(n > 0)
+}
+
+/// Accept an invitation token as `user`, whose address must be the one it
+/// was sent to, and add them to the organization.
+pub async fn accept(db: &mut DbConn, token: &str, user: &User) -> Result<Membership, AcceptError> {
+    use crate::schema::{org_invitations::dsl as inv, org_members::dsl as m, organizations::dsl as o};
+
+    let claims: InvitationClaims =
+        keyring::verify_for(token, INVITATION_AUDIENCE).map_err(|_| AcceptError::Invalid)?;
+    let org_id: i32 = claims.sub.parse().map_err(|_| AcceptError::Invalid)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let email = user.email.to_lowercase();
+    let outcome: Result<Membership, AcceptError> = transaction!(*db, |c| {
+        let invitation: Option<OrgInvitation> = inv::org_invitations
+            .filter(inv::jti.eq(&claims.jti))
+            .filter(inv::org_id.eq(org_id))
+            .filter(inv::accepted_at.is_null())
+            .filter(inv::revoked_at.is_null())
+            .filter(inv::expires_at.gt(now))
+            .select(OrgInvitation::as_select())
+            .first(c)
+            .await
+            .optional()?;
+        let Some(invitation) = invitation else {
+            return Ok(Err(AcceptError::Invalid));
+        };
+        if invitation.email != email {
+            return Ok(Err(AcceptError::WrongRecipient));
+        }
+
+        let existing: i64 = m::org_members
+            .filter(m::org_id.eq(org_id))
+            .filter(m::user_id.eq(user.id))
+            .count()
+            .get_result(c)
+            .await?;
+        if existing > 0 {
+            return Ok(Err(AcceptError::AlreadyMember));
+        }
+
+        diesel::insert_into(m::org_members)
+            .values(&NewOrgMember {
+                org_id,
+                user_id: user.id,
+                role: invitation.role.clone(),
+                created_at: now,
+            })
+            .execute(c)
+            .await?;
+        diesel::update(inv::org_invitations.find(invitation.id))
+            .set((inv::accepted_at.eq(now), inv::accepted_by.eq(user.id)))
+            .execute(c)
+            .await?;
+        let org: Organization = o::organizations
+            .find(org_id)
+            .select(Organization::as_select())
+            .first(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(Ok(Membership { org, role: OrgRole::of(&invitation.role) }))
+    })?;
+    outcome
+}
+
+/// The email carrying an invitation to `org` from `inviter`.
+pub fn invitation_email(org: &Organization, inviter: &str, to: &str, token: &str) -> Email {
+    let base = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let base = base.trim_end_matches('/');
+    Email {
+        to: to.to_string(),
+        subject: format!("Join {} on Orsta", org.name),
+        body: format!(
+            "Hi,\n\n{} invited you to join the organization \"{}\" on Orsta. To accept, open this link while logged in with this email address (or sign up with it first):\n\n{}/accept-invitation?token={}\n\nThe link is valid for 7 days. If you were not expecting this, you can ignore this email.\n",
+            inviter, org.name, base, token
+        ),
+    }
+}
@@ -59,6 +59,11 @@ impl PaymentProvider for DummyPaymentProvider {
         details: &'a PaymentDetails,
     ) -> Pin<Box<dyn Future<Output = PaymentOutcome> + Send + 'a>> {
         Box::pin(async move {
+            tracing::debug!(
+                description = details.description,
+                metadata = ?details.metadata,
+                "Dummy provider approving charge."
+            );
             PaymentOutcome {
                 success: true,
                 provider: "dummy".to_string(),
@@ -0,0 +1,722 @@
+use crate::{
+    account, audit,
+    auth::{AccountOwner, AuthUser, hash_password, verify_password},
+    email_token::{self, Purpose, RedeemError},
+    mailer::{Email, Mailer},
+    mfa,
+    route::auth::too_many_attempts,
+    session::{self, ClientInfo},
+    sql::{Orchestrator, transaction, user::User, with_conn},
+    throttle::{self, Scope},
+    validate::{self, Valid},
+};
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header::CONTENT_DISPOSITION},
+    response::IntoResponse,
+};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct TokenRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub token: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ForgotPasswordRequest {
+    #[serde(deserialize_with = "validate::email")]
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ResetPasswordRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub token: String,
+    #[validate(custom(function = validate::password))]
+    pub password: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangePasswordRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub current_password: String,
+    #[validate(custom(function = validate::password))]
+    pub new_password: String,
+    /// TOTP or recovery code, required when TOTP is enabled.
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangeEmailRequest {
+    #[serde(deserialize_with = "validate::email")]
+    #[validate(email)]
+    pub email: String,
+    #[validate(custom(function = validate::not_blank))]
+    pub password: String,
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct DeleteAccountRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub password: String,
+    pub code: Option<String>,
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/verify-email
+// ---------------------------------------------------------------------------
+
+/// Mark the address a verification link was sent to as verified.
+pub async fn verify_email(
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<TokenRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let row = match email_token::redeem(&mut db, &body.token, Purpose::VerifyEmail).await {
+        Ok(r) => r,
+        Err(RedeemError::Invalid) => return invalid_link(),
+        Err(RedeemError::Db(e)) => {
+            warn!("Email verification failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to verify email"})));
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let updated = with_conn!(db, |c| {
+        diesel::update(users.find(row.user_id).filter(email.eq(&row.email)))
+            .set(email_verified_at.eq(now))
+            .execute(c)
+            .await
+    });
+
+    match updated {
+        Ok(0) => invalid_link(),
+        Ok(_) => {
+            info!(user_id = row.user_id, "Email address verified.");
+            (StatusCode::OK, Json(serde_json::json!({"ok": true, "email": row.email})))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to verify email"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/verify-email/resend
+// ---------------------------------------------------------------------------
+
+/// Send the caller a new verification link, replacing earlier ones.
+pub async fn resend_verification(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let user: User = match with_conn!(db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await
+    }) {
+        Ok(u) => u,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+    drop(db);
+
+    if user.email_verified_at.is_some() {
+        return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Email address is already verified"})));
+    }
+
+    email_token::send_in_background(orch.clone(), mailer, user, Purpose::VerifyEmail);
+    (StatusCode::ACCEPTED, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/forgot-password
+// ---------------------------------------------------------------------------
+
+/// Mail a reset link if an account uses `email`. The response is the same
+/// either way, so it cannot be used to find out who has an account.
+pub async fn forgot_password(
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Valid(body): Valid<ForgotPasswordRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let ip = ClientInfo::new(&headers, peer).ip.unwrap_or_default();
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+    };
+
+    // Every request counts against the IP, known address or not, so one
+    // client cannot flood inboxes with reset links.
+    match throttle::retry_after(&mut db, &[(Scope::Ip, &ip)]).await {
+        Ok(None) => {}
+        Ok(Some(secs)) => return too_many_attempts(secs),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start password reset"),
+    }
+    if let Ok(Some(secs)) = throttle::record_failure(&mut db, Scope::Ip, &ip, None, Some(&ip)).await {
+        return too_many_attempts(secs);
+    }
+
+    let user: QueryResult<Option<User>> = with_conn!(db, |c| {
+        users
+            .filter(email.eq(&body.email))
+            .select(User::as_select())
+            .first(c)
+            .await
+            .optional()
+    });
+    drop(db);
+
+    match user {
+        Ok(Some(user)) => email_token::send_in_background(orch.clone(), mailer, user, Purpose::ResetPassword),
+        Ok(None) => info!("Password reset requested for an unknown address."),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start password reset"),
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        HeaderMap::new(),
+        Json(serde_json::json!({
+            "ok": true,
+            "message": "If an account uses this address, a reset link is on its way.",
+        })),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/reset-password
+// ---------------------------------------------------------------------------
+
+/// Set a new password with a reset link. Signs the user out everywhere.
+pub async fn reset_password(
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<ResetPasswordRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let row = match email_token::redeem(&mut db, &body.token, Purpose::ResetPassword).await {
+        Ok(r) => r,
+        Err(RedeemError::Invalid) => return invalid_link(),
+        Err(RedeemError::Db(e)) => {
+            warn!("Password reset failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to reset password"})));
+        }
+    };
+    // Release the connection before the (slow) Argon2 hash.
+    drop(db);
+
+    let password = body.password.clone();
+    let hashed = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(h)) => h,
+        _ => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Password hashing failed"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let updated: QueryResult<usize> = transaction!(db, |c| {
+        let n = diesel::update(users.find(row.user_id).filter(email.eq(&row.email)))
+            .set(password_hash.eq(&hashed))
+            .execute(c)
+            .await?;
+        // Following the link also proves the user receives mail there.
+        diesel::update(users.find(row.user_id).filter(email.eq(&row.email)).filter(email_verified_at.is_null()))
+            .set(email_verified_at.eq(now))
+            .execute(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(n)
+    });
+    match updated {
+        Ok(0) => return invalid_link(),
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to reset password"}))),
+    }
+
+    let revoked = session::revoke_all(&mut db, row.user_id, None, "password_reset").await.unwrap_or(0);
+    info!(user_id = row.user_id, revoked, "Password reset.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /account/password
+// ---------------------------------------------------------------------------
+
+/// Change the password, confirming the current one. Every other session is
+/// signed out.
+pub async fn change_password(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<ChangePasswordRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    let user = match reauthenticate(&orch, uid, &body.current_password, body.code.as_deref(), ip.as_deref()).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+
+    let password = body.new_password.clone();
+    let hashed = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(h)) => h,
+        _ => return error(StatusCode::INTERNAL_SERVER_ERROR, "Password hashing failed"),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+    };
+
+    let updated = with_conn!(db, |c| {
+        diesel::update(users.find(uid))
+            .set(password_hash.eq(&hashed))
+            .execute(c)
+            .await
+    });
+    if updated.is_err() {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to change password");
+    }
+
+    let revoked = session::revoke_all(&mut db, uid, claims.sid, "password_changed").await.unwrap_or(0);
+    audit::record(
+        &mut db,
+        audit::PASSWORD_CHANGED,
+        Some(uid),
+        Some(uid),
+        ip.as_deref(),
+        serde_json::json!({"sessions_revoked": revoked}),
+    )
+    .await;
+    info!(user_id = uid, revoked, "Password changed.");
+
+    send_notice(
+        mailer,
+        account::notice(&user.email, &user.username, "Your password was changed", "The password of your Orsta account was just changed, and your other sessions were signed out."),
+    );
+
+    (StatusCode::OK, HeaderMap::new(), Json(serde_json::json!({"ok": true, "sessions_revoked": revoked})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /account/email
+// ---------------------------------------------------------------------------
+
+/// Start moving the account to another address. The change takes effect
+/// once a link sent to the new address is followed.
+pub async fn change_email(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<ChangeEmailRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+    let new_email = body.email;
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    let user = match reauthenticate(&orch, uid, &body.password, body.code.as_deref(), ip.as_deref()).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if validate::normalize_email(&user.email) == new_email {
+        return error(StatusCode::BAD_REQUEST, "This is already the account's address");
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+    };
+    let taken: QueryResult<i64> = with_conn!(db, |c| {
+        users.filter(email.eq(&new_email)).count().get_result(c).await
+    });
+    match taken {
+        Ok(0) => {}
+        Ok(_) => return error(StatusCode::CONFLICT, "Email already in use"),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to change email"),
+    }
+    drop(db);
+
+    let target = User { email: new_email.clone(), ..user };
+    email_token::send_in_background(orch.clone(), mailer, target, Purpose::ChangeEmail);
+
+    (StatusCode::ACCEPTED, HeaderMap::new(), Json(serde_json::json!({"ok": true, "email": new_email})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /account/email/confirm
+// ---------------------------------------------------------------------------
+
+/// Switch the account to the address a change-email link was sent to. The
+/// old address is told about it.
+pub async fn confirm_email_change(
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<TokenRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let row = match email_token::redeem(&mut db, &body.token, Purpose::ChangeEmail).await {
+        Ok(r) => r,
+        Err(RedeemError::Invalid) => return invalid_link(),
+        Err(RedeemError::Db(e)) => {
+            warn!("Email change failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to change email"})));
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let new_email = row.email.clone();
+    let changed: QueryResult<Option<User>> = transaction!(db, |c| {
+        let Some(before) = users.find(row.user_id).select(User::as_select()).first(c).await.optional()? else {
+            return Ok(None);
+        };
+        diesel::update(users.find(row.user_id))
+            .set((email.eq(&new_email), email_verified_at.eq(now)))
+            .execute(c)
+            .await?;
+        Ok(Some(before))
+    });
+
+    let before = match changed {
+        Ok(Some(u)) => u,
+        Ok(None) => return invalid_link(),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Email already in use"})));
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to change email"}))),
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::EMAIL_CHANGED,
+        Some(row.user_id),
+        Some(row.user_id),
+        ip.as_deref(),
+        serde_json::json!({"from": before.email, "to": row.email}),
+    )
+    .await;
+    info!(user_id = row.user_id, "Email address changed.");
+
+    send_notice(
+        mailer,
+        account::notice(
+            &before.email,
+            &before.username,
+            "Your email address was changed",
+            &format!("Your Orsta account now uses {} instead of this address.", row.email),
+        ),
+    );
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true, "email": row.email})))
+}
+
+// ---------------------------------------------------------------------------
+// GET /account/export
+// ---------------------------------------------------------------------------
+
+/// Everything stored about the caller, as a JSON file download.
+pub async fn export(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+    };
+
+    match account::export(&mut db, uid).await {
+        Ok(Some(archive)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(&format!("attachment; filename=\"orsta-export-{}.json\"", uid)).unwrap(),
+            );
+            (StatusCode::OK, headers, Json(archive))
+        }
+        Ok(None) => error(StatusCode::UNAUTHORIZED, "Invalid token"),
+        Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to export data"),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /account/delete
+// ---------------------------------------------------------------------------
+
+/// Schedule the account's deletion after the cooling-off period. Other
+/// sessions are signed out; logging in again still works until then.
+pub async fn schedule_deletion(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<DeleteAccountRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    let user = match reauthenticate(&orch, uid, &body.password, body.code.as_deref(), ip.as_deref()).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if let Some(due) = user.deletion_due_at {
+        return (
+            StatusCode::CONFLICT,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Deletion is already scheduled", "deletion_due_at": due})),
+        );
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable"),
+    };
+
+    match account::sole_owned(&mut db, uid).await {
+        Ok(orgs) if orgs.is_empty() => {}
+        Ok(orgs) => {
+            return (
+                StatusCode::CONFLICT,
+                HeaderMap::new(),
+                Json(serde_json::json!({
+                    "error": "Appoint another owner of these organizations first",
+                    "organizations": orgs
+                        .iter()
+                        .map(|o| serde_json::json!({"id": o.id, "name": o.name}))
+                        .collect::<Vec<_>>(),
+                })),
+            );
+        }
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to schedule deletion"),
+    }
+
+    let due = chrono::Utc::now().timestamp() + account::deletion_grace_secs();
+    match account::set_deletion(&mut db, uid, Some(due)).await {
+        Ok(true) => {}
+        Ok(false) => return error(StatusCode::CONFLICT, "Deletion is already scheduled"),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to schedule deletion"),
+    }
+
+    let revoked = session::revoke_all(&mut db, uid, claims.sid, "deletion_scheduled").await.unwrap_or(0);
+    audit::record(
+        &mut db,
+        audit::ACCOUNT_DELETION_SCHEDULED,
+        Some(uid),
+        Some(uid),
+        ip.as_deref(),
+        serde_json::json!({"deletion_due_at": due}),
+    )
+    .await;
+    info!(user_id = uid, due, "Account deletion scheduled.");
+
+    let when = chrono::DateTime::from_timestamp(due, 0).map(|t| t.to_rfc2822()).unwrap_or_default();
+    send_notice(
+        mailer,
+        account::notice(
+            &user.email,
+            &user.username,
+            "Your account will be deleted",
+            &format!(
+                "Your Orsta account and its data will be deleted on {}. Until then you can log in and cancel the deletion.",
+                when
+            ),
+        ),
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        HeaderMap::new(),
+        Json(serde_json::json!({"ok": true, "deletion_due_at": due, "sessions_revoked": revoked})),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// POST /account/delete/cancel
+// ---------------------------------------------------------------------------
+
+pub async fn cancel_deletion(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match account::set_deletion(&mut db, uid, None).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "No deletion is scheduled"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to cancel deletion"}))),
+    }
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ACCOUNT_DELETION_CANCELLED,
+        Some(uid),
+        Some(uid),
+        ip.as_deref(),
+        serde_json::json!({}),
+    )
+    .await;
+    info!(user_id = uid, "Account deletion cancelled.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+fn invalid_link() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "Invalid or expired link"})))
+}
+
+fn error(status: StatusCode, message: &str) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    (status, HeaderMap::new(), Json(serde_json::json!({"error": message})))
+}
+
+/// Check the caller's password, and their TOTP or recovery code if they
+/// have TOTP, before a change to the account. Wrong passwords count towards
+/// the account's lockout like failed logins.
+async fn reauthenticate(
+    orch: &Orchestrator,
+    uid: i32,
+    password: &str,
+    code: Option<&str>,
+    ip: Option<&str>,
+) -> Result<User, (StatusCode, HeaderMap, Json<serde_json::Value>)> {
+    use crate::schema::users::dsl::*;
+
+    let unavailable = || error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable");
+
+    let mut db = orch.conn().await.map_err(|_| unavailable())?;
+    let user: User = with_conn!(db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await
+    })
+    .map_err(|_| error(StatusCode::UNAUTHORIZED, "Invalid token"))?;
+
+    let key = throttle::account_key(&user.email);
+    match throttle::retry_after(&mut db, &[(Scope::Account, &key)]).await {
+        Ok(None) => {}
+        Ok(Some(secs)) => return Err(too_many_attempts(secs)),
+        Err(_) => return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to check password")),
+    }
+    // Release the connection before the (slow) Argon2 check.
+    drop(db);
+
+    let candidate = password.to_string();
+    let stored_hash = user.password_hash.clone();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&candidate, &stored_hash))
+        .await
+        .unwrap_or(false);
+
+    let mut db = orch.conn().await.map_err(|_| unavailable())?;
+    if !verified {
+        return Err(match throttle::record_failure(&mut db, Scope::Account, &key, Some(uid), ip).await {
+            Ok(Some(secs)) => too_many_attempts(secs),
+            _ => error(StatusCode::UNAUTHORIZED, "Invalid credentials"),
+        });
+    }
+
+    if mfa::totp_enabled(&mut db, uid).await.unwrap_or(false) {
+        let Some(code) = code else {
+            return Err(error(StatusCode::UNAUTHORIZED, "A two-factor code is required"));
+        };
+        match mfa::verify_code(&mut db, uid, code).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(error(StatusCode::UNAUTHORIZED, "Invalid code")),
+            Err(_) => return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to check code")),
+        }
+    }
+
+    Ok(user)
+}
+
+/// Mail a security notice without holding up the response.
+fn send_notice(mailer: Arc<dyn Mailer>, message: Email) {
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&message).await {
+            warn!(to = message.to, "Could not send notice: {}", e);
+        }
+    });
+}
@@ -0,0 +1,785 @@
+use crate::{
+    api_key, audit,
+    auth::{RequireRole, Role, generate_impersonation_token, role},
+    impersonation,
+    instance::{self, Op},
+    org,
+    session::{self, ClientInfo},
+    sql::{
+        DbConn, Orchestrator,
+        billing::Billing,
+        instance::Instance,
+        transaction,
+        user::User,
+        user_property::UserProperty,
+        with_conn,
+    },
+    throttle,
+    validate::{self, Valid},
+};
+use super::node;
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+
+/// Most users returned by one search.
+const MAX_PAGE_SIZE: i64 = 200;
+
+diesel::define_sql_function! {
+    fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text;
+}
+
+/// Operator endpoints, mounted under `/admin`. Support staff can look;
+/// changing an account takes an admin.
+pub fn router() -> Router<Arc<Orchestrator>> {
+    Router::new()
+        .route("/users", get(list_users))
+        .route("/users/{id}", get(user_detail))
+        .route("/users/{id}/suspend", post(suspend))
+        .route("/users/{id}/unsuspend", post(unsuspend))
+        .route("/users/{id}/wallet", post(adjust_wallet))
+        .route("/users/{id}/deactivate-api-key", post(deactivate_api_key))
+        .route("/users/{id}/role", put(set_role))
+        .route("/users/{id}/impersonate", post(impersonate))
+        .route("/instances/{id}/suspend", post(suspend_instance))
+        .route("/instances/{id}/unsuspend", post(unsuspend_instance))
+        .route("/nodes", get(node::list).post(node::create))
+        .route("/nodes/{id}", get(node::get).delete(node::remove))
+        .route("/nodes/{id}/drain", post(node::drain))
+        .route("/nodes/{id}/undrain", post(node::undrain))
+        .route("/lockouts", get(lockouts))
+        .route("/lockouts/{id}", delete(unlock))
+}
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize)]
+pub struct UserSearch {
+    /// Part of a username or email address, case-insensitive.
+    pub q: Option<String>,
+    pub role: Option<String>,
+    pub suspended: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct SuspendRequest {
+    #[validate(custom(function = validate::not_blank), length(max = 500))]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct WalletRequest {
+    /// Added to the wallet; negative to take money out.
+    #[validate(custom(function = validate::adjustment))]
+    pub amount: f64,
+    #[validate(custom(function = validate::not_blank), length(max = 500))]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct DeactivateApiKeyRequest {
+    #[validate(length(max = 500))]
+    pub reason: Option<String>,
+    /// Also revoke every key, so paying again does not bring them back.
+    #[serde(default)]
+    pub revoke_keys: bool,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RoleRequest {
+    #[validate(custom(function = validate::role))]
+    pub role: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ImpersonateRequest {
+    /// Why the account is being viewed, e.g. a ticket number.
+    #[validate(custom(function = validate::not_blank), length(max = 500))]
+    pub reason: String,
+    /// How long the token lasts (seconds).
+    #[validate(range(min = 1, max = impersonation::MAX_IMPERSONATION_SECS))]
+    pub duration_secs: Option<i64>,
+}
+
+/// The parts of a user operators see; never the password hash.
+fn user_json(u: &User) -> serde_json::Value {
+    serde_json::json!({
+        "id": u.id,
+        "username": u.username,
+        "email": u.email,
+        "role": u.role,
+        "email_verified_at": u.email_verified_at,
+        "require_passkey": u.require_passkey,
+        "suspended_at": u.suspended_at,
+        "suspended_reason": u.suspended_reason,
+    })
+}
+
+async fn find_user(db: &mut DbConn, uid: i32) -> QueryResult<Option<User>> {
+    use crate::schema::users::dsl::*;
+
+    with_conn!(*db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await.optional()
+    })
+}
+
+// ---------------------------------------------------------------------------
+// GET /admin/users
+// ---------------------------------------------------------------------------
+
+/// Users matching `q`, `role` and `suspended`, in signup order.
+pub async fn list_users(
+    _staff: RequireRole<role::Support>,
+    State(orch): State<Arc<Orchestrator>>,
+    Query(search): Query<UserSearch>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let limit = search.limit.unwrap_or(50).clamp(1, MAX_PAGE_SIZE);
+    let offset = search.offset.unwrap_or(0).max(0);
+    let pattern = search.q.as_deref().map(str::trim).filter(|q| !q.is_empty()).map(|q| {
+        let escaped = q.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        format!("%{}%", escaped)
+    });
+    if let Some(r) = &search.role
+        && Role::parse(r).is_none()
+    {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("Unknown role '{}'", r)})));
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let found: QueryResult<Vec<User>> = with_conn!(db, |c| {
+        let mut query = users.select(User::as_select()).order(id.asc()).limit(limit).offset(offset).into_boxed();
+        if let Some(p) = &pattern {
+            query = query.filter(lower(username).like(p).escape('\\').or(lower(email).like(p).escape('\\')));
+        }
+        if let Some(r) = &search.role {
+            query = query.filter(role.eq(r));
+        }
+        match search.suspended {
+            Some(true) => query = query.filter(suspended_at.is_not_null()),
+            Some(false) => query = query.filter(suspended_at.is_null()),
+            None => {}
+        }
+        query.load(c).await
+    });
+
+    match found {
+        Ok(list) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "users": list.iter().map(user_json).collect::<Vec<_>>(),
+                "limit": limit,
+                "offset": offset,
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load users"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /admin/users/{id}
+// ---------------------------------------------------------------------------
+
+/// A user with their `user_property`, the organizations they belong to,
+/// and the `instances` and `billing` rows of their personal organization.
+pub async fn user_detail(
+    _staff: RequireRole<role::Support>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(uid): Path<i32>,
+) -> impl IntoResponse {
+    use crate::schema::{billing::dsl as b, instances::dsl as i, user_property::dsl as p};
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let user = match find_user(&mut db, uid).await {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load user"}))),
+    };
+    let orgs = match org::memberships(&mut db, uid).await {
+        Ok(rows) => rows,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load user"}))),
+    };
+    let personal = orgs.iter().find(|(o, _)| o.personal_user_id == Some(uid)).map(|(o, _)| o.id);
+
+    let rows: QueryResult<(Option<UserProperty>, Vec<Instance>, Option<Billing>)> = transaction!(db, |c| {
+        let property = p::user_property
+            .filter(p::user_id.eq(uid))
+            .select(UserProperty::as_select())
+            .first(c)
+            .await
+            .optional()?;
+        let instances = i::instances
+            .filter(i::org_id.eq(personal))
+            .order(i::id.asc())
+            .select(Instance::as_select())
+            .load(c)
+            .await?;
+        let billing = b::billing
+            .filter(b::org_id.eq(personal))
+            .select(Billing::as_select())
+            .first(c)
+            .await
+            .optional()?;
+        Ok::<_, diesel::result::Error>((property, instances, billing))
+    });
+
+    match rows {
+        Ok((property, instances, billing)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "user": user_json(&user),
+                "user_property": property,
+                "organizations": orgs
+                    .iter()
+                    .map(|(o, m)| serde_json::json!({
+                        "id": o.id,
+                        "name": o.name,
+                        "personal": o.personal_user_id.is_some(),
+                        "role": m.role,
+                    }))
+                    .collect::<Vec<_>>(),
+                "instances": instances,
+                "billing": billing,
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load user"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/users/{id}/suspend
+// ---------------------------------------------------------------------------
+
+/// Block an account: its sessions end, and it can neither log in nor use
+/// its API keys until unsuspended.
+pub async fn suspend(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+    Valid(body): Valid<SuspendRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    if actor == Some(uid) {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "You cannot suspend yourself"})));
+    }
+    let reason = body.reason.trim();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let updated = with_conn!(db, |c| {
+        diesel::update(users.find(uid).filter(suspended_at.is_null()))
+            .set((suspended_at.eq(now), suspended_reason.eq(reason)))
+            .execute(c)
+            .await
+    });
+    match updated {
+        Ok(0) => {
+            return match find_user(&mut db, uid).await {
+                Ok(Some(_)) => (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Account is already suspended"}))),
+                _ => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+            };
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to suspend account"}))),
+    }
+
+    let sessions = session::revoke_all(&mut db, uid, None, "suspended").await.unwrap_or(0);
+    api_key::announce_disabled(uid);
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ACCOUNT_SUSPENDED,
+        Some(uid),
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"reason": reason, "sessions_revoked": sessions}),
+    )
+    .await;
+    info!(user_id = uid, admin_id = actor, "Account suspended.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true, "sessions_revoked": sessions})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/users/{id}/unsuspend
+// ---------------------------------------------------------------------------
+
+pub async fn unsuspend(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let updated = with_conn!(db, |c| {
+        diesel::update(users.find(uid).filter(suspended_at.is_not_null()))
+            .set((suspended_at.eq(None::<i64>), suspended_reason.eq(None::<String>)))
+            .execute(c)
+            .await
+    });
+    match updated {
+        Ok(0) => {
+            return match find_user(&mut db, uid).await {
+                Ok(Some(_)) => (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Account is not suspended"}))),
+                _ => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+            };
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to unsuspend account"}))),
+    }
+
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(&mut db, audit::ACCOUNT_UNSUSPENDED, Some(uid), actor, ip.as_deref(), serde_json::json!({})).await;
+    info!(user_id = uid, admin_id = actor, "Account unsuspended.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/instances/{id}/suspend, /admin/instances/{id}/unsuspend
+// ---------------------------------------------------------------------------
+
+/// Take an instance offline, in any organization. Its members cannot start
+/// it again until it is unsuspended.
+pub async fn suspend_instance(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+    Valid(body): Valid<SuspendRequest>,
+) -> impl IntoResponse {
+    let ip = ClientInfo::new(&headers, peer).ip;
+    set_instance_suspension(admin, &orch, ip, id, Op::Suspend, body.reason.trim()).await
+}
+
+/// Lift an instance's suspension, leaving it stopped.
+pub async fn unsuspend_instance(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let ip = ClientInfo::new(&headers, peer).ip;
+    set_instance_suspension(admin, &orch, ip, id, Op::Unsuspend, "Suspension lifted").await
+}
+
+async fn set_instance_suspension(
+    admin: RequireRole<role::Admin>,
+    orch: &Orchestrator,
+    ip: Option<String>,
+    id: i32,
+    op: Op,
+    reason: &str,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let changed = match instance::apply(&mut db, None, actor, id, op, Some(reason)).await {
+        Ok(i) => i,
+        Err(e) => return crate::route::instance::error_response(e),
+    };
+
+    let action = if op == Op::Suspend { audit::INSTANCE_SUSPENDED } else { audit::INSTANCE_UNSUSPENDED };
+    audit::record(
+        &mut db,
+        action,
+        changed.created_by,
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"instance_id": id, "org_id": changed.org_id, "reason": reason}),
+    )
+    .await;
+    info!(instance_id = id, org_id = changed.org_id, admin_id = actor, "Instance {:?}.", op);
+
+    (StatusCode::OK, Json(instance::view(&changed)))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/users/{id}/wallet
+// ---------------------------------------------------------------------------
+
+/// Credit or debit the wallet of a user's personal organization. The reason
+/// goes to the audit log.
+pub async fn adjust_wallet(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+    Valid(body): Valid<WalletRequest>,
+) -> impl IntoResponse {
+    use crate::schema::billing::dsl::*;
+
+    let reason = body.reason.trim();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let personal = match org::personal_id(&mut db, uid).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to adjust wallet"}))),
+    };
+
+    // `None` when there is no wallet; `Some(Err(balance))` when the debit is
+    // larger than it.
+    let adjusted: QueryResult<Option<Result<f64, f64>>> = transaction!(db, |c| {
+        let wallet: Option<f64> = billing
+            .filter(org_id.eq(personal))
+            .select(amount_in_wallet)
+            .first(c)
+            .await
+            .optional()?;
+        let Some(wallet) = wallet else {
+            return Ok(None);
+        };
+        let balance = wallet + body.amount;
+        if balance < 0.0 {
+            return Ok(Some(Err(wallet)));
+        }
+        diesel::update(billing.filter(org_id.eq(personal)))
+            .set(amount_in_wallet.eq(balance))
+            .execute(c)
+            .await?;
+        Ok(Some(Ok(balance)))
+    });
+
+    let balance = match adjusted {
+        Ok(Some(Ok(b))) => b,
+        Ok(Some(Err(wallet))) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({"error": "Wallet cannot go below zero", "amount_in_wallet": wallet})),
+            );
+        }
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to adjust wallet"}))),
+    };
+
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::WALLET_ADJUSTED,
+        Some(uid),
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"amount": body.amount, "reason": reason, "amount_in_wallet": balance}),
+    )
+    .await;
+    info!(user_id = uid, admin_id = actor, amount = body.amount, "Wallet adjusted.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true, "amount_in_wallet": balance})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/users/{id}/deactivate-api-key
+// ---------------------------------------------------------------------------
+
+/// Switch off a user's API access, and optionally revoke all their keys.
+pub async fn deactivate_api_key(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+    Valid(body): Valid<DeactivateApiKeyRequest>,
+) -> impl IntoResponse {
+    use crate::schema::user_property::dsl::*;
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let updated = with_conn!(db, |c| {
+        diesel::update(user_property.filter(user_id.eq(uid)))
+            .set(api_key_active.eq(false))
+            .execute(c)
+            .await
+    });
+    match updated {
+        Ok(0) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Ok(_) => api_key::announce_disabled(uid),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to deactivate API key"}))),
+    }
+
+    let revoked = if body.revoke_keys {
+        match api_key::revoke_all(&mut db, uid).await {
+            Ok(n) => n,
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to revoke API keys"}))),
+        }
+    } else {
+        0
+    };
+
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::API_KEY_DEACTIVATED,
+        Some(uid),
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"reason": body.reason, "keys_revoked": revoked}),
+    )
+    .await;
+    info!(user_id = uid, admin_id = actor, keys_revoked = revoked, "API access deactivated by an administrator.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true, "keys_revoked": revoked})))
+}
+
+// ---------------------------------------------------------------------------
+// PUT /admin/users/{id}/role
+// ---------------------------------------------------------------------------
+
+/// Change a user's role. A demotion applies at once; a promotion once the
+/// user's access token is next refreshed.
+pub async fn set_role(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+    Valid(body): Valid<RoleRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let Some(new_role) = Role::parse(&body.role) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("Unknown role '{}'", body.role)})));
+    };
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    if actor == Some(uid) {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "You cannot change your own role"})));
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let old = match find_user(&mut db, uid).await {
+        Ok(Some(u)) => u.role,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load user"}))),
+    };
+
+    let updated = with_conn!(db, |c| {
+        diesel::update(users.find(uid)).set(role.eq(new_role.as_str())).execute(c).await
+    });
+    if updated.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to change role"})));
+    }
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ROLE_CHANGED,
+        Some(uid),
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"from": old, "to": new_role}),
+    )
+    .await;
+    info!(user_id = uid, admin_id = actor, role = new_role.as_str(), "Role changed.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true, "role": new_role})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/users/{id}/impersonate
+// ---------------------------------------------------------------------------
+
+/// Mint a token to act as a user. Only plain users can be impersonated, and
+/// the token is not set as a cookie so the admin's own session is kept.
+pub async fn impersonate(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(uid): Path<i32>,
+    Valid(body): Valid<ImpersonateRequest>,
+) -> impl IntoResponse {
+    let Some(actor) = admin.claims.sub.parse::<i32>().ok() else {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"})));
+    };
+    let reason = body.reason.trim();
+    let secs = body.duration_secs.unwrap_or(impersonation::DEFAULT_IMPERSONATION_SECS);
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let user = match find_user(&mut db, uid).await {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load user"}))),
+    };
+    if Role::of(&user.role) != Role::User {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Staff accounts cannot be impersonated"})));
+    }
+    if user.suspended_at.is_some() {
+        return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Account is suspended"})));
+    }
+
+    let client = ClientInfo::new(&headers, peer);
+    let ip = client.ip.clone();
+    let expires_at = chrono::Utc::now().timestamp() + secs;
+    let session = match session::start_impersonation(&mut db, uid, actor, expires_at, client).await {
+        Ok(s) => s,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start session"}))),
+    };
+    let token = match generate_impersonation_token(uid, &user.username, session.id, actor, expires_at, None) {
+        Ok(t) => t,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Token generation failed"}))),
+    };
+
+    audit::record(
+        &mut db,
+        audit::IMPERSONATION_STARTED,
+        Some(uid),
+        Some(actor),
+        ip.as_deref(),
+        serde_json::json!({"reason": reason, "sid": session.id, "expires_at": expires_at}),
+    )
+    .await;
+    info!(user_id = uid, admin_id = actor, sid = session.id, "Impersonation started.");
+
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "token": token,
+            "expires_in": secs,
+            "expires_at": expires_at,
+            "session_id": session.id,
+            "user_id": uid,
+            "username": user.username,
+        })),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// GET /admin/lockouts
+// ---------------------------------------------------------------------------
+
+/// Accounts and IPs currently locked out after failed attempts.
+pub async fn lockouts(
+    _staff: RequireRole<role::Support>,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match throttle::locked(&mut db).await {
+        Ok(rows) => {
+            let list: Vec<serde_json::Value> = rows
+                .into_iter()
+                .map(|t| {
+                    serde_json::json!({
+                        "id": t.id,
+                        "scope": t.scope,
+                        "subject": t.subject,
+                        "failures": t.failures,
+                        "last_failure_at": t.last_failure_at,
+                        "locked_until": t.locked_until,
+                    })
+                })
+                .collect();
+            (StatusCode::OK, Json(serde_json::json!({"lockouts": list})))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load lockouts"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /admin/lockouts/{id}
+// ---------------------------------------------------------------------------
+
+/// Lift a lockout and reset its failure count.
+pub async fn unlock(
+    staff: RequireRole<role::Support>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(throttle_id): Path<i32>,
+) -> impl IntoResponse {
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let removed = match throttle::unlock(&mut db, throttle_id).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "Lockout not found"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to unlock"}))),
+    };
+
+    let actor: Option<i32> = staff.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::LOCKOUT_CLEARED,
+        None,
+        actor,
+        ip.as_deref(),
+        serde_json::json!({
+            "scope": removed.scope,
+            "subject": removed.subject,
+            "failures": removed.failures,
+        }),
+    )
+    .await;
+    info!(scope = removed.scope, subject = removed.subject, "Lockout lifted by staff.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+}
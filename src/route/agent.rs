@@ -0,0 +1,317 @@
+//! `/agent`: the WebSocket worker agents keep open to the server.
+//!
+//! Agents authenticate with their node's token (`Authorization: Bearer
+//! on_...`) and speak the same `{action, payload}` envelopes as `/ws`. The
+//! first message must be `register`; after that the server sends `start`
+//! and `stop` commands, and the agent sends `heartbeat`s and the `status`
+//! of its instances. See the README for the messages.
+
+use crate::{
+    instance::{self, Op, Status},
+    node::{self, Command, Worker},
+    scheduler,
+    sql::{DbConn, Orchestrator, node::Node},
+};
+use axum::{
+    extract::{
+        State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
+    },
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use validator::Validate;
+
+/// How long a new connection has to send `register`.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct AgentIncoming {
+    action: String,
+    payload: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct AgentOutgoing {
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AgentOutgoing {
+    fn reply(action: &str, result: Result<Value, String>) -> AgentOutgoing {
+        match result {
+            Ok(data) => AgentOutgoing { action: action.to_string(), data: Some(data), error: None },
+            Err(error) => AgentOutgoing { action: action.to_string(), data: None, error: Some(error) },
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+struct RegisterPayload {
+    /// Active instances the node can host.
+    #[validate(range(min = 0, max = node::MAX_CAPACITY))]
+    capacity: i32,
+    #[validate(length(max = 64))]
+    version: Option<String>,
+    /// Instances the agent is running, e.g. after reconnecting.
+    #[serde(default)]
+    instances: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+struct HeartbeatPayload {
+    #[serde(default)]
+    instances: Vec<i32>,
+}
+
+#[derive(Deserialize, Validate)]
+struct StatusPayload {
+    id: i32,
+    #[validate(custom(function = instance::validate_reported_status))]
+    status: String,
+    #[validate(length(max = instance::MAX_REASON_LEN))]
+    reason: Option<String>,
+}
+
+/// Parse and validate a payload; errors are worded for the agent's log.
+fn payload<T: DeserializeOwned + Validate>(payload: Option<Value>) -> Result<T, String> {
+    let value: T =
+        serde_json::from_value(payload.unwrap_or(Value::Null)).map_err(|e| format!("Invalid payload: {}", e))?;
+    value.validate().map_err(|e| format!("Invalid payload: {}", e))?;
+    Ok(value)
+}
+
+/// A connected agent, fed through the connection's command queue.
+struct AgentLink(mpsc::UnboundedSender<Command>);
+
+impl Worker for AgentLink {
+    fn deliver(&self, command: Command) -> bool {
+        self.0.send(command).is_ok()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// /agent upgrade handler
+// ---------------------------------------------------------------------------
+
+pub async fn agent_handler(
+    ws: WebSocketUpgrade,
+    State(orch): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let token = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return (StatusCode::UNAUTHORIZED, "Missing node token").into_response();
+    };
+    let found = match orch.conn().await {
+        Ok(mut db) => node::authenticate(&mut db, token).await,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response(),
+    };
+    match found {
+        Ok(Some(n)) if node::is_attached(n.id) => (StatusCode::CONFLICT, "Node already connected").into_response(),
+        Ok(Some(n)) => ws.on_upgrade(move |socket| serve(socket, n, orch)).into_response(),
+        Ok(None) => (StatusCode::UNAUTHORIZED, "Invalid node token").into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Per-connection handler
+// ---------------------------------------------------------------------------
+
+type Sink = SplitSink<WebSocket, Message>;
+
+async fn send(sender: &mut Sink, message: &impl Serialize) {
+    let _ = sender.send(Message::Text(serde_json::to_string(message).unwrap().into())).await;
+}
+
+async fn close(sender: &mut Sink, code: u16, reason: &str) {
+    let _ = sender.send(Message::Close(Some(CloseFrame { code, reason: reason.into() }))).await;
+}
+
+/// Serve the agent of node `node` until it goes away or misses its
+/// heartbeats. The node is online for as long as this runs past
+/// registration.
+async fn serve(socket: WebSocket, node: Node, orch: Arc<Orchestrator>) {
+    let (mut sender, mut receiver) = socket.split();
+
+    let hello = match tokio::time::timeout(REGISTER_TIMEOUT, receiver.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<AgentIncoming>(&text).ok(),
+        _ => None,
+    };
+    let register = match hello {
+        Some(m) if m.action == "register" => payload::<RegisterPayload>(m.payload),
+        _ => Err("Expected register".to_string()),
+    };
+    let register = match register {
+        Ok(r) => r,
+        Err(e) => {
+            send(&mut sender, &AgentOutgoing::reply("register", Err(e))).await;
+            close(&mut sender, close_code::POLICY, "Not registered").await;
+            return;
+        }
+    };
+
+    let (commands, mut queue) = mpsc::unbounded_channel();
+    if !node::attach(node.id, Arc::new(AgentLink(commands.clone()))) {
+        send(&mut sender, &AgentOutgoing::reply("register", Err("Node already connected".to_string()))).await;
+        close(&mut sender, close_code::POLICY, "Node already connected").await;
+        return;
+    }
+    let mut removals = node::removals();
+
+    let registered = match orch.conn().await {
+        Ok(mut db) => welcome(&mut db, &node, &register, &commands).await,
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = registered {
+        warn!(node_id = node.id, "Could not register node: {}", e);
+        node::detach(node.id);
+        send(&mut sender, &AgentOutgoing::reply("register", Err("Registration failed".to_string()))).await;
+        close(&mut sender, close_code::ERROR, "Registration failed").await;
+        return;
+    }
+    let heartbeat = node::heartbeat_secs();
+    let data = serde_json::json!({"node_id": node.id, "name": node.name, "heartbeat_secs": heartbeat});
+    send(&mut sender, &AgentOutgoing::reply("registered", Ok(data))).await;
+
+    let allowance = Duration::from_secs(node::heartbeat_allowance());
+    let mut last_heard = Instant::now();
+    let mut check = tokio::time::interval(Duration::from_secs(heartbeat));
+    check.tick().await;
+
+    loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    last_heard = Instant::now();
+                    let reply = match orch.conn().await {
+                        Ok(mut db) => dispatch(&text, &mut db, &node, &commands).await,
+                        Err(_) => Some(AgentOutgoing::reply("error", Err("Database unavailable".to_string()))),
+                    };
+                    if let Some(reply) = reply {
+                        send(&mut sender, &reply).await;
+                    }
+                }
+                Some(Ok(Message::Ping(data))) => {
+                    last_heard = Instant::now();
+                    let _ = sender.send(Message::Pong(data)).await;
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => last_heard = Instant::now(),
+            },
+            Some(command) = queue.recv() => send(&mut sender, &command).await,
+            removed = removals.recv() => {
+                if removed.is_ok_and(|id| id == node.id) {
+                    close(&mut sender, close_code::POLICY, "Node removed").await;
+                    break;
+                }
+            }
+            _ = check.tick() => {
+                if last_heard.elapsed() > allowance {
+                    debug!(node_id = node.id, "Node missed its heartbeats.");
+                    close(&mut sender, close_code::AWAY, "Heartbeat missed").await;
+                    break;
+                }
+            }
+        }
+    }
+
+    node::detach(node.id);
+    match orch.conn().await {
+        Ok(mut db) => {
+            if let Err(e) = node::disconnect(&mut db, node.id).await {
+                warn!(node_id = node.id, "Could not mark node offline: {}", e);
+            }
+        }
+        Err(e) => warn!(node_id = node.id, "Could not mark node offline: {}", e),
+    }
+}
+
+/// Bring a newly registered node online: record it, line up what it
+/// should start and stop, and have the scheduler give it waiting instances.
+async fn welcome(
+    db: &mut DbConn,
+    node: &Node,
+    register: &RegisterPayload,
+    commands: &mpsc::UnboundedSender<Command>,
+) -> Result<(), String> {
+    node::register(db, node.id, register.capacity, register.version.as_deref())
+        .await
+        .map_err(|e| e.to_string())?;
+    for command in node::reconcile(db, node.id, &register.instances).await.map_err(|e| e.to_string())? {
+        let _ = commands.send(command);
+    }
+    scheduler::wake();
+    Ok(())
+}
+
+/// Handle one message from a registered agent. Heartbeats are not
+/// answered; everything else is.
+async fn dispatch(
+    text: &str,
+    db: &mut DbConn,
+    node: &Node,
+    commands: &mpsc::UnboundedSender<Command>,
+) -> Option<AgentOutgoing> {
+    let Ok(incoming) = serde_json::from_str::<AgentIncoming>(text) else {
+        return Some(AgentOutgoing::reply("error", Err("Invalid JSON message".to_string())));
+    };
+    match incoming.action.as_str() {
+        "heartbeat" => {
+            let beat: HeartbeatPayload = match serde_json::from_value(incoming.payload.unwrap_or(Value::Null)) {
+                Ok(b) => b,
+                Err(e) => return Some(AgentOutgoing::reply("heartbeat", Err(format!("Invalid payload: {}", e)))),
+            };
+            let catch_up = match node::seen(db, node.id).await {
+                Ok(()) => node::reconcile(db, node.id, &beat.instances).await,
+                Err(e) => Err(e),
+            };
+            match catch_up {
+                Ok(list) => list.into_iter().for_each(|c| {
+                    let _ = commands.send(c);
+                }),
+                Err(e) => warn!(node_id = node.id, "Could not process heartbeat: {}", e),
+            }
+            None
+        }
+        "status" => Some(AgentOutgoing::reply("status", report(db, node, incoming.payload).await)),
+        "ping" => Some(AgentOutgoing::reply("pong", Ok(serde_json::json!({"node_id": node.id})))),
+        unknown => Some(AgentOutgoing::reply("error", Err(format!("Unknown action: {}", unknown)))),
+    }
+}
+
+/// Record the status an agent reports for one of its instances.
+async fn report(db: &mut DbConn, node: &Node, payload: Option<Value>) -> Result<Value, String> {
+    let body: StatusPayload = self::payload(payload)?;
+    match node::hosts(db, node.id, body.id).await {
+        Ok(true) => {}
+        Ok(false) => return Err("Instance is not placed on this node".to_string()),
+        Err(e) => {
+            warn!(node_id = node.id, "Could not check instance placement: {}", e);
+            return Err("Status could not be recorded".to_string());
+        }
+    }
+    let op = Op::Report(Status::of(&body.status));
+    match instance::apply(db, None, None, body.id, op, body.reason.as_deref()).await {
+        Ok(after) => Ok(serde_json::json!({"id": after.id, "status": after.status})),
+        Err(instance::Error::Db(e)) => {
+            warn!(node_id = node.id, instance_id = body.id, "Could not record reported status: {}", e);
+            Err(instance::Error::Db(e).describe())
+        }
+        Err(e) => Err(e.describe()),
+    }
+}
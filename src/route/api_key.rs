@@ -0,0 +1,205 @@
+use crate::{
+    api_key::{self, Spec},
+    auth::{AccountOwner, AuthUser},
+    session::ClientInfo,
+    sql::{Orchestrator, api_key::ApiKey},
+    validate::{self, Valid},
+};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct CreateKeyRequest {
+    #[validate(custom(function = validate::not_blank), length(max = 64))]
+    pub name: String,
+    #[validate(custom(function = api_key::validate_scopes))]
+    pub scopes: Vec<String>,
+    /// Unix seconds after which the key stops working.
+    #[validate(custom(function = api_key::validate_expiry))]
+    pub expires_at: Option<i64>,
+    /// IPs or CIDR ranges the key may be used from; any if empty.
+    #[serde(default)]
+    #[validate(custom(function = api_key::validate_ranges))]
+    pub allowed_ips: Vec<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RotateKeyRequest {
+    /// How long the replaced key keeps working (seconds); 0 stops it at once.
+    #[validate(range(min = 0, max = api_key::MAX_ROTATION_GRACE_SECS))]
+    pub grace_secs: Option<i64>,
+}
+
+fn key_json(k: &ApiKey) -> serde_json::Value {
+    serde_json::json!({
+        "id": k.id,
+        "name": k.name,
+        "prefix": k.prefix,
+        "scopes": k.scopes.split_whitespace().collect::<Vec<_>>(),
+        "allowed_ips": k.allowed_ips.as_deref().map(|r| r.split_whitespace().collect::<Vec<_>>()),
+        "created_at": k.created_at,
+        "expires_at": k.expires_at,
+        "last_used_at": k.last_used_at,
+        "last_used_ip": k.last_used_ip,
+        "rotated_at": k.rotated_at,
+        "previous_prefix": k.previous_prefix,
+        "previous_expires_at": k.previous_expires_at,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// GET /api-keys
+// ---------------------------------------------------------------------------
+
+pub async fn list(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match api_key::list(&mut db, uid).await {
+        Ok(keys) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "api_keys": keys.iter().map(key_json).collect::<Vec<_>>(),
+                "available_scopes": api_key::SCOPES,
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load API keys"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /api-keys
+// ---------------------------------------------------------------------------
+
+/// Create a key. The response is the only time the key itself is shown.
+pub async fn create(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<CreateKeyRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let spec = Spec {
+        name: body.name,
+        scopes: body.scopes,
+        expires_at: body.expires_at,
+        allowed_ips: body.allowed_ips,
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match api_key::create(&mut db, uid, &spec).await {
+        Ok(Some((row, key))) => {
+            info!(user_id = uid, key_id = row.id, "API key created.");
+            let mut body = key_json(&row);
+            body["key"] = serde_json::Value::String(key);
+            (StatusCode::CREATED, Json(body))
+        }
+        Ok(None) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("At most {} API keys are allowed", api_key::MAX_KEYS_PER_USER),
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to create API key"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /api-keys/{id}
+// ---------------------------------------------------------------------------
+
+pub async fn revoke(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(key_id): Path<i32>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match api_key::revoke(&mut db, uid, key_id).await {
+        Ok(true) => {
+            info!(user_id = uid, key_id, "API key revoked.");
+            (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "API key not found"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to revoke API key"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /api-keys/{id}/rotate
+// ---------------------------------------------------------------------------
+
+/// Give a key a new value, keeping the old one valid for a grace period.
+/// As on creation, the response is the only time the new key is shown.
+pub async fn rotate(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(key_id): Path<i32>,
+    body: Option<Valid<RotateKeyRequest>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let grace_secs = body
+        .and_then(|Valid(b)| b.grace_secs)
+        .unwrap_or_else(api_key::default_grace_secs);
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    match api_key::rotate(&mut db, uid, key_id, grace_secs, ip.as_deref()).await {
+        Ok(Some((row, key))) => {
+            info!(user_id = uid, key_id = row.id, grace_secs, "API key rotated.");
+            let mut body = key_json(&row);
+            body["key"] = serde_json::Value::String(key);
+            (StatusCode::OK, Json(body))
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "API key not found"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to rotate API key"}))),
+    }
+}
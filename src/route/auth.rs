@@ -1,59 +1,98 @@
 use crate::{
     auth::{
-        AuthUser, clear_session_cookie, generate_eakey, generate_token, hash_password,
-        session_cookie, verify_password,
+        ACCESS_TOKEN_EXPIRY_SECS, AuthUser, DUMMY_PASSWORD_HASH, REFRESH_COOKIE_NAME, Role, clear_refresh_cookie,
+        clear_session_cookie, cookie_value, extract_token, generate_token,
+        hash_password, keyring, refresh_cookie, session_cookie, validate_token, verify_password,
     },
+    challenge,
+    email_token::{self, Purpose},
+    mailer::Mailer,
+    mfa,
+    org::{self, OrgRole},
+    session::{self, ClientInfo, RefreshError},
     sql::{
-        Orchestrator,
+        DbConn, Orchestrator,
         billing::NewBilling,
         instance::NewInstance,
+        org::{NewOrgMember, NewOrganization, Organization},
+        transaction, with_conn,
         user::{NewUser, User},
         user_property::NewUserProperty,
     },
+    throttle::{self, Scope},
+    validate::{self, Valid},
 };
 use axum::{
-    Json,
-    extract::State,
-    http::{HeaderMap, HeaderValue, StatusCode},
+    Extension, Json,
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header::RETRY_AFTER},
     response::IntoResponse,
 };
 use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
 use diesel_async::RunQueryDsl;
 use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 // ---------------------------------------------------------------------------
 // Request/Response types
 // ---------------------------------------------------------------------------
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct SignupRequest {
+    #[validate(custom(function = validate::username))]
     pub username: String,
+    #[serde(deserialize_with = "validate::email")]
+    #[validate(email)]
     pub email: String,
+    #[validate(custom(function = validate::password))]
     pub password: String,
-    pub passkey: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct LoginRequest {
+    #[serde(deserialize_with = "validate::email")]
+    #[validate(custom(function = validate::not_blank))]
     pub email: String,
+    /// Not checked against the policy, which may have changed since it was set.
+    #[validate(custom(function = validate::not_blank))]
     pub password: String,
 }
 
+#[derive(Deserialize, Validate)]
+pub struct SecondFactorRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub mfa_token: String,
+    /// TOTP code or recovery code.
+    #[validate(custom(function = validate::not_blank))]
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: Option<String>,
+}
+
 // ---------------------------------------------------------------------------
 // POST /auth/signup
 // ---------------------------------------------------------------------------
 
 pub async fn signup(
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
-    Json(body): Json<SignupRequest>,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<SignupRequest>,
 ) -> impl IntoResponse {
     use crate::schema::users::dsl::*;
 
-    let hashed_password = match hash_password(&body.password) {
-        Ok(h) => h,
-        Err(_) => {
+    let password = body.password.clone();
+    let hashed_password = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(h)) => h,
+        _ => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 HeaderMap::new(),
@@ -62,151 +101,585 @@ pub async fn signup(
         }
     };
 
-    let eakey_val = generate_eakey();
     let new_user = NewUser {
         username: body.username.clone(),
         email: body.email.clone(),
         password_hash: hashed_password,
-        passkey: body.passkey.clone(),
-        eakey: eakey_val.clone(),
+        eakey: crate::api_key::retired_eakey(),
     };
 
-    let mut db = orch.lock().await;
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
 
-    let insert_result = diesel::insert_into(users)
-        .values(&new_user)
-        .execute(&mut db.sqlite)
-        .await;
+    // The user, its personal organization and their companion rows are
+    // created together or not at all.
+    let now = chrono::Utc::now().timestamp();
+    let created: QueryResult<User> = transaction!(db, |c| {
+        diesel::insert_into(users)
+            .values(&new_user)
+            .execute(c)
+            .await?;
 
-    if let Err(e) = insert_result {
-        let msg = if e.to_string().contains("UNIQUE") {
-            "Username or email already exists"
-        } else {
-            "Failed to create user"
-        };
-        return (
-            StatusCode::CONFLICT,
-            HeaderMap::new(),
-            Json(serde_json::json!({"error": msg})),
-        );
-    }
+        let user: User = users
+            .filter(email.eq(&new_user.email))
+            .select(User::as_select())
+            .first(c)
+            .await?;
 
-    // Fetch the newly created user row by email
-    let user: User = match users
-        .filter(email.eq(&body.email))
-        .select(User::as_select())
-        .first(&mut db.sqlite)
-        .await
-    {
+        let personal: Organization = diesel::insert_into(crate::schema::organizations::table)
+            .values(&NewOrganization {
+                name: user.username.clone(),
+                personal_user_id: Some(user.id),
+                created_at: now,
+            })
+            .returning(Organization::as_returning())
+            .get_result(c)
+            .await?;
+
+        diesel::insert_into(crate::schema::org_members::table)
+            .values(&NewOrgMember {
+                org_id: personal.id,
+                user_id: user.id,
+                role: OrgRole::Owner.as_str().to_string(),
+                created_at: now,
+            })
+            .execute(c)
+            .await?;
+
+        diesel::insert_into(crate::schema::user_property::table)
+            .values(&NewUserProperty {
+                user_id: user.id,
+                instance_usage: 0.0,
+                api_key_active: false,
+            })
+            .execute(c)
+            .await?;
+
+        diesel::insert_into(crate::schema::instances::table)
+            .values(&NewInstance {
+                user_id: user.id,
+                instances_count: 0,
+                expected_consumption: 0.0,
+                instances_overall_consumption: 0.0,
+                org_id: Some(personal.id),
+            })
+            .execute(c)
+            .await?;
+
+        diesel::insert_into(crate::schema::billing::table)
+            .values(&NewBilling {
+                user_id: user.id,
+                amount_in_wallet: 0.0,
+                amount_spent: 0.0,
+                total_amount_spent: 0.0,
+                average_hourly_consumption: 0.0,
+                org_id: Some(personal.id),
+            })
+            .execute(c)
+            .await?;
+
+        Ok(user)
+    });
+
+    let user = match created {
         Ok(u) => u,
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return (
+                StatusCode::CONFLICT,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Username or email already exists"})),
+            );
+        }
+        Err(e) => {
+            tracing::warn!("Signup failed: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to create user"})),
+            );
+        }
+    };
+
+    email_token::send_in_background(orch.clone(), mailer, user.clone(), Purpose::VerifyEmail);
+
+    open_session(&mut db, &user, ClientInfo::new(&headers, peer), StatusCode::CREATED).await
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/login
+// ---------------------------------------------------------------------------
+
+pub async fn login(
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<LoginRequest>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let client = ClientInfo::new(&headers, peer);
+    let account = throttle::account_key(&body.email);
+    let ip = client.ip.clone().unwrap_or_default();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+
+    match throttle::retry_after(&mut db, &[(Scope::Account, &account), (Scope::Ip, &ip)]).await {
+        Ok(None) => {}
+        Ok(Some(secs)) => return too_many_attempts(secs),
         Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 HeaderMap::new(),
-                Json(serde_json::json!({"error": "Failed to retrieve created user"})),
+                Json(serde_json::json!({"error": "Failed to start login"})),
+            );
+        }
+    }
+
+    let result: QueryResult<User> = with_conn!(db, |c| {
+        users
+            .filter(email.eq(&body.email))
+            .select(User::as_select())
+            .first(c)
+            .await
+    });
+
+    // Release the connection before the (slow) Argon2 check.
+    drop(db);
+
+    // An unknown address is checked against a dummy hash, so it answers no
+    // sooner than a wrong password for a known one.
+    let password = body.password.clone();
+    let stored_hash = result.as_ref().map_or(DUMMY_PASSWORD_HASH, |u| u.password_hash.as_str()).to_string();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&password, &stored_hash))
+        .await
+        .unwrap_or(false);
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
             );
         }
     };
 
-    // Create companion rows
-    let _ = diesel::insert_into(crate::schema::user_property::table)
-        .values(&NewUserProperty {
-            user_id: user.id,
-            instance_status: "inactive".to_string(),
-            instance_usage: 0.0,
-            api_key_active: false,
-        })
-        .execute(&mut db.sqlite)
-        .await;
-
-    let _ = diesel::insert_into(crate::schema::instances::table)
-        .values(&NewInstance {
-            user_id: user.id,
-            instances_count: 0,
-            expected_consumption: 0.0,
-            instances_overall_consumption: 0.0,
-        })
-        .execute(&mut db.sqlite)
-        .await;
-
-    let _ = diesel::insert_into(crate::schema::billing::table)
-        .values(&NewBilling {
-            user_id: user.id,
-            amount_in_wallet: 0.0,
-            amount_spent: 0.0,
-            total_amount_spent: 0.0,
-            average_hourly_consumption: 0.0,
-        })
-        .execute(&mut db.sqlite)
-        .await;
-
-    let token = match generate_token(user.id, &user.username) {
-        Ok(t) => t,
+    let user = match result {
+        Ok(u) if verified => u,
+        Ok(u) => return login_failed(&mut db, &account, Some(u.id), &ip, "Invalid credentials").await,
+        Err(_) => return login_failed(&mut db, &account, None, &ip, "Invalid credentials").await,
+    };
+    // The failure count is reset by `open_session`, once any second factor
+    // has been passed too.
+
+    let methods = match mfa::methods(&mut db, &user).await {
+        Ok(m) => m,
         Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 HeaderMap::new(),
-                Json(serde_json::json!({"error": "Token generation failed"})),
+                Json(serde_json::json!({"error": "Failed to start login"})),
             );
         }
     };
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "set-cookie",
-        HeaderValue::from_str(&session_cookie(&token)).unwrap(),
-    );
+    // The password alone is not enough: hand out a token for the second step.
+    if !methods.is_empty() {
+        return match challenge::issue_mfa_token(&mut db, user.id).await {
+            Ok(token) => (
+                StatusCode::OK,
+                HeaderMap::new(),
+                Json(serde_json::json!({
+                    "mfa_required": true,
+                    "mfa_token": token,
+                    "expires_in": challenge::MFA_TOKEN_EXPIRY_SECS,
+                    "methods": methods,
+                })),
+            ),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to start login"})),
+            ),
+        };
+    }
+
+    open_session(&mut db, &user, client, StatusCode::OK).await
+}
+
+/// Count a failed password or second-factor attempt against the account
+/// and the client IP, answering `401` with `error` unless that started a
+/// lockout.
+async fn login_failed(
+    db: &mut DbConn,
+    account: &str,
+    uid: Option<i32>,
+    ip: &str,
+    error: &str,
+) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    let by_account = throttle::record_failure(db, Scope::Account, account, uid, Some(ip)).await;
+    let by_ip = throttle::record_failure(db, Scope::Ip, ip, None, Some(ip)).await;
+
+    match by_account.ok().flatten().max(by_ip.ok().flatten()) {
+        Some(secs) => too_many_attempts(secs),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": error})),
+        ),
+    }
+}
 
+/// `429` telling the client when to try again.
+pub(crate) fn too_many_attempts(secs: i64) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, HeaderValue::from(secs));
     (
-        StatusCode::CREATED,
+        StatusCode::TOO_MANY_REQUESTS,
         headers,
         Json(serde_json::json!({
-            "token": token,
-            "user_id": user.id,
-            "username": user.username,
-            "eakey": user.eakey,
+            "error": "Too many failed attempts, try again later",
+            "retry_after": secs,
         })),
     )
 }
 
 // ---------------------------------------------------------------------------
-// POST /auth/login
+// POST /auth/login/2fa
 // ---------------------------------------------------------------------------
 
-pub async fn login(
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
-    Json(body): Json<LoginRequest>,
+/// Finish a password login with a TOTP or recovery code. A token is
+/// discarded after too many wrong codes, sending the user back to the
+/// password step, and every wrong code counts as a failed login, so fresh
+/// tokens do not buy more guesses. Accounts that require a passkey cannot
+/// finish here.
+pub async fn login_2fa(
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<SecondFactorRequest>,
 ) -> impl IntoResponse {
     use crate::schema::users::dsl::*;
 
-    let mut db = orch.lock().await;
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
 
-    let result: QueryResult<User> = users
-        .filter(email.eq(&body.email))
-        .select(User::as_select())
-        .first(&mut db.sqlite)
-        .await;
+    let invalid_token = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Invalid or expired MFA token"})),
+        )
+    };
 
-    let user = match result {
+    let uid = match challenge::peek(&mut db, &body.mfa_token, challenge::MFA).await {
+        Ok(Some(c)) => match c.user_id {
+            Some(uid) => uid,
+            None => return invalid_token(),
+        },
+        Ok(None) => return invalid_token(),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to check MFA token"})),
+            );
+        }
+    };
+
+    let user: User = match with_conn!(db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await
+    }) {
+        Ok(u) => u,
+        Err(_) => return invalid_token(),
+    };
+    if user.require_passkey {
+        return (
+            StatusCode::FORBIDDEN,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "This account requires a passkey as its second factor"})),
+        );
+    }
+
+    let client = ClientInfo::new(&headers, peer);
+    let ip = client.ip.clone().unwrap_or_default();
+    let account = throttle::account_key(&user.email);
+    match throttle::retry_after(&mut db, &[(Scope::Account, &account), (Scope::Ip, &ip)]).await {
+        Ok(None) => {}
+        Ok(Some(secs)) => return too_many_attempts(secs),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to check code"})),
+            );
+        }
+    }
+
+    match mfa::verify_code(&mut db, uid, &body.code).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            let _ = challenge::record_failure(&mut db, &body.mfa_token, challenge::MFA, mfa::MAX_CODE_ATTEMPTS).await;
+            return login_failed(&mut db, &account, Some(uid), &ip, "Invalid code").await;
+        }
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to check code"})),
+            );
+        }
+    }
+
+    // Consume the token; losing a race to a concurrent request is a failure.
+    match challenge::take(&mut db, &body.mfa_token, challenge::MFA).await {
+        Ok(Some(_)) => {}
+        _ => return invalid_token(),
+    }
+
+    open_session(&mut db, &user, client, StatusCode::OK).await
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/refresh
+// ---------------------------------------------------------------------------
+
+/// Trade a refresh token (JSON body or `orsta_refresh` cookie) for a new
+/// access token and refresh token. The presented token stops working.
+pub async fn refresh(
+    State(orch): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let Some(presented) = presented_refresh_token(&headers, &body) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Missing refresh token"})),
+        );
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+
+    let (session, refresh_token) = match session::rotate(&mut db, &presented).await {
+        Ok(v) => v,
+        Err(RefreshError::Invalid | RefreshError::Reused) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                cleared_cookies(),
+                Json(serde_json::json!({"error": "Invalid refresh token"})),
+            );
+        }
+        Err(RefreshError::Db(e)) => {
+            tracing::warn!("Session refresh failed: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to refresh session"})),
+            );
+        }
+    };
+
+    let user: User = match with_conn!(db, |c| {
+        users
+            .find(session.user_id)
+            .select(User::as_select())
+            .first(c)
+            .await
+    }) {
         Ok(u) => u,
         Err(_) => {
             return (
                 StatusCode::UNAUTHORIZED,
+                cleared_cookies(),
+                Json(serde_json::json!({"error": "Invalid refresh token"})),
+            );
+        }
+    };
+
+    if user.suspended_at.is_some() {
+        return account_suspended();
+    }
+
+    // Keep the organization chosen on this session while the user is still
+    // a member of it; otherwise go back to the personal one.
+    let active = match org::resolve(&mut db, user.id, session.active_org_id).await {
+        Ok(Some(m)) => Ok(Some(m)),
+        Ok(None) => org::resolve(&mut db, user.id, None).await,
+        Err(e) => Err(e),
+    };
+    let active = match active {
+        Ok(m) => m.map(|m| m.org.id),
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
                 HeaderMap::new(),
-                Json(serde_json::json!({"error": "Invalid credentials"})),
+                Json(serde_json::json!({"error": "Database unavailable"})),
             );
         }
     };
+    token_response(StatusCode::OK, &user, session.id, active, &refresh_token)
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/logout
+// ---------------------------------------------------------------------------
 
-    if !verify_password(&body.password, &user.password_hash) {
+/// Revoke the current session, identified by the access token or, if that
+/// has already expired, by the refresh token, and clear both cookies.
+pub async fn logout(
+    State(orch): State<Arc<Orchestrator>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+
+    let claims = extract_token(&headers).and_then(|t| validate_token(&t).ok());
+    // An impersonation token is sent as a bearer by an admin who may also be
+    // signed in with cookies; ending it must not sign them out.
+    let impersonating = claims.as_ref().is_some_and(|c| c.act.is_some());
+    let mut sid = claims.and_then(|c| c.sid);
+    if sid.is_none()
+        && let Some(presented) = presented_refresh_token(&headers, &body)
+    {
+        sid = session::find_by_refresh_token(&mut db, &presented)
+            .await
+            .ok()
+            .flatten();
+    }
+
+    if let Some(sid) = sid
+        && session::revoke(&mut db, sid, "logout").await.is_err()
+    {
         return (
-            StatusCode::UNAUTHORIZED,
+            StatusCode::INTERNAL_SERVER_ERROR,
             HeaderMap::new(),
-            Json(serde_json::json!({"error": "Invalid credentials"})),
+            Json(serde_json::json!({"error": "Failed to revoke session"})),
         );
     }
 
-    let token = match generate_token(user.id, &user.username) {
+    let cookies = if impersonating { HeaderMap::new() } else { cleared_cookies() };
+    (StatusCode::OK, cookies, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// GET /.well-known/jwks.json
+// ---------------------------------------------------------------------------
+
+/// Public keys that verify Orsta access tokens, for other services.
+pub async fn jwks() -> impl IntoResponse {
+    Json(keyring::jwks())
+}
+
+// ---------------------------------------------------------------------------
+// GET /me  (example authenticated HTTP endpoint)
+// ---------------------------------------------------------------------------
+
+pub async fn me(AuthUser(claims): AuthUser) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "user_id": claims.sub,
+        "username": claims.username,
+        "role": claims.role,
+        "org_id": claims.org,
+    }))
+}
+
+// ---------------------------------------------------------------------------
+// Session helpers
+// ---------------------------------------------------------------------------
+
+/// Start a session for `user` and build the response carrying its tokens.
+/// Suspended accounts are refused here, whichever way they logged in.
+pub(crate) async fn open_session(
+    db: &mut DbConn,
+    user: &User,
+    client: ClientInfo,
+    status: StatusCode,
+) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    if user.suspended_at.is_some() {
+        return account_suspended();
+    }
+    // Every factor has been passed: forget the account's failed attempts.
+    if let Err(e) = throttle::clear(db, Scope::Account, &throttle::account_key(&user.email)).await {
+        tracing::warn!(user_id = user.id, "Could not reset failed login count: {}", e);
+    }
+    // A login acts in the personal organization.
+    let personal = match org::personal_id(db, user.id).await {
+        Ok(id) => id,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+    match session::start(db, user.id, client).await {
+        Ok((session, refresh_token)) => token_response(status, user, session.id, personal, &refresh_token),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Failed to start session"})),
+        ),
+    }
+}
+
+/// Issue an access token for session `sid` in organization `org` and
+/// return it together with `refresh_token`, in the body and as cookies.
+fn token_response(
+    status: StatusCode,
+    user: &User,
+    sid: i32,
+    org: Option<i32>,
+    refresh_token: &str,
+) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    let token = match generate_token(user.id, &user.username, Role::of(&user.role), sid, org) {
         Ok(t) => t,
         Err(_) => {
             return (
@@ -218,47 +691,54 @@ pub async fn login(
     };
 
     let mut headers = HeaderMap::new();
-    headers.insert(
+    headers.append(
         "set-cookie",
         HeaderValue::from_str(&session_cookie(&token)).unwrap(),
     );
+    headers.append(
+        "set-cookie",
+        HeaderValue::from_str(&refresh_cookie(refresh_token)).unwrap(),
+    );
 
     (
-        StatusCode::OK,
+        status,
         headers,
         Json(serde_json::json!({
             "token": token,
+            "expires_in": ACCESS_TOKEN_EXPIRY_SECS,
+            "refresh_token": refresh_token,
             "user_id": user.id,
             "username": user.username,
-            "eakey": user.eakey,
+            "org_id": org,
         })),
     )
 }
 
-// ---------------------------------------------------------------------------
-// POST /auth/logout
-// ---------------------------------------------------------------------------
+fn account_suspended() -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    (
+        StatusCode::FORBIDDEN,
+        cleared_cookies(),
+        Json(serde_json::json!({"error": "Account suspended"})),
+    )
+}
 
-pub async fn logout() -> impl IntoResponse {
+fn cleared_cookies() -> HeaderMap {
     let mut headers = HeaderMap::new();
-    headers.insert(
+    headers.append(
         "set-cookie",
         HeaderValue::from_str(&clear_session_cookie()).unwrap(),
     );
-    (
-        StatusCode::OK,
-        headers,
-        Json(serde_json::json!({"ok": true})),
-    )
+    headers.append(
+        "set-cookie",
+        HeaderValue::from_str(&clear_refresh_cookie()).unwrap(),
+    );
+    headers
 }
 
-// ---------------------------------------------------------------------------
-// GET /me  (example authenticated HTTP endpoint)
-// ---------------------------------------------------------------------------
-
-pub async fn me(AuthUser(claims): AuthUser) -> impl IntoResponse {
-    Json(serde_json::json!({
-        "user_id": claims.sub,
-        "username": claims.username,
-    }))
+/// Refresh token from a `{"refresh_token": ...}` body or the refresh cookie.
+fn presented_refresh_token(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<RefreshRequest>(body)
+        .ok()
+        .and_then(|b| b.refresh_token)
+        .or_else(|| cookie_value(headers, REFRESH_COOKIE_NAME))
 }
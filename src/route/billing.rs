@@ -1,27 +1,33 @@
 use crate::{
-    auth::AuthUser,
+    api_key,
+    auth::{AccountOwner, Caller, OrgAccess, org_role, scope},
+    org,
     payment::{PaymentDetails, PaymentProvider},
-    sql::Orchestrator,
+    sql::{Orchestrator, transaction, with_conn},
+    validate::{self, Valid},
 };
 use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use serde::Deserialize;
+use validator::Validate;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
 // ---------------------------------------------------------------------------
 // Request types
 // ---------------------------------------------------------------------------
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct EnableApiKeyRequest {
     /// Payment amount to charge for API key activation.
+    #[validate(custom(function = validate::charge))]
     pub amount: f64,
     /// Human-readable reason shown in payment receipt.
+    #[validate(length(max = 200))]
     pub description: Option<String>,
     /// Provider-specific metadata (card token, etc.).
+    #[validate(custom(function = validate::metadata))]
     pub metadata: Option<serde_json::Value>,
 }
 
@@ -29,11 +35,13 @@ pub struct EnableApiKeyRequest {
 // POST /billing/enable-api-key
 // ---------------------------------------------------------------------------
 
+/// Pay to switch on the caller's API access. The payment is booked to the
+/// active organization's wallet, so it takes an admin of the organization.
 pub async fn enable_api_key(
-    AuthUser(claims): AuthUser,
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
+    OrgAccess { claims, org, .. }: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
     Extension(payment): Extension<Arc<dyn PaymentProvider>>,
-    Json(body): Json<EnableApiKeyRequest>,
+    Valid(body): Valid<EnableApiKeyRequest>,
 ) -> impl IntoResponse {
     use crate::schema::{billing::dsl as bdsl, user_property::dsl::*};
 
@@ -42,6 +50,27 @@ pub async fn enable_api_key(
         Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
     };
 
+    // --- Only verified accounts may be charged ---
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+    let verified: QueryResult<Option<i64>> = with_conn!(db, |c| {
+        use crate::schema::users::dsl as udsl;
+        udsl::users.find(uid).select(udsl::email_verified_at).first(c).await
+    });
+    drop(db);
+    match verified {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({"error": "Verify your email address before enabling billing"})),
+            );
+        }
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    }
+
     // --- Attempt payment ---
     let details = PaymentDetails {
         amount: body.amount,
@@ -72,28 +101,57 @@ pub async fn enable_api_key(
         provider = outcome.provider,
         txn = outcome.transaction_id,
         user_id = uid,
+        org_id = org.id,
         amount = body.amount,
         "API key activation payment succeeded."
     );
 
-    let mut db = orch.lock().await;
-
-    // Activate API key
-    let _ = diesel::update(user_property.filter(user_id.eq(uid)))
-        .set(api_key_active.eq(true))
-        .execute(&mut db.sqlite)
-        .await;
-
-    // Update billing records
-    let _ = diesel::update(bdsl::billing.filter(bdsl::user_id.eq(uid)))
-        .set((
-            bdsl::amount_spent.eq(body.amount),
-            bdsl::total_amount_spent.eq(
-                bdsl::total_amount_spent + body.amount,
-            ),
-        ))
-        .execute(&mut db.sqlite)
-        .await;
+    // The customer has paid: activation and the billing record are written
+    // together, and a failure is reported with the transaction so it can be
+    // put right by hand rather than lost.
+    let recorded: Result<(), String> = match orch.conn().await {
+        Ok(mut db) => transaction!(db, |c| {
+            let activated = diesel::update(user_property.filter(user_id.eq(uid)))
+                .set(api_key_active.eq(true))
+                .execute(c)
+                .await?;
+            let charged = diesel::update(bdsl::billing.filter(bdsl::org_id.eq(org.id)))
+                .set((
+                    bdsl::amount_spent.eq(body.amount),
+                    bdsl::total_amount_spent.eq(
+                        bdsl::total_amount_spent + body.amount,
+                    ),
+                ))
+                .execute(c)
+                .await?;
+            // No row to record it in is as much a failure as a write error.
+            if activated != 1 || charged != 1 {
+                return Err(diesel::result::Error::NotFound);
+            }
+            Ok::<_, diesel::result::Error>(())
+        })
+        .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = recorded {
+        error!(
+            provider = outcome.provider,
+            txn = outcome.transaction_id,
+            user_id = uid,
+            org_id = org.id,
+            amount = body.amount,
+            "Payment taken but the API key was not activated: {}",
+            e
+        );
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": "Payment was taken but API access could not be activated; contact support",
+                "transaction_id": outcome.transaction_id,
+                "provider": outcome.provider,
+            })),
+        );
+    }
 
     (
         StatusCode::OK,
@@ -112,8 +170,8 @@ pub async fn enable_api_key(
 // ---------------------------------------------------------------------------
 
 pub async fn disable_api_key(
-    AuthUser(claims): AuthUser,
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
 ) -> impl IntoResponse {
     use crate::schema::user_property::dsl::*;
 
@@ -122,15 +180,23 @@ pub async fn disable_api_key(
         Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
     };
 
-    let mut db = orch.lock().await;
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
 
-    let result = diesel::update(user_property.filter(user_id.eq(uid)))
-        .set(api_key_active.eq(false))
-        .execute(&mut db.sqlite)
-        .await;
+    let result = with_conn!(db, |c| {
+        diesel::update(user_property.filter(user_id.eq(uid)))
+            .set(api_key_active.eq(false))
+            .execute(c)
+            .await
+    });
 
     match result {
-        Ok(_) => (StatusCode::OK, Json(serde_json::json!({"ok": true, "message": "API key deactivated"}))),
+        Ok(_) => {
+            api_key::announce_disabled(uid);
+            (StatusCode::OK, Json(serde_json::json!({"ok": true, "message": "API key deactivated"})))
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to deactivate API key"}))),
     }
 }
@@ -140,37 +206,44 @@ pub async fn disable_api_key(
 // ---------------------------------------------------------------------------
 
 pub async fn api_key_status(
-    AuthUser(claims): AuthUser,
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
+    caller: Caller<scope::BillingRead>,
+    State(orch): State<Arc<Orchestrator>>,
 ) -> impl IntoResponse {
-    use crate::schema::{user_property::dsl as pdsl, users::dsl as udsl};
-    use crate::sql::{user::User, user_property::UserProperty};
+    use crate::schema::{api_keys::dsl as kdsl, user_property::dsl as pdsl};
 
-    let uid: i32 = match claims.sub.parse() {
+    let uid: i32 = match caller.claims.sub.parse() {
         Ok(v) => v,
         Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
     };
 
-    let mut db = orch.lock().await;
-
-    let user_res = udsl::users
-        .filter(udsl::id.eq(uid))
-        .select(User::as_select())
-        .first(&mut db.sqlite)
-        .await;
-
-    let prop_res: Result<UserProperty, _> = pdsl::user_property
-        .filter(pdsl::user_id.eq(uid))
-        .select(UserProperty::as_select())
-        .first(&mut db.sqlite)
-        .await;
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
 
-    match (user_res, prop_res) {
-        (Ok(user), Ok(prop)) => (
+    let active: QueryResult<bool> = with_conn!(db, |c| {
+        pdsl::user_property
+            .filter(pdsl::user_id.eq(uid))
+            .select(pdsl::api_key_active)
+            .first(c)
+            .await
+    });
+
+    let keys: QueryResult<i64> = with_conn!(db, |c| {
+        kdsl::api_keys
+            .filter(kdsl::user_id.eq(uid))
+            .filter(kdsl::revoked_at.is_null())
+            .count()
+            .get_result(c)
+            .await
+    });
+
+    match (active, keys) {
+        (Ok(active), Ok(keys)) => (
             StatusCode::OK,
             Json(serde_json::json!({
-                "api_key": user.eakey,
-                "active": prop.api_key_active,
+                "active": active,
+                "keys": keys,
             })),
         ),
         _ => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "User not found"}))),
@@ -181,9 +254,10 @@ pub async fn api_key_status(
 // GET /billing/summary
 // ---------------------------------------------------------------------------
 
+/// The wallet of the active organization; API keys see the personal one.
 pub async fn summary(
-    AuthUser(claims): AuthUser,
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
+    Caller { claims, .. }: Caller<scope::BillingRead>,
+    State(orch): State<Arc<Orchestrator>>,
 ) -> impl IntoResponse {
     use crate::schema::billing::dsl::*;
     use crate::sql::billing::Billing;
@@ -193,18 +267,32 @@ pub async fn summary(
         Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
     };
 
-    let mut db = orch.lock().await;
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let active = match org::resolve(&mut db, uid, claims.org).await {
+        Ok(Some(m)) => m.org,
+        Ok(None) => {
+            return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Not a member of this organization"})));
+        }
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
 
-    let result: Result<Billing, _> = billing
-        .filter(user_id.eq(uid))
-        .select(Billing::as_select())
-        .first(&mut db.sqlite)
-        .await;
+    let result: Result<Billing, _> = with_conn!(db, |c| {
+        billing
+            .filter(org_id.eq(active.id))
+            .select(Billing::as_select())
+            .first(c)
+            .await
+    });
 
     match result {
         Ok(b) => (
             StatusCode::OK,
             Json(serde_json::json!({
+                "org_id": active.id,
                 "amount_in_wallet": b.amount_in_wallet,
                 "amount_spent": b.amount_spent,
                 "total_amount_spent": b.total_amount_spent,
@@ -0,0 +1,23 @@
+use crate::sql::{Orchestrator, replication};
+use axum::{Json, extract::State, response::IntoResponse};
+use std::sync::Arc;
+
+// ---------------------------------------------------------------------------
+// GET /health/db
+// ---------------------------------------------------------------------------
+
+/// Report which backend is serving traffic, Postgres health, and how many
+/// SQLite writes are still waiting to replicate.
+pub async fn db_status(State(orch): State<Arc<Orchestrator>>) -> impl IntoResponse {
+    let outbox_pending = match orch.sqlite().await {
+        Ok(mut conn) => replication::pending(&mut conn).await.ok(),
+        Err(_) => None,
+    };
+
+    Json(serde_json::json!({
+        "primary": orch.primary,
+        "active": orch.active(),
+        "postgres": orch.pg_status(),
+        "outbox_pending": outbox_pending,
+    }))
+}
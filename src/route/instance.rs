@@ -0,0 +1,278 @@
+use crate::{
+    auth::{Caller, Claims, scope},
+    instance::{self, Error, Op, Spec},
+    node, org, scheduler,
+    sql::{DbConn, Orchestrator},
+    validate::Valid,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{info, warn};
+use validator::Validate;
+
+type ErrorResponse = (StatusCode, Json<Value>);
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct CreateInstanceRequest {
+    /// Stored trimmed; the same rules as organization names.
+    #[validate(custom(function = org::validate_name))]
+    pub name: String,
+    /// E.164, e.g. `+15551234567`.
+    #[validate(custom(function = instance::validate_phone_number))]
+    pub phone_number: Option<String>,
+    #[validate(custom(function = instance::validate_config))]
+    pub config: Option<Value>,
+    /// Only nodes in this region may run it.
+    #[validate(custom(function = node::validate_name))]
+    pub region: Option<String>,
+    /// Labels its nodes must carry, e.g. `{"tier": "dedicated"}`.
+    #[validate(custom(function = node::validate_labels))]
+    pub affinity: Option<Value>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ReasonRequest {
+    /// Recorded in the instance's history.
+    #[validate(length(max = instance::MAX_REASON_LEN))]
+    pub reason: Option<String>,
+}
+
+impl CreateInstanceRequest {
+    pub fn into_spec(self) -> Spec {
+        Spec {
+            name: self.name,
+            phone_number: self.phone_number,
+            config: self.config,
+            region: self.region,
+            affinity: self.affinity,
+        }
+    }
+}
+
+/// The response for a refused instance operation. Illegal transitions
+/// name the instance's status and where it could go instead.
+pub(crate) fn error_response(e: Error) -> ErrorResponse {
+    let message = e.describe();
+    match e {
+        Error::NotFound => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": message}))),
+        Error::IllegalTransition { from, .. } => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": message, "status": from, "allowed": from.next()})),
+        ),
+        Error::LimitReached | Error::PhoneTaken => (StatusCode::CONFLICT, Json(serde_json::json!({"error": message}))),
+        Error::Db(e) => {
+            warn!("Instance operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": message})))
+        }
+    }
+}
+
+/// A connection and the caller's id and active organization.
+pub(crate) async fn acting(orch: &Orchestrator, claims: &Claims) -> Result<(DbConn, i32, i32), ErrorResponse> {
+    let uid: i32 = claims
+        .sub
+        .parse()
+        .map_err(|_| (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))))?;
+    let unavailable = || (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"})));
+    let mut db = orch.conn().await.map_err(|_| unavailable())?;
+    match org::resolve(&mut db, uid, claims.org).await {
+        Ok(Some(m)) => Ok((db, uid, m.org.id)),
+        Ok(None) => Err((StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Not a member of this organization"})))),
+        Err(_) => Err(unavailable()),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /instances
+// ---------------------------------------------------------------------------
+
+/// Instances of the active organization.
+pub async fn list(
+    Caller { claims, .. }: Caller<scope::InstancesRead>,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let (mut db, _, org_id) = match acting(&orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match instance::list(&mut db, org_id).await {
+        Ok(rows) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "org_id": org_id,
+                "instances": rows.iter().map(instance::view).collect::<Vec<_>>(),
+            })),
+        ),
+        Err(e) => error_response(Error::Db(e)),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /instances
+// ---------------------------------------------------------------------------
+
+pub async fn create(
+    Caller { claims, .. }: Caller<scope::InstancesWrite>,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<CreateInstanceRequest>,
+) -> impl IntoResponse {
+    let (mut db, uid, org_id) = match acting(&orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match instance::create(&mut db, org_id, uid, &body.into_spec()).await {
+        Ok(created) => {
+            info!(user_id = uid, org_id, instance_id = created.id, "Instance created.");
+            (StatusCode::CREATED, Json(instance::view(&created)))
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /instances/{id}
+// ---------------------------------------------------------------------------
+
+pub async fn get(
+    Caller { claims, .. }: Caller<scope::InstancesRead>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let (mut db, _, org_id) = match acting(&orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match instance::get(&mut db, org_id, id).await {
+        Ok(found) => (StatusCode::OK, Json(instance::view(&found))),
+        Err(e) => error_response(e),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /instances/{id}/events
+// ---------------------------------------------------------------------------
+
+/// The instance's status history, oldest first.
+pub async fn events(
+    Caller { claims, .. }: Caller<scope::InstancesRead>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let (mut db, _, org_id) = match acting(&orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match instance::events(&mut db, org_id, id).await {
+        Ok(rows) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"events": rows.iter().map(instance::event_view).collect::<Vec<_>>()})),
+        ),
+        Err(e) => error_response(e),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /instances/{id}/placement
+// ---------------------------------------------------------------------------
+
+/// Where the instance runs: its node, if placed, and why it was put there
+/// or is waiting.
+pub async fn placement(
+    Caller { claims, .. }: Caller<scope::InstancesRead>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let (mut db, _, org_id) = match acting(&orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match whereis(&mut db, org_id, id).await {
+        Ok(body) => (StatusCode::OK, Json(body)),
+        Err(e) => error_response(e),
+    }
+}
+
+/// The placement view of instance `id` of `org_id`.
+pub(crate) async fn whereis(db: &mut DbConn, org_id: i32, id: i32) -> Result<Value, Error> {
+    let found = instance::get(db, org_id, id).await?;
+    let placed_on = match found.node_id {
+        Some(node_id) => match node::get(db, node_id).await {
+            Ok(n) => Some(n),
+            Err(node::Error::Db(e)) => return Err(Error::Db(e)),
+            Err(_) => None,
+        },
+        None => None,
+    };
+    Ok(scheduler::placement_view(&found, placed_on.as_ref()))
+}
+
+// ---------------------------------------------------------------------------
+// POST /instances/{id}/start|stop|restart|status, DELETE /instances/{id}
+// ---------------------------------------------------------------------------
+
+async fn lifecycle(claims: Claims, orch: &Orchestrator, id: i32, op: Op, reason: Option<String>) -> ErrorResponse {
+    let (mut db, uid, org_id) = match acting(orch, &claims).await {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match instance::apply(&mut db, Some(org_id), Some(uid), id, op, reason.as_deref()).await {
+        Ok(after) => {
+            info!(user_id = uid, org_id, instance_id = id, status = after.status, "Instance {:?}.", op);
+            (StatusCode::OK, Json(instance::view(&after)))
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+pub async fn start(
+    Caller { claims, .. }: Caller<scope::InstancesWrite>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+    body: Option<Valid<ReasonRequest>>,
+) -> impl IntoResponse {
+    let reason = body.and_then(|Valid(b)| b.reason);
+    lifecycle(claims, &orch, id, Op::Start, reason).await
+}
+
+pub async fn stop(
+    Caller { claims, .. }: Caller<scope::InstancesWrite>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+    body: Option<Valid<ReasonRequest>>,
+) -> impl IntoResponse {
+    let reason = body.and_then(|Valid(b)| b.reason);
+    lifecycle(claims, &orch, id, Op::Stop, reason).await
+}
+
+pub async fn restart(
+    Caller { claims, .. }: Caller<scope::InstancesWrite>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+    body: Option<Valid<ReasonRequest>>,
+) -> impl IntoResponse {
+    let reason = body.and_then(|Valid(b)| b.reason);
+    lifecycle(claims, &orch, id, Op::Restart, reason).await
+}
+
+/// Soft-delete: the instance keeps its history with status `deleted`.
+pub async fn delete(
+    Caller { claims, .. }: Caller<scope::InstancesWrite>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+    body: Option<Valid<ReasonRequest>>,
+) -> impl IntoResponse {
+    let reason = body.and_then(|Valid(b)| b.reason);
+    lifecycle(claims, &orch, id, Op::Delete, reason).await
+}
+
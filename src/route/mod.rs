@@ -1,18 +1,28 @@
+pub mod account;
+pub mod admin;
+pub mod agent;
+pub mod api_key;
 pub mod auth;
 pub mod billing;
+pub mod health;
+pub mod instance;
+pub mod node;
+pub mod org;
+pub mod passkey;
+pub mod session;
+pub mod two_factor;
 pub mod user;
 pub mod ws;
 
 use crate::sql::Orchestrator;
 use axum::{
-    Router,
-    routing::{get, post},
+    Router, middleware,
+    routing::{delete, get, post, put},
 };
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tower_http::cors::{Any, CorsLayer};
 
-pub fn start_client_api_service(orch: Arc<Mutex<Orchestrator>>) -> Router {
+pub fn start_client_api_service(orch: Arc<Orchestrator>) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -20,15 +30,64 @@ pub fn start_client_api_service(orch: Arc<Mutex<Orchestrator>>) -> Router {
 
     Router::new()
         .route("/health", get(|| async { "OK" }))
+        .route("/health/db", get(health::db_status))
+        .route("/.well-known/jwks.json", get(auth::jwks))
         .route("/auth/signup", post(auth::signup))
         .route("/auth/login", post(auth::login))
+        .route("/auth/login/2fa", post(auth::login_2fa))
+        .route("/auth/verify-email", post(account::verify_email))
+        .route("/auth/verify-email/resend", post(account::resend_verification))
+        .route("/auth/forgot-password", post(account::forgot_password))
+        .route("/auth/reset-password", post(account::reset_password))
+        .route("/account/password", post(account::change_password))
+        .route("/account/email", post(account::change_email))
+        .route("/account/email/confirm", post(account::confirm_email_change))
+        .route("/account/export", get(account::export))
+        .route("/account/delete", post(account::schedule_deletion))
+        .route("/account/delete/cancel", post(account::cancel_deletion))
+        .route("/auth/refresh", post(auth::refresh))
         .route("/auth/logout", post(auth::logout))
+        .route("/auth/sessions", get(session::list))
+        .route("/auth/sessions/revoke-others", post(session::revoke_others))
+        .route("/auth/sessions/{id}", delete(session::revoke))
+        .route("/auth/2fa", get(two_factor::status))
+        .route("/auth/2fa/totp/setup", post(two_factor::totp_setup))
+        .route("/auth/2fa/totp/confirm", post(two_factor::totp_confirm))
+        .route("/auth/2fa/recovery-codes", post(two_factor::regenerate_recovery_codes))
+        .route("/auth/2fa/disable", post(two_factor::disable))
+        .route("/auth/passkeys", get(passkey::list))
+        .route("/auth/passkeys/register/start", post(passkey::register_start))
+        .route("/auth/passkeys/register/finish", post(passkey::register_finish))
+        .route("/auth/passkeys/login/start", post(passkey::login_start))
+        .route("/auth/passkeys/login/finish", post(passkey::login_finish))
+        .route("/auth/passkeys/require", post(passkey::require))
+        .route("/auth/passkeys/{id}", delete(passkey::remove))
         .route("/me", get(auth::me))
+        .route("/api-keys", get(api_key::list).post(api_key::create))
+        .route("/api-keys/{id}", delete(api_key::revoke))
+        .route("/api-keys/{id}/rotate", post(api_key::rotate))
         .route("/billing/enable-api-key", post(billing::enable_api_key))
         .route("/billing/disable-api-key", post(billing::disable_api_key))
         .route("/billing/api-key-status", get(billing::api_key_status))
         .route("/billing/summary", get(billing::summary))
+        .route("/instances", get(instance::list).post(instance::create))
+        .route("/instances/{id}", get(instance::get).delete(instance::delete))
+        .route("/instances/{id}/start", post(instance::start))
+        .route("/instances/{id}/stop", post(instance::stop))
+        .route("/instances/{id}/restart", post(instance::restart))
+        .route("/instances/{id}/events", get(instance::events))
+        .route("/instances/{id}/placement", get(instance::placement))
+        .route("/orgs", get(org::list).post(org::create))
+        .route("/orgs/{id}/switch", post(org::switch))
+        .route("/org", get(org::current).patch(org::rename).delete(org::delete_org))
+        .route("/org/members/{user_id}", put(org::set_member_role).delete(org::remove_member))
+        .route("/org/invitations", get(org::list_invitations).post(org::invite))
+        .route("/org/invitations/{id}", delete(org::revoke_invitation))
+        .route("/invitations/accept", post(org::accept_invitation))
         .route("/ws", get(ws::ws_handler))
+        .route("/agent", get(agent::agent_handler))
+        .nest("/admin", admin::router())
+        .layer(middleware::from_fn_with_state(orch.clone(), crate::impersonation::track))
         .with_state(orch)
         .layer(cors)
 }
@@ -0,0 +1,251 @@
+use crate::{
+    audit,
+    auth::{RequireRole, role},
+    instance,
+    node::{self, Error, Spec},
+    session::ClientInfo,
+    sql::{DbConn, Orchestrator},
+    validate::Valid,
+};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+use validator::Validate;
+
+type ErrorResponse = (StatusCode, Json<Value>);
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct CreateNodeRequest {
+    #[validate(custom(function = node::validate_name))]
+    pub name: String,
+    #[validate(custom(function = node::validate_name))]
+    pub region: Option<String>,
+    /// e.g. `{"tier": "dedicated"}`.
+    #[validate(custom(function = node::validate_labels))]
+    pub labels: Option<Value>,
+}
+
+fn error_response(e: Error) -> ErrorResponse {
+    let message = e.describe();
+    match e {
+        Error::NotFound => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": message}))),
+        Error::NameTaken => (StatusCode::CONFLICT, Json(serde_json::json!({"error": message}))),
+        Error::Busy(n) => (StatusCode::CONFLICT, Json(serde_json::json!({"error": message, "active_instances": n}))),
+        Error::Db(e) => {
+            warn!("Node operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": message})))
+        }
+    }
+}
+
+async fn connect(orch: &Orchestrator) -> Result<DbConn, ErrorResponse> {
+    orch.conn()
+        .await
+        .map_err(|_| (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))))
+}
+
+// ---------------------------------------------------------------------------
+// GET /admin/nodes
+// ---------------------------------------------------------------------------
+
+/// Every node with how many active instances it hosts.
+pub async fn list(_staff: RequireRole<role::Support>, State(orch): State<Arc<Orchestrator>>) -> impl IntoResponse {
+    let mut db = match connect(&orch).await {
+        Ok(db) => db,
+        Err(e) => return e,
+    };
+    match node::list(&mut db).await {
+        Ok(rows) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "nodes": rows.iter().map(|(n, used)| node::view(n, *used)).collect::<Vec<_>>(),
+            })),
+        ),
+        Err(e) => error_response(Error::Db(e)),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/nodes
+// ---------------------------------------------------------------------------
+
+/// Register a node. The response holds the token its agent connects with;
+/// it is shown this once.
+pub async fn create(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<CreateNodeRequest>,
+) -> impl IntoResponse {
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    let mut db = match connect(&orch).await {
+        Ok(db) => db,
+        Err(e) => return e,
+    };
+    let spec = Spec { name: body.name, region: body.region, labels: body.labels };
+    let (created, token) = match node::create(&mut db, &spec).await {
+        Ok(v) => v,
+        Err(e) => return error_response(e),
+    };
+
+    audit::record(
+        &mut db,
+        audit::NODE_CREATED,
+        None,
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"node_id": created.id, "name": created.name}),
+    )
+    .await;
+    info!(node_id = created.id, admin_id = actor, "Node created.");
+
+    let mut body = node::view(&created, 0);
+    body["token"] = token.into();
+    (StatusCode::CREATED, Json(body))
+}
+
+// ---------------------------------------------------------------------------
+// GET /admin/nodes/{id}
+// ---------------------------------------------------------------------------
+
+/// A node with the instances placed on it.
+pub async fn get(
+    _staff: RequireRole<role::Support>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let mut db = match connect(&orch).await {
+        Ok(db) => db,
+        Err(e) => return e,
+    };
+    let found = match node::get(&mut db, id).await {
+        Ok(n) => n,
+        Err(e) => return error_response(e),
+    };
+    let hosted = match node::hosted(&mut db, id).await {
+        Ok(rows) => rows,
+        Err(e) => return error_response(Error::Db(e)),
+    };
+    let used = hosted.iter().filter(|i| instance::Status::of(&i.status).is_active()).count() as i64;
+    let mut body = node::view(&found, used);
+    body["instances"] = hosted.iter().map(instance::view).collect::<Vec<_>>().into();
+    (StatusCode::OK, Json(body))
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /admin/nodes/{id}
+// ---------------------------------------------------------------------------
+
+/// Remove a node and disconnect its agent. Its active instances must be
+/// stopped first.
+pub async fn remove(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(&headers, peer).ip;
+    let mut db = match connect(&orch).await {
+        Ok(db) => db,
+        Err(e) => return e,
+    };
+    let removed = match node::remove(&mut db, id).await {
+        Ok(n) => n,
+        Err(e) => return error_response(e),
+    };
+
+    audit::record(
+        &mut db,
+        audit::NODE_REMOVED,
+        None,
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"node_id": id, "name": removed.name}),
+    )
+    .await;
+    info!(node_id = id, admin_id = actor, "Node removed.");
+
+    (StatusCode::OK, Json(serde_json::json!({"message": "Node removed"})))
+}
+
+// ---------------------------------------------------------------------------
+// POST /admin/nodes/{id}/drain|undrain
+// ---------------------------------------------------------------------------
+
+async fn set_draining(
+    admin: RequireRole<role::Admin>,
+    orch: &Orchestrator,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    id: i32,
+    draining: bool,
+) -> ErrorResponse {
+    let actor: Option<i32> = admin.claims.sub.parse().ok();
+    let ip = ClientInfo::new(headers, peer).ip;
+    let mut db = match connect(orch).await {
+        Ok(db) => db,
+        Err(e) => return e,
+    };
+    let changed = match node::set_draining(&mut db, id, draining).await {
+        Ok(n) => n,
+        Err(e) => return error_response(e),
+    };
+    let used = match node::usage(&mut db).await {
+        Ok(u) => u.get(&id).copied().unwrap_or(0),
+        Err(e) => return error_response(Error::Db(e)),
+    };
+
+    let action = if draining { audit::NODE_DRAINED } else { audit::NODE_UNDRAINED };
+    audit::record(
+        &mut db,
+        action,
+        None,
+        actor,
+        ip.as_deref(),
+        serde_json::json!({"node_id": id, "name": changed.name}),
+    )
+    .await;
+    info!(node_id = id, admin_id = actor, draining, "Node drain changed.");
+
+    (StatusCode::OK, Json(node::view(&changed, used)))
+}
+
+/// Stop placing instances on a node and move its instances elsewhere as
+/// other nodes have room.
+pub async fn drain(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    set_draining(admin, &orch, peer, &headers, id, true).await
+}
+
+/// Let a drained node take instances again. Instances already moved stay
+/// where they are.
+pub async fn undrain(
+    admin: RequireRole<role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    set_draining(admin, &orch, peer, &headers, id, false).await
+}
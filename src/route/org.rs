@@ -0,0 +1,804 @@
+use crate::{
+    audit,
+    auth::{
+        ACCESS_TOKEN_EXPIRY_SECS, AccountOwner, AuthUser, OrgAccess, generate_impersonation_token, generate_token,
+        org_role, session_cookie,
+    },
+    mailer::Mailer,
+    org::{self, AcceptError, OrgRole},
+    session::{self, ClientInfo},
+    sql::{
+        DbConn, Orchestrator,
+        billing::NewBilling,
+        instance::NewInstance,
+        org::{NewOrgMember, NewOrganization, OrgInvitation, Organization},
+        transaction, with_conn,
+        user::User,
+    },
+    validate::{self, Valid},
+};
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+// ---------------------------------------------------------------------------
+// Request types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct OrgNameRequest {
+    #[validate(custom(function = org::validate_name))]
+    pub name: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct MemberRoleRequest {
+    #[validate(custom(function = validate::org_role))]
+    pub role: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct InviteRequest {
+    #[serde(deserialize_with = "validate::email")]
+    #[validate(email)]
+    pub email: String,
+    /// Role on accepting; `member` if absent.
+    #[validate(custom(function = validate::org_role))]
+    pub role: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct AcceptInvitationRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub token: String,
+}
+
+fn org_json(org: &Organization, role: OrgRole) -> serde_json::Value {
+    serde_json::json!({
+        "id": org.id,
+        "name": org.name,
+        "personal": org.personal_user_id.is_some(),
+        "created_at": org.created_at,
+        "role": role,
+    })
+}
+
+fn invitation_json(inv: &OrgInvitation) -> serde_json::Value {
+    serde_json::json!({
+        "id": inv.id,
+        "email": inv.email,
+        "role": inv.role,
+        "invited_by": inv.invited_by,
+        "created_at": inv.created_at,
+        "expires_at": inv.expires_at,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// GET /orgs
+// ---------------------------------------------------------------------------
+
+/// The organizations the caller belongs to, flagging the one the token acts
+/// in.
+pub async fn list(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match org::memberships(&mut db, uid).await {
+        Ok(rows) => {
+            let active = claims.org.or_else(|| rows.iter().find(|(o, _)| o.personal_user_id == Some(uid)).map(|(o, _)| o.id));
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "organizations": rows
+                        .iter()
+                        .map(|(o, m)| {
+                            let mut body = org_json(o, OrgRole::of(&m.role));
+                            body["active"] = serde_json::Value::Bool(Some(o.id) == active);
+                            body
+                        })
+                        .collect::<Vec<_>>(),
+                })),
+            )
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load organizations"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /orgs
+// ---------------------------------------------------------------------------
+
+/// Create an organization owned by the caller, with an empty wallet.
+pub async fn create(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<OrgNameRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+    let name = body.name.trim().to_string();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let created: QueryResult<Organization> = transaction!(db, |c| {
+        let created: Organization = diesel::insert_into(crate::schema::organizations::table)
+            .values(&NewOrganization { name: name.clone(), personal_user_id: None, created_at: now })
+            .returning(Organization::as_returning())
+            .get_result(c)
+            .await?;
+        diesel::insert_into(crate::schema::org_members::table)
+            .values(&NewOrgMember {
+                org_id: created.id,
+                user_id: uid,
+                role: OrgRole::Owner.as_str().to_string(),
+                created_at: now,
+            })
+            .execute(c)
+            .await?;
+        diesel::insert_into(crate::schema::billing::table)
+            .values(&NewBilling {
+                user_id: uid,
+                amount_in_wallet: 0.0,
+                amount_spent: 0.0,
+                total_amount_spent: 0.0,
+                average_hourly_consumption: 0.0,
+                org_id: Some(created.id),
+            })
+            .execute(c)
+            .await?;
+        diesel::insert_into(crate::schema::instances::table)
+            .values(&NewInstance {
+                user_id: uid,
+                instances_count: 0,
+                expected_consumption: 0.0,
+                instances_overall_consumption: 0.0,
+                org_id: Some(created.id),
+            })
+            .execute(c)
+            .await?;
+        Ok(created)
+    });
+
+    match created {
+        Ok(o) => {
+            info!(user_id = uid, org_id = o.id, "Organization created.");
+            (StatusCode::CREATED, Json(org_json(&o, OrgRole::Owner)))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to create organization"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /orgs/{id}/switch
+// ---------------------------------------------------------------------------
+
+/// Issue an access token acting in organization `id` and keep it as the
+/// session's organization for later refreshes.
+pub async fn switch(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(org_id): Path<i32>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, HeaderMap::new(), Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+    let Some(sid) = claims.sid else {
+        return (StatusCode::UNAUTHORIZED, HeaderMap::new(), Json(serde_json::json!({"error": "Invalid token"})));
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+
+    let membership = match org::resolve(&mut db, uid, Some(org_id)).await {
+        Ok(Some(m)) => m,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Organization not found"})),
+            );
+        }
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to switch organization"})),
+            );
+        }
+    };
+    if session::set_active_org(&mut db, sid, org_id).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Failed to switch organization"})),
+        );
+    }
+
+    // An impersonation token stays one, with the same expiry, and is not
+    // put in the admin's cookie.
+    let (token, expires_in) = match claims.impersonator() {
+        Some(admin_id) => (
+            generate_impersonation_token(uid, &claims.username, sid, admin_id, claims.exp as i64, Some(org_id)),
+            claims.exp as i64 - chrono::Utc::now().timestamp(),
+        ),
+        None => (
+            generate_token(uid, &claims.username, claims.role, sid, Some(org_id)),
+            ACCESS_TOKEN_EXPIRY_SECS,
+        ),
+    };
+    let Ok(token) = token else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Token generation failed"})),
+        );
+    };
+
+    let mut headers = HeaderMap::new();
+    if claims.act.is_none() {
+        headers.append("set-cookie", HeaderValue::from_str(&session_cookie(&token)).unwrap());
+    }
+    (
+        StatusCode::OK,
+        headers,
+        Json(serde_json::json!({
+            "token": token,
+            "expires_in": expires_in,
+            "org": org_json(&membership.org, membership.role),
+        })),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// GET /org
+// ---------------------------------------------------------------------------
+
+/// `(user_id, username, email, role, joined_at)`
+type MemberRow = (i32, String, String, String, i64);
+
+/// The active organization and its members.
+pub async fn current(
+    access: OrgAccess<org_role::Member>,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    use crate::schema::{org_members::dsl as m, users::dsl as u};
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let members: QueryResult<Vec<MemberRow>> = with_conn!(db, |c| {
+        m::org_members
+            .inner_join(u::users)
+            .filter(m::org_id.eq(access.org.id))
+            .order(m::id.asc())
+            .select((u::id, u::username, u::email, m::role, m::created_at))
+            .load(c)
+            .await
+    });
+
+    match members {
+        Ok(members) => {
+            let mut body = org_json(&access.org, access.role);
+            body["members"] = members
+                .iter()
+                .map(|(id, username, email, role, joined)| {
+                    serde_json::json!({
+                        "user_id": id,
+                        "username": username,
+                        "email": email,
+                        "role": role,
+                        "joined_at": joined,
+                    })
+                })
+                .collect();
+            (StatusCode::OK, Json(body))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load organization"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// PATCH /org
+// ---------------------------------------------------------------------------
+
+pub async fn rename(
+    access: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<OrgNameRequest>,
+) -> impl IntoResponse {
+    use crate::schema::organizations::dsl::*;
+
+    let new_name = body.name.trim().to_string();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let renamed: QueryResult<Organization> = with_conn!(db, |c| {
+        diesel::update(organizations.find(access.org.id))
+            .set(name.eq(&new_name))
+            .returning(Organization::as_returning())
+            .get_result(c)
+            .await
+    });
+
+    match renamed {
+        Ok(o) => (StatusCode::OK, Json(org_json(&o, access.role))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to rename organization"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /org
+// ---------------------------------------------------------------------------
+
+/// Delete the active organization with its instances and wallet. Personal
+/// organizations and organizations with money in the wallet are kept.
+pub async fn delete_org(
+    access: OrgAccess<org_role::Owner>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    use crate::schema::{billing::dsl as b, organizations::dsl as o};
+
+    if access.org.personal_user_id.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "Personal organizations cannot be deleted"})),
+        );
+    }
+    let actor: Option<i32> = access.claims.sub.parse().ok();
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    // `Err(balance)` when the wallet still holds money.
+    let deleted: QueryResult<Result<(), f64>> = transaction!(db, |c| {
+        let wallet: Option<f64> = b::billing
+            .filter(b::org_id.eq(access.org.id))
+            .select(b::amount_in_wallet)
+            .first(c)
+            .await
+            .optional()?;
+        if let Some(balance) = wallet.filter(|w| *w > 0.0) {
+            return Ok(Err(balance));
+        }
+        diesel::delete(o::organizations.find(access.org.id)).execute(c).await?;
+        Ok(Ok(()))
+    });
+
+    match deleted {
+        Ok(Ok(())) => {
+            let ip = ClientInfo::new(&headers, peer).ip;
+            audit::record(
+                &mut db,
+                audit::ORG_DELETED,
+                actor,
+                actor,
+                ip.as_deref(),
+                serde_json::json!({"org_id": access.org.id, "name": access.org.name}),
+            )
+            .await;
+            info!(user_id = actor, org_id = access.org.id, "Organization deleted.");
+            (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+        }
+        Ok(Err(balance)) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": "The wallet must be empty before the organization is deleted",
+                "amount_in_wallet": balance,
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to delete organization"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// PUT /org/members/{user_id}
+// ---------------------------------------------------------------------------
+
+/// Change a member's role. Only owners can make or unmake owners, and the
+/// last owner cannot step down.
+pub async fn set_member_role(
+    access: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(member_id): Path<i32>,
+    Valid(body): Valid<MemberRoleRequest>,
+) -> impl IntoResponse {
+    use crate::schema::org_members::dsl::*;
+
+    let Some(new_role) = OrgRole::parse(&body.role) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "Role must be one of: member, admin, owner"})),
+        );
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let org = access.org.id;
+    let caller = access.role;
+    // `Ok(old role)`, or the refusal.
+    let changed: QueryResult<Result<OrgRole, (StatusCode, &'static str)>> = transaction!(db, |c| {
+        let current: Option<String> = org_members
+            .filter(org_id.eq(org))
+            .filter(user_id.eq(member_id))
+            .select(role)
+            .first(c)
+            .await
+            .optional()?;
+        let Some(current) = current.map(|r| OrgRole::of(&r)) else {
+            return Ok(Err((StatusCode::NOT_FOUND, "Member not found")));
+        };
+        if (current == OrgRole::Owner || new_role == OrgRole::Owner) && caller < OrgRole::Owner {
+            return Ok(Err((StatusCode::FORBIDDEN, "Only owners can appoint or demote owners")));
+        }
+        if current == OrgRole::Owner && new_role != OrgRole::Owner {
+            let owners: i64 = org_members
+                .filter(org_id.eq(org))
+                .filter(role.eq(OrgRole::Owner.as_str()))
+                .count()
+                .get_result(c)
+                .await?;
+            if owners <= 1 {
+                return Ok(Err((StatusCode::CONFLICT, "An organization needs at least one owner")));
+            }
+        }
+        diesel::update(org_members.filter(org_id.eq(org)).filter(user_id.eq(member_id)))
+            .set(role.eq(new_role.as_str()))
+            .execute(c)
+            .await?;
+        Ok(Ok(current))
+    });
+
+    let old = match changed {
+        Ok(Ok(old)) => old,
+        Ok(Err((status, error))) => return (status, Json(serde_json::json!({"error": error}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to change role"}))),
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ORG_ROLE_CHANGED,
+        Some(member_id),
+        access.claims.sub.parse().ok(),
+        ip.as_deref(),
+        serde_json::json!({"org_id": org, "from": old, "to": new_role}),
+    )
+    .await;
+
+    (StatusCode::OK, Json(serde_json::json!({"user_id": member_id, "role": new_role})))
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /org/members/{user_id}
+// ---------------------------------------------------------------------------
+
+/// Remove a member, or leave the organization when `user_id` is the
+/// caller. Removing others takes an admin, and removing an owner an owner;
+/// the last owner cannot leave.
+pub async fn remove_member(
+    access: OrgAccess<org_role::Member>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(member_id): Path<i32>,
+) -> impl IntoResponse {
+    use crate::schema::org_members::dsl::*;
+
+    if access.claims.act.is_some() {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Not allowed while impersonating"})));
+    }
+    let Some(caller_id) = access.claims.sub.parse::<i32>().ok() else {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"})));
+    };
+    if access.org.personal_user_id.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "Personal organizations have no other members"})),
+        );
+    }
+    if member_id != caller_id && access.role < OrgRole::Admin {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Insufficient organization role"})));
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let org = access.org.id;
+    let caller = access.role;
+    let removed: QueryResult<Result<(), (StatusCode, &'static str)>> = transaction!(db, |c| {
+        let current: Option<String> = org_members
+            .filter(org_id.eq(org))
+            .filter(user_id.eq(member_id))
+            .select(role)
+            .first(c)
+            .await
+            .optional()?;
+        let Some(current) = current.map(|r| OrgRole::of(&r)) else {
+            return Ok(Err((StatusCode::NOT_FOUND, "Member not found")));
+        };
+        if current == OrgRole::Owner {
+            if member_id != caller_id && caller < OrgRole::Owner {
+                return Ok(Err((StatusCode::FORBIDDEN, "Only owners can remove owners")));
+            }
+            let owners: i64 = org_members
+                .filter(org_id.eq(org))
+                .filter(role.eq(OrgRole::Owner.as_str()))
+                .count()
+                .get_result(c)
+                .await?;
+            if owners <= 1 {
+                return Ok(Err((StatusCode::CONFLICT, "An organization needs at least one owner")));
+            }
+        }
+        diesel::delete(org_members.filter(org_id.eq(org)).filter(user_id.eq(member_id)))
+            .execute(c)
+            .await?;
+        Ok(Ok(()))
+    });
+
+    match removed {
+        Ok(Ok(())) => {}
+        Ok(Err((status, error))) => return (status, Json(serde_json::json!({"error": error}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to remove member"}))),
+    }
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ORG_MEMBER_REMOVED,
+        Some(member_id),
+        Some(caller_id),
+        ip.as_deref(),
+        serde_json::json!({"org_id": org}),
+    )
+    .await;
+    info!(user_id = member_id, org_id = org, by = caller_id, "Organization member removed.");
+
+    (StatusCode::OK, Json(serde_json::json!({"ok": true})))
+}
+
+// ---------------------------------------------------------------------------
+// GET /org/invitations
+// ---------------------------------------------------------------------------
+
+pub async fn list_invitations(
+    access: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match org::pending_invitations(&mut db, access.org.id).await {
+        Ok(rows) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "invitations": rows.iter().map(invitation_json).collect::<Vec<_>>(),
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load invitations"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /org/invitations
+// ---------------------------------------------------------------------------
+
+/// Email an invitation to join the active organization. A new invitation
+/// to the same address replaces the pending one.
+pub async fn invite(
+    access: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Valid(body): Valid<InviteRequest>,
+) -> impl IntoResponse {
+    use crate::schema::{org_members::dsl as m, users::dsl as u};
+
+    let Some(inviter) = access.claims.sub.parse::<i32>().ok() else {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"})));
+    };
+    if access.org.personal_user_id.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "Create an organization to invite others"})),
+        );
+    }
+    let email = body.email;
+    let role = body.role.as_deref().and_then(OrgRole::parse).unwrap_or(OrgRole::Member);
+    if role > access.role {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({"error": "Cannot invite with a higher role than your own"})),
+        );
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let emails: QueryResult<Vec<String>> = with_conn!(db, |c| {
+        m::org_members
+            .inner_join(u::users)
+            .filter(m::org_id.eq(access.org.id))
+            .select(u::email)
+            .load(c)
+            .await
+    });
+    match emails {
+        Ok(emails) if emails.iter().any(|e| e.to_lowercase() == email) => {
+            return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Already a member"})));
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to invite"}))),
+    }
+
+    let (invitation, token) = match org::invite(&mut db, access.org.id, &email, role, inviter).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!(org_id = access.org.id, "Could not create invitation: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to invite"})));
+        }
+    };
+    drop(db);
+
+    let message = org::invitation_email(&access.org, &access.claims.username, &email, &token);
+    let org_id = access.org.id;
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&message).await {
+            warn!(org_id, "Could not send invitation email: {}", e);
+        }
+    });
+    info!(org_id, invited_by = inviter, invitation_id = invitation.id, "Invitation sent.");
+
+    (StatusCode::CREATED, Json(invitation_json(&invitation)))
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /org/invitations/{id}
+// ---------------------------------------------------------------------------
+
+pub async fn revoke_invitation(
+    access: OrgAccess<org_role::Admin>,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(invitation_id): Path<i32>,
+) -> impl IntoResponse {
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match org::revoke_invitation(&mut db, access.org.id, invitation_id).await {
+        Ok(true) => (StatusCode::OK, Json(serde_json::json!({"ok": true}))),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "Invitation not found"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to revoke invitation"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /invitations/accept
+// ---------------------------------------------------------------------------
+
+/// Join an organization with an invitation token. The caller's email must
+/// be the address the invitation was sent to.
+pub async fn accept_invitation(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<AcceptInvitationRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let user = match find_user(&mut db, uid).await {
+        Ok(Some(u)) => u,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to accept invitation"}))),
+    };
+
+    let membership = match org::accept(&mut db, &body.token, &user).await {
+        Ok(m) => m,
+        Err(AcceptError::Invalid) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "Invalid or expired invitation"})),
+            );
+        }
+        Err(AcceptError::WrongRecipient) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({"error": "This invitation was sent to another email address"})),
+            );
+        }
+        Err(AcceptError::AlreadyMember) => {
+            return (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Already a member"})));
+        }
+        Err(AcceptError::Db(e)) => {
+            warn!(user_id = uid, "Could not accept invitation: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to accept invitation"})));
+        }
+    };
+
+    let ip = ClientInfo::new(&headers, peer).ip;
+    audit::record(
+        &mut db,
+        audit::ORG_MEMBER_ADDED,
+        Some(uid),
+        Some(uid),
+        ip.as_deref(),
+        serde_json::json!({"org_id": membership.org.id, "role": membership.role}),
+    )
+    .await;
+    info!(user_id = uid, org_id = membership.org.id, "Invitation accepted.");
+
+    (StatusCode::OK, Json(org_json(&membership.org, membership.role)))
+}
+
+async fn find_user(db: &mut DbConn, uid: i32) -> QueryResult<Option<User>> {
+    use crate::schema::users::dsl::*;
+
+    with_conn!(*db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await.optional()
+    })
+}
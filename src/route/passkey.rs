@@ -0,0 +1,530 @@
+use crate::{
+    auth::{
+        AccountOwner, AuthUser,
+        webauthn::{self, AssertionCredential, CEREMONY_TIMEOUT_SECS, RegistrationCredential},
+    },
+    challenge,
+    session::ClientInfo,
+    sql::{
+        Orchestrator,
+        passkey::{NewPasskey, Passkey},
+        transaction, with_conn,
+        user::User,
+    },
+    validate::{self, Valid},
+};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use validator::Validate;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::auth::open_session;
+
+// ---------------------------------------------------------------------------
+// Request/Response types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct RegisterFinishRequest {
+    #[validate(length(max = 64))]
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct LoginStartRequest {
+    #[serde(default, deserialize_with = "validate::optional_email")]
+    #[validate(email)]
+    pub email: Option<String>,
+    pub mfa_token: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct LoginFinishRequest {
+    pub credential: AssertionCredential,
+    pub mfa_token: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RequirePasskeyRequest {
+    pub enabled: bool,
+}
+
+fn passkey_json(p: &Passkey) -> serde_json::Value {
+    serde_json::json!({
+        "id": p.id,
+        "name": p.name,
+        "alg": p.alg,
+        "created_at": p.created_at,
+        "last_used_at": p.last_used_at,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/passkeys/register/start
+// ---------------------------------------------------------------------------
+
+/// Options for `navigator.credentials.create()`, excluding the caller's
+/// existing passkeys.
+pub async fn register_start(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    use crate::schema::passkeys::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let existing: Vec<String> = match with_conn!(db, |c| {
+        passkeys
+            .filter(user_id.eq(uid))
+            .select(credential_id)
+            .load(c)
+            .await
+    }) {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load passkeys"}))),
+    };
+
+    let value = webauthn::new_challenge();
+    if challenge::issue(&mut db, Some(uid), challenge::PASSKEY_REGISTER, &value, CEREMONY_TIMEOUT_SECS)
+        .await
+        .is_err()
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start registration"})));
+    }
+
+    (
+        StatusCode::OK,
+        Json(webauthn::creation_options(&value, uid, &claims.username, &existing)),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/passkeys/register/finish
+// ---------------------------------------------------------------------------
+
+pub async fn register_finish(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<RegisterFinishRequest>,
+) -> impl IntoResponse {
+    use crate::schema::passkeys::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let Ok(value) = webauthn::challenge_of(&body.credential.response.client_data_json) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "Malformed credential"})));
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match challenge::take(&mut db, &value, challenge::PASSKEY_REGISTER).await {
+        Ok(Some(c)) if c.user_id == Some(uid) => {}
+        Ok(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "Unknown or expired challenge"}))),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to check challenge"}))),
+    }
+
+    let verified = match webauthn::verify_registration(&body.credential, &value, false) {
+        Ok(v) => v,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("Invalid passkey: {}", e)}))),
+    };
+
+    let new_passkey = NewPasskey {
+        user_id: uid,
+        credential_id: verified.credential_id,
+        public_key: verified.public_key,
+        alg: verified.alg,
+        sign_count: verified.sign_count as i64,
+        name: body
+            .name
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| "Passkey".to_string()),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+
+    let created: QueryResult<Passkey> = with_conn!(db, |c| {
+        diesel::insert_into(passkeys)
+            .values(&new_passkey)
+            .returning(Passkey::as_returning())
+            .get_result(c)
+            .await
+    });
+
+    match created {
+        Ok(p) => (StatusCode::CREATED, Json(passkey_json(&p))),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Passkey already registered"})))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to save passkey"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// GET /auth/passkeys
+// ---------------------------------------------------------------------------
+
+pub async fn list(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    use crate::schema::{passkeys::dsl as p, users::dsl as u};
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let loaded: QueryResult<(bool, Vec<Passkey>)> = transaction!(db, |c| {
+        let required: bool = u::users.find(uid).select(u::require_passkey).first(c).await?;
+        let keys: Vec<Passkey> = p::passkeys
+            .filter(p::user_id.eq(uid))
+            .order(p::created_at.asc())
+            .select(Passkey::as_select())
+            .load(c)
+            .await?;
+        Ok::<_, diesel::result::Error>((required, keys))
+    });
+
+    match loaded {
+        Ok((required, keys)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "require_passkey": required,
+                "passkeys": keys.iter().map(passkey_json).collect::<Vec<_>>(),
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load passkeys"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /auth/passkeys/{id}
+// ---------------------------------------------------------------------------
+
+/// Remove a passkey. The last one cannot be removed while passkeys are
+/// required, or the account could no longer be logged into.
+pub async fn remove(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(pid): Path<i32>,
+) -> impl IntoResponse {
+    use crate::schema::{passkeys::dsl as p, users::dsl as u};
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    // None: not found; Some(false): refused; Some(true): removed.
+    let outcome: QueryResult<Option<bool>> = transaction!(db, |c| {
+        let remaining: Vec<i32> = p::passkeys
+            .filter(p::user_id.eq(uid))
+            .select(p::id)
+            .load(c)
+            .await?;
+        if !remaining.contains(&pid) {
+            return Ok(None);
+        }
+        let required: bool = u::users.find(uid).select(u::require_passkey).first(c).await?;
+        if required && remaining.len() == 1 {
+            return Ok(Some(false));
+        }
+        diesel::delete(p::passkeys.find(pid)).execute(c).await?;
+        Ok::<_, diesel::result::Error>(Some(true))
+    });
+
+    match outcome {
+        Ok(Some(true)) => (StatusCode::OK, Json(serde_json::json!({"ok": true}))),
+        Ok(Some(false)) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": "Cannot remove your last passkey while passkeys are required"})),
+        ),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "Passkey not found"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to remove passkey"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/passkeys/require
+// ---------------------------------------------------------------------------
+
+/// Turn the passkey second factor for password logins on or off.
+pub async fn require(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<RequirePasskeyRequest>,
+) -> impl IntoResponse {
+    use crate::schema::{passkeys::dsl as p, users::dsl as u};
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let enabled = body.enabled;
+    let updated: QueryResult<bool> = transaction!(db, |c| {
+        if enabled {
+            let n: i64 = p::passkeys.filter(p::user_id.eq(uid)).count().get_result(c).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+        }
+        diesel::update(u::users.find(uid))
+            .set(u::require_passkey.eq(enabled))
+            .execute(c)
+            .await?;
+        Ok::<_, diesel::result::Error>(true)
+    });
+
+    match updated {
+        Ok(true) => (StatusCode::OK, Json(serde_json::json!({"ok": true, "require_passkey": enabled}))),
+        Ok(false) => (StatusCode::CONFLICT, Json(serde_json::json!({"error": "Register a passkey first"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to update setting"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/passkeys/login/start
+// ---------------------------------------------------------------------------
+
+/// Options for `navigator.credentials.get()`.
+///
+/// With an `mfa_token` (from a password login that needs a second factor)
+/// or an `email`, only that user's passkeys are offered; with neither, the
+/// authenticator picks a discoverable credential. Passwordless logins must
+/// verify the user (PIN or biometric); a second factor only needs presence.
+pub async fn login_start(
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<LoginStartRequest>,
+) -> impl IntoResponse {
+    use crate::schema::{passkeys::dsl as p, users::dsl as u};
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let uid: Option<i32> = if let Some(token) = &body.mfa_token {
+        match challenge::peek(&mut db, token, challenge::MFA).await {
+            Ok(Some(c)) => c.user_id,
+            Ok(None) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid or expired MFA token"}))),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to check MFA token"}))),
+        }
+    } else if let Some(addr) = &body.email {
+        match with_conn!(db, |c| {
+            u::users.filter(u::email.eq(addr)).select(u::id).first(c).await.optional()
+        }) {
+            Ok(v) => v,
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start login"}))),
+        }
+    } else {
+        None
+    };
+
+    let allow: Vec<String> = match uid {
+        Some(uid) => match with_conn!(db, |c| {
+            p::passkeys
+                .filter(p::user_id.eq(uid))
+                .select(p::credential_id)
+                .load(c)
+                .await
+        }) {
+            Ok(v) => v,
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start login"}))),
+        },
+        None => Vec::new(),
+    };
+
+    let value = webauthn::new_challenge();
+    if challenge::issue(&mut db, uid, challenge::PASSKEY_LOGIN, &value, CEREMONY_TIMEOUT_SECS)
+        .await
+        .is_err()
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start login"})));
+    }
+
+    let user_verification = if body.mfa_token.is_some() { "preferred" } else { "required" };
+    (
+        StatusCode::OK,
+        Json(webauthn::request_options(&value, &allow, user_verification)),
+    )
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/passkeys/login/finish
+// ---------------------------------------------------------------------------
+
+/// Verify an assertion and open a session, either passwordless or as the
+/// second step of a password login (`mfa_token`).
+pub async fn login_finish(
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(body): Valid<LoginFinishRequest>,
+) -> impl IntoResponse {
+    use crate::schema::{passkeys::dsl as p, users::dsl as u};
+
+    let unauthorized = |msg: &str| {
+        (
+            StatusCode::UNAUTHORIZED,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": msg})),
+        )
+    };
+
+    let Ok(value) = webauthn::challenge_of(&body.credential.response.client_data_json) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Malformed credential"})),
+        );
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Database unavailable"})),
+            );
+        }
+    };
+
+    let ceremony = match challenge::take(&mut db, &value, challenge::PASSKEY_LOGIN).await {
+        Ok(Some(c)) => c,
+        Ok(None) => return unauthorized("Unknown or expired challenge"),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to check challenge"})),
+            );
+        }
+    };
+
+    let found: QueryResult<Option<Passkey>> = with_conn!(db, |c| {
+        p::passkeys
+            .filter(p::credential_id.eq(&body.credential.id))
+            .select(Passkey::as_select())
+            .first(c)
+            .await
+            .optional()
+    });
+    let passkey = match found {
+        Ok(Some(pk)) if ceremony.user_id.is_none_or(|uid| uid == pk.user_id) => pk,
+        Ok(_) => return unauthorized("Unknown passkey"),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(serde_json::json!({"error": "Failed to load passkey"})),
+            );
+        }
+    };
+
+    let verified = match webauthn::verify_assertion(
+        &body.credential,
+        &value,
+        &passkey.public_key,
+        passkey.alg,
+        passkey.sign_count,
+        body.mfa_token.is_none(),
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::info!(passkey = passkey.id, "Passkey assertion rejected: {}", e);
+            return unauthorized("Passkey verification failed");
+        }
+    };
+
+    if let Some(token) = &body.mfa_token {
+        match challenge::take(&mut db, token, challenge::MFA).await {
+            Ok(Some(c)) if c.user_id == Some(passkey.user_id) => {}
+            Ok(_) => return unauthorized("Invalid or expired MFA token"),
+            Err(_) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    HeaderMap::new(),
+                    Json(serde_json::json!({"error": "Failed to check MFA token"})),
+                );
+            }
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let new_count = verified.sign_count as i64;
+    let user: QueryResult<Option<User>> = transaction!(db, |c| {
+        // Only the first of two assertions racing with the same count may
+        // store it; authenticators without a counter always send zero.
+        let updated = diesel::update(
+            p::passkeys
+                .find(passkey.id)
+                .filter(p::sign_count.lt(new_count).or(p::sign_count.eq(0).and(p::sign_count.eq(new_count)))),
+        )
+        .set((p::sign_count.eq(new_count), p::last_used_at.eq(now)))
+        .execute(c)
+        .await?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        u::users
+            .find(passkey.user_id)
+            .select(User::as_select())
+            .first(c)
+            .await
+            .map(Some)
+    });
+
+    match user {
+        Ok(Some(user)) => open_session(&mut db, &user, ClientInfo::new(&headers, peer), StatusCode::OK).await,
+        Ok(None) => {
+            tracing::info!(passkey = passkey.id, "Passkey assertion rejected: signature counter already used");
+            unauthorized("Passkey verification failed")
+        }
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            Json(serde_json::json!({"error": "Failed to complete login"})),
+        ),
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{auth::{AccountOwner, AuthUser}, session, sql::Orchestrator};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use std::sync::Arc;
+
+// ---------------------------------------------------------------------------
+// GET /auth/sessions
+// ---------------------------------------------------------------------------
+
+/// List the caller's active sessions, flagging the one making the request.
+pub async fn list(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match session::list(&mut db, uid).await {
+        Ok(sessions) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "sessions": sessions
+                    .iter()
+                    .map(|s| serde_json::json!({
+                        "id": s.id,
+                        "current": Some(s.id) == claims.sid,
+                        "user_agent": s.user_agent,
+                        "ip": s.ip,
+                        "created_at": s.created_at,
+                        "last_seen_at": s.last_used_at,
+                        "expires_at": s.expires_at,
+                        "impersonated_by": s.impersonator_id,
+                    }))
+                    .collect::<Vec<_>>(),
+            })),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to list sessions"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// DELETE /auth/sessions/{id}
+// ---------------------------------------------------------------------------
+
+pub async fn revoke(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Path(sid): Path<i32>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match session::revoke_owned(&mut db, uid, sid, "revoked_by_user").await {
+        Ok(true) => (StatusCode::OK, Json(serde_json::json!({"ok": true}))),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": "Session not found"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to revoke session"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/sessions/revoke-others
+// ---------------------------------------------------------------------------
+
+/// Sign out everywhere except the session making the request.
+pub async fn revoke_others(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match session::revoke_all(&mut db, uid, claims.sid, "revoked_by_user").await {
+        Ok(n) => (StatusCode::OK, Json(serde_json::json!({"ok": true, "revoked": n}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to revoke sessions"}))),
+    }
+}
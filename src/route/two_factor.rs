@@ -0,0 +1,245 @@
+use crate::{
+    auth::{AccountOwner, AuthUser, totp, verify_password},
+    mfa,
+    sql::{Orchestrator, user::User, with_conn},
+    validate::{self, Valid},
+};
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use validator::Validate;
+use std::sync::Arc;
+
+// ---------------------------------------------------------------------------
+// Request/Response types
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize, Validate)]
+pub struct CodeRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub code: String,
+}
+
+/// Password plus a current TOTP or recovery code.
+#[derive(Deserialize, Validate)]
+pub struct ReauthRequest {
+    #[validate(custom(function = validate::not_blank))]
+    pub password: String,
+    #[validate(custom(function = validate::not_blank))]
+    pub code: String,
+}
+
+// ---------------------------------------------------------------------------
+// GET /auth/2fa
+// ---------------------------------------------------------------------------
+
+pub async fn status(
+    AuthUser(claims): AuthUser,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    use crate::schema::users::dsl::*;
+
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    let passkey_required: QueryResult<bool> = with_conn!(db, |c| {
+        users.find(uid).select(require_passkey).first(c).await
+    });
+    let totp_enabled = mfa::totp_enabled(&mut db, uid).await;
+    let remaining = mfa::recovery_codes_remaining(&mut db, uid).await;
+
+    match (passkey_required, totp_enabled, remaining) {
+        (Ok(passkey_required), Ok(totp_enabled), Ok(remaining)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "totp_enabled": totp_enabled,
+                "recovery_codes_remaining": remaining,
+                "passkey_required": passkey_required,
+            })),
+        ),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to load 2FA status"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/2fa/totp/setup
+// ---------------------------------------------------------------------------
+
+/// Generate a TOTP secret for the caller. It only takes effect once
+/// confirmed with a code from the authenticator app.
+pub async fn totp_setup(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match mfa::begin_totp(&mut db, uid).await {
+        Ok(Some(secret)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "otpauth_uri": totp::provisioning_uri(&secret, &claims.username, &totp::issuer()),
+                "secret": secret,
+            })),
+        ),
+        Ok(None) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": "Two-factor authentication is already enabled"})),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to start enrollment"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/2fa/totp/confirm
+// ---------------------------------------------------------------------------
+
+/// Enable TOTP with a first code from the app. Returns the recovery codes,
+/// which are never shown again.
+pub async fn totp_confirm(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<CodeRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match mfa::confirm_totp(&mut db, uid, &body.code).await {
+        Ok(Some(codes)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"ok": true, "recovery_codes": codes})),
+        ),
+        Ok(None) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "Invalid code or no enrollment pending"}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to enable 2FA"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/2fa/recovery-codes
+// ---------------------------------------------------------------------------
+
+/// Replace the caller's recovery codes. Requires re-authentication.
+pub async fn regenerate_recovery_codes(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<ReauthRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    if let Err(e) = reauthenticate(&orch, uid, &body).await {
+        return e;
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match mfa::regenerate_recovery_codes(&mut db, uid).await {
+        Ok(codes) => (StatusCode::OK, Json(serde_json::json!({"recovery_codes": codes}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to generate recovery codes"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// POST /auth/2fa/disable
+// ---------------------------------------------------------------------------
+
+/// Turn TOTP off and discard the recovery codes. Requires
+/// re-authentication.
+pub async fn disable(
+    AccountOwner(claims): AccountOwner,
+    State(orch): State<Arc<Orchestrator>>,
+    Valid(body): Valid<ReauthRequest>,
+) -> impl IntoResponse {
+    let uid: i32 = match claims.sub.parse() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))),
+    };
+
+    if let Err(e) = reauthenticate(&orch, uid, &body).await {
+        return e;
+    }
+
+    let mut db = match orch.conn().await {
+        Ok(c) => c,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"}))),
+    };
+
+    match mfa::disable_totp(&mut db, uid).await {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"ok": true}))),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to disable 2FA"}))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+/// Check the caller's password and a current second-factor code (which is
+/// consumed) before a sensitive change.
+async fn reauthenticate(
+    orch: &Orchestrator,
+    uid: i32,
+    body: &ReauthRequest,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    use crate::schema::users::dsl::*;
+
+    let unavailable = || (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Database unavailable"})));
+
+    let mut db = orch.conn().await.map_err(|_| unavailable())?;
+    if !mfa::totp_enabled(&mut db, uid).await.unwrap_or(false) {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": "Two-factor authentication is not enabled"})),
+        ));
+    }
+    let user: User = with_conn!(db, |c| {
+        users.find(uid).select(User::as_select()).first(c).await
+    })
+    .map_err(|_| (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid token"}))))?;
+
+    // Release the connection before the (slow) Argon2 check.
+    drop(db);
+
+    let password = body.password.clone();
+    let stored_hash = user.password_hash.clone();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&password, &stored_hash))
+        .await
+        .unwrap_or(false);
+    if !verified {
+        return Err((StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid credentials"}))));
+    }
+
+    let mut db = orch.conn().await.map_err(|_| unavailable())?;
+    match mfa::verify_code(&mut db, uid, &body.code).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err((StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "Invalid code"})))),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Failed to check code"})))),
+    }
+}
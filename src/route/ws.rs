@@ -1,23 +1,32 @@
 use crate::{
-    auth::{Claims, COOKIE_NAME, validate_token},
+    api_key::{self, Principal},
+    auth::{COOKIE_NAME, Claims, RequiredScope, authenticate_api_key, extract_api_key, scope, validate_token},
+    instance::{self, Op, Status},
+    org,
+    route::instance::{CreateInstanceRequest, ReasonRequest, acting, error_response, whereis},
+    session::{self, ClientInfo},
     sql::Orchestrator,
+    validate,
 };
 use axum::{
     extract::{
-        State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ConnectInfo, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
     },
     http::StatusCode,
     response::IntoResponse,
 };
-use chrono;
-use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use validator::Validate;
+
+/// How often an open connection re-validates its session or API key.
+const SESSION_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 // ---------------------------------------------------------------------------
 // Message envelope
@@ -26,7 +35,6 @@ use tokio::sync::Mutex;
 #[derive(Deserialize)]
 struct WsIncoming {
     action: String,
-    #[allow(dead_code)]
     payload: Option<Value>,
 }
 
@@ -45,24 +53,47 @@ struct WsOutgoing {
 
 /// Upgrade to WebSocket. Accepts two auth methods:
 ///   1. JWT — `Authorization: Bearer <token>` or `orsta_session` cookie
-///   2. API Key — `X-Api-Key: <eakey>` (must have api_key_active = true)
+///   2. API Key — `X-Api-Key: <key>` with the `messages:send` scope
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    State(orch): State<Arc<Mutex<Orchestrator>>>,
+    State(orch): State<Arc<Orchestrator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: axum::http::HeaderMap,
 ) -> impl IntoResponse {
     // --- API Key path ---
     if let Some(api_key) = extract_api_key(&headers) {
-        return match resolve_api_key(&api_key, &orch).await {
-            Some(claims) => ws.on_upgrade(move |socket| handle_socket(socket, claims, orch)).into_response(),
-            None => (StatusCode::UNAUTHORIZED, "Invalid or inactive API key").into_response(),
+        let ip = ClientInfo::new(&headers, peer).ip.unwrap_or_default();
+        let principal = match authenticate_api_key(&orch, &api_key, &ip).await {
+            Ok(p) => p,
+            Err(rejection) => return rejection,
         };
+        if !principal.has_scope(scope::MessagesSend::NAME) {
+            return (StatusCode::FORBIDDEN, "API key lacks the 'messages:send' scope").into_response();
+        }
+        tracing::debug!(user_id = principal.user_id, key_id = principal.key_id, "WebSocket opened with an API key.");
+        let claims = principal.claims();
+        return ws
+            .on_upgrade(move |socket| handle_socket(socket, claims, Some(principal), orch))
+            .into_response();
     }
 
     // --- JWT path ---
-    match extract_bearer_or_cookie(&headers).as_deref().map(validate_token) {
-        Some(Ok(claims)) => ws.on_upgrade(move |socket| handle_socket(socket, claims, orch)).into_response(),
-        _ => (StatusCode::UNAUTHORIZED, "Missing or invalid session token").into_response(),
+    let claims = match extract_bearer_or_cookie(&headers).as_deref().map(validate_token) {
+        Some(Ok(claims)) => claims,
+        _ => return (StatusCode::UNAUTHORIZED, "Missing or invalid session token").into_response(),
+    };
+
+    let Some(sid) = claims.sid else {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid session token").into_response();
+    };
+    let active = match orch.conn().await {
+        Ok(mut db) => session::touch(&mut db, sid).await,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response(),
+    };
+    match active {
+        Ok(true) => ws.on_upgrade(move |socket| handle_socket(socket, claims, None, orch)).into_response(),
+        Ok(false) => (StatusCode::UNAUTHORIZED, "Session expired or revoked").into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response(),
     }
 }
 
@@ -70,10 +101,14 @@ pub async fn ws_handler(
 // Per-connection handler
 // ---------------------------------------------------------------------------
 
+/// Serve one connection. `key` is the API key it was opened with, if any;
+/// such connections have no session to revoke, are limited to the key's
+/// scopes, and are closed once the key stops working.
 async fn handle_socket(
     socket: WebSocket,
     claims: crate::auth::Claims,
-    orch: Arc<Mutex<Orchestrator>>,
+    key: Option<Principal>,
+    orch: Arc<Orchestrator>,
 ) {
     let (mut sender, mut receiver) = socket.split();
 
@@ -89,22 +124,198 @@ async fn handle_socket(
     .unwrap();
     let _ = sender.send(Message::Text(welcome.into())).await;
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            Message::Text(text) => {
-                let response = dispatch(&text, &claims, &orch).await;
-                let _ = sender
-                    .send(Message::Text(
-                        serde_json::to_string(&response).unwrap().into(),
-                    ))
-                    .await;
+    let sid = claims.sid;
+    let key_id = key.as_ref().map(|k| k.key_id);
+    let mut revocations = session::revocations();
+    let mut disabled = api_key::disabled();
+    let mut key_deadline = key.as_ref().and_then(|k| k.valid_until);
+    let mut rotations = api_key::rotations();
+    let mut changes = instance::changes();
+    // Catches revocations made by other processes, and expiry.
+    let mut recheck = tokio::time::interval(SESSION_RECHECK_INTERVAL);
+    recheck.tick().await;
+
+    loop {
+        let revoked = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(msg)) => {
+                    if !handle_message(msg, &mut sender, &claims, key.as_ref(), &orch).await {
+                        break;
+                    }
+                    false
+                }
+                _ => break,
+            },
+            event = revocations.recv(), if sid.is_some() => match event {
+                Ok(revoked_sid) => Some(revoked_sid) == sid,
+                Err(broadcast::error::RecvError::Lagged(_)) => !session_active(&orch, sid).await,
+                Err(broadcast::error::RecvError::Closed) => false,
+            },
+            event = disabled.recv(), if key.is_some() => match event {
+                Ok(uid) if uid.to_string() != claims.sub => false,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    !key_usable(&orch, key.as_ref(), &mut key_deadline).await
+                }
+                Err(broadcast::error::RecvError::Closed) => false,
+            },
+            // The key expires, or the value it was opened with is past its
+            // grace period.
+            _ = tokio::time::sleep(until(key_deadline)), if key_deadline.is_some() => {
+                !key_usable(&orch, key.as_ref(), &mut key_deadline).await
+            }
+            // A missed notice only costs the client a heads-up; the periodic
+            // check still ends a replaced value's grace period.
+            Ok(rotated) = rotations.recv() => {
+                if rotated.user_id.to_string() == claims.sub {
+                    let notice = rotation_notice(&rotated, key_id);
+                    let _ = sender
+                        .send(Message::Text(serde_json::to_string(&notice).unwrap().into()))
+                        .await;
+                }
+                // The value this socket was opened with now runs out with
+                // the grace period, or at once without one.
+                key_id == Some(rotated.key_id) && !key_usable(&orch, key.as_ref(), &mut key_deadline).await
             }
-            Message::Close(_) => break,
-            Message::Ping(data) => {
-                let _ = sender.send(Message::Pong(data)).await;
+            // As are changes missed while lagging; clients re-list on reconnect.
+            Ok(change) = changes.recv() => {
+                if may_follow(&orch, &claims, key.as_ref(), change.org_id).await {
+                    let notice = change_notice(&change);
+                    let _ = sender
+                        .send(Message::Text(serde_json::to_string(&notice).unwrap().into()))
+                        .await;
+                }
+                false
+            }
+            _ = recheck.tick() => {
+                !session_active(&orch, sid).await || !key_usable(&orch, key.as_ref(), &mut key_deadline).await
             }
-            _ => {}
+        };
+
+        if revoked {
+            let reason = if key.is_some() { "API key revoked or expired" } else { "Session revoked" };
+            let _ = sender
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: reason.into(),
+                })))
+                .await;
+            break;
+        }
+    }
+}
+
+/// Answer one incoming frame. Returns `false` once the client has closed.
+async fn handle_message(
+    msg: Message,
+    sender: &mut SplitSink<WebSocket, Message>,
+    claims: &Claims,
+    key: Option<&Principal>,
+    orch: &Arc<Orchestrator>,
+) -> bool {
+    match msg {
+        Message::Text(text) => {
+            let response = dispatch(&text, claims, key, orch).await;
+            let _ = sender
+                .send(Message::Text(
+                    serde_json::to_string(&response).unwrap().into(),
+                ))
+                .await;
+        }
+        Message::Close(_) => return false,
+        Message::Ping(data) => {
+            let _ = sender.send(Message::Pong(data)).await;
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Whether session `sid` is still usable. Database errors keep the socket
+/// open; the next check will tell.
+async fn session_active(orch: &Orchestrator, sid: Option<i32>) -> bool {
+    let Some(sid) = sid else {
+        return true;
+    };
+    match orch.conn().await {
+        Ok(mut db) => session::touch(&mut db, sid).await.unwrap_or(true),
+        Err(_) => true,
+    }
+}
+
+/// Whether the API key the connection was opened with, if any, still
+/// works, updating when it stops working on its own. Database errors keep
+/// the socket open; the next check will tell.
+async fn key_usable(orch: &Orchestrator, key: Option<&Principal>, deadline: &mut Option<i64>) -> bool {
+    let Some(key) = key else {
+        return true;
+    };
+    let Ok(mut db) = orch.conn().await else {
+        return true;
+    };
+    match api_key::recheck(&mut db, key).await {
+        Ok(until) => {
+            *deadline = until;
+            true
+        }
+        Err(api_key::Rejection::Db(_)) => true,
+        Err(_) => false,
+    }
+}
+
+/// Time left until `deadline`, a Unix timestamp; at least a second, so a
+/// check that still passes does not spin.
+fn until(deadline: Option<i64>) -> Duration {
+    let left = deadline.unwrap_or(0) - chrono::Utc::now().timestamp();
+    Duration::from_secs(left.max(1) as u64)
+}
+
+/// Tell the owner of a rotated key, flagging whether this connection was
+/// opened with it and so must switch before `previous_expires_at`.
+fn rotation_notice(rotated: &api_key::Rotated, key_id: Option<i32>) -> WsOutgoing {
+    WsOutgoing {
+        action: "api_key_rotated".to_string(),
+        data: Some(serde_json::json!({
+            "key_id": rotated.key_id,
+            "name": rotated.name,
+            "prefix": rotated.prefix,
+            "previous_prefix": rotated.previous_prefix,
+            "previous_expires_at": rotated.previous_expires_at,
+            "this_connection": key_id == Some(rotated.key_id),
+        })),
+        error: None,
+    }
+}
+
+/// Whether this connection is shown the changes to instances of `org_id`:
+/// it must act in that organization, still be a member, and, when opened
+/// with an API key, hold `instances:read`.
+async fn may_follow(orch: &Orchestrator, claims: &Claims, key: Option<&Principal>, org_id: i32) -> bool {
+    if key.is_some_and(|k| !k.has_scope(scope::InstancesRead::NAME)) {
+        return false;
+    }
+    let Ok(uid) = claims.sub.parse::<i32>() else {
+        return false;
+    };
+    let Ok(mut db) = orch.conn().await else {
+        return false;
+    };
+    matches!(org::resolve(&mut db, uid, claims.org).await, Ok(Some(m)) if m.org.id == org_id)
+}
+
+/// `instance_deleted` for deletions, `instance_updated` for other changes;
+/// both carry the history entry the change wrote.
+fn change_notice(change: &instance::Change) -> WsOutgoing {
+    let event = instance::event_view(&change.event);
+    if change.instance.status == Status::Deleted.as_str() {
+        WsOutgoing {
+            action: "instance_deleted".to_string(),
+            data: Some(serde_json::json!({"id": change.instance.id, "org_id": change.org_id, "event": event})),
+            error: None,
         }
+    } else {
+        let mut data = instance::view(&change.instance);
+        data["event"] = event;
+        WsOutgoing { action: "instance_updated".to_string(), data: Some(data), error: None }
     }
 }
 
@@ -115,7 +326,8 @@ async fn handle_socket(
 async fn dispatch(
     text: &str,
     claims: &crate::auth::Claims,
-    _orch: &Arc<Mutex<Orchestrator>>,
+    key: Option<&Principal>,
+    orch: &Arc<Orchestrator>,
 ) -> WsOutgoing {
     let incoming: WsIncoming = match serde_json::from_str(text) {
         Ok(v) => v,
@@ -142,6 +354,13 @@ async fn dispatch(
             })),
             error: None,
         },
+        action if action.starts_with("instances.") => {
+            let (data, error) = match instance_action(action, incoming.payload, claims, key, orch).await {
+                Ok(data) => (Some(data), None),
+                Err((data, error)) => (data, Some(error)),
+            };
+            WsOutgoing { action: incoming.action, data, error }
+        }
         unknown => WsOutgoing {
             action: "error".to_string(),
             data: None,
@@ -150,6 +369,105 @@ async fn dispatch(
     }
 }
 
+#[derive(Deserialize)]
+struct InstanceRef {
+    id: i32,
+}
+
+/// Run an `instances.*` action in the connection's organization. Payloads
+/// are the HTTP request bodies, plus the instance's `id` where the endpoint
+/// takes it in the path. Errors come with their message and, where useful,
+/// details such as the fields that failed validation or the instance's
+/// status.
+async fn instance_action(
+    action: &str,
+    payload: Option<Value>,
+    claims: &Claims,
+    key: Option<&Principal>,
+    orch: &Orchestrator,
+) -> Result<Value, (Option<Value>, String)> {
+    let needed = match action {
+        "instances.list" | "instances.get" | "instances.events" | "instances.placement" => {
+            scope::InstancesRead::NAME
+        }
+        "instances.create" | "instances.start" | "instances.stop" | "instances.restart" | "instances.delete" => {
+            scope::InstancesWrite::NAME
+        }
+        unknown => return Err((None, format!("Unknown action: {}", unknown))),
+    };
+    if key.is_some_and(|k| !k.has_scope(needed)) {
+        return Err((None, format!("API key lacks the '{}' scope", needed)));
+    }
+
+    // Unpack an HTTP-style error body into this envelope's shape.
+    let refused = |(_, axum::Json(body)): (StatusCode, axum::Json<Value>)| {
+        let message = body["error"].as_str().unwrap_or("Request failed").to_string();
+        let details = body.as_object().filter(|o| o.len() > 1).map(|_| body.clone());
+        (details, message)
+    };
+    // Parse and check the payload as `T`.
+    fn body<T: serde::de::DeserializeOwned + Validate>(payload: &Value) -> Result<T, (Option<Value>, String)> {
+        let value: T =
+            serde_json::from_value(payload.clone()).map_err(|e| (None, format!("Invalid payload: {}", e)))?;
+        value.validate().map_err(|e| {
+            let (_, axum::Json(body)) = validate::invalid(&e);
+            (Some(body), "Invalid input".to_string())
+        })?;
+        Ok(value)
+    }
+    let payload = payload.unwrap_or(Value::Null);
+    let (mut db, uid, org_id) = acting(orch, claims).await.map_err(refused)?;
+
+    if action == "instances.list" {
+        let rows = instance::list(&mut db, org_id)
+            .await
+            .map_err(|e| refused(error_response(instance::Error::Db(e))))?;
+        return Ok(serde_json::json!({
+            "org_id": org_id,
+            "instances": rows.iter().map(instance::view).collect::<Vec<_>>(),
+        }));
+    }
+    if action == "instances.create" {
+        let request: CreateInstanceRequest = body(&payload)?;
+        let created = instance::create(&mut db, org_id, uid, &request.into_spec())
+            .await
+            .map_err(|e| refused(error_response(e)))?;
+        return Ok(instance::view(&created));
+    }
+
+    let InstanceRef { id } = serde_json::from_value(payload.clone()).map_err(|e| (None, format!("Invalid payload: {}", e)))?;
+    let (op, reason) = match action {
+        "instances.get" => {
+            let found = instance::get(&mut db, org_id, id).await.map_err(|e| refused(error_response(e)))?;
+            return Ok(instance::view(&found));
+        }
+        "instances.events" => {
+            let rows = instance::events(&mut db, org_id, id).await.map_err(|e| refused(error_response(e)))?;
+            return Ok(serde_json::json!({
+                "id": id,
+                "events": rows.iter().map(instance::event_view).collect::<Vec<_>>(),
+            }));
+        }
+        "instances.placement" => {
+            return whereis(&mut db, org_id, id).await.map_err(|e| refused(error_response(e)));
+        }
+        other => {
+            let op = match other {
+                "instances.start" => Op::Start,
+                "instances.stop" => Op::Stop,
+                "instances.restart" => Op::Restart,
+                _ => Op::Delete,
+            };
+            let request: ReasonRequest = body(&payload)?;
+            (op, request.reason)
+        }
+    };
+    let changed = instance::apply(&mut db, Some(org_id), Some(uid), id, op, reason.as_deref())
+        .await
+        .map_err(|e| refused(error_response(e)))?;
+    Ok(instance::view(&changed))
+}
+
 // ---------------------------------------------------------------------------
 // Token extraction helpers
 // ---------------------------------------------------------------------------
@@ -157,69 +475,22 @@ async fn dispatch(
 /// Try `Authorization: Bearer` header, then cookie.
 fn extract_bearer_or_cookie(headers: &axum::http::HeaderMap) -> Option<String> {
     // 1. Authorization header
-    if let Some(auth) = headers.get("authorization") {
-        if let Ok(val) = auth.to_str() {
-            if let Some(t) = val.strip_prefix("Bearer ") {
-                return Some(t.to_string());
-            }
-        }
+    if let Some(auth) = headers.get("authorization")
+        && let Ok(val) = auth.to_str()
+        && let Some(t) = val.strip_prefix("Bearer ")
+    {
+        return Some(t.to_string());
     }
     // 2. Cookie
-    if let Some(cookie_header) = headers.get("cookie") {
-        if let Ok(val) = cookie_header.to_str() {
-            for pair in val.split(';') {
-                let pair = pair.trim();
-                if let Some(v) = pair.strip_prefix(&format!("{}=", COOKIE_NAME)) {
-                    return Some(v.to_string());
-                }
+    if let Some(cookie_header) = headers.get("cookie")
+        && let Ok(val) = cookie_header.to_str()
+    {
+        for pair in val.split(';') {
+            let pair = pair.trim();
+            if let Some(v) = pair.strip_prefix(&format!("{}=", COOKIE_NAME)) {
+                return Some(v.to_string());
             }
         }
     }
     None
 }
-
-/// Extract the value of the `X-Api-Key` header.
-fn extract_api_key(headers: &axum::http::HeaderMap) -> Option<String> {
-    headers
-        .get("x-api-key")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string())
-}
-
-/// Look up a user by their eakey and return Claims if api_key_active is true.
-async fn resolve_api_key(
-    key: &str,
-    orch: &Arc<Mutex<Orchestrator>>,
-) -> Option<Claims> {
-    use crate::schema::{user_property::dsl as prop, users::dsl as udsl};
-    use crate::sql::user::User;
-    use crate::sql::user_property::UserProperty;
-
-    let mut db = orch.lock().await;
-
-    let user: User = udsl::users
-        .filter(udsl::eakey.eq(key))
-        .select(User::as_select())
-        .first(&mut db.sqlite)
-        .await
-        .ok()?;
-
-    let property: UserProperty = prop::user_property
-        .filter(prop::user_id.eq(user.id))
-        .select(UserProperty::as_select())
-        .first(&mut db.sqlite)
-        .await
-        .ok()?;
-
-    if !property.api_key_active {
-        return None;
-    }
-
-    let now = chrono::Utc::now().timestamp() as usize;
-    Some(Claims {
-        sub: user.id.to_string(),
-        username: user.username,
-        exp: now + crate::auth::TOKEN_EXPIRY_SECS as usize,
-        iat: now,
-    })
-}
@@ -0,0 +1,419 @@
+//! Instance scheduling.
+//!
+//! Every run of an instance is placed on a worker node (see [`crate::node`])
+//! when it starts. The candidates are the nodes whose agents are connected
+//! and keeping up their heartbeats, and which are not draining; a node must
+//! also be in the instance's region, if it names one, and carry every label
+//! of its affinity. Of the candidates with room, an instance stays on the
+//! node it was on, else goes to the one with the most room (see [`choose`]).
+//! With no such node it waits, still `provisioning`, for one. The decision
+//! and the reason for it are stored on the instance, so clients can ask
+//! where it runs and why (`GET /instances/{id}/placement`).
+//!
+//! A scheduler pass runs every heartbeat interval, and straight away when a
+//! node registers or is drained (see [`wake`]). It moves the active
+//! instances of nodes that missed their heartbeats, moves those of draining
+//! nodes as far as other nodes have room, and places waiting instances.
+//! Moving an instance restarts it on its new node ([`Op::Reschedule`]),
+//! which is recorded in its history like any other change.
+
+use crate::{
+    instance::{self, Op, Status},
+    node::{self, Command},
+    sql::{DbConn, Orchestrator, node::Node, transaction, wa_instance::WaInstance, with_conn},
+};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel_async::RunQueryDsl;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// A node instances can be placed on.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: i32,
+    pub name: String,
+    pub region: Option<String>,
+    pub labels: Map<String, Value>,
+    pub capacity: i32,
+}
+
+/// Where an instance goes, `None` meaning it waits, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub node_id: Option<i32>,
+    pub reason: String,
+}
+
+/// The labels a stored JSON object holds; anything else holds none.
+pub fn labels(stored: &str) -> Map<String, Value> {
+    serde_json::from_str(stored).unwrap_or_default()
+}
+
+/// Candidates from `(id, name, region, labels, capacity)` rows.
+pub fn candidates(rows: Vec<(i32, String, Option<String>, String, i32)>) -> Vec<Candidate> {
+    rows.into_iter()
+        .map(|(id, name, region, stored, capacity)| Candidate { id, name, region, labels: labels(&stored), capacity })
+        .collect()
+}
+
+/// What `instance` asks of its node, in words: empty, or e.g.
+/// ` in region 'eu-west' labelled tier=dedicated`.
+fn requirements(instance: &WaInstance) -> String {
+    let mut wants = String::new();
+    if let Some(region) = &instance.region {
+        wants.push_str(&format!(" in region '{}'", region));
+    }
+    let affinity = labels(&instance.affinity);
+    if !affinity.is_empty() {
+        let pairs: Vec<String> =
+            affinity.iter().map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default())).collect();
+        wants.push_str(&format!(" labelled {}", pairs.join(",")));
+    }
+    wants
+}
+
+/// Where to run `instance`, given the candidate nodes and the active
+/// instances each already hosts, not counting `instance` itself: back on
+/// its node if that matches and has room, else on the matching node with
+/// the most room (the oldest on a tie), else nowhere for now.
+pub fn choose(candidates: &[Candidate], used: &HashMap<i32, i64>, instance: &WaInstance) -> Decision {
+    let affinity = labels(&instance.affinity);
+    let matching: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|n| instance.region.as_ref().is_none_or(|r| n.region.as_ref() == Some(r)))
+        .filter(|n| affinity.iter().all(|(k, v)| n.labels.get(k) == Some(v)))
+        .collect();
+    let free = |n: &Candidate| n.capacity as i64 - used.get(&n.id).copied().unwrap_or(0);
+    let wait = |reason: String| Decision { node_id: None, reason };
+
+    if matching.is_empty() {
+        return wait(format!("Waiting: no node{} is available", requirements(instance)));
+    }
+    let open: Vec<&Candidate> = matching.iter().copied().filter(|n| free(n) > 0).collect();
+    if open.is_empty() {
+        return wait(match matching.len() {
+            1 => format!("Waiting: the only node{} is full", requirements(instance)),
+            n => format!("Waiting: all {} nodes{} are full", n, requirements(instance)),
+        });
+    }
+    if let Some(n) = open.iter().find(|n| Some(n.id) == instance.node_id) {
+        return Decision {
+            node_id: Some(n.id),
+            reason: format!("Stayed on node '{}' ({} of {} free)", n.name, free(n), n.capacity),
+        };
+    }
+    let best = open.iter().max_by_key(|n| (free(n), -n.id)).expect("open is not empty");
+    let among = match matching.len() {
+        1 => "the only matching node".to_string(),
+        n => format!("the roomiest of {} matching nodes", n),
+    };
+    Decision {
+        node_id: Some(best.id),
+        reason: format!("Placed on node '{}' ({} of {} free), {}", best.name, free(best), best.capacity, among),
+    }
+}
+
+/// Where to run `$instance`, inside a transaction on `$c`; see [`choose`].
+macro_rules! place {
+    ($c:ident, $instance:expr) => {{
+        use crate::schema::{nodes::dsl as n, wa_instances::dsl as w};
+
+        let instance: &crate::sql::wa_instance::WaInstance = $instance;
+        let rows: Vec<(i32, String, Option<String>, String, i32)> = n::nodes
+            .filter(n::status.eq(crate::node::ONLINE))
+            .filter(n::draining.eq(false))
+            .filter(n::last_seen_at.ge(crate::node::heard_since()))
+            .select((n::id, n::name, n::region, n::labels, n::capacity))
+            .load($c)
+            .await?;
+        let used: Vec<(Option<i32>, i64)> = w::wa_instances
+            .filter(w::node_id.is_not_null())
+            .filter(w::status.eq_any(crate::instance::ACTIVE))
+            .filter(w::id.ne(instance.id))
+            .group_by(w::node_id)
+            .select((w::node_id, diesel::dsl::count_star()))
+            .load($c)
+            .await?;
+        let used: std::collections::HashMap<i32, i64> =
+            used.into_iter().filter_map(|(node, n)| Some((node?, n))).collect();
+        crate::scheduler::choose(&crate::scheduler::candidates(rows), &used, instance)
+    }};
+}
+pub(crate) use place;
+
+/// How clients are shown where an instance runs: its node, if placed, with
+/// the decision that put it there and what it asks of its node.
+pub fn placement_view(instance: &WaInstance, node: Option<&Node>) -> Value {
+    serde_json::json!({
+        "id": instance.id,
+        "status": instance.status,
+        "node": node.map(|n| serde_json::json!({
+            "id": n.id,
+            "name": n.name,
+            "region": n.region,
+            "status": n.status,
+            "draining": n.draining,
+        })),
+        "region": instance.region,
+        "affinity": labels(&instance.affinity),
+        "placement": instance.placement,
+        "placed_at": instance.placed_at,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Scheduler pass
+// ---------------------------------------------------------------------------
+
+static WAKE: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Run a scheduler pass now rather than at the next interval.
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+/// Start the scheduler, passing every `NODE_HEARTBEAT_SECS` and whenever
+/// woken. Nodes are not taken for lost until they have had the time to
+/// reconnect after this process started.
+pub fn spawn(orch: Arc<Orchestrator>) {
+    let started = chrono::Utc::now().timestamp();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(node::heartbeat_secs())) => {}
+                _ = WAKE.notified() => {}
+            }
+            if let Err(e) = pass(&orch, started).await {
+                warn!("Scheduler pass skipped: {}", e);
+            }
+        }
+    });
+}
+
+/// Move instances off lost and draining nodes, then place waiting ones.
+async fn pass(orch: &Orchestrator, started: i64) -> Result<(), String> {
+    use crate::schema::nodes::dsl as n;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let since = node::heard_since();
+
+    if started < since {
+        let lost: Vec<(i32, String)> = with_conn!(db, |c| {
+            n::nodes
+                .filter(n::last_seen_at.is_null().or(n::last_seen_at.lt(since)))
+                .select((n::id, n::name))
+                .load(c)
+                .await
+        })
+        .map_err(|e| e.to_string())?;
+        for (id, name) in lost {
+            let reason = format!("Node '{}' missed its heartbeats", name);
+            for iid in active_on(&mut db, id).await.map_err(|e| e.to_string())? {
+                reschedule(&mut db, iid, &reason).await;
+            }
+        }
+    }
+
+    let draining: Vec<(i32, String)> = with_conn!(db, |c| {
+        n::nodes.filter(n::draining.eq(true)).select((n::id, n::name)).load(c).await
+    })
+    .map_err(|e| e.to_string())?;
+    for (id, name) in draining {
+        let reason = format!("Node '{}' is draining", name);
+        for iid in active_on(&mut db, id).await.map_err(|e| e.to_string())? {
+            // Leave it running until another node can take it.
+            match has_room(&mut db, iid).await {
+                Ok(true) => reschedule(&mut db, iid, &reason).await,
+                Ok(false) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    place_waiting(&mut db).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Ids of the active instances on node `id`, oldest first.
+async fn active_on(db: &mut DbConn, id: i32) -> QueryResult<Vec<i32>> {
+    use crate::schema::wa_instances::dsl as w;
+
+    with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.eq(id))
+            .filter(w::status.eq_any(instance::ACTIVE))
+            .order(w::id.asc())
+            .select(w::id)
+            .load(c)
+            .await
+    })
+}
+
+/// Whether a node other than its own could take instance `id` now.
+async fn has_room(db: &mut DbConn, id: i32) -> QueryResult<bool> {
+    use crate::schema::wa_instances::dsl as w;
+
+    let decision: QueryResult<Option<Decision>> = transaction!(*db, |c| {
+        let Some(current) = w::wa_instances.find(id).select(WaInstance::as_select()).first(c).await.optional()? else {
+            return Ok(None);
+        };
+        Ok::<_, DieselError>(Some(place!(c, &current)))
+    });
+    Ok(decision?.is_some_and(|d| d.node_id.is_some()))
+}
+
+async fn reschedule(db: &mut DbConn, id: i32, reason: &str) {
+    match instance::apply(db, None, None, id, Op::Reschedule, Some(reason)).await {
+        Ok(moved) => info!(instance_id = id, node_id = moved.node_id, "Instance rescheduled: {}.", reason),
+        // Stopped or deleted meanwhile.
+        Err(instance::Error::IllegalTransition { .. } | instance::Error::NotFound) => {}
+        Err(e) => warn!(instance_id = id, "Could not reschedule instance: {}", e.describe()),
+    }
+}
+
+/// Place instances waiting for a node, oldest first, and have the chosen
+/// nodes start them. Waiting instances keep the reason they wait.
+async fn place_waiting(db: &mut DbConn) -> QueryResult<usize> {
+    use crate::schema::wa_instances::dsl as w;
+
+    let waiting: Vec<i32> = with_conn!(*db, |c| {
+        w::wa_instances
+            .filter(w::node_id.is_null())
+            .filter(w::status.eq(Status::Provisioning.as_str()))
+            .order(w::id.asc())
+            .select(w::id)
+            .load(c)
+            .await
+    })?;
+    let mut placed = 0;
+    for id in waiting {
+        let now = chrono::Utc::now().timestamp();
+        let outcome: QueryResult<Option<WaInstance>> = transaction!(*db, |c| {
+            // Another pass may have placed it, or it stopped, meanwhile.
+            let current: Option<WaInstance> = w::wa_instances
+                .find(id)
+                .filter(w::node_id.is_null())
+                .filter(w::status.eq(Status::Provisioning.as_str()))
+                .select(WaInstance::as_select())
+                .first(c)
+                .await
+                .optional()?;
+            let Some(current) = current else {
+                return Ok(None);
+            };
+            let decision = place!(c, &current);
+            if decision.node_id.is_none() && current.placement.as_deref() == Some(decision.reason.as_str()) {
+                return Ok(None);
+            }
+            let mut next = current;
+            next.node_id = decision.node_id;
+            next.placement = Some(decision.reason);
+            next.placed_at = Some(now);
+            diesel::update(w::wa_instances.find(id)).set(&next).execute(c).await?;
+            Ok::<_, DieselError>(Some(next))
+        });
+        if let Some(instance) = outcome?
+            && let Some(node_id) = instance.node_id
+        {
+            info!(instance_id = id, node_id, "Placed waiting instance.");
+            node::deliver(node_id, Command::start(&instance));
+            placed += 1;
+        }
+    }
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn node(id: i32, capacity: i32, labels: Value) -> Candidate {
+        let labels = labels.as_object().cloned().unwrap_or_default();
+        Candidate { id, name: format!("worker-{}", id), region: Some("eu-west".to_string()), labels, capacity }
+    }
+
+    fn instance(node_id: Option<i32>, affinity: Value) -> WaInstance {
+        WaInstance {
+            id: 1,
+            org_id: 1,
+            created_by: None,
+            name: "support line".to_string(),
+            phone_number: None,
+            status: Status::Provisioning.as_str().to_string(),
+            config: "{}".to_string(),
+            created_at: 0,
+            started_at: None,
+            stopped_at: None,
+            run_secs: 0,
+            node_id,
+            region: None,
+            affinity: affinity.to_string(),
+            placement: None,
+            placed_at: None,
+        }
+    }
+
+    fn used(pairs: &[(i32, i64)]) -> HashMap<i32, i64> {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn most_room_wins_and_the_oldest_breaks_a_tie() {
+        let nodes = [node(1, 4, json!({})), node(2, 4, json!({})), node(3, 4, json!({}))];
+        let any = instance(None, json!({}));
+
+        let d = choose(&nodes, &used(&[(1, 2)]), &any);
+        assert_eq!(d.node_id, Some(2));
+        assert_eq!(d.reason, "Placed on node 'worker-2' (4 of 4 free), the roomiest of 3 matching nodes");
+        assert_eq!(choose(&nodes, &used(&[(1, 1), (2, 3)]), &any).node_id, Some(3));
+        assert_eq!(choose(&nodes, &used(&[(1, 4), (2, 4), (3, 3)]), &any).node_id, Some(3));
+        assert_eq!(choose(&nodes, &used(&[(1, 4), (2, 4), (3, 4)]), &any).reason, "Waiting: all 3 nodes are full");
+    }
+
+    #[test]
+    fn an_instance_stays_on_its_node_while_it_has_room() {
+        let nodes = [node(1, 4, json!({})), node(2, 10, json!({}))];
+
+        let d = choose(&nodes, &used(&[(1, 3)]), &instance(Some(1), json!({})));
+        assert_eq!(d, Decision { node_id: Some(1), reason: "Stayed on node 'worker-1' (1 of 4 free)".to_string() });
+        assert_eq!(choose(&nodes, &used(&[(1, 4)]), &instance(Some(1), json!({}))).node_id, Some(2));
+    }
+
+    #[test]
+    fn affinity_misses_wait() {
+        let nodes = [node(1, 4, json!({"tier": "shared"})), node(2, 4, json!({"tier": "dedicated", "gpu": "yes"}))];
+
+        let dedicated = instance(None, json!({"tier": "dedicated"}));
+        assert_eq!(choose(&nodes, &used(&[]), &dedicated).node_id, Some(2));
+        let full = choose(&nodes, &used(&[(2, 4)]), &dedicated);
+        assert_eq!(full.reason, "Waiting: the only node labelled tier=dedicated is full");
+
+        let d = choose(&nodes, &used(&[]), &instance(None, json!({"tier": "premium"})));
+        assert_eq!(d.node_id, None);
+        assert_eq!(d.reason, "Waiting: no node labelled tier=premium is available");
+
+        let mut elsewhere = instance(None, json!({}));
+        elsewhere.region = Some("us-east".to_string());
+        assert_eq!(choose(&nodes, &used(&[]), &elsewhere).reason, "Waiting: no node in region 'us-east' is available");
+    }
+
+    #[test]
+    fn draining_nodes_are_no_candidates() {
+        // `place!` leaves draining nodes out, so the instance's own node is
+        // not among those `choose` sees.
+        let nodes = [node(2, 2, json!({})), node(3, 2, json!({}))];
+        let on_draining = instance(Some(1), json!({}));
+
+        let d = choose(&nodes, &used(&[(1, 5), (2, 1)]), &on_draining);
+        assert_eq!(d.node_id, Some(3));
+        assert!(d.reason.starts_with("Placed on node 'worker-3'"), "{}", d.reason);
+        assert_eq!(choose(&nodes, &used(&[(2, 2), (3, 2)]), &on_draining).node_id, None);
+        assert_eq!(choose(&[], &used(&[]), &on_draining).reason, "Waiting: no node is available");
+    }
+}
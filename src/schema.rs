@@ -6,8 +6,13 @@ diesel::table! {
         username -> Text,
         email -> Text,
         password_hash -> Text,
-        passkey -> Nullable<Text>,
         eakey -> Text,
+        require_passkey -> Bool,
+        email_verified_at -> Nullable<BigInt>,
+        role -> Text,
+        suspended_at -> Nullable<BigInt>,
+        suspended_reason -> Nullable<Text>,
+        deletion_due_at -> Nullable<BigInt>,
     }
 }
 
@@ -15,7 +20,6 @@ diesel::table! {
     user_property (id) {
         id -> Integer,
         user_id -> Integer,
-        instance_status -> Text,
         instance_usage -> Double,
         api_key_active -> Bool,
     }
@@ -28,6 +32,7 @@ diesel::table! {
         instances_count -> Integer,
         expected_consumption -> Double,
         instances_overall_consumption -> Double,
+        org_id -> Nullable<Integer>,
     }
 }
 
@@ -39,11 +44,292 @@ diesel::table! {
         amount_spent -> Double,
         total_amount_spent -> Double,
         average_hourly_consumption -> Double,
+        org_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    replication_outbox (id) {
+        id -> Integer,
+        table_name -> Text,
+        row_id -> Integer,
+        op -> Text,
+        created_at -> BigInt,
+        attempts -> Integer,
+        next_attempt_at -> BigInt,
+        last_error -> Nullable<Text>,
+        requeues -> Integer,
+    }
+}
+
+diesel::table! {
+    replication_dead_letters (id) {
+        id -> Integer,
+        table_name -> Text,
+        row_id -> Integer,
+        op -> Text,
+        created_at -> BigInt,
+        error -> Text,
+        dead_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Integer,
+        user_id -> Integer,
+        created_at -> BigInt,
+        last_used_at -> BigInt,
+        expires_at -> BigInt,
+        revoked_at -> Nullable<BigInt>,
+        revoked_reason -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        impersonator_id -> Nullable<Integer>,
+        active_org_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Integer,
+        session_id -> Integer,
+        token_hash -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    passkeys (id) {
+        id -> Integer,
+        user_id -> Integer,
+        credential_id -> Text,
+        public_key -> Text,
+        alg -> Integer,
+        sign_count -> BigInt,
+        name -> Text,
+        created_at -> BigInt,
+        last_used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    auth_challenges (id) {
+        id -> Integer,
+        user_id -> Nullable<Integer>,
+        purpose -> Text,
+        challenge -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        attempts -> Integer,
+    }
+}
+
+diesel::table! {
+    totp_credentials (id) {
+        id -> Integer,
+        user_id -> Integer,
+        secret -> Text,
+        created_at -> BigInt,
+        enabled_at -> Nullable<BigInt>,
+        last_used_step -> BigInt,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        created_at -> BigInt,
+        used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    email_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        purpose -> Text,
+        jti -> Text,
+        email -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        used_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    auth_throttles (id) {
+        id -> Integer,
+        scope -> Text,
+        subject -> Text,
+        failures -> Integer,
+        last_failure_at -> BigInt,
+        locked_until -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Integer,
+        actor_id -> Nullable<Integer>,
+        user_id -> Nullable<Integer>,
+        action -> Text,
+        detail -> Text,
+        ip -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    api_keys (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        prefix -> Text,
+        key_hash -> Text,
+        scopes -> Text,
+        allowed_ips -> Nullable<Text>,
+        created_at -> BigInt,
+        expires_at -> Nullable<BigInt>,
+        last_used_at -> Nullable<BigInt>,
+        last_used_ip -> Nullable<Text>,
+        revoked_at -> Nullable<BigInt>,
+        previous_key_hash -> Nullable<Text>,
+        previous_prefix -> Nullable<Text>,
+        previous_expires_at -> Nullable<BigInt>,
+        rotated_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    organizations (id) {
+        id -> Integer,
+        name -> Text,
+        personal_user_id -> Nullable<Integer>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    org_members (id) {
+        id -> Integer,
+        org_id -> Integer,
+        user_id -> Integer,
+        role -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    org_invitations (id) {
+        id -> Integer,
+        org_id -> Integer,
+        email -> Text,
+        role -> Text,
+        invited_by -> Nullable<Integer>,
+        jti -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        accepted_at -> Nullable<BigInt>,
+        accepted_by -> Nullable<Integer>,
+        revoked_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    wa_instances (id) {
+        id -> Integer,
+        org_id -> Integer,
+        created_by -> Nullable<Integer>,
+        name -> Text,
+        phone_number -> Nullable<Text>,
+        status -> Text,
+        config -> Text,
+        created_at -> BigInt,
+        started_at -> Nullable<BigInt>,
+        stopped_at -> Nullable<BigInt>,
+        run_secs -> BigInt,
+        node_id -> Nullable<Integer>,
+        region -> Nullable<Text>,
+        affinity -> Text,
+        placement -> Nullable<Text>,
+        placed_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    wa_instance_events (id) {
+        id -> Integer,
+        instance_id -> Integer,
+        from_status -> Nullable<Text>,
+        to_status -> Text,
+        reason -> Text,
+        actor_id -> Nullable<Integer>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    nodes (id) {
+        id -> Integer,
+        name -> Text,
+        region -> Nullable<Text>,
+        labels -> Text,
+        token_prefix -> Text,
+        token_hash -> Text,
+        capacity -> Integer,
+        status -> Text,
+        agent_version -> Nullable<Text>,
+        created_at -> BigInt,
+        connected_at -> Nullable<BigInt>,
+        last_seen_at -> Nullable<BigInt>,
+        draining -> Bool,
     }
 }
 
 diesel::joinable!(user_property -> users (user_id));
 diesel::joinable!(instances -> users (user_id));
 diesel::joinable!(billing -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(refresh_tokens -> sessions (session_id));
+diesel::joinable!(passkeys -> users (user_id));
+diesel::joinable!(auth_challenges -> users (user_id));
+diesel::joinable!(totp_credentials -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(email_tokens -> users (user_id));
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(org_members -> organizations (org_id));
+diesel::joinable!(org_members -> users (user_id));
+diesel::joinable!(org_invitations -> organizations (org_id));
+diesel::joinable!(wa_instances -> organizations (org_id));
+diesel::joinable!(wa_instance_events -> wa_instances (instance_id));
+diesel::joinable!(wa_instances -> nodes (node_id));
 
-diesel::allow_tables_to_appear_in_same_query!(users, user_property, instances, billing,);
+diesel::allow_tables_to_appear_in_same_query!(
+    users,
+    user_property,
+    instances,
+    billing,
+    replication_outbox,
+    replication_dead_letters,
+    sessions,
+    refresh_tokens,
+    passkeys,
+    auth_challenges,
+    totp_credentials,
+    recovery_codes,
+    email_tokens,
+    auth_throttles,
+    audit_log,
+    api_keys,
+    organizations,
+    org_members,
+    org_invitations,
+    wa_instances,
+    wa_instance_events,
+    nodes,
+);
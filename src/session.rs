@@ -0,0 +1,477 @@
+//! Server-side session store.
+//!
+//! A session is created at login and named by the `sid` claim of every
+//! access token issued for it, so revoking the session invalidates those
+//! tokens immediately rather than when they expire. Access tokens are
+//! short-lived; clients trade their refresh token at `/auth/refresh` for a
+//! new pair. Each refresh token works once: presenting one that was already
+//! used means it was copied, and the whole session is revoked.
+//!
+//! Revocations are also announced in-process (see [`revocations`]) so open
+//! WebSockets of a revoked session can be closed straight away.
+
+use crate::{
+    api_key::{in_range, parse_range},
+    auth::{REFRESH_TOKEN_EXPIRY_SECS, generate_refresh_token, hash_refresh_token},
+    sql::{
+        DbConn,
+        session::{NewRefreshToken, NewSession, RefreshToken, Session},
+        transaction, with_conn,
+    },
+};
+use axum::http::HeaderMap;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// How often `last_used_at` is bumped for a session in active use.
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+static REVOCATIONS: LazyLock<broadcast::Sender<i32>> =
+    LazyLock::new(|| broadcast::channel(256).0);
+
+/// Subscribe to the ids of sessions revoked by this process from now on.
+pub fn revocations() -> broadcast::Receiver<i32> {
+    REVOCATIONS.subscribe()
+}
+
+fn announce(ids: impl IntoIterator<Item = i32>) {
+    for sid in ids {
+        // No receivers just means no open sockets.
+        let _ = REVOCATIONS.send(sid);
+    }
+}
+
+/// Proxies allowed to name the client in `X-Forwarded-For`, from the
+/// comma- or space-separated addresses and ranges in `TRUSTED_PROXIES`.
+/// None by default: the client is whoever opened the connection.
+static TRUSTED_PROXIES: LazyLock<Vec<(IpAddr, u8)>> = LazyLock::new(|| {
+    let value = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|r| !r.is_empty())
+        .filter_map(|r| {
+            let range = parse_range(r);
+            if range.is_none() {
+                warn!("Ignoring '{}' in TRUSTED_PROXIES: not an address or range.", r);
+            }
+            range
+        })
+        .collect()
+});
+
+/// Client details recorded with a session so users can tell them apart.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    /// The user agent, and the address of the client behind `peer`.
+    pub fn new(headers: &HeaderMap, peer: SocketAddr) -> Self {
+        Self::resolve(headers, peer, &TRUSTED_PROXIES)
+    }
+
+    /// The client is `peer`, unless `peer` is one of `proxies`: then it is
+    /// the right-most `X-Forwarded-For` hop that is not a proxy too, since
+    /// everything left of that was written by the client and can be
+    /// anything. `X-Real-IP` stands in when a proxy sends no
+    /// `X-Forwarded-For`.
+    fn resolve(headers: &HeaderMap, peer: SocketAddr, proxies: &[(IpAddr, u8)]) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        let trusted = |ip: IpAddr| proxies.iter().any(|&(net, len)| in_range(ip, net, len));
+
+        let mut client = peer.ip().to_canonical();
+        if trusted(client) {
+            let hops: Vec<&str> = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .collect();
+            if hops.is_empty()
+                && let Some(ip) = header("x-real-ip").and_then(|v| v.parse::<IpAddr>().ok())
+            {
+                client = ip.to_canonical();
+            }
+            for hop in hops.iter().rev() {
+                // A garbled hop ends what can be believed.
+                let Ok(ip) = hop.parse::<IpAddr>() else { break };
+                client = ip.to_canonical();
+                if !trusted(client) {
+                    break;
+                }
+            }
+        }
+        Self {
+            user_agent: header("user-agent"),
+            ip: Some(client.to_string()),
+        }
+    }
+}
+
+/// Why a refresh token was refused.
+#[derive(Debug)]
+pub enum RefreshError {
+    /// Unknown, expired, or belonging to a revoked session.
+    Invalid,
+    /// Already used once; the session has been revoked.
+    Reused,
+    Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for RefreshError {
+    fn from(e: diesel::result::Error) -> Self {
+        RefreshError::Db(e)
+    }
+}
+
+enum Rotation {
+    Rotated(Session, String),
+    Reused(i32),
+    Invalid,
+}
+
+/// Open a session for `user_id` and return it with its first refresh token.
+pub async fn start(
+    db: &mut DbConn,
+    user_id: i32,
+    client: ClientInfo,
+) -> QueryResult<(Session, String)> {
+    use crate::schema::{refresh_tokens, sessions};
+
+    let now = chrono::Utc::now().timestamp();
+    let token = generate_refresh_token();
+    let token_hash = hash_refresh_token(&token);
+
+    let session: Session = transaction!(*db, |c| {
+        let session: Session = diesel::insert_into(sessions::table)
+            .values(&NewSession {
+                user_id,
+                created_at: now,
+                last_used_at: now,
+                expires_at: now + REFRESH_TOKEN_EXPIRY_SECS,
+                user_agent: client.user_agent,
+                ip: client.ip,
+                impersonator_id: None,
+            })
+            .returning(Session::as_returning())
+            .get_result(c)
+            .await?;
+
+        diesel::insert_into(refresh_tokens::table)
+            .values(&NewRefreshToken {
+                session_id: session.id,
+                token_hash,
+                created_at: now,
+                expires_at: now + REFRESH_TOKEN_EXPIRY_SECS,
+            })
+            .execute(c)
+            .await?;
+
+        Ok::<_, diesel::result::Error>(session)
+    })?;
+
+    Ok((session, token))
+}
+
+/// Open a session for `admin_id` to act as `user_id` until `expires_at`.
+/// It has no refresh token, so it cannot outlive that.
+pub async fn start_impersonation(
+    db: &mut DbConn,
+    user_id: i32,
+    admin_id: i32,
+    expires_at: i64,
+    client: ClientInfo,
+) -> QueryResult<Session> {
+    use crate::schema::sessions;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        diesel::insert_into(sessions::table)
+            .values(&NewSession {
+                user_id,
+                created_at: now,
+                last_used_at: now,
+                expires_at,
+                user_agent: client.user_agent,
+                ip: client.ip,
+                impersonator_id: Some(admin_id),
+            })
+            .returning(Session::as_returning())
+            .get_result(c)
+            .await
+    })
+}
+
+/// Exchange `presented` for a new refresh token, extending the session.
+pub async fn rotate(db: &mut DbConn, presented: &str) -> Result<(Session, String), RefreshError> {
+    use crate::schema::{refresh_tokens::dsl as rt, sessions::dsl as s};
+
+    let now = chrono::Utc::now().timestamp();
+    let presented_hash = hash_refresh_token(presented);
+    let token = generate_refresh_token();
+    let token_hash = hash_refresh_token(&token);
+
+    let outcome: QueryResult<Rotation> = transaction!(*db, |c| {
+        let Some(current) = rt::refresh_tokens
+            .filter(rt::token_hash.eq(&presented_hash))
+            .select(RefreshToken::as_select())
+            .first(c)
+            .await
+            .optional()?
+        else {
+            return Ok(Rotation::Invalid);
+        };
+
+        let session: Session = s::sessions
+            .find(current.session_id)
+            .select(Session::as_select())
+            .first(c)
+            .await?;
+
+        if session.revoked_at.is_some() || session.expires_at <= now || current.expires_at <= now {
+            return Ok(Rotation::Invalid);
+        }
+
+        // Claim the token; zero rows means it was already used, possibly by
+        // a concurrent request holding the same copy.
+        let claimed = diesel::update(rt::refresh_tokens.find(current.id).filter(rt::used_at.is_null()))
+            .set(rt::used_at.eq(now))
+            .execute(c)
+            .await?;
+
+        if claimed == 0 {
+            diesel::update(s::sessions.find(session.id))
+                .set((s::revoked_at.eq(now), s::revoked_reason.eq("refresh_token_reuse")))
+                .execute(c)
+                .await?;
+            return Ok(Rotation::Reused(session.id));
+        }
+
+        // Expired tokens can no longer be replayed, so they need not be kept.
+        diesel::delete(
+            rt::refresh_tokens
+                .filter(rt::session_id.eq(session.id))
+                .filter(rt::expires_at.le(now)),
+        )
+        .execute(c)
+        .await?;
+
+        diesel::insert_into(rt::refresh_tokens)
+            .values(&NewRefreshToken {
+                session_id: session.id,
+                token_hash,
+                created_at: now,
+                expires_at: now + REFRESH_TOKEN_EXPIRY_SECS,
+            })
+            .execute(c)
+            .await?;
+
+        let session: Session = diesel::update(s::sessions.find(session.id))
+            .set((
+                s::last_used_at.eq(now),
+                s::expires_at.eq(now + REFRESH_TOKEN_EXPIRY_SECS),
+            ))
+            .returning(Session::as_returning())
+            .get_result(c)
+            .await?;
+
+        Ok(Rotation::Rotated(session, token))
+    });
+
+    match outcome? {
+        Rotation::Rotated(session, token) => Ok((session, token)),
+        Rotation::Reused(session_id) => {
+            warn!(session_id, "Refresh token reused; session revoked.");
+            announce([session_id]);
+            Err(RefreshError::Reused)
+        }
+        Rotation::Invalid => Err(RefreshError::Invalid),
+    }
+}
+
+/// Session that `presented` was issued for, whether or not it is still usable.
+pub async fn find_by_refresh_token(db: &mut DbConn, presented: &str) -> QueryResult<Option<i32>> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    let presented_hash = hash_refresh_token(presented);
+    with_conn!(*db, |c| {
+        refresh_tokens
+            .filter(token_hash.eq(&presented_hash))
+            .select(session_id)
+            .first(c)
+            .await
+            .optional()
+    })
+}
+
+/// Whether session `sid` exists, has not expired and has not been revoked.
+/// Records activity on it at most once per [`TOUCH_INTERVAL_SECS`].
+pub async fn touch(db: &mut DbConn, sid: i32) -> QueryResult<bool> {
+    use crate::schema::sessions::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let active = sessions
+        .find(sid)
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(now));
+
+    let touched = with_conn!(*db, |c| {
+        diesel::update(active.filter(last_used_at.le(now - TOUCH_INTERVAL_SECS)))
+            .set(last_used_at.eq(now))
+            .execute(c)
+            .await
+    })?;
+    if touched > 0 {
+        return Ok(true);
+    }
+
+    let n: i64 = with_conn!(*db, |c| active.count().get_result(c).await)?;
+    Ok(n > 0)
+}
+
+/// Active sessions of `uid`, most recently used first.
+pub async fn list(db: &mut DbConn, uid: i32) -> QueryResult<Vec<Session>> {
+    use crate::schema::sessions::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        sessions
+            .filter(user_id.eq(uid))
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(now))
+            .order(last_used_at.desc())
+            .select(Session::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Make `org_id` the organization session `sid` acts in, so refreshed
+/// tokens keep it.
+pub async fn set_active_org(db: &mut DbConn, sid: i32, org_id: i32) -> QueryResult<()> {
+    use crate::schema::sessions::dsl::*;
+
+    with_conn!(*db, |c| {
+        diesel::update(sessions.find(sid))
+            .set(active_org_id.eq(org_id))
+            .execute(c)
+            .await
+            .map(|_| ())
+    })
+}
+
+/// Revoke session `sid`. Returns `false` if it was already revoked.
+pub async fn revoke(db: &mut DbConn, sid: i32, reason: &str) -> QueryResult<bool> {
+    use crate::schema::sessions::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let n = with_conn!(*db, |c| {
+        diesel::update(sessions.find(sid).filter(revoked_at.is_null()))
+            .set((revoked_at.eq(now), revoked_reason.eq(reason)))
+            .execute(c)
+            .await
+    })?;
+    if n > 0 {
+        announce([sid]);
+    }
+    Ok(n > 0)
+}
+
+/// Revoke session `sid` if it belongs to `uid`. Returns `false` if there is
+/// no such active session.
+pub async fn revoke_owned(db: &mut DbConn, uid: i32, sid: i32, reason: &str) -> QueryResult<bool> {
+    use crate::schema::sessions::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let n = with_conn!(*db, |c| {
+        diesel::update(
+            sessions
+                .find(sid)
+                .filter(user_id.eq(uid))
+                .filter(revoked_at.is_null()),
+        )
+        .set((revoked_at.eq(now), revoked_reason.eq(reason)))
+        .execute(c)
+        .await
+    })?;
+    if n > 0 {
+        announce([sid]);
+    }
+    Ok(n > 0)
+}
+
+/// Revoke every session of `uid` except `keep`. Returns how many were revoked.
+pub async fn revoke_all(
+    db: &mut DbConn,
+    uid: i32,
+    keep: Option<i32>,
+    reason: &str,
+) -> QueryResult<usize> {
+    use crate::schema::sessions::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let revoked: Vec<i32> = with_conn!(*db, |c| {
+        diesel::update(
+            sessions
+                .filter(user_id.eq(uid))
+                .filter(id.ne(keep.unwrap_or(0)))
+                .filter(revoked_at.is_null()),
+        )
+        .set((revoked_at.eq(now), revoked_reason.eq(reason)))
+        .returning(id)
+        .get_results(c)
+        .await
+    })?;
+    let n = revoked.len();
+    announce(revoked);
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(peer: &str, forwarded: &[&str], proxies: &[&str]) -> String {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        let proxies: Vec<_> = proxies.iter().map(|r| parse_range(r).unwrap()).collect();
+        ClientInfo::resolve(&headers, peer.parse().unwrap(), &proxies).ip.unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        assert_eq!(resolve("192.0.2.1:5000", &["198.51.100.7"], &[]), "192.0.2.1");
+        assert_eq!(resolve("192.0.2.1:5000", &["198.51.100.7"], &["10.0.0.0/8"]), "192.0.2.1");
+    }
+
+    #[test]
+    fn trusted_proxy_passes_on_the_right_most_untrusted_hop() {
+        let proxies = ["10.0.0.0/8"];
+        assert_eq!(resolve("10.0.0.2:5000", &["198.51.100.7"], &proxies), "198.51.100.7");
+        assert_eq!(resolve("10.0.0.2:5000", &["1.2.3.4, 198.51.100.7, 10.0.0.3"], &proxies), "198.51.100.7");
+        assert_eq!(resolve("10.0.0.2:5000", &["1.2.3.4", "198.51.100.7"], &proxies), "198.51.100.7");
+        assert_eq!(resolve("10.0.0.2:5000", &["junk, 198.51.100.7"], &proxies), "198.51.100.7");
+        assert_eq!(resolve("10.0.0.2:5000", &["198.51.100.7, junk"], &proxies), "10.0.0.2");
+        assert_eq!(resolve("10.0.0.2:5000", &[], &proxies), "10.0.0.2");
+    }
+
+    #[test]
+    fn mapped_ipv4_peer_matches_ipv4_ranges() {
+        assert_eq!(resolve("[::ffff:10.0.0.2]:5000", &["198.51.100.7"], &["10.0.0.0/8"]), "198.51.100.7");
+    }
+}
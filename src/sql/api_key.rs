@@ -0,0 +1,46 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::api_keys)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// First characters of the key, safe to display.
+    pub prefix: String,
+    /// SHA-256 hex of the full key.
+    pub key_hash: String,
+    /// Space-separated, e.g. `instances:read billing:read`.
+    pub scopes: String,
+    /// Space-separated IPs or CIDR ranges; `None` allows any address.
+    pub allowed_ips: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub last_used_ip: Option<String>,
+    pub revoked_at: Option<i64>,
+    /// SHA-256 hex of the key this one replaced, valid until
+    /// `previous_expires_at`.
+    pub previous_key_hash: Option<String>,
+    pub previous_prefix: Option<String>,
+    pub previous_expires_at: Option<i64>,
+    pub rotated_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::api_keys)]
+pub struct NewApiKey {
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: String,
+    pub allowed_ips: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
@@ -0,0 +1,33 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::audit_log)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEntry {
+    pub id: i32,
+    /// Who caused the event, if it was a known user.
+    pub actor_id: Option<i32>,
+    /// The account the event concerns.
+    pub user_id: Option<i32>,
+    /// Dotted event name, e.g. `lockout.started`.
+    pub action: String,
+    /// JSON object with event-specific fields.
+    pub detail: String,
+    pub ip: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::audit_log)]
+pub struct NewAuditEntry {
+    pub actor_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub action: String,
+    pub detail: String,
+    pub ip: Option<String>,
+    pub created_at: i64,
+}
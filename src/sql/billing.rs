@@ -2,8 +2,9 @@
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::schema::billing)]
+#[diesel(treat_none_as_null = true)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Billing {
@@ -13,6 +14,7 @@ pub struct Billing {
     pub amount_spent: f64,
     pub total_amount_spent: f64,
     pub average_hourly_consumption: f64,
+    pub org_id: Option<i32>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -23,4 +25,5 @@ pub struct NewBilling {
     pub amount_spent: f64,
     pub total_amount_spent: f64,
     pub average_hourly_consumption: f64,
+    pub org_id: Option<i32>,
 }
@@ -0,0 +1,32 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::email_tokens)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailToken {
+    pub id: i32,
+    pub user_id: i32,
+    /// `verify_email` or `reset_password`.
+    pub purpose: String,
+    pub jti: String,
+    /// Address the link was sent to.
+    pub email: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::email_tokens)]
+pub struct NewEmailToken {
+    pub user_id: i32,
+    pub purpose: String,
+    pub jti: String,
+    pub email: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
@@ -2,8 +2,9 @@
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::schema::instances)]
+#[diesel(treat_none_as_null = true)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Instance {
@@ -12,6 +13,7 @@ pub struct Instance {
     pub instances_count: i32,
     pub expected_consumption: f64,
     pub instances_overall_consumption: f64,
+    pub org_id: Option<i32>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -21,4 +23,5 @@ pub struct NewInstance {
     pub instances_count: i32,
     pub expected_consumption: f64,
     pub instances_overall_consumption: f64,
+    pub org_id: Option<i32>,
 }
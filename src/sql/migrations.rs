@@ -0,0 +1,42 @@
+//! Embedded schema migrations.
+//!
+//! `migrations/sqlite` and `migrations/postgres` hold the same versions with
+//! backend-specific DDL. Both sets are compiled into the binary and applied
+//! at startup; each migration runs inside its own transaction and is recorded
+//! in `__diesel_schema_migrations`, so a failure leaves the schema at the last
+//! good version instead of half-applied.
+
+use diesel::backend::Backend;
+use diesel::migration::MigrationVersion;
+use diesel_async::{AsyncConnection, AsyncMigrationHarness};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+use std::error::Error;
+
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+pub const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+
+pub type MigrationError = Box<dyn Error + Send + Sync>;
+
+/// Apply every pending migration on `conn` and return the resulting schema
+/// version (the newest applied migration), if any.
+pub fn run_pending<C>(
+    conn: C,
+    migrations: EmbeddedMigrations,
+) -> Result<Option<String>, MigrationError>
+where
+    C: AsyncConnection + 'static,
+    AsyncMigrationHarness<C>: MigrationHarness<C::Backend>,
+    C::Backend: Backend,
+{
+    let mut harness = AsyncMigrationHarness::new(conn);
+
+    for version in harness.run_pending_migrations(migrations)? {
+        tracing::info!("Applied migration {}", version);
+    }
+
+    Ok(harness
+        .applied_migrations()?
+        .into_iter()
+        .max()
+        .map(|v: MigrationVersion| v.to_string()))
+}
@@ -1,7 +1,23 @@
+pub mod api_key;
+pub mod audit;
 pub mod billing;
+pub mod email_token;
 pub mod instance;
+pub mod migrations;
+pub mod node;
 pub mod orchestrator;
+pub mod org;
+pub mod outbox;
+pub mod passkey;
+pub mod reconcile;
+pub mod replication;
+pub mod session;
+pub mod supervisor;
+pub mod throttle;
+pub mod totp;
 pub mod user;
 pub mod user_property;
+pub mod wa_instance;
 
-pub use orchestrator::Orchestrator;
+pub use orchestrator::{DbBackend, DbConn, Orchestrator};
+pub(crate) use orchestrator::{transaction, with_conn};
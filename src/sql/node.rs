@@ -0,0 +1,41 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::nodes)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Node {
+    pub id: i32,
+    pub name: String,
+    pub region: Option<String>,
+    /// JSON object of strings, e.g. `{"tier": "dedicated"}`.
+    pub labels: String,
+    /// First characters of the agent token, safe to display.
+    pub token_prefix: String,
+    /// SHA-256 hex of the full agent token.
+    pub token_hash: String,
+    /// Active instances the agent said it can host when it last registered.
+    pub capacity: i32,
+    /// `online` while its agent is connected, else `offline`.
+    pub status: String,
+    pub agent_version: Option<String>,
+    pub created_at: i64,
+    pub connected_at: Option<i64>,
+    pub last_seen_at: Option<i64>,
+    /// Set by an operator to move its instances elsewhere.
+    pub draining: bool,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::nodes)]
+pub struct NewNode {
+    pub name: String,
+    pub region: Option<String>,
+    pub labels: String,
+    pub token_prefix: String,
+    pub token_hash: String,
+    pub created_at: i64,
+}
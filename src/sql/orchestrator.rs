@@ -1,125 +1,411 @@
-use diesel::pg::Pg;
-use diesel::prelude::*;
-use diesel::query_builder::{QueryFragment, QueryId};
-use diesel::sqlite::{Sqlite, SqliteConnection};
+use deadpool::Runtime;
+use diesel::sqlite::SqliteConnection;
+use diesel_async::pooled_connection::deadpool::{Object, Pool, PoolError};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
 use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
-use diesel_async::{AsyncConnection, RunQueryDsl, pg::AsyncPgConnection};
+use diesel_async::{AsyncConnection, SimpleAsyncConnection, pg::AsyncPgConnection};
+use futures_util::FutureExt;
+use serde::Serialize;
 use std::env;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 use tracing::{info, warn};
 
+use super::{migrations, replication};
+
+pub type SqliteConn = SyncConnectionWrapper<SqliteConnection>;
+pub type SqlitePool = Pool<SqliteConn>;
+pub type PgPool = Pool<AsyncPgConnection>;
+
+/// Shared handle to both database backends. Cheap to share behind an `Arc`:
+/// every handler checks out its own pooled connection instead of locking a
+/// single global one.
 pub struct Orchestrator {
-    pub sqlite: SyncConnectionWrapper<SqliteConnection>,
-    pub pg: Option<AsyncPgConnection>,
-}
-
-const SCHEMA_SQL: &str = "
-PRAGMA foreign_keys = ON;
-
-CREATE TABLE IF NOT EXISTS users (
-    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-    username TEXT NOT NULL UNIQUE,
-    email TEXT NOT NULL UNIQUE,
-    password_hash TEXT NOT NULL,
-    passkey TEXT,
-    eakey TEXT NOT NULL UNIQUE
-);
-
-CREATE TABLE IF NOT EXISTS user_property (
-    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-    user_id INTEGER NOT NULL,
-    instance_status TEXT NOT NULL DEFAULT 'inactive',
-    instance_usage REAL NOT NULL DEFAULT 0.0,
-    api_key_active INTEGER NOT NULL DEFAULT 0,
-    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
-);
-
-CREATE TABLE IF NOT EXISTS instances (
-    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-    user_id INTEGER NOT NULL,
-    instances_count INTEGER NOT NULL DEFAULT 0,
-    expected_consumption REAL NOT NULL DEFAULT 0.0,
-    instances_overall_consumption REAL NOT NULL DEFAULT 0.0,
-    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
-);
-
-CREATE TABLE IF NOT EXISTS billing (
-    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-    user_id INTEGER NOT NULL,
-    amount_in_wallet REAL NOT NULL DEFAULT 0.0,
-    amount_spent REAL NOT NULL DEFAULT 0.0,
-    total_amount_spent REAL NOT NULL DEFAULT 0.0,
-    average_hourly_consumption REAL NOT NULL DEFAULT 0.0,
-    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
-);
-
-ALTER TABLE user_property ADD COLUMN IF NOT EXISTS api_key_active INTEGER NOT NULL DEFAULT 0;
-";
+    pub sqlite: SqlitePool,
+    /// Postgres pool, present whenever `POSTGRES_DATABASE_URL` is set. The
+    /// pool connects lazily, so it exists even if Postgres was down at startup.
+    pub pg: Option<PgPool>,
+    /// Backend that should serve traffic while healthy (`DB_PRIMARY`).
+    pub primary: DbBackend,
+    /// Initialised once the Postgres schema has been migrated in this process.
+    pg_ready: OnceCell<()>,
+    /// Backend currently serving traffic; differs from `primary` during failover.
+    active: RwLock<DbBackend>,
+    pg_status: RwLock<PgStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    Sqlite,
+    Postgres,
+}
+
+impl fmt::Display for DbBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbBackend::Sqlite => write!(f, "SQLite"),
+            DbBackend::Postgres => write!(f, "Postgres"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PgState {
+    /// No `POSTGRES_DATABASE_URL` configured.
+    Disabled,
+    /// Configured but not yet checked.
+    Connecting,
+    Online,
+    Offline,
+}
+
+/// Last known Postgres health, maintained by the supervisor.
+#[derive(Debug, Clone, Serialize)]
+pub struct PgStatus {
+    pub state: PgState,
+    /// Unix seconds of the last state change.
+    pub since: i64,
+    pub last_error: Option<String>,
+}
+
+/// A pooled connection to whichever backend is currently active. Use
+/// [`with_conn!`] to run the same diesel code against either variant.
+pub enum DbConn {
+    Sqlite(Object<SqliteConn>),
+    Pg(Object<AsyncPgConnection>),
+}
+
+/// Run the same diesel expression against whichever backend `$conn` holds.
+///
+/// ```ignore
+/// let mut db = orch.conn().await?;
+/// let user: User = with_conn!(db, |c| users.find(uid).first(c).await)?;
+/// ```
+macro_rules! with_conn {
+    ($conn:expr, |$c:ident| $body:expr) => {
+        match $conn {
+            $crate::sql::DbConn::Sqlite(ref mut __conn) => {
+                let $c = &mut **__conn;
+                $body
+            }
+            $crate::sql::DbConn::Pg(ref mut __conn) => {
+                let $c = &mut **__conn;
+                $body
+            }
+        }
+    };
+}
+pub(crate) use with_conn;
+
+/// Run `$body` inside a database transaction on whichever backend `$conn`
+/// holds. The body is an `async` block evaluated with `$c` bound to the
+/// transaction's connection; returning `Err` (or failing a query with `?`)
+/// rolls every statement back, `Ok` commits.
+///
+/// The error type must implement `From<diesel::result::Error>`; annotate the
+/// result when inference cannot see it.
+///
+/// ```ignore
+/// let mut db = orch.conn().await?;
+/// let moved: QueryResult<()> = transaction!(db, |c| {
+///     diesel::update(billing.find(from)).set(amount_in_wallet.eq(amount_in_wallet - amt)).execute(c).await?;
+///     diesel::update(billing.find(to)).set(amount_in_wallet.eq(amount_in_wallet + amt)).execute(c).await?;
+///     Ok(())
+/// });
+/// ```
+///
+/// SQLite transactions begin `IMMEDIATE`, taking the write lock up front: a
+/// deferred one that reads and then writes fails at once with
+/// `SQLITE_BUSY`, without waiting out the busy timeout, when another
+/// writer committed in between.
+macro_rules! transaction {
+    ($conn:expr, |$c:ident| $body:expr) => {
+        match $conn {
+            $crate::sql::DbConn::Sqlite(ref mut __conn) => {
+                let __tx_conn = &mut **__conn;
+                __tx_conn
+                    .immediate_transaction(|$c| {
+                        diesel_async::scoped_futures::ScopedFutureExt::scope_boxed(async move { $body })
+                    })
+                    .await
+            }
+            $crate::sql::DbConn::Pg(ref mut __conn) => {
+                let __tx_conn = &mut **__conn;
+                diesel_async::AsyncConnection::transaction(__tx_conn, |$c| {
+                    diesel_async::scoped_futures::ScopedFutureExt::scope_boxed(async move { $body })
+                })
+                .await
+            }
+        }
+    };
+}
+pub(crate) use transaction;
+
+// ---------------------------------------------------------------------------
+// Pool configuration
+// ---------------------------------------------------------------------------
+
+/// Pool sizing and timeouts, read from the environment.
+///
+/// | Variable                  | Default |
+/// |---------------------------|---------|
+/// | `SQLITE_POOL_SIZE`        | 8       |
+/// | `POSTGRES_POOL_SIZE`      | 16      |
+/// | `DB_POOL_TIMEOUT_SECS`    | 5       |
+/// | `SQLITE_BUSY_TIMEOUT_MS`  | 5000    |
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub sqlite_size: usize,
+    pub pg_size: usize,
+    /// How long a handler waits for a free connection (and for a new one to
+    /// be established) before giving up.
+    pub timeout: Duration,
+    /// How long SQLite waits on a locked database before returning `SQLITE_BUSY`.
+    pub sqlite_busy_timeout_ms: u64,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        Self {
+            sqlite_size: env_or("SQLITE_POOL_SIZE", 8),
+            pg_size: env_or("POSTGRES_POOL_SIZE", 16),
+            timeout: Duration::from_secs(env_or("DB_POOL_TIMEOUT_SECS", 5)),
+            sqlite_busy_timeout_ms: env_or("SQLITE_BUSY_TIMEOUT_MS", 5000),
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
 
 impl Orchestrator {
     pub async fn init() -> Self {
         let sqlite_url = env::var("SQLITE_DATABASE_URL").unwrap_or_else(|_| "database.db".to_string());
         let pg_url = env::var("POSTGRES_DATABASE_URL").ok().filter(|s| !s.is_empty());
+        let config = PoolConfig::from_env();
 
-        info!("Connecting to SQLite at {}", sqlite_url);
+        info!(
+            "Connecting to SQLite at {} (pool size {}).",
+            sqlite_url, config.sqlite_size
+        );
 
-        let mut sqlite_conn = SyncConnectionWrapper::<SqliteConnection>::establish(&sqlite_url)
-            .await
-            .expect("SQLite must start");
+        let sqlite = build_sqlite_pool(&sqlite_url, &config);
+
+        let sqlite_conn = sqlite.get().await.expect("SQLite must start");
+        match migrations::run_pending(sqlite_conn, migrations::SQLITE_MIGRATIONS) {
+            Ok(version) => info!("SQLite schema at version {}.", version.unwrap_or_default()),
+            Err(e) => panic!("SQLite migration failed: {}", e),
+        }
 
-        // Apply schema for each statement individually
-        for stmt in SCHEMA_SQL.split(';') {
-            let trimmed = stmt.trim();
-            if trimmed.is_empty() {
-                continue;
+        if pg_url.is_some() {
+            let mut conn = sqlite.get().await.expect("SQLite must start");
+            if let Err(e) = replication::reserve_sqlite_ids(&mut conn).await {
+                panic!("Could not move SQLite ids out of the Postgres range: {}", e);
             }
-            let query = diesel::sql_query(trimmed);
-            let _ = query.execute(&mut sqlite_conn).await.map_err(|e| {
-                warn!("Schema init error: {}", e);
-            });
         }
 
-        let pg_conn = if let Some(url) = pg_url {
-            info!("Connecting to Postgres...");
-            match AsyncPgConnection::establish(&url).await {
-                Ok(c) => {
-                    info!("Postgres connected.");
-                    Some(c)
+        let primary = match env::var("DB_PRIMARY").as_deref() {
+            Ok("postgres") if pg_url.is_some() => DbBackend::Postgres,
+            Ok("postgres") => {
+                warn!("DB_PRIMARY=postgres but no POSTGRES_DATABASE_URL set — using SQLite.");
+                DbBackend::Sqlite
+            }
+            Ok("sqlite") | Err(_) => DbBackend::Sqlite,
+            Ok(other) => panic!("Invalid DB_PRIMARY '{}': expected 'sqlite' or 'postgres'", other),
+        };
+
+        let orch = Self {
+            sqlite,
+            pg: pg_url.map(|url| {
+                info!("Connecting to Postgres (pool size {})...", config.pg_size);
+                build_pg_pool(&url, &config)
+            }),
+            primary,
+            pg_ready: OnceCell::new(),
+            active: RwLock::new(DbBackend::Sqlite),
+            pg_status: RwLock::new(PgStatus {
+                state: PgState::Disabled,
+                since: chrono::Utc::now().timestamp(),
+                last_error: None,
+            }),
+        };
+
+        if orch.pg.is_none() {
+            info!("No POSTGRES_DATABASE_URL set — SQLite-only mode.");
+        } else {
+            orch.set_pg_status(PgState::Connecting, None);
+            match orch.pg().await {
+                Ok(_) => {
+                    orch.set_pg_status(PgState::Online, None);
+                    if primary == DbBackend::Postgres {
+                        *orch.active.write().unwrap() = DbBackend::Postgres;
+                    }
                 }
+                Err(PgError::Migration(e)) => panic!("Postgres migration failed: {}", e),
+                Err(e) => orch.set_pg_status(PgState::Offline, Some(e.to_string())),
+            }
+        }
+
+        info!("{} is primary; {} is serving traffic.", orch.primary, orch.active());
+
+        orch
+    }
+
+    /// Check out a connection to the active backend. If Postgres is active
+    /// but cannot hand out a connection the error is returned as is: failing
+    /// over is left to the supervisor, which only does so after several
+    /// failed checks in a row, so a blip doesn't send writes to SQLite.
+    pub async fn conn(&self) -> Result<DbConn, PoolError> {
+        if self.active() == DbBackend::Postgres {
+            return match self.pg().await {
+                Ok(c) => Ok(DbConn::Pg(c)),
                 Err(e) => {
-                    warn!("Postgres offline: {}. Operating in SQLite-only mode.", e);
-                    None
+                    self.set_pg_status(PgState::Offline, Some(e.to_string()));
+                    Err(match e {
+                        PgError::Pool(e) => e,
+                        // Postgres only becomes active once migrated.
+                        PgError::NotConfigured | PgError::Migration(_) => PoolError::Closed,
+                    })
                 }
-            }
-        } else {
-            info!("No POSTGRES_DATABASE_URL set — SQLite-only mode.");
-            None
-        };
+            };
+        }
 
-        Self {
-            sqlite: sqlite_conn,
-            pg: pg_conn,
+        self.sqlite().await.map(DbConn::Sqlite)
+    }
+
+    /// Backend currently serving reads and writes.
+    pub fn active(&self) -> DbBackend {
+        *self.active.read().unwrap()
+    }
+
+    pub fn set_active(&self, backend: DbBackend) {
+        let mut active = self.active.write().unwrap();
+        if *active == backend {
+            return;
         }
+        *active = backend;
+        if backend == self.primary {
+            info!("{} is serving traffic again.", backend);
+        } else {
+            warn!("Failing over to {}: {} is unavailable.", backend, self.primary);
+        }
+    }
+
+    pub fn pg_status(&self) -> PgStatus {
+        self.pg_status.read().unwrap().clone()
     }
 
-    pub async fn sync_write<T>(&mut self, query: T) -> QueryResult<usize>
-    where
-        T: RunQueryDsl<SyncConnectionWrapper<SqliteConnection>>
-            + RunQueryDsl<AsyncPgConnection>
-            + QueryFragment<Sqlite>
-            + QueryFragment<Pg>
-            + QueryId
-            + Send
-            + Clone
-            + 'static,
-    {
-        let result = query.clone().execute(&mut self.sqlite).await;
-
-        if let Some(ref mut pg_conn) = self.pg {
-            let _ = query.execute(pg_conn).await.map_err(|e| {
-                eprintln!("Postgres Sync Failed: {}", e);
-            });
+    /// Record the latest Postgres health, logging state transitions.
+    pub fn set_pg_status(&self, state: PgState, error: Option<String>) {
+        let mut status = self.pg_status.write().unwrap();
+        if status.state != state {
+            match (state, &error) {
+                (PgState::Online, _) => info!("Postgres online."),
+                (PgState::Offline, Some(e)) => warn!("Postgres offline: {}", e),
+                _ => {}
+            }
+            status.state = state;
+            status.since = chrono::Utc::now().timestamp();
         }
+        status.last_error = error;
+    }
 
-        result
+    /// Check out a SQLite connection from the pool.
+    pub async fn sqlite(&self) -> Result<Object<SqliteConn>, PoolError> {
+        self.sqlite.get().await
     }
+
+    /// Check out a Postgres connection. The first successful checkout in this
+    /// process applies any pending Postgres migrations.
+    pub async fn pg(&self) -> Result<Object<AsyncPgConnection>, PgError> {
+        let pool = self.pg.as_ref().ok_or(PgError::NotConfigured)?;
+
+        self.pg_ready
+            .get_or_try_init(|| async {
+                let conn = pool.get().await.map_err(PgError::Pool)?;
+                let version = migrations::run_pending(conn, migrations::POSTGRES_MIGRATIONS)
+                    .map_err(PgError::Migration)?;
+                info!("Postgres schema at version {}.", version.unwrap_or_default());
+                Ok(())
+            })
+            .await?;
+
+        pool.get().await.map_err(PgError::Pool)
+    }
+}
+
+/// Why a Postgres connection could not be handed out.
+#[derive(Debug)]
+pub enum PgError {
+    NotConfigured,
+    Pool(PoolError),
+    Migration(migrations::MigrationError),
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgError::NotConfigured => write!(f, "POSTGRES_DATABASE_URL is not set"),
+            PgError::Pool(e) => write!(f, "{}", e),
+            PgError::Migration(e) => write!(f, "migration failed: {}", e),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Pool construction
+// ---------------------------------------------------------------------------
+
+fn build_sqlite_pool(url: &str, config: &PoolConfig) -> SqlitePool {
+    let busy_timeout = config.sqlite_busy_timeout_ms;
+    let mut manager_config = ManagerConfig::<SqliteConn>::default();
+    manager_config.custom_setup = Box::new(move |url| {
+        let url = url.to_string();
+        async move {
+            let mut conn = SqliteConn::establish(&url).await?;
+            // WAL lets readers proceed while a writer holds the lock; the
+            // busy timeout makes concurrent writers queue instead of failing.
+            conn.batch_execute(&format!(
+                "PRAGMA journal_mode = WAL; \
+                 PRAGMA synchronous = NORMAL; \
+                 PRAGMA busy_timeout = {}; \
+                 PRAGMA foreign_keys = ON;",
+                busy_timeout
+            ))
+            .await
+            .map_err(diesel::ConnectionError::CouldntSetupConfiguration)?;
+            Ok(conn)
+        }
+        .boxed()
+    });
+
+    let manager = AsyncDieselConnectionManager::<SqliteConn>::new_with_config(url, manager_config);
+    Pool::builder(manager)
+        .max_size(config.sqlite_size)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(config.timeout))
+        .create_timeout(Some(config.timeout))
+        .recycle_timeout(Some(config.timeout))
+        .build()
+        .expect("Invalid SQLite pool configuration")
+}
+
+fn build_pg_pool(url: &str, config: &PoolConfig) -> PgPool {
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+    Pool::builder(manager)
+        .max_size(config.pg_size)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(config.timeout))
+        .create_timeout(Some(config.timeout))
+        .recycle_timeout(Some(config.timeout))
+        .build()
+        .expect("Invalid Postgres pool configuration")
 }
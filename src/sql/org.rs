@@ -0,0 +1,80 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::organizations)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    /// The user whose personal organization this is, if it is one.
+    pub personal_user_id: Option<i32>,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::organizations)]
+pub struct NewOrganization {
+    pub name: String,
+    pub personal_user_id: Option<i32>,
+    pub created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::org_members)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrgMember {
+    pub id: i32,
+    pub org_id: i32,
+    pub user_id: i32,
+    /// `owner`, `admin` or `member`.
+    pub role: String,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::org_members)]
+pub struct NewOrgMember {
+    pub org_id: i32,
+    pub user_id: i32,
+    pub role: String,
+    pub created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::org_invitations)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrgInvitation {
+    pub id: i32,
+    pub org_id: i32,
+    /// Address the invitation was sent to, lowercased.
+    pub email: String,
+    /// Role the invitee gets on accepting.
+    pub role: String,
+    pub invited_by: Option<i32>,
+    /// Id carried by the emailed token.
+    pub jti: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub accepted_at: Option<i64>,
+    pub accepted_by: Option<i32>,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::org_invitations)]
+pub struct NewOrgInvitation {
+    pub org_id: i32,
+    pub email: String,
+    pub role: String,
+    pub invited_by: Option<i32>,
+    pub jti: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
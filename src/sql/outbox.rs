@@ -0,0 +1,18 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::replication_outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OutboxEntry {
+    pub id: i32,
+    pub table_name: String,
+    pub row_id: i32,
+    pub op: String,
+    pub created_at: i64,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub requeues: i32,
+}
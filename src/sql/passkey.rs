@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::passkeys)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Passkey {
+    pub id: i32,
+    pub user_id: i32,
+    /// Credential id, base64url.
+    pub credential_id: String,
+    /// COSE_Key, base64url.
+    pub public_key: String,
+    /// COSE algorithm identifier (-7 ES256, -8 EdDSA, -257 RS256).
+    pub alg: i32,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::passkeys)]
+pub struct NewPasskey {
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: String,
+    pub alg: i32,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::auth_challenges)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuthChallenge {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub purpose: String,
+    pub challenge: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub attempts: i32,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::auth_challenges)]
+pub struct NewAuthChallenge {
+    pub user_id: Option<i32>,
+    pub purpose: String,
+    pub challenge: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
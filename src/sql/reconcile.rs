@@ -0,0 +1,325 @@
+//! SQLite ↔ Postgres consistency checker.
+//!
+//! Loads every replicated table from both backends, compares rows by id and
+//! reports rows missing on either side or whose contents differ. Rows with an
+//! entry still queued in either outbox are skipped: they are in flight, not
+//! divergent. A repair copies the chosen side's state of every divergent row
+//! to the other side (deleting rows the source does not have), using the
+//! same code path as the replication worker.
+//!
+//! Tables are loaded whole, which is fine at our row counts; revisit before
+//! pointing this at anything large.
+
+use crate::sql::{
+    DbBackend, Orchestrator,
+    api_key::ApiKey,
+    audit::AuditEntry,
+    billing::Billing,
+    instance::Instance,
+    node::Node,
+    org::{OrgInvitation, OrgMember, Organization},
+    outbox::OutboxEntry,
+    passkey::Passkey,
+    replication,
+    session::{RefreshToken, Session},
+    totp::{RecoveryCode, TotpCredential},
+    user::User,
+    user_property::UserProperty,
+    wa_instance::{WaInstance, WaInstanceEvent},
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Replicated tables, parents first.
+const TABLES: [&str; 17] = [
+    "users",
+    "organizations",
+    "org_members",
+    "org_invitations",
+    "user_property",
+    "instances",
+    "nodes",
+    "wa_instances",
+    "wa_instance_events",
+    "billing",
+    "sessions",
+    "refresh_tokens",
+    "passkeys",
+    "totp_credentials",
+    "recovery_codes",
+    "audit_log",
+    "api_keys",
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct TableReport {
+    pub table: &'static str,
+    pub sqlite_rows: usize,
+    pub postgres_rows: usize,
+    pub missing_in_postgres: Vec<i32>,
+    pub missing_in_sqlite: Vec<i32>,
+    pub mismatched: Vec<i32>,
+    /// Rows skipped because a replication entry for them is still queued.
+    pub in_flight: usize,
+}
+
+impl TableReport {
+    pub fn divergent(&self) -> usize {
+        self.missing_in_postgres.len() + self.missing_in_sqlite.len() + self.mismatched.len()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub tables: Vec<TableReport>,
+}
+
+impl Report {
+    pub fn divergent(&self) -> usize {
+        self.tables.iter().map(TableReport::divergent).sum()
+    }
+
+    /// One `info`/`warn` line per table.
+    pub fn log(&self) {
+        for t in &self.tables {
+            if t.divergent() == 0 {
+                info!(
+                    table = t.table,
+                    rows = t.sqlite_rows,
+                    in_flight = t.in_flight,
+                    "Reconcile: consistent."
+                );
+            } else {
+                warn!(
+                    table = t.table,
+                    sqlite_rows = t.sqlite_rows,
+                    postgres_rows = t.postgres_rows,
+                    missing_in_postgres = ?t.missing_in_postgres,
+                    missing_in_sqlite = ?t.missing_in_sqlite,
+                    mismatched = ?t.mismatched,
+                    "Reconcile: {} divergent rows.",
+                    t.divergent()
+                );
+            }
+        }
+    }
+}
+
+/// Compare every replicated table between SQLite and Postgres.
+pub async fn check(orch: &Orchestrator) -> Result<Report, String> {
+    use crate::schema::replication_outbox::dsl::*;
+
+    let mut sqlite = orch.sqlite().await.map_err(|e| e.to_string())?;
+    let mut pg = orch.pg().await.map_err(|e| e.to_string())?;
+
+    let mut queued: HashSet<(String, i32)> = HashSet::new();
+    for entries in [
+        replication_outbox
+            .select(OutboxEntry::as_select())
+            .load(&mut sqlite)
+            .await,
+        replication_outbox
+            .select(OutboxEntry::as_select())
+            .load(&mut pg)
+            .await,
+    ] {
+        let entries = entries.map_err(|e| e.to_string())?;
+        queued.extend(entries.into_iter().map(|e| (e.table_name, e.row_id)));
+    }
+
+    macro_rules! compare {
+        ($table:ident, $model:ty) => {{
+            use crate::schema::$table::dsl;
+
+            let lite: Vec<$model> = dsl::$table
+                .select(<$model>::as_select())
+                .load(&mut sqlite)
+                .await
+                .map_err(|e| e.to_string())?;
+            let remote: Vec<$model> = dsl::$table
+                .select(<$model>::as_select())
+                .load(&mut pg)
+                .await
+                .map_err(|e| e.to_string())?;
+            diff(stringify!($table), lite, remote, |r| r.id, &queued)
+        }};
+    }
+
+    Ok(Report {
+        tables: vec![
+            compare!(users, User),
+            compare!(organizations, Organization),
+            compare!(org_members, OrgMember),
+            compare!(org_invitations, OrgInvitation),
+            compare!(user_property, UserProperty),
+            compare!(instances, Instance),
+            compare!(nodes, Node),
+            compare!(wa_instances, WaInstance),
+            compare!(wa_instance_events, WaInstanceEvent),
+            compare!(billing, Billing),
+            compare!(sessions, Session),
+            compare!(refresh_tokens, RefreshToken),
+            compare!(passkeys, Passkey),
+            compare!(totp_credentials, TotpCredential),
+            compare!(recovery_codes, RecoveryCode),
+            compare!(audit_log, AuditEntry),
+            compare!(api_keys, ApiKey),
+        ],
+    })
+}
+
+fn diff<T: PartialEq>(
+    table: &'static str,
+    sqlite_rows: Vec<T>,
+    postgres_rows: Vec<T>,
+    id_of: impl Fn(&T) -> i32,
+    queued: &HashSet<(String, i32)>,
+) -> TableReport {
+    let mut report = TableReport {
+        table,
+        sqlite_rows: sqlite_rows.len(),
+        postgres_rows: postgres_rows.len(),
+        ..Default::default()
+    };
+
+    let mut remote: BTreeMap<i32, T> = postgres_rows.into_iter().map(|r| (id_of(&r), r)).collect();
+
+    for row in sqlite_rows {
+        let id = id_of(&row);
+        let other = remote.remove(&id);
+        if queued.contains(&(table.to_string(), id)) {
+            report.in_flight += 1;
+            continue;
+        }
+        match other {
+            None => report.missing_in_postgres.push(id),
+            Some(other) if other != row => report.mismatched.push(id),
+            Some(_) => {}
+        }
+    }
+
+    for id in remote.into_keys() {
+        if queued.contains(&(table.to_string(), id)) {
+            report.in_flight += 1;
+        } else {
+            report.missing_in_sqlite.push(id);
+        }
+    }
+
+    report
+}
+
+/// Make `from`'s copy of every divergent row in `report` authoritative.
+/// Returns the number of rows written or deleted on the other side.
+pub async fn repair(orch: &Orchestrator, report: &Report, from: DbBackend) -> Result<usize, String> {
+    let mut sqlite = orch.sqlite().await.map_err(|e| e.to_string())?;
+    let mut pg = orch.pg().await.map_err(|e| e.to_string())?;
+
+    // Upserts parents first so foreign keys hold; deletes children first.
+    let mut upserts = Vec::new();
+    let mut deletes = Vec::new();
+    for table in TABLES {
+        let Some(t) = report.tables.iter().find(|t| t.table == table) else {
+            continue;
+        };
+        let (only_in_source, only_in_target) = match from {
+            DbBackend::Sqlite => (&t.missing_in_postgres, &t.missing_in_sqlite),
+            DbBackend::Postgres => (&t.missing_in_sqlite, &t.missing_in_postgres),
+        };
+        for &id in only_in_source.iter().chain(&t.mismatched) {
+            upserts.push(synthetic_entry(table, id, "upsert"));
+        }
+        for &id in only_in_target {
+            deletes.push(synthetic_entry(table, id, "delete"));
+        }
+    }
+    deletes.reverse();
+
+    let mut repaired = 0;
+    for entry in upserts.iter().chain(&deletes) {
+        let result = match from {
+            DbBackend::Sqlite => replication::to_pg(entry, &mut sqlite, &mut pg).await,
+            DbBackend::Postgres => replication::to_sqlite(entry, &mut pg, &mut sqlite).await,
+        };
+        result.map_err(|e| format!("{} #{}: {}", entry.table_name, entry.row_id, e))?;
+        repaired += 1;
+    }
+
+    Ok(repaired)
+}
+
+fn synthetic_entry(table: &str, row_id: i32, op: &str) -> OutboxEntry {
+    OutboxEntry {
+        id: 0,
+        table_name: table.to_string(),
+        row_id,
+        op: op.to_string(),
+        created_at: 0,
+        attempts: 0,
+        next_attempt_at: 0,
+        last_error: None,
+        requeues: 0,
+    }
+}
+
+/// Parse a repair direction as accepted by the CLI and `RECONCILE_REPAIR`.
+pub fn parse_direction(s: &str) -> Option<DbBackend> {
+    match s {
+        "sqlite-to-postgres" => Some(DbBackend::Sqlite),
+        "postgres-to-sqlite" => Some(DbBackend::Postgres),
+        _ => None,
+    }
+}
+
+/// Start the periodic checker. Runs every `RECONCILE_INTERVAL_SECS`
+/// (default 3600, `0` disables) and, if `RECONCILE_REPAIR` names a
+/// direction, repairs whatever it finds.
+pub fn spawn(orch: Arc<Orchestrator>) {
+    if orch.pg.is_none() {
+        return;
+    }
+
+    let interval: u64 = std::env::var("RECONCILE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+    if interval == 0 {
+        return;
+    }
+
+    let auto_repair = std::env::var("RECONCILE_REPAIR").ok().map(|v| {
+        parse_direction(&v).unwrap_or_else(|| {
+            panic!("Invalid RECONCILE_REPAIR '{}': expected 'sqlite-to-postgres' or 'postgres-to-sqlite'", v)
+        })
+    });
+
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(interval)).await;
+
+            let report = match check(&orch).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Reconcile skipped: {}", e);
+                    continue;
+                }
+            };
+            report.log();
+
+            if let Some(from) = auto_repair
+                && report.divergent() > 0
+            {
+                match repair(&orch, &report, from).await {
+                    Ok(n) => info!("Reconcile repaired {} rows from {}.", n, from),
+                    Err(e) => warn!("Reconcile repair failed: {}", e),
+                }
+            }
+        }
+    });
+}
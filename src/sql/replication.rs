@@ -0,0 +1,475 @@
+//! Replication between SQLite and Postgres through durable outboxes.
+//!
+//! Triggers on every replicated table append `(table_name, row_id, op)` to
+//! `replication_outbox` in the same transaction as the write itself, on both
+//! backends, so no mutation can bypass the mirror. A background worker
+//! replays each outbox to the other backend strictly in `id` order: an
+//! `upsert` copies the row's current state, a `delete` removes it. On failure
+//! the head entry is retried with exponential backoff and nothing behind it
+//! is replayed, so the two sides converge once both are reachable again. The
+//! exception is an upsert whose row already refers to rows queued behind it:
+//! it is moved to the back of the queue to wait for them. Entries for a
+//! table the worker does not mirror can never succeed and are moved to
+//! `replication_dead_letters` instead of blocking the queue.
+//!
+//! Writes made by the worker itself are not recorded again: on Postgres the
+//! triggers skip rows written with `orsta.replicating` set, and on SQLite the
+//! entries the replay produced are removed in the same transaction.
+//!
+//! Both backends take writes around a failover, so they hand out ids from
+//! ranges that cannot overlap: Postgres sequences stay below
+//! [`SQLITE_ID_BASE`] and SQLite allocates from there up. A row created on
+//! one side while the other was unreachable never lands on top of a row the
+//! other side created and had not yet copied over.
+
+use crate::sql::{
+    DbBackend, DbConn, Orchestrator,
+    api_key::ApiKey,
+    audit::AuditEntry,
+    billing::Billing,
+    instance::Instance,
+    node::Node,
+    orchestrator::SqliteConn,
+    org::{OrgInvitation, OrgMember, Organization},
+    outbox::OutboxEntry,
+    passkey::Passkey,
+    session::{RefreshToken, Session},
+    totp::{RecoveryCode, TotpCredential},
+    transaction,
+    user::User,
+    user_property::UserProperty,
+    wa_instance::{WaInstance, WaInstanceEvent},
+    with_conn,
+};
+use diesel::dsl::max;
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl, SimpleAsyncConnection, pg::AsyncPgConnection};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// Entries replayed per pass before the queue is polled again.
+const BATCH_SIZE: i64 = 100;
+/// Idle delay between polls when the outbox is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Upper bound for the retry delay of a failing entry.
+const MAX_BACKOFF_SECS: i64 = 300;
+/// How often an upsert breaking a foreign key is moved behind the rows it
+/// refers to before it is retried in place.
+const MAX_REQUEUES: i32 = 3;
+/// First id SQLite hands out in a replicated table once Postgres is
+/// configured. Postgres sequences are kept below it.
+pub(crate) const SQLITE_ID_BASE: i32 = 1_000_000_000;
+/// Tables the worker mirrors. Each has a `mirror_table!` arm and a serial
+/// `id` column.
+const REPLICATED_TABLES: &[&str] = &[
+    "users",
+    "organizations",
+    "org_members",
+    "org_invitations",
+    "user_property",
+    "instances",
+    "nodes",
+    "wa_instances",
+    "wa_instance_events",
+    "billing",
+    "sessions",
+    "refresh_tokens",
+    "passkeys",
+    "totp_credentials",
+    "recovery_codes",
+    "audit_log",
+    "api_keys",
+];
+
+/// Start the replication worker. Does nothing in SQLite-only mode.
+pub fn spawn(orch: Arc<Orchestrator>) {
+    if orch.pg.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Ok(mut conn) = orch.sqlite().await
+            && let Ok(n) = pending(&mut conn).await
+            && n > 0
+        {
+            info!("{} queued writes waiting to replicate to Postgres.", n);
+        }
+
+        loop {
+            // Drain SQLite first: after a failover it holds the newest writes.
+            let result = match drain(&orch, DbBackend::Sqlite).await {
+                Ok(0) => drain(&orch, DbBackend::Postgres).await,
+                other => other,
+            };
+            match result {
+                Ok(0) => sleep(POLL_INTERVAL).await,
+                Ok(n) => debug!("Replicated {} outbox entries.", n),
+                Err(wait) => sleep(wait).await,
+            }
+        }
+    });
+}
+
+/// Number of SQLite writes still waiting to reach Postgres.
+pub async fn pending(conn: &mut SqliteConn) -> QueryResult<i64> {
+    use crate::schema::replication_outbox::dsl::*;
+
+    replication_outbox.count().get_result(conn).await
+}
+
+/// Replay one batch of due entries from `source`'s outbox to the other
+/// backend. Returns how many were replicated, or how long to wait before
+/// trying again.
+async fn drain(orch: &Orchestrator, source: DbBackend) -> Result<usize, Duration> {
+    use crate::schema::replication_outbox::dsl::*;
+
+    let mut outbox = match source {
+        DbBackend::Sqlite => match orch.sqlite().await {
+            Ok(c) => DbConn::Sqlite(c),
+            Err(e) => {
+                warn!("Replication: SQLite unavailable: {}", e);
+                return Err(POLL_INTERVAL);
+            }
+        },
+        // Postgres being down is the supervisor's business; nothing to drain.
+        DbBackend::Postgres => match orch.pg().await {
+            Ok(c) => DbConn::Pg(c),
+            Err(_) => return Ok(0),
+        },
+    };
+
+    let batch: Vec<OutboxEntry> = match with_conn!(outbox, |c| {
+        replication_outbox
+            .order(id.asc())
+            .limit(BATCH_SIZE)
+            .select(OutboxEntry::as_select())
+            .load(c)
+            .await
+    }) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Replication: failed to read {} outbox: {}", source, e);
+            return Err(POLL_INTERVAL);
+        }
+    };
+
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let head = &batch[0];
+
+    let now = chrono::Utc::now().timestamp();
+    if head.next_attempt_at > now {
+        return Err(Duration::from_secs((head.next_attempt_at - now) as u64));
+    }
+
+    let mut target = match source {
+        DbBackend::Sqlite => match orch.pg().await {
+            Ok(c) => DbConn::Pg(c),
+            Err(e) => return Err(record_failure(&mut outbox, head, &e.to_string()).await),
+        },
+        DbBackend::Postgres => match orch.sqlite().await {
+            Ok(c) => DbConn::Sqlite(c),
+            Err(e) => return Err(record_failure(&mut outbox, head, &e.to_string()).await),
+        },
+    };
+
+    let mut done = 0;
+    for entry in &batch {
+        if !REPLICATED_TABLES.contains(&entry.table_name.as_str()) {
+            if !dead_letter(&mut outbox, entry).await {
+                return Err(POLL_INTERVAL);
+            }
+            done += 1;
+            continue;
+        }
+
+        let result = match (&mut outbox, &mut target) {
+            (DbConn::Sqlite(sqlite), DbConn::Pg(pg)) => to_pg(entry, sqlite, pg).await,
+            (DbConn::Pg(pg), DbConn::Sqlite(sqlite)) => to_sqlite(entry, pg, sqlite).await,
+            _ => unreachable!("replication source and target are always different backends"),
+        };
+
+        if let Err(e) = result {
+            if requeue(&mut outbox, entry, &e).await {
+                continue;
+            }
+            return Err(record_failure(&mut outbox, entry, &e.to_string()).await);
+        }
+
+        if entry.attempts > 0 {
+            info!(
+                table = entry.table_name,
+                row_id = entry.row_id,
+                attempts = entry.attempts,
+                "Replication from {} recovered.",
+                source
+            );
+        }
+
+        let _ = with_conn!(outbox, |c| {
+            diesel::delete(replication_outbox.find(entry.id))
+                .execute(c)
+                .await
+        });
+        done += 1;
+    }
+
+    Ok(done)
+}
+
+/// Move a failed upsert to the back of the queue if it broke a foreign key.
+/// An upsert copies the row's current state, which may refer to rows created
+/// after the entry was queued (a session switched to a new organization,
+/// say); their entries are still behind it. Replaying an upsert later is
+/// always safe, so it waits for them, up to [`MAX_REQUEUES`] times before it
+/// is retried in place like any other failure. Returns whether it was moved.
+async fn requeue(outbox: &mut DbConn, entry: &OutboxEntry, error: &diesel::result::Error) -> bool {
+    use crate::schema::replication_outbox::dsl::*;
+    use diesel::result::{DatabaseErrorKind, Error};
+
+    if entry.op != "upsert"
+        || entry.requeues >= MAX_REQUEUES
+        || !matches!(error, Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _))
+    {
+        return false;
+    }
+    let moved: QueryResult<()> = transaction!(*outbox, |c| {
+        diesel::insert_into(replication_outbox)
+            .values((
+                table_name.eq(&entry.table_name),
+                row_id.eq(entry.row_id),
+                op.eq(&entry.op),
+                attempts.eq(entry.attempts),
+                requeues.eq(entry.requeues + 1),
+                last_error.eq(error.to_string()),
+            ))
+            .execute(c)
+            .await?;
+        diesel::delete(replication_outbox.find(entry.id)).execute(c).await?;
+        Ok::<_, diesel::result::Error>(())
+    });
+    match moved {
+        Ok(()) => {
+            debug!(
+                table = entry.table_name,
+                row_id = entry.row_id,
+                "Replication: row refers to rows queued behind it; moved to the back."
+            );
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Move an entry for a table the worker does not mirror out of the queue.
+/// Returns whether it was moved.
+async fn dead_letter(outbox: &mut DbConn, entry: &OutboxEntry) -> bool {
+    use crate::schema::{replication_dead_letters as dead, replication_outbox::dsl::*};
+
+    let moved: QueryResult<()> = transaction!(*outbox, |c| {
+        diesel::insert_into(dead::table)
+            .values((
+                dead::table_name.eq(&entry.table_name),
+                dead::row_id.eq(entry.row_id),
+                dead::op.eq(&entry.op),
+                dead::created_at.eq(entry.created_at),
+                dead::error.eq(format!("unknown table '{}'", entry.table_name)),
+            ))
+            .execute(c)
+            .await?;
+        diesel::delete(replication_outbox.find(entry.id)).execute(c).await?;
+        Ok::<_, diesel::result::Error>(())
+    });
+    match moved {
+        Ok(()) => {
+            warn!(
+                table = entry.table_name,
+                row_id = entry.row_id,
+                "Replication: entry for unknown table moved to replication_dead_letters."
+            );
+            true
+        }
+        Err(e) => {
+            warn!("Replication: failed to dead-letter outbox entry {}: {}", entry.id, e);
+            false
+        }
+    }
+}
+
+/// Bump the entry's attempt counter and schedule its next retry.
+async fn record_failure(outbox: &mut DbConn, entry: &OutboxEntry, error: &str) -> Duration {
+    use crate::schema::replication_outbox::dsl::*;
+
+    let backoff = 2_i64.saturating_pow(entry.attempts as u32).min(MAX_BACKOFF_SECS);
+    warn!(
+        table = entry.table_name,
+        row_id = entry.row_id,
+        attempts = entry.attempts + 1,
+        retry_in_secs = backoff,
+        "Replication failed: {}",
+        error
+    );
+
+    let _ = with_conn!(*outbox, |c| {
+        diesel::update(replication_outbox.find(entry.id))
+            .set((
+                attempts.eq(attempts + 1),
+                next_attempt_at.eq(chrono::Utc::now().timestamp() + backoff),
+                last_error.eq(error),
+            ))
+            .execute(c)
+            .await
+    });
+
+    Duration::from_secs(backoff as u64)
+}
+
+/// Copy row `$entry.row_id` of `$table` from `$from` to `$to`, or delete it
+/// there.
+macro_rules! mirror {
+    ($table:ident, $model:ty, $entry:expr, $from:expr, $to:expr) => {{
+        use crate::schema::$table::dsl;
+
+        if $entry.op == "delete" {
+            diesel::delete(dsl::$table.find($entry.row_id))
+                .execute($to)
+                .await
+                .map(|_| ())
+        } else {
+            let row: Option<$model> = dsl::$table
+                .find($entry.row_id)
+                .select(<$model>::as_select())
+                .first($from)
+                .await
+                .optional()?;
+
+            match row {
+                Some(row) => diesel::insert_into(dsl::$table)
+                    .values(&row)
+                    .on_conflict(dsl::id)
+                    .do_update()
+                    .set(&row)
+                    .execute($to)
+                    .await
+                    .map(|_| ()),
+                // Deleted since; the queued delete follows.
+                None => Ok(()),
+            }
+        }
+    }};
+}
+
+/// Dispatch `mirror!` on the entry's table name.
+macro_rules! mirror_table {
+    ($entry:expr, $from:expr, $to:expr) => {
+        match $entry.table_name.as_str() {
+            "users" => mirror!(users, User, $entry, $from, $to),
+            "organizations" => mirror!(organizations, Organization, $entry, $from, $to),
+            "org_members" => mirror!(org_members, OrgMember, $entry, $from, $to),
+            "org_invitations" => mirror!(org_invitations, OrgInvitation, $entry, $from, $to),
+            "user_property" => mirror!(user_property, UserProperty, $entry, $from, $to),
+            "instances" => mirror!(instances, Instance, $entry, $from, $to),
+            "nodes" => mirror!(nodes, Node, $entry, $from, $to),
+            "wa_instances" => mirror!(wa_instances, WaInstance, $entry, $from, $to),
+            "wa_instance_events" => mirror!(wa_instance_events, WaInstanceEvent, $entry, $from, $to),
+            "billing" => mirror!(billing, Billing, $entry, $from, $to),
+            "sessions" => mirror!(sessions, Session, $entry, $from, $to),
+            "refresh_tokens" => mirror!(refresh_tokens, RefreshToken, $entry, $from, $to),
+            "passkeys" => mirror!(passkeys, Passkey, $entry, $from, $to),
+            "totp_credentials" => mirror!(totp_credentials, TotpCredential, $entry, $from, $to),
+            "recovery_codes" => mirror!(recovery_codes, RecoveryCode, $entry, $from, $to),
+            "audit_log" => mirror!(audit_log, AuditEntry, $entry, $from, $to),
+            "api_keys" => mirror!(api_keys, ApiKey, $entry, $from, $to),
+            other => Err(diesel::result::Error::QueryBuilderError(
+                format!("table '{}' is not replicated", other).into(),
+            )),
+        }
+    };
+}
+
+pub(crate) async fn to_pg(
+    entry: &OutboxEntry,
+    sqlite: &mut SqliteConn,
+    pg: &mut AsyncPgConnection,
+) -> QueryResult<()> {
+    pg.transaction(|pg| {
+        async move {
+            // Keep the Postgres triggers from echoing this write back.
+            diesel::sql_query("SET LOCAL orsta.replicating = 'on'")
+                .execute(pg)
+                .await?;
+
+            mirror_table!(entry, sqlite, pg)?;
+
+            // Rows arrive with explicit ids; keep the serial sequence ahead of
+            // those in its own range so inserts made while Postgres is primary
+            // don't collide, without following SQLite's into theirs.
+            // The name comes from the fixed list, never from the entry.
+            let table = REPLICATED_TABLES.iter().find(|t| **t == entry.table_name);
+            if let (Some(table), "upsert") = (table, entry.op.as_str()) {
+                diesel::sql_query(format!(
+                    "SELECT setval(pg_get_serial_sequence('{0}', 'id'), \
+                     (SELECT COALESCE(MAX(id), 1) FROM {0} WHERE id < {1}))",
+                    table, SQLITE_ID_BASE
+                ))
+                .execute(pg)
+                .await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Move every replicated table's SQLite id sequence up to
+/// [`SQLITE_ID_BASE`], out of the range Postgres allocates from. Rows that
+/// already exist keep their ids.
+pub(crate) async fn reserve_sqlite_ids(sqlite: &mut SqliteConn) -> QueryResult<()> {
+    for table in REPLICATED_TABLES {
+        sqlite
+            .batch_execute(&format!(
+                "INSERT INTO sqlite_sequence (name, seq) SELECT '{0}', 0 \
+                 WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = '{0}'); \
+                 UPDATE sqlite_sequence SET seq = {1} WHERE name = '{0}' AND seq < {1};",
+                table,
+                SQLITE_ID_BASE - 1
+            ))
+            .await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn to_sqlite(
+    entry: &OutboxEntry,
+    pg: &mut AsyncPgConnection,
+    sqlite: &mut SqliteConn,
+) -> QueryResult<()> {
+    use crate::schema::replication_outbox::dsl::*;
+
+    // IMMEDIATE takes the write lock up front, so every outbox entry created
+    // after `before` inside this transaction is our own echo.
+    sqlite.batch_execute("BEGIN IMMEDIATE").await?;
+
+    let result = async {
+        let before: Option<i32> = replication_outbox.select(max(id)).first(sqlite).await?;
+        mirror_table!(entry, pg, sqlite)?;
+        diesel::delete(replication_outbox.filter(id.gt(before.unwrap_or(0))))
+            .execute(sqlite)
+            .await
+            .map(|_| ())
+    }
+    .await;
+
+    match result {
+        Ok(()) => sqlite.batch_execute("COMMIT").await,
+        Err(e) => {
+            let _ = sqlite.batch_execute("ROLLBACK").await;
+            Err(e)
+        }
+    }
+}
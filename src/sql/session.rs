@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::sessions)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+    pub revoked_at: Option<i64>,
+    pub revoked_reason: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Staff member acting as the user, for impersonation sessions.
+    pub impersonator_id: Option<i32>,
+    /// Organization the session acts in; the user's personal one if `None`.
+    pub active_org_id: Option<i32>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::sessions)]
+pub struct NewSession {
+    pub user_id: i32,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub impersonator_id: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RefreshToken {
+    pub id: i32,
+    pub session_id: i32,
+    pub token_hash: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+pub struct NewRefreshToken {
+    pub session_id: i32,
+    pub token_hash: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
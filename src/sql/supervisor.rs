@@ -0,0 +1,90 @@
+//! Postgres health supervisor.
+//!
+//! Periodically checks whether Postgres is reachable, records the result in
+//! [`Orchestrator::pg_status`] and, when Postgres is the configured primary,
+//! decides which backend serves traffic: Postgres while it is healthy and the
+//! SQLite outbox has been replayed into it, SQLite once it has failed several
+//! checks in a row.
+
+use crate::sql::{DbBackend, Orchestrator, orchestrator::PgState, replication};
+use diesel_async::RunQueryDsl;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Start the supervisor. Does nothing in SQLite-only mode.
+///
+/// The check interval is `POSTGRES_HEALTHCHECK_SECS` (default 5); traffic
+/// moves to SQLite after `POSTGRES_FAILOVER_CHECKS` (default 3) consecutive
+/// failed checks.
+pub fn spawn(orch: Arc<Orchestrator>) {
+    if orch.pg.is_none() {
+        return;
+    }
+
+    let interval = std::env::var("POSTGRES_HEALTHCHECK_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(5));
+    let failover_after: u32 = std::env::var("POSTGRES_FAILOVER_CHECKS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(3);
+
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            sleep(interval).await;
+            check(&orch, &mut failures, failover_after).await;
+        }
+    });
+}
+
+/// `failures` counts the checks that have failed since the last success.
+async fn check(orch: &Orchestrator, failures: &mut u32, failover_after: u32) {
+    let online = match orch.pg().await {
+        Ok(mut conn) => diesel::sql_query("SELECT 1")
+            .execute(&mut conn)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match online {
+        Ok(_) => {
+            *failures = 0;
+            orch.set_pg_status(PgState::Online, None);
+        }
+        Err(e) => {
+            *failures = failures.saturating_add(1);
+            orch.set_pg_status(PgState::Offline, Some(e));
+        }
+    }
+
+    if orch.primary != DbBackend::Postgres {
+        return;
+    }
+
+    // A single failed check may be a blip; failing over on it would have
+    // SQLite take writes while Postgres still holds ones it hasn't copied.
+    if *failures > 0 {
+        if *failures >= failover_after {
+            orch.set_active(DbBackend::Sqlite);
+        }
+        return;
+    }
+
+    // Only hand traffic back to Postgres once it has every write SQLite took
+    // while it was away.
+    if orch.active() == DbBackend::Sqlite {
+        let caught_up = match orch.sqlite().await {
+            Ok(mut conn) => replication::pending(&mut conn).await.is_ok_and(|n| n == 0),
+            Err(_) => false,
+        };
+        if caught_up {
+            orch.set_active(DbBackend::Postgres);
+        }
+    }
+}
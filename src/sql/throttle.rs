@@ -0,0 +1,28 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::auth_throttles)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuthThrottle {
+    pub id: i32,
+    /// `account` or `ip`.
+    pub scope: String,
+    /// The email address or IP the failures are counted against.
+    pub subject: String,
+    pub failures: i32,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::auth_throttles)]
+pub struct NewAuthThrottle {
+    pub scope: String,
+    pub subject: String,
+    pub failures: i32,
+    pub last_failure_at: i64,
+}
@@ -0,0 +1,49 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::totp_credentials)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TotpCredential {
+    pub id: i32,
+    pub user_id: i32,
+    /// Shared secret, base32 without padding.
+    pub secret: String,
+    pub created_at: i64,
+    /// `None` until enrollment has been confirmed with a valid code.
+    pub enabled_at: Option<i64>,
+    /// Latest time step a code was accepted for.
+    pub last_used_step: i64,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::totp_credentials)]
+pub struct NewTotpCredential {
+    pub user_id: i32,
+    pub secret: String,
+    pub created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::recovery_codes)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub created_at: i64,
+    pub used_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+    pub created_at: i64,
+}
@@ -1,8 +1,9 @@
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::schema::users)]
+#[diesel(treat_none_as_null = true)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct User {
@@ -10,8 +11,18 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password_hash: String,
-    pub passkey: Option<String>,
     pub eakey: String,
+    /// Password logins must be completed with a passkey assertion.
+    pub require_passkey: bool,
+    /// When the user proved they own `email`; `None` until then.
+    pub email_verified_at: Option<i64>,
+    /// `user`, `support` or `admin`; see [`crate::auth::Role`].
+    pub role: String,
+    /// When an operator suspended the account; `None` while it is usable.
+    pub suspended_at: Option<i64>,
+    pub suspended_reason: Option<String>,
+    /// When the account is deleted, if the user asked for that.
+    pub deletion_due_at: Option<i64>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -20,6 +31,5 @@ pub struct NewUser {
     pub username: String,
     pub email: String,
     pub password_hash: String,
-    pub passkey: Option<String>,
     pub eakey: String,
 }
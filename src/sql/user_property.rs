@@ -2,14 +2,13 @@
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(table_name = crate::schema::user_property)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct UserProperty {
     pub id: i32,
     pub user_id: i32,
-    pub instance_status: String,
     pub instance_usage: f64,
     pub api_key_active: bool,
 }
@@ -18,7 +17,6 @@ pub struct UserProperty {
 #[diesel(table_name = crate::schema::user_property)]
 pub struct NewUserProperty {
     pub user_id: i32,
-    pub instance_status: String,
     pub instance_usage: f64,
     pub api_key_active: bool,
 }
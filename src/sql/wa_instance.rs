@@ -0,0 +1,77 @@
+#![allow(dead_code)]
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::wa_instances)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WaInstance {
+    pub id: i32,
+    pub org_id: i32,
+    /// The member who created it; `None` once their account is gone.
+    pub created_by: Option<i32>,
+    pub name: String,
+    /// E.164, e.g. `+15551234567`; unique across all organizations.
+    pub phone_number: Option<String>,
+    pub status: String,
+    /// JSON object.
+    pub config: String,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub stopped_at: Option<i64>,
+    /// Running time of earlier runs (seconds), not counting the current one.
+    pub run_secs: i64,
+    /// The node it runs on, or last ran on; `None` until first placed.
+    pub node_id: Option<i32>,
+    /// Region its node must be in, if any.
+    pub region: Option<String>,
+    /// JSON object of labels its node must carry, e.g. `{"tier": "dedicated"}`.
+    pub affinity: String,
+    /// Why it was placed where it is, or why it waits for a node.
+    pub placement: Option<String>,
+    pub placed_at: Option<i64>,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::wa_instances)]
+pub struct NewWaInstance {
+    pub org_id: i32,
+    pub created_by: Option<i32>,
+    pub name: String,
+    pub phone_number: Option<String>,
+    pub status: String,
+    pub config: String,
+    pub created_at: i64,
+    pub region: Option<String>,
+    pub affinity: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::wa_instance_events)]
+#[diesel(treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WaInstanceEvent {
+    pub id: i32,
+    pub instance_id: i32,
+    /// `None` for the first entry of an instance.
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub reason: String,
+    /// Who asked for the change; `None` for changes the system made.
+    pub actor_id: Option<i32>,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::wa_instance_events)]
+pub struct NewWaInstanceEvent {
+    pub instance_id: i32,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub reason: String,
+    pub actor_id: Option<i32>,
+    pub created_at: i64,
+}
@@ -0,0 +1,204 @@
+//! Brute-force protection for credential checks.
+//!
+//! Failed password logins are counted against the email address tried and
+//! against the client IP; unknown API keys and password reset requests
+//! against the IP. The first few failures are free. Each one after that
+//! locks the subject out for twice as long as the one before, from
+//! [`BASE_LOCKOUT_SECS`] up to [`MAX_LOCKOUT_SECS`]. A counter is forgotten
+//! [`RESET_AFTER_SECS`] after its last failure, and an account's counter is
+//! cleared by a successful login. Counters live in the database, so a restart does not reset them.
+
+use crate::{
+    audit,
+    sql::{
+        DbConn,
+        throttle::{AuthThrottle, NewAuthThrottle},
+        transaction, with_conn,
+    },
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+
+/// Lockout after the first failure beyond the free allowance (seconds).
+pub const BASE_LOCKOUT_SECS: i64 = 30;
+
+/// Longest single lockout (seconds).
+pub const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+
+/// Quiet period after which failures are forgotten (seconds).
+pub const RESET_AFTER_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// An email address used to log in, whether or not an account has it.
+    Account,
+    /// A client IP.
+    Ip,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Account => "account",
+            Scope::Ip => "ip",
+        }
+    }
+
+    /// Failures allowed before the first lockout. An IP gets more, as
+    /// several users may share one.
+    fn free_attempts(self) -> i32 {
+        match self {
+            Scope::Account => 5,
+            Scope::Ip => 20,
+        }
+    }
+}
+
+/// The subject an email address is counted under.
+pub fn account_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Seconds until every one of `subjects` may try again, or `None` if none
+/// is locked out.
+pub async fn retry_after(db: &mut DbConn, subjects: &[(Scope, &str)]) -> QueryResult<Option<i64>> {
+    use crate::schema::auth_throttles::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let mut longest: Option<i64> = None;
+    for &(s, key) in subjects {
+        let until: Option<Option<i64>> = with_conn!(*db, |c| {
+            auth_throttles
+                .filter(scope.eq(s.as_str()))
+                .filter(subject.eq(key))
+                .select(locked_until)
+                .first(c)
+                .await
+                .optional()
+        })?;
+        if let Some(Some(until)) = until
+            && until > now
+        {
+            longest = Some(longest.unwrap_or(0).max(until - now));
+        }
+    }
+    Ok(longest)
+}
+
+/// Count a failed attempt against `key`. Returns the lockout it started, in
+/// seconds, if any; lockouts are also written to the audit log.
+pub async fn record_failure(
+    db: &mut DbConn,
+    s: Scope,
+    key: &str,
+    uid: Option<i32>,
+    ip: Option<&str>,
+) -> QueryResult<Option<i64>> {
+    use crate::schema::auth_throttles::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    let mine = auth_throttles.filter(scope.eq(s.as_str())).filter(subject.eq(key));
+    let row: AuthThrottle = transaction!(*db, |c| {
+        diesel::insert_into(auth_throttles)
+            .values(&NewAuthThrottle {
+                scope: s.as_str().to_string(),
+                subject: key.to_string(),
+                failures: 0,
+                last_failure_at: now,
+            })
+            .on_conflict((scope, subject))
+            .do_nothing()
+            .execute(c)
+            .await?;
+        diesel::update(mine.filter(last_failure_at.le(now - RESET_AFTER_SECS)))
+            .set((failures.eq(0), locked_until.eq(None::<i64>)))
+            .execute(c)
+            .await?;
+        let row: AuthThrottle = diesel::update(mine)
+            .set((failures.eq(failures + 1), last_failure_at.eq(now)))
+            .returning(AuthThrottle::as_returning())
+            .get_result(c)
+            .await?;
+
+        let Some(secs) = lockout_secs(s, row.failures) else {
+            return Ok(row);
+        };
+        diesel::update(auth_throttles.find(row.id))
+            .set(locked_until.eq(now + secs))
+            .returning(AuthThrottle::as_returning())
+            .get_result(c)
+            .await
+    })?;
+
+    let Some(until) = row.locked_until.filter(|&u| u > now) else {
+        return Ok(None);
+    };
+    let secs = until - now;
+    audit::record(
+        db,
+        audit::LOCKOUT_STARTED,
+        uid,
+        None,
+        ip,
+        serde_json::json!({
+            "scope": row.scope,
+            "subject": row.subject,
+            "failures": row.failures,
+            "locked_until": until,
+        }),
+    )
+    .await;
+    Ok(Some(secs))
+}
+
+/// Forget the failures counted against `key`.
+pub async fn clear(db: &mut DbConn, s: Scope, key: &str) -> QueryResult<()> {
+    use crate::schema::auth_throttles::dsl::*;
+
+    with_conn!(*db, |c| {
+        diesel::delete(auth_throttles.filter(scope.eq(s.as_str())).filter(subject.eq(key)))
+            .execute(c)
+            .await
+    })?;
+    Ok(())
+}
+
+/// Subjects currently locked out, the latest lockout first.
+pub async fn locked(db: &mut DbConn) -> QueryResult<Vec<AuthThrottle>> {
+    use crate::schema::auth_throttles::dsl::*;
+
+    let now = chrono::Utc::now().timestamp();
+    with_conn!(*db, |c| {
+        auth_throttles
+            .filter(locked_until.gt(now))
+            .order(last_failure_at.desc())
+            .select(AuthThrottle::as_select())
+            .load(c)
+            .await
+    })
+}
+
+/// Lift the lockout with id `throttle_id` and reset its counter. Returns
+/// the removed row, or `None` if there was none.
+pub async fn unlock(db: &mut DbConn, throttle_id: i32) -> QueryResult<Option<AuthThrottle>> {
+    use crate::schema::auth_throttles::dsl::*;
+
+    with_conn!(*db, |c| {
+        diesel::delete(auth_throttles.find(throttle_id))
+            .returning(AuthThrottle::as_returning())
+            .get_result(c)
+            .await
+            .optional()
+    })
+}
+
+/// How long the `failures`th failure locks a subject out, if at all.
+fn lockout_secs(s: Scope, failures: i32) -> Option<i64> {
+    let beyond = failures - s.free_attempts();
+    if beyond < 1 {
+        return None;
+    }
+    // 30s, 60s, 120s, ... capped well before the shift could overflow.
+    let doublings = (beyond - 1).min(20) as u32;
+    Some((BASE_LOCKOUT_SECS << doublings).min(MAX_LOCKOUT_SECS))
+}
@@ -0,0 +1,336 @@
+//! Request validation.
+//!
+//! Request types derive [`validator::Validate`] and handlers take them as
+//! [`Valid<T>`] instead of `Json<T>`. A body that fails its rules is refused
+//! with `400 {"error": "Invalid input", "fields": {field: message}}`, one
+//! message per field, before the handler runs; a body that is not JSON of
+//! the right shape gets the same shape with no fields.
+//!
+//! Email addresses are case-folded on the way in (see [`normalize_email`]),
+//! so `Alice@x.com` and `alice@x.com` name the same account. Passwords are
+//! checked against a policy read from the environment:
+//!
+//! - `PASSWORD_MIN_LENGTH` (default 8) and `PASSWORD_MAX_LENGTH` (default 128)
+//! - `PASSWORD_MIN_CLASSES` (default 1): how many of lowercase letters,
+//!   uppercase letters, digits and other characters a password must mix.
+
+use crate::sql::{Orchestrator, with_conn};
+use axum::{
+    Json,
+    extract::{FromRequest, OptionalFromRequest, Request, rejection::JsonRejection},
+    http::StatusCode,
+};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use tracing::warn;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+/// Largest single charge accepted from a client.
+pub const MAX_CHARGE: f64 = 10_000.0;
+
+/// Largest provider metadata object accepted with a payment (bytes of JSON).
+pub const MAX_METADATA_BYTES: usize = 4096;
+
+type Rejection = (StatusCode, Json<Value>);
+
+/// Axum extractor like `Json<T>` that also runs `T`'s validation rules.
+pub struct Valid<T>(pub T);
+
+impl<T, S> FromRequest<S> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = <Json<T> as FromRequest<S>>::from_request(req, state)
+            .await
+            .map_err(malformed)?;
+        check(value).map(Valid)
+    }
+}
+
+/// An absent body (no `Content-Type`) is `None`; a present one must be valid.
+impl<T, S> OptionalFromRequest<S> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        match <Json<T> as OptionalFromRequest<S>>::from_request(req, state).await {
+            Ok(Some(Json(value))) => check(value).map(|v| Some(Valid(v))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(malformed(e)),
+        }
+    }
+}
+
+fn check<T: Validate>(value: T) -> Result<T, Rejection> {
+    match value.validate() {
+        Ok(()) => Ok(value),
+        Err(errors) => Err(invalid(&errors)),
+    }
+}
+
+fn malformed(e: JsonRejection) -> Rejection {
+    (e.status(), Json(serde_json::json!({"error": e.body_text(), "fields": {}})))
+}
+
+/// The `400` response for `errors`: the first message for each field.
+pub fn invalid(errors: &ValidationErrors) -> Rejection {
+    let fields: BTreeMap<Cow<str>, String> = errors
+        .field_errors()
+        .into_iter()
+        .filter_map(|(field, errs)| {
+            let e = errs.as_slice().first()?;
+            let message = e.message.as_deref().map(str::to_string).unwrap_or_else(|| describe(e));
+            Some((field, message))
+        })
+        .collect();
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({"error": "Invalid input", "fields": fields})),
+    )
+}
+
+/// A message for the built-in rules that were not given one.
+fn describe(e: &ValidationError) -> String {
+    let param = |name: &str| e.params.get(name).filter(|v| !v.is_null()).map(Value::to_string);
+    match e.code.as_ref() {
+        "length" => match (param("min"), param("max")) {
+            (Some(min), Some(max)) => format!("Must be between {} and {} characters", min, max),
+            (Some(min), None) => format!("Must be at least {} characters", min),
+            (None, Some(max)) => format!("Must be at most {} characters", max),
+            _ => "Invalid length".to_string(),
+        },
+        "range" => match (param("min"), param("max")) {
+            (Some(min), Some(max)) => format!("Must be between {} and {}", min, max),
+            (Some(min), None) => format!("Must be at least {}", min),
+            (None, Some(max)) => format!("Must be at most {}", max),
+            _ => "Out of range".to_string(),
+        },
+        "email" => "Invalid email address".to_string(),
+        code => format!("Invalid value ({})", code),
+    }
+}
+
+fn error(code: &'static str, message: impl Into<Cow<'static, str>>) -> ValidationError {
+    ValidationError::new(code).with_message(message.into())
+}
+
+// ---------------------------------------------------------------------------
+// Emails
+// ---------------------------------------------------------------------------
+
+/// The form an email address is stored and compared in.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// `deserialize_with` for email fields.
+pub fn email<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    String::deserialize(d).map(|e| normalize_email(&e))
+}
+
+/// `deserialize_with` for optional email fields; use with `#[serde(default)]`.
+pub fn optional_email<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(d).map(|e| e.map(|e| normalize_email(&e)))
+}
+
+// ---------------------------------------------------------------------------
+// Field rules
+// ---------------------------------------------------------------------------
+
+const USERNAME_MIN: usize = 3;
+const USERNAME_MAX: usize = 32;
+
+/// 3 to 32 ASCII letters, digits, `_`, `-` or `.`, starting with a letter
+/// or digit.
+pub fn username(name: &str) -> Result<(), ValidationError> {
+    let len = name.chars().count();
+    if !(USERNAME_MIN..=USERNAME_MAX).contains(&len) {
+        return Err(error(
+            "username_length",
+            format!("Must be between {} and {} characters", USERNAME_MIN, USERNAME_MAX),
+        ));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err(error("username_chars", "May only contain letters, digits, '_', '-' and '.'"));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(error("username_start", "Must start with a letter or digit"));
+    }
+    Ok(())
+}
+
+struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    min_classes: usize,
+}
+
+static PASSWORD_POLICY: LazyLock<PasswordPolicy> = LazyLock::new(|| {
+    let var = |name: &str, default: usize| {
+        std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    };
+    let min_length = var("PASSWORD_MIN_LENGTH", 8).max(1);
+    PasswordPolicy {
+        min_length,
+        max_length: var("PASSWORD_MAX_LENGTH", 128).max(min_length),
+        min_classes: var("PASSWORD_MIN_CLASSES", 1).clamp(1, 4),
+    }
+});
+
+/// A new password that meets the configured policy.
+pub fn password(pw: &str) -> Result<(), ValidationError> {
+    let policy = &*PASSWORD_POLICY;
+    let len = pw.chars().count();
+    if len < policy.min_length {
+        return Err(error("password_short", format!("Must be at least {} characters", policy.min_length)));
+    }
+    if len > policy.max_length {
+        return Err(error("password_long", format!("Must be at most {} characters", policy.max_length)));
+    }
+    let classes = [
+        pw.chars().any(|c| c.is_lowercase()),
+        pw.chars().any(|c| c.is_uppercase()),
+        pw.chars().any(|c| c.is_numeric()),
+        pw.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|&&x| x).count() < policy.min_classes {
+        return Err(error(
+            "password_weak",
+            format!(
+                "Must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                policy.min_classes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Anything but empty or whitespace.
+pub fn not_blank(s: &str) -> Result<(), ValidationError> {
+    if s.trim().is_empty() {
+        return Err(error("required", "Must not be empty"));
+    }
+    Ok(())
+}
+
+/// A charge: positive, whole cents, at most [`MAX_CHARGE`].
+pub fn charge(amount: f64) -> Result<(), ValidationError> {
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(error("amount_positive", "Must be a positive number"));
+    }
+    if amount > MAX_CHARGE {
+        return Err(error("amount_max", format!("Must be at most {}", MAX_CHARGE)));
+    }
+    if ((amount * 100.0).round() - amount * 100.0).abs() > 1e-6 {
+        return Err(error("amount_cents", "Must have at most two decimal places"));
+    }
+    Ok(())
+}
+
+/// A wallet adjustment: non-zero, whole cents, at most [`MAX_CHARGE`] either way.
+pub fn adjustment(amount: f64) -> Result<(), ValidationError> {
+    if !amount.is_finite() || amount == 0.0 {
+        return Err(error("amount_nonzero", "Must be a non-zero number"));
+    }
+    charge(amount.abs())
+}
+
+/// Provider metadata: a JSON object of at most [`MAX_METADATA_BYTES`].
+pub fn metadata(value: &Value) -> Result<(), ValidationError> {
+    if !value.is_object() {
+        return Err(error("metadata_object", "Must be an object"));
+    }
+    if value.to_string().len() > MAX_METADATA_BYTES {
+        return Err(error("metadata_size", format!("Must be at most {} bytes", MAX_METADATA_BYTES)));
+    }
+    Ok(())
+}
+
+/// One of the user roles.
+pub fn role(value: &str) -> Result<(), ValidationError> {
+    match crate::auth::Role::parse(value) {
+        Some(_) => Ok(()),
+        None => Err(error("role", format!("Unknown role '{}'", value))),
+    }
+}
+
+/// One of the organization roles.
+pub fn org_role(value: &str) -> Result<(), ValidationError> {
+    match crate::org::OrgRole::parse(value) {
+        Some(_) => Ok(()),
+        None => Err(error("org_role", format!("Unknown role '{}'", value))),
+    }
+}
+
+/// Fold stored email addresses to [`normalize_email`] form, along with
+/// the pending links bound to them. An address whose folded form another
+/// account already has, or would get, is left alone and logged; such
+/// accounts cannot log in by password until an operator resolves the
+/// clash. Returns how many there are.
+pub async fn fold_emails(orch: &Orchestrator) -> Result<usize, String> {
+    use crate::schema::{email_tokens::dsl as t, users::dsl as u};
+    use crate::sql::transaction;
+    use std::collections::HashMap;
+
+    let mut db = orch.conn().await.map_err(|e| e.to_string())?;
+    let rows: QueryResult<Vec<(i32, String)>> = with_conn!(db, |c| u::users.select((u::id, u::email)).load(c).await);
+    let rows = rows.map_err(|e| e.to_string())?;
+
+    let mut owners: HashMap<String, usize> = HashMap::new();
+    for (_, address) in &rows {
+        *owners.entry(normalize_email(address)).or_default() += 1;
+    }
+
+    let mut clashes = 0;
+    for (uid, address) in rows {
+        let folded = normalize_email(&address);
+        if address == folded {
+            continue;
+        }
+        if owners[&folded] > 1 {
+            warn!(user_id = uid, "Email address '{}' clashes with another account once case-folded.", address);
+            clashes += 1;
+            continue;
+        }
+        let done: QueryResult<()> = transaction!(db, |c| {
+            diesel::update(u::users.find(uid).filter(u::email.eq(&address)))
+                .set(u::email.eq(&folded))
+                .execute(c)
+                .await?;
+            // Pending links stay bound to the account's (now folded) address.
+            diesel::update(t::email_tokens.filter(t::user_id.eq(uid)).filter(t::email.eq(&address)))
+                .set(t::email.eq(&folded))
+                .execute(c)
+                .await?;
+            Ok::<_, diesel::result::Error>(())
+        });
+        done.map_err(|e| e.to_string())?;
+    }
+
+    // Addresses an email change is waiting to confirm.
+    let pending: QueryResult<Vec<(i32, String)>> = with_conn!(db, |c| {
+        t::email_tokens.filter(t::purpose.eq("change_email")).select((t::id, t::email)).load(c).await
+    });
+    for (token_id, address) in pending.map_err(|e| e.to_string())? {
+        let folded = normalize_email(&address);
+        if address != folded {
+            let done: QueryResult<usize> = with_conn!(db, |c| {
+                diesel::update(t::email_tokens.find(token_id)).set(t::email.eq(&folded)).execute(c).await
+            });
+            done.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(clashes)
+}
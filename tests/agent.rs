@@ -0,0 +1,180 @@
+//! The reference agent against a real server: token checks, placement and
+//! status reports, and a node going offline when its heartbeats stop and
+//! its instances waiting for another.
+
+mod common;
+
+use common::{Server, eventually};
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+
+/// A running `orsta-agent`, killed when dropped.
+struct Agent(Child);
+
+impl Agent {
+    fn start(server: &Server, token: &str) -> Agent {
+        let log = std::fs::File::create(server.dir.join("agent.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_orsta-agent"))
+            .args(["--url", &format!("ws://127.0.0.1:{}/agent", server.port)])
+            .args(["--token", token, "--capacity", "2", "--step-ms", "200"])
+            .env_remove("ORSTA_AGENT_URL")
+            .env_remove("ORSTA_NODE_TOKEN")
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .expect("agent binary");
+        Agent(child)
+    }
+
+    /// Send `signal` to the agent, e.g. `STOP` to freeze it with its
+    /// connection still open.
+    fn signal(&self, signal: &str) {
+        let status = Command::new("kill").arg(format!("-{}", signal)).arg(self.0.id().to_string()).status().unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Register a node as an admin and return its id and token.
+fn register_node(server: &Server, name: &str) -> (i64, String) {
+    server.signup("operator");
+    let admin = server.promote("operator", "admin");
+    let r = server.post("/admin/nodes", json!({"name": name}), &admin);
+    assert_eq!(r.status, 201, "{}", r.body);
+    (r.body["id"].as_i64().unwrap(), r.body["token"].as_str().unwrap().to_string())
+}
+
+fn node_status(server: &Server, id: i64) -> Option<String> {
+    server.sql_value(&format!("SELECT status FROM nodes WHERE id = {}", id))
+}
+
+/// Status of a plain WebSocket upgrade of `/agent` with `token`.
+fn upgrade_status(server: &Server, token: &str) -> u16 {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    write!(
+        stream,
+        "GET /agent HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Authorization: Bearer {}\r\n\r\n",
+        server.port, token
+    )
+    .unwrap();
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).unwrap();
+    String::from_utf8_lossy(&head[9..12]).parse().unwrap()
+}
+
+#[test]
+fn placed_instance_is_started_and_reported_online() {
+    let server = Server::start();
+    let (node_id, token) = register_node(&server, "worker-1");
+    let _agent = Agent::start(&server, &token);
+    assert!(
+        eventually(10, || node_status(&server, node_id).as_deref() == Some("online")),
+        "node did not come online:\n{}",
+        server.log()
+    );
+
+    let (member, _) = server.signup("member");
+    let r = server.post("/instances", json!({"name": "support line"}), &member);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+    let r = server.post(&format!("/instances/{}/start", id), json!({}), &member);
+    assert_eq!(r.status, 200, "{}", r.body);
+
+    let status = || server.get(&format!("/instances/{}", id), &member).body["status"].as_str().map(String::from);
+    assert!(eventually(10, || status().as_deref() == Some("online")), "instance is {:?}:\n{}", status(), server.log());
+    let r = server.get(&format!("/instances/{}", id), &member);
+    assert_eq!(r.body["node_id"].as_i64(), Some(node_id), "{}", r.body);
+
+    // The agent walked it through the session statuses, without an actor.
+    let r = server.get(&format!("/instances/{}/events", id), &member);
+    let reported: Vec<(&str, bool)> = r.body["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .skip_while(|e| e["to"] != "provisioning")
+        .skip(1)
+        .map(|e| (e["to"].as_str().unwrap(), e["actor_id"].is_null()))
+        .collect();
+    assert_eq!(reported, [("awaiting_pairing", true), ("connecting", true), ("online", true)], "{}", r.body);
+}
+
+#[test]
+fn agent_needs_its_node_token() {
+    let server = Server::start();
+    let (node_id, token) = register_node(&server, "worker-1");
+
+    assert_eq!(upgrade_status(&server, "on_not-a-real-token"), 401);
+    assert_eq!(upgrade_status(&server, &format!("{}x", token)), 401);
+
+    let _agent = Agent::start(&server, "on_not-a-real-token");
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    assert_eq!(node_status(&server, node_id).as_deref(), Some("offline"));
+    assert_eq!(server.sql_value(&format!("SELECT connected_at IS NULL FROM nodes WHERE id = {}", node_id)).as_deref(), Some("1"));
+}
+
+#[test]
+fn silent_node_goes_offline_after_missed_heartbeats() {
+    // NODE_HEARTBEAT_SECS is 1, so three missed heartbeats take 3 seconds.
+    let server = Server::start();
+    let (node_id, token) = register_node(&server, "worker-1");
+    let agent = Agent::start(&server, &token);
+    assert!(eventually(10, || node_status(&server, node_id).as_deref() == Some("online")), "{}", server.log());
+
+    // Frozen, the agent keeps its connection open but sends nothing.
+    agent.signal("STOP");
+    assert!(
+        eventually(10, || node_status(&server, node_id).as_deref() == Some("offline")),
+        "node stayed {:?}:\n{}",
+        node_status(&server, node_id),
+        server.log()
+    );
+
+    // Once it wakes up it reconnects and is back.
+    agent.signal("CONT");
+    assert!(eventually(15, || node_status(&server, node_id).as_deref() == Some("online")), "{}", server.log());
+}
+
+#[test]
+fn lost_node_puts_its_instances_back_to_waiting() {
+    let server = Server::start();
+    let (node_id, token) = register_node(&server, "worker-1");
+    let agent = Agent::start(&server, &token);
+    assert!(eventually(10, || node_status(&server, node_id).as_deref() == Some("online")), "{}", server.log());
+
+    let (member, _) = server.signup("member");
+    let r = server.post("/instances", json!({"name": "support line"}), &member);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+    let r = server.post(&format!("/instances/{}/start", id), json!({}), &member);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let placement = || server.get(&format!("/instances/{}/placement", id), &member).body;
+    assert!(eventually(10, || placement()["status"] == "online"), "{}:\n{}", placement(), server.log());
+
+    // The only node goes quiet: nowhere else to go, the instance waits.
+    agent.signal("STOP");
+    assert!(
+        eventually(15, || placement()["node"].is_null()),
+        "instance stayed placed: {}:\n{}",
+        placement(),
+        server.log()
+    );
+    let p = placement();
+    assert_eq!(p["status"], "provisioning", "{}", p);
+    assert_eq!(p["placement"], "Waiting: no node is available", "{}", p);
+    let r = server.get(&format!("/instances/{}/events", id), &member);
+    let last = r.body["events"].as_array().unwrap().last().unwrap().clone();
+    assert_eq!(last["to"], "provisioning", "{}", r.body);
+    assert_eq!(last["reason"], "Node 'worker-1' missed its heartbeats", "{}", r.body);
+    assert!(last["actor_id"].is_null());
+}
@@ -0,0 +1,40 @@
+//! Activating API access after a successful charge is all or nothing.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+#[test]
+fn failed_billing_record_leaves_the_key_inactive_and_says_so() {
+    let server = Server::start();
+    let (token, user_id) = server.signup("payer");
+    server.sql("UPDATE users SET email_verified_at = 1 WHERE username = 'payer'");
+    server.sql("CREATE TRIGGER break_billing BEFORE UPDATE ON billing BEGIN SELECT RAISE(ABORT, 'billing is broken'); END");
+
+    let r = server.post("/billing/enable-api-key", json!({"amount": 9.99}), &token);
+    assert_eq!(r.status, 500, "{}", r.body);
+    assert!(r.body["transaction_id"].is_string(), "{}", r.body);
+    let active = server.sql_value(&format!("SELECT api_key_active FROM user_property WHERE user_id = {}", user_id));
+    assert_eq!(active.as_deref(), Some("0"));
+
+    server.sql("DROP TRIGGER break_billing");
+    let r = server.post("/billing/enable-api-key", json!({"amount": 9.99}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let active = server.sql_value(&format!("SELECT api_key_active FROM user_property WHERE user_id = {}", user_id));
+    assert_eq!(active.as_deref(), Some("1"));
+}
+
+#[test]
+fn missing_wallet_is_not_reported_as_success() {
+    let server = Server::start();
+    let (token, user_id) = server.signup("walletless");
+    server.sql("UPDATE users SET email_verified_at = 1 WHERE username = 'walletless'");
+    server.sql(&format!("DELETE FROM billing WHERE user_id = {}", user_id));
+
+    let r = server.post("/billing/enable-api-key", json!({"amount": 9.99}), &token);
+    assert_eq!(r.status, 500, "{}", r.body);
+    assert!(r.body["transaction_id"].is_string(), "{}", r.body);
+    let active = server.sql_value(&format!("SELECT api_key_active FROM user_property WHERE user_id = {}", user_id));
+    assert_eq!(active.as_deref(), Some("0"));
+}
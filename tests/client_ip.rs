@@ -0,0 +1,89 @@
+//! The client address used for lockouts comes from the connection, not
+//! from headers the client can set.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+fn failed_login(server: &Server, n: usize, forwarded_for: &str) -> u16 {
+    server
+        .request(
+            "POST",
+            "/auth/login",
+            Some(&json!({"email": format!("nobody{}@example.com", n), "password": "wrong password"})),
+            &[("X-Forwarded-For", forwarded_for), ("X-Real-IP", forwarded_for)],
+        )
+        .status
+}
+
+#[test]
+fn rotating_forwarded_for_does_not_reset_the_ip_lockout() {
+    let server = Server::start();
+
+    // Different accounts each time, so only the address counter grows.
+    let statuses: Vec<u16> = (0..22).map(|n| failed_login(&server, n, &format!("198.51.100.{}", n + 1))).collect();
+    assert!(statuses[..20].iter().all(|&s| s == 401), "{:?}", statuses);
+    assert_eq!(statuses[21], 429, "{:?}", statuses);
+}
+
+#[test]
+fn trusted_proxy_names_the_client() {
+    let server = Server::with_env(&[("TRUSTED_PROXIES", "127.0.0.1")]);
+
+    // Behind a trusted proxy each forwarded address is its own client...
+    for n in 0..25 {
+        let status = failed_login(&server, n, &format!("203.0.113.9, 198.51.100.{}", n + 1));
+        assert_eq!(status, 401, "attempt {}", n);
+    }
+    // ...and a fixed one is locked out, whatever the client puts in front.
+    let statuses: Vec<u16> =
+        (0..22).map(|n| failed_login(&server, 100 + n, &format!("10.9.9.{}, 192.0.2.77", n + 1))).collect();
+    assert_eq!(statuses[21], 429, "{:?}", statuses);
+}
+
+#[test]
+fn forwarded_for_cannot_satisfy_an_api_key_allowlist() {
+    let server = Server::start();
+    let (token, _) = server.signup("keyowner");
+    server.enable_api_keys("keyowner", &token);
+
+    let create = |allowed: &str| {
+        let r = server.post(
+            "/api-keys",
+            json!({"name": allowed, "scopes": ["instances:read"], "allowed_ips": [allowed]}),
+            &token,
+        );
+        assert_eq!(r.status, 201, "{}", r.body);
+        r.body["key"].as_str().unwrap().to_string()
+    };
+    let list = |key: &str, forwarded_for: &str| {
+        server
+            .request(
+                "GET",
+                "/instances",
+                None,
+                &[("X-Api-Key", key), ("X-Forwarded-For", forwarded_for), ("X-Real-IP", forwarded_for)],
+            )
+            .status
+    };
+
+    let elsewhere = create("198.51.100.7");
+    assert_eq!(list(&elsewhere, "198.51.100.7"), 403);
+    let local = create("127.0.0.0/8");
+    assert_eq!(list(&local, "198.51.100.7"), 200);
+}
+
+#[test]
+fn reset_requests_count_against_the_ip() {
+    let server = Server::start();
+    server.signup("target");
+
+    let statuses: Vec<u16> = (0..22)
+        .map(|_| server.post_anon("/auth/forgot-password", json!({"email": "target@example.com"})).status)
+        .collect();
+    assert!(statuses[..20].iter().all(|&s| s == 202), "{:?}", statuses);
+    assert_eq!(statuses[20..], [429, 429], "{:?}", statuses);
+    // The same counter guards logins from there.
+    assert_eq!(failed_login(&server, 0, "198.51.100.1"), 429);
+}
@@ -0,0 +1,283 @@
+//! Shared helpers for the integration tests. Each test starts the server
+//! binary on a database and port of its own and talks plain HTTP to it.
+
+#![allow(dead_code)]
+
+use diesel::sql_types::Text;
+use diesel::{Connection, QueryableByName, RunQueryDsl, SqliteConnection};
+use serde_json::{Value, json};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+pub const PASSWORD: &str = "correct horse";
+
+static NEXT: AtomicU32 = AtomicU32::new(0);
+
+/// A running server, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    pub dir: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Value,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+impl Server {
+    pub fn start() -> Server {
+        Server::with_env(&[])
+    }
+
+    /// Start the server with `vars` on top of the test defaults.
+    pub fn with_env(vars: &[(&str, &str)]) -> Server {
+        let dir = std::env::temp_dir().join(format!(
+            "orsta-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let mut env: Vec<(String, String)> = [
+            ("PORT", port.to_string()),
+            ("SQLITE_DATABASE_URL", dir.join("test.db").display().to_string()),
+            ("JWT_SECRET", "integration-test-secret".to_string()),
+            ("DUMMY_PAYMENT_MODE", "true".to_string()),
+            ("PASSWORD_MIN_LENGTH", "8".to_string()),
+            ("MAIL_DIR", dir.join("mail").display().to_string()),
+            ("NODE_HEARTBEAT_SECS", "1".to_string()),
+            ("WEBAUTHN_RP_ID", "localhost".to_string()),
+            ("WEBAUTHN_ORIGINS", "http://localhost:3000".to_string()),
+            ("RUST_LOG", "info".to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        for (k, v) in vars {
+            env.retain(|(key, _)| key != k);
+            env.push((k.to_string(), v.to_string()));
+        }
+
+        let child = spawn(&dir, &env);
+        let mut server = Server { child, port, dir, env };
+        server.wait_ready();
+        server
+    }
+
+    /// Stop the server and start it again on the same database.
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.child = spawn(&self.dir, &self.env);
+        self.wait_ready();
+    }
+
+    fn wait_ready(&mut self) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("server exited with {}:\n{}", status, self.log());
+            }
+            if TcpStream::connect(("127.0.0.1", self.port)).is_ok() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("server did not start:\n{}", self.log());
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+
+    /// A connection to the server's database that waits out its writes.
+    fn db(&self) -> SqliteConnection {
+        let mut db = SqliteConnection::establish(self.dir.join("test.db").to_str().unwrap()).unwrap();
+        diesel::sql_query("PRAGMA busy_timeout = 5000").execute(&mut db).unwrap();
+        db
+    }
+
+    /// Run `statement` on the server's database.
+    pub fn sql(&self, statement: &str) {
+        let mut db = self.db();
+        diesel::sql_query(statement).execute(&mut db).unwrap();
+    }
+
+    /// The first column of the first row `query` returns, as text.
+    pub fn sql_value(&self, query: &str) -> Option<String> {
+        #[derive(QueryableByName)]
+        struct Row {
+            #[diesel(sql_type = Text)]
+            v: String,
+        }
+        let mut db = self.db();
+        let rows: Vec<Row> = diesel::sql_query(format!("SELECT CAST(({}) AS TEXT) AS v", query)).load(&mut db).unwrap();
+        rows.into_iter().next().map(|r| r.v)
+    }
+
+    /// Mark `name`'s email address verified and pay for API access.
+    pub fn enable_api_keys(&self, name: &str, token: &str) {
+        self.sql(&format!("UPDATE users SET email_verified_at = 1 WHERE username = '{}'", name));
+        let r = self.post("/billing/enable-api-key", json!({"amount": 9.99}), token);
+        assert_eq!(r.status, 200, "enable API keys: {}", r.body);
+    }
+
+    /// Run the server binary with `args` against the same database.
+    pub fn cli(&self, args: &[&str]) -> Output {
+        command(&self.dir, &self.env).args(args).output().unwrap()
+    }
+
+    pub fn request(&self, method: &str, path: &str, body: Option<&Value>, headers: &[(&str, &str)]) -> Response {
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            self.port,
+            body.len()
+        );
+        if !body.is_empty() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        for (k, v) in headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body.as_bytes()).unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+        parse_response(&raw)
+    }
+
+    pub fn get(&self, path: &str, token: &str) -> Response {
+        self.request("GET", path, None, &[("Authorization", &format!("Bearer {}", token))])
+    }
+
+    pub fn post(&self, path: &str, body: Value, token: &str) -> Response {
+        self.request("POST", path, Some(&body), &[("Authorization", &format!("Bearer {}", token))])
+    }
+
+    pub fn post_anon(&self, path: &str, body: Value) -> Response {
+        self.request("POST", path, Some(&body), &[])
+    }
+
+    /// Sign up `name` (at `<name>@example.com`) and return its access token
+    /// and user id.
+    pub fn signup(&self, name: &str) -> (String, i64) {
+        let r = self.post_anon(
+            "/auth/signup",
+            json!({"username": name, "email": format!("{}@example.com", name), "password": PASSWORD}),
+        );
+        assert_eq!(r.status, 201, "signup {}: {}", name, r.body);
+        (r.body["token"].as_str().unwrap().to_string(), r.body["user_id"].as_i64().unwrap())
+    }
+
+    /// Log `name` in with its password.
+    pub fn login(&self, name: &str) -> Response {
+        self.post_anon("/auth/login", json!({"email": format!("{}@example.com", name), "password": PASSWORD}))
+    }
+
+    /// Give `name` a role through the command line, then log in again so
+    /// the token carries it.
+    pub fn promote(&self, name: &str, role: &str) -> String {
+        let out = self.cli(&["set-role", &format!("{}@example.com", name), role]);
+        assert!(out.status.success(), "set-role: {}", String::from_utf8_lossy(&out.stderr));
+        let r = self.login(name);
+        assert_eq!(r.status, 200, "login {}: {}", name, r.body);
+        r.body["token"].as_str().unwrap().to_string()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+fn spawn(dir: &PathBuf, env: &[(String, String)]) -> Child {
+    let log = std::fs::OpenOptions::new().create(true).append(true).open(dir.join("server.log")).unwrap();
+    command(dir, env)
+        .stdout(log.try_clone().unwrap())
+        .stderr(log)
+        .spawn()
+        .expect("server binary")
+}
+
+fn command(dir: &PathBuf, env: &[(String, String)]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_Orsta-Client"));
+    // Run in the scratch directory, away from any .env file, with nothing
+    // but the test settings.
+    cmd.current_dir(dir).env_clear().stdin(Stdio::null());
+    if let Ok(path) = std::env::var("PATH") {
+        cmd.env("PATH", path);
+    }
+    cmd.envs(env.iter().map(|(k, v)| (k, v)));
+    cmd
+}
+
+fn parse_response(raw: &[u8]) -> Response {
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("response head");
+    let head = String::from_utf8_lossy(&raw[..split]).to_string();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let mut body = raw[split + 4..].to_vec();
+    let chunked = headers
+        .iter()
+        .any(|(k, v)| k.eq_ignore_ascii_case("transfer-encoding") && v.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        let mut out = Vec::new();
+        let mut rest = &body[..];
+        loop {
+            let end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(String::from_utf8_lossy(&rest[..end]).trim(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            out.extend_from_slice(&rest[end + 2..end + 2 + size]);
+            rest = &rest[end + 2 + size + 2..];
+        }
+        body = out;
+    }
+    let body = serde_json::from_slice(&body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()));
+    Response { status, headers, body }
+}
+
+/// Poll `check` until it holds or `secs` pass.
+pub fn eventually(secs: u64, mut check: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    while Instant::now() < deadline {
+        if check() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    check()
+}
@@ -0,0 +1,27 @@
+//! Stored addresses are folded at startup with the same Unicode rules as
+//! input, and clashing ones are left for an operator.
+
+mod common;
+
+use common::Server;
+
+#[test]
+fn startup_folds_non_ascii_addresses_and_leaves_clashes_alone() {
+    let mut server = Server::start();
+    server.signup("emile");
+    server.signup("bob");
+    server.signup("robert");
+    server.sql("UPDATE users SET email = 'ÉMILE@Example.COM' WHERE username = 'emile'");
+    server.sql("UPDATE users SET email = 'BOB@example.com' WHERE username = 'robert'");
+
+    server.restart();
+
+    let email = |name: &str| server.sql_value(&format!("SELECT email FROM users WHERE username = '{}'", name));
+    assert_eq!(email("emile").as_deref(), Some("émile@example.com"));
+    assert_eq!(email("bob").as_deref(), Some("bob@example.com"));
+    assert_eq!(email("robert").as_deref(), Some("BOB@example.com"));
+    assert!(server.log().contains("clashes with another account"), "{}", server.log());
+
+    let r = server.post_anon("/auth/login", serde_json::json!({"email": "Émile@example.com", "password": common::PASSWORD}));
+    assert_eq!(r.status, 200, "{}", r.body);
+}
@@ -0,0 +1,30 @@
+//! The account export covers the WhatsApp instances and their history.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+#[test]
+fn export_includes_wa_instances_and_their_events() {
+    let server = Server::start();
+    let (token, _) = server.signup("exporter");
+    let (other, _) = server.signup("bystander");
+
+    let r = server.post("/instances", json!({"name": "support line"}), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+    let r = server.post(&format!("/instances/{}/start", id), json!({}), &token);
+    assert!(r.status < 300, "{}", r.body);
+    let r = server.post("/instances", json!({"name": "not mine"}), &other);
+    assert_eq!(r.status, 201, "{}", r.body);
+
+    let r = server.get("/account/export", &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let instances = r.body["wa_instances"].as_array().unwrap();
+    assert_eq!(instances.len(), 1, "{}", r.body);
+    assert_eq!(instances[0]["name"], "support line");
+    let events = r.body["wa_instance_events"].as_array().unwrap();
+    assert!(!events.is_empty(), "{}", r.body);
+    assert!(events.iter().all(|e| e["instance_id"].as_i64() == Some(id)), "{}", r.body);
+}
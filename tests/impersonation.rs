@@ -0,0 +1,57 @@
+//! An impersonation token sent as a bearer wins over the admin's own
+//! session cookie.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+/// `name=value` of the cookie `name` set by `response`.
+fn cookie(response: &common::Response, name: &str) -> String {
+    response
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("set-cookie"))
+        .filter_map(|(_, v)| v.split(';').next())
+        .find(|pair| pair.starts_with(&format!("{}=", name)))
+        .unwrap_or_else(|| panic!("no {} cookie", name))
+        .to_string()
+}
+
+#[test]
+fn logout_with_both_credentials_ends_only_the_impersonation() {
+    let server = Server::start();
+    let (_, user_id) = server.signup("customer");
+    server.signup("boss");
+    server.promote("boss", "admin");
+    let login = server.login("boss");
+    let admin_cookie = cookie(&login, "orsta_session");
+
+    let r = server.request(
+        "POST",
+        &format!("/admin/users/{}/impersonate", user_id),
+        Some(&json!({"reason": "Support ticket 42"})),
+        &[("Cookie", &admin_cookie)],
+    );
+    assert_eq!(r.status, 201, "{}", r.body);
+    let bearer = format!("Bearer {}", r.body["token"].as_str().unwrap());
+    let both = [("Cookie", admin_cookie.as_str()), ("Authorization", bearer.as_str())];
+
+    // Requests with both are the customer's, and are audited as impersonated.
+    let me = server.request("GET", "/me", None, &both);
+    assert_eq!(me.status, 200);
+    assert_eq!(me.body["username"], "customer", "{}", me.body);
+    assert_eq!(
+        server.sql_value("SELECT COUNT(*) FROM audit_log WHERE action = 'impersonation.request'").as_deref(),
+        Some("1")
+    );
+
+    let out = server.request("POST", "/auth/logout", Some(&json!({})), &both);
+    assert_eq!(out.status, 200, "{}", out.body);
+    assert!(out.header("set-cookie").is_none(), "the admin's cookies were cleared");
+
+    assert_eq!(server.request("GET", "/me", None, &[("Authorization", bearer.as_str())]).status, 401);
+    let admin = server.request("GET", "/me", None, &[("Cookie", admin_cookie.as_str())]);
+    assert_eq!(admin.status, 200, "the admin was signed out: {}", admin.body);
+    assert_eq!(admin.body["role"], "admin");
+}
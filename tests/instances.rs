@@ -0,0 +1,52 @@
+//! Members drive the lifecycle; only the node running an instance reports
+//! how its session is doing.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+#[test]
+fn members_cannot_report_session_statuses() {
+    let server = Server::start();
+    let (token, _) = server.signup("member");
+    let r = server.post("/instances", json!({"name": "support line"}), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+    let r = server.post(&format!("/instances/{}/start", id), json!({}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+
+    let r = server.post(&format!("/instances/{}/status", id), json!({"status": "connecting"}), &token);
+    assert_eq!(r.status, 404, "{}", r.body);
+    let status = server.sql_value(&format!("SELECT status FROM wa_instances WHERE id = {}", id));
+    assert_eq!(status.as_deref(), Some("provisioning"));
+}
+
+#[test]
+fn suspended_instance_keeps_its_number() {
+    let server = Server::start();
+    server.signup("operator");
+    let admin = server.promote("operator", "admin");
+    let (token, _) = server.signup("member");
+    let number = json!({"name": "support line", "phone_number": "+15551234567"});
+    let r = server.post("/instances", number.clone(), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+
+    let r = server.post(&format!("/admin/instances/{}/suspend", id), json!({"reason": "spam"}), &admin);
+    assert_eq!(r.status, 200, "{}", r.body);
+
+    let r = server.request("DELETE", &format!("/instances/{}", id), None, &[("Authorization", &format!("Bearer {}", token))]);
+    assert_eq!(r.status, 409, "{}", r.body);
+    assert_eq!(r.body["allowed"], json!(["stopped"]), "{}", r.body);
+    let r = server.post("/instances", json!({"name": "second line", "phone_number": "+15551234567"}), &token);
+    assert_eq!(r.status, 409, "{}", r.body);
+
+    // Once lifted, the member may delete it and reuse the number.
+    let r = server.post(&format!("/admin/instances/{}/unsuspend", id), json!({}), &admin);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let r = server.request("DELETE", &format!("/instances/{}", id), None, &[("Authorization", &format!("Bearer {}", token))]);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let r = server.post("/instances", number, &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+}
@@ -0,0 +1,72 @@
+//! Instances run as child processes of a server with `LOCAL_NODE` set:
+//! crashes are restarted after a growing delay, and a crash loop ends in
+//! `failed`.
+
+mod common;
+
+use common::{Server, eventually};
+use serde_json::{Value, json};
+
+/// A server acting as its own node, running `command` for every instance
+/// and giving up after two restarts.
+fn local_node(command: &str) -> Server {
+    Server::with_env(&[
+        ("LOCAL_NODE", "local"),
+        ("LOCAL_NODE_COMMAND", command),
+        ("LOCAL_NODE_BACKOFF_MS", "100"),
+        ("LOCAL_NODE_MAX_RESTARTS", "2"),
+    ])
+}
+
+/// Start an instance and wait for it to fail; its events since it started.
+fn run_until_failed(server: &Server) -> Vec<Value> {
+    let (member, _) = server.signup("member");
+    let r = server.post("/instances", json!({"name": "support line"}), &member);
+    assert_eq!(r.status, 201, "{}", r.body);
+    let id = r.body["id"].as_i64().unwrap();
+    let r = server.post(&format!("/instances/{}/start", id), json!({}), &member);
+    assert_eq!(r.status, 200, "{}", r.body);
+
+    let status = || server.get(&format!("/instances/{}", id), &member).body["status"].as_str().map(String::from);
+    assert!(eventually(10, || status().as_deref() == Some("failed")), "instance is {:?}:\n{}", status(), server.log());
+    let r = server.get(&format!("/instances/{}/events", id), &member);
+    r.body["events"].as_array().unwrap().iter().skip_while(|e| e["to"] != "provisioning").skip(1).cloned().collect()
+}
+
+#[test]
+fn crash_loop_backs_off_and_ends_in_failed() {
+    let server = local_node("false");
+    let events = run_until_failed(&server);
+
+    let moves: Vec<(&str, &str)> =
+        events.iter().map(|e| (e["to"].as_str().unwrap(), e["reason"].as_str().unwrap())).collect();
+    assert_eq!(
+        moves,
+        [
+            ("disconnected", "Process exited with status 1; restarting in 100 ms"),
+            ("disconnected", "Process exited with status 1; restarting in 200 ms"),
+            ("failed", "Process exited with status 1; gave up after 2 restarts"),
+        ],
+        "{:#?}",
+        events
+    );
+    assert!(events.iter().all(|e| e["actor_id"].is_null()));
+}
+
+#[test]
+fn crash_while_awaiting_pairing_is_recorded() {
+    let script = std::env::temp_dir().join(format!("orsta-pairing-crash-{}.sh", std::process::id()));
+    std::fs::write(&script, "echo '::status awaiting_pairing Scan the code'\nsleep 0.2\nexit 3\n").unwrap();
+    let server = local_node(&format!("sh {}", script.display()));
+    let events = run_until_failed(&server);
+    let _ = std::fs::remove_file(&script);
+
+    let statuses: Vec<&str> = events.iter().map(|e| e["to"].as_str().unwrap()).collect();
+    assert_eq!(
+        statuses,
+        ["awaiting_pairing", "disconnected", "awaiting_pairing", "disconnected", "awaiting_pairing", "failed"],
+        "{:#?}",
+        events
+    );
+    assert_eq!(events[1]["reason"], "Process exited with status 3; restarting in 100 ms");
+}
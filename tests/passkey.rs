@@ -0,0 +1,319 @@
+//! WebAuthn ceremonies against a software authenticator: registration,
+//! passwordless login and passkeys as a second factor.
+
+mod common;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use ciborium::Value as Cbor;
+use common::Server;
+use data_encoding::BASE32_NOPAD;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+const ORIGIN: &str = "http://localhost:3000";
+const RP_ID: &str = "localhost";
+
+/// An ES256 authenticator holding one credential.
+struct Authenticator {
+    key: EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    count: u32,
+}
+
+/// What to get wrong in a ceremony.
+#[derive(Default)]
+struct Tweak {
+    origin: Option<&'static str>,
+    rp_id: Option<&'static str>,
+    /// Sign with the current count instead of the next one.
+    keep_count: bool,
+}
+
+impl Authenticator {
+    fn new() -> Authenticator {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        Authenticator { key, credential_id: rand::random::<[u8; 16]>().to_vec(), count: 0 }
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.public_key().as_ref();
+        let key = Cbor::Map(vec![
+            (Cbor::from(1), Cbor::from(2)),
+            (Cbor::from(3), Cbor::from(-7)),
+            (Cbor::from(-1), Cbor::from(1)),
+            (Cbor::from(-2), Cbor::Bytes(point[1..33].to_vec())),
+            (Cbor::from(-3), Cbor::Bytes(point[33..65].to_vec())),
+        ]);
+        cbor(&key)
+    }
+
+    fn auth_data(&self, flags: u8, rp_id: &str, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.count.to_be_bytes());
+        if attested {
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            data.extend_from_slice(&self.cose_key());
+        }
+        data
+    }
+
+    fn client_data(kind: &str, options: &Value, origin: &str) -> Vec<u8> {
+        json!({"type": kind, "challenge": options["publicKey"]["challenge"], "origin": origin})
+            .to_string()
+            .into_bytes()
+    }
+
+    /// Answer `navigator.credentials.create()` with `options`.
+    fn create(&mut self, options: &Value, tweak: Tweak) -> Value {
+        let client_data = Self::client_data("webauthn.create", options, tweak.origin.unwrap_or(ORIGIN));
+        self.count += 1;
+        // User present, verified, attested credential data included.
+        let auth_data = self.auth_data(0x45, tweak.rp_id.unwrap_or(RP_ID), true);
+        let attestation = Cbor::Map(vec![
+            (Cbor::from("fmt"), Cbor::from("none")),
+            (Cbor::from("attStmt"), Cbor::Map(vec![])),
+            (Cbor::from("authData"), Cbor::Bytes(auth_data)),
+        ]);
+        json!({
+            "id": B64.encode(&self.credential_id),
+            "rawId": B64.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": B64.encode(client_data),
+                "attestationObject": B64.encode(cbor(&attestation)),
+            },
+        })
+    }
+
+    /// Answer `navigator.credentials.get()` with `options`.
+    fn get(&mut self, options: &Value, tweak: Tweak) -> Value {
+        let client_data = Self::client_data("webauthn.get", options, tweak.origin.unwrap_or(ORIGIN));
+        if !tweak.keep_count {
+            self.count += 1;
+        }
+        let auth_data = self.auth_data(0x05, tweak.rp_id.unwrap_or(RP_ID), false);
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+        let signature = self.key.sign(&SystemRandom::new(), &signed).unwrap();
+        json!({
+            "id": B64.encode(&self.credential_id),
+            "rawId": B64.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": B64.encode(client_data),
+                "authenticatorData": B64.encode(auth_data),
+                "signature": B64.encode(signature.as_ref()),
+            },
+        })
+    }
+}
+
+/// The TOTP code for base32 `secret` right now.
+fn totp(secret: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &BASE32_NOPAD.decode(secret.as_bytes()).unwrap());
+    let step = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() / 30;
+    let mac = hmac::sign(&key, &step.to_be_bytes());
+    let mac = mac.as_ref();
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!("{:06}", binary % 1_000_000)
+}
+
+fn cbor(value: &Cbor) -> Vec<u8> {
+    let mut out = Vec::new();
+    ciborium::into_writer(value, &mut out).unwrap();
+    out
+}
+
+/// Sign up `name` and register a passkey for it.
+fn enrolled(server: &Server, name: &str) -> (String, Authenticator) {
+    let (token, _) = server.signup(name);
+    let mut authenticator = Authenticator::new();
+    let options = server.post("/auth/passkeys/register/start", json!({}), &token).body;
+    let credential = authenticator.create(&options, Tweak::default());
+    let r = server.post("/auth/passkeys/register/finish", json!({"name": "laptop", "credential": credential}), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    (token, authenticator)
+}
+
+fn login_options(server: &Server) -> Value {
+    let r = server.post_anon("/auth/passkeys/login/start", json!({}));
+    assert_eq!(r.status, 200, "{}", r.body);
+    r.body
+}
+
+fn finish_login(server: &Server, credential: Value) -> common::Response {
+    server.post_anon("/auth/passkeys/login/finish", json!({"credential": credential}))
+}
+
+#[test]
+fn registration() {
+    let server = Server::start();
+    let (token, _) = server.signup("registrant");
+
+    let options = server.post("/auth/passkeys/register/start", json!({}), &token).body;
+    assert_eq!(options["publicKey"]["rp"]["id"], RP_ID);
+    let mut authenticator = Authenticator::new();
+    let credential = authenticator.create(&options, Tweak::default());
+    let r = server.post("/auth/passkeys/register/finish", json!({"credential": credential}), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    assert_eq!(r.body["alg"], -7);
+
+    // The challenge works once.
+    let r = server.post("/auth/passkeys/register/finish", json!({"credential": credential}), &token);
+    assert_eq!(r.status, 400, "replayed registration: {}", r.body);
+
+    let options = server.post("/auth/passkeys/register/start", json!({}), &token).body;
+    let credential = Authenticator::new().create(&options, Tweak { origin: Some("http://evil.test"), ..Tweak::default() });
+    let r = server.post("/auth/passkeys/register/finish", json!({"credential": credential}), &token);
+    assert_eq!(r.status, 400, "wrong origin: {}", r.body);
+
+    let options = server.post("/auth/passkeys/register/start", json!({}), &token).body;
+    let credential = Authenticator::new().create(&options, Tweak { rp_id: Some("evil.test"), ..Tweak::default() });
+    let r = server.post("/auth/passkeys/register/finish", json!({"credential": credential}), &token);
+    assert_eq!(r.status, 400, "wrong rpId: {}", r.body);
+
+    let listed = server.get("/auth/passkeys", &token).body;
+    assert_eq!(listed["passkeys"].as_array().unwrap().len(), 1, "{}", listed);
+}
+
+#[test]
+fn passwordless_login() {
+    let server = Server::start();
+    let (_, mut authenticator) = enrolled(&server, "passwordless");
+
+    let credential = authenticator.get(&login_options(&server), Tweak::default());
+    let r = finish_login(&server, credential.clone());
+    assert_eq!(r.status, 200, "{}", r.body);
+    assert_eq!(r.body["username"], "passwordless");
+    assert!(r.body["token"].is_string());
+
+    let r = finish_login(&server, credential);
+    assert_eq!(r.status, 401, "replayed challenge: {}", r.body);
+
+    let credential = authenticator.get(&login_options(&server), Tweak { keep_count: true, ..Tweak::default() });
+    let r = finish_login(&server, credential);
+    assert_eq!(r.status, 401, "sign count did not go up: {}", r.body);
+
+    let credential = authenticator.get(&login_options(&server), Tweak { origin: Some("http://evil.test"), ..Tweak::default() });
+    assert_eq!(finish_login(&server, credential).status, 401, "wrong origin");
+
+    let credential = authenticator.get(&login_options(&server), Tweak { rp_id: Some("evil.test"), ..Tweak::default() });
+    assert_eq!(finish_login(&server, credential).status, 401, "wrong rpId");
+
+    let credential = Authenticator::new().get(&login_options(&server), Tweak::default());
+    assert_eq!(finish_login(&server, credential).status, 401, "unknown credential");
+
+    let credential = authenticator.get(&login_options(&server), Tweak::default());
+    assert_eq!(finish_login(&server, credential).status, 200, "still works after rejections");
+}
+
+#[test]
+fn concurrent_assertions_with_one_count() {
+    let server = Server::start();
+    let (_, mut authenticator) = enrolled(&server, "racer");
+
+    // Two assertions for separate challenges, both signed with the same count.
+    let first = authenticator.get(&login_options(&server), Tweak::default());
+    let second = authenticator.get(&login_options(&server), Tweak { keep_count: true, ..Tweak::default() });
+    let statuses: Vec<u16> = std::thread::scope(|s| {
+        let a = s.spawn(|| finish_login(&server, first).status);
+        let b = s.spawn(|| finish_login(&server, second).status);
+        vec![a.join().unwrap(), b.join().unwrap()]
+    });
+    assert_eq!(statuses.iter().filter(|&&s| s == 200).count(), 1, "{:?}", statuses);
+}
+
+#[test]
+fn second_factor() {
+    let server = Server::start();
+    let (token, mut authenticator) = enrolled(&server, "twofactor");
+    let r = server.post("/auth/passkeys/require", json!({"enabled": true}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+
+    let r = server.login("twofactor");
+    assert_eq!(r.status, 200);
+    assert_eq!(r.body["mfa_required"], true, "{}", r.body);
+    assert!(r.body.get("token").is_none());
+    let mfa_token = r.body["mfa_token"].as_str().unwrap().to_string();
+
+    let options = server.post_anon("/auth/passkeys/login/start", json!({"mfa_token": mfa_token})).body;
+    let credential = authenticator.get(&options, Tweak { origin: Some("http://evil.test"), ..Tweak::default() });
+    let r = server.post_anon("/auth/passkeys/login/finish", json!({"credential": credential, "mfa_token": mfa_token}));
+    assert_eq!(r.status, 401, "wrong origin: {}", r.body);
+
+    let options = server.post_anon("/auth/passkeys/login/start", json!({"mfa_token": mfa_token})).body;
+    let credential = authenticator.get(&options, Tweak::default());
+    let r = server.post_anon("/auth/passkeys/login/finish", json!({"credential": credential, "mfa_token": mfa_token}));
+    assert_eq!(r.status, 200, "{}", r.body);
+    assert_eq!(r.body["username"], "twofactor");
+
+    // The MFA token was spent.
+    let r = server.post_anon("/auth/passkeys/login/start", json!({"mfa_token": mfa_token}));
+    assert_eq!(r.status, 401, "{}", r.body);
+}
+
+#[test]
+fn required_passkey_is_not_satisfied_by_totp() {
+    let server = Server::start();
+    let (token, _) = enrolled(&server, "strict");
+    let secret = server.post("/auth/2fa/totp/setup", json!({}), &token).body["secret"].as_str().unwrap().to_string();
+    let r = server.post("/auth/2fa/totp/confirm", json!({"code": totp(&secret)}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let recovery_code = r.body["recovery_codes"][0].as_str().unwrap().to_string();
+
+    // With TOTP alone, a code finishes the login.
+    let r = server.login("strict");
+    assert_eq!(r.body["methods"], json!(["totp", "recovery_code"]), "{}", r.body);
+
+    server.post("/auth/passkeys/require", json!({"enabled": true}), &token);
+    let r = server.login("strict");
+    assert_eq!(r.body["methods"], json!(["passkey"]), "{}", r.body);
+    let mfa_token = r.body["mfa_token"].as_str().unwrap().to_string();
+    for code in [totp(&secret), recovery_code] {
+        let r = server.post_anon("/auth/login/2fa", json!({"mfa_token": mfa_token, "code": code}));
+        assert_eq!(r.status, 403, "{}", r.body);
+    }
+}
+
+#[test]
+fn wrong_codes_across_fresh_tokens_lock_the_account() {
+    let server = Server::start();
+    let (token, _) = server.signup("guessed");
+    let secret = server.post("/auth/2fa/totp/setup", json!({}), &token).body["secret"].as_str().unwrap().to_string();
+    let r = server.post("/auth/2fa/totp/confirm", json!({"code": totp(&secret)}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let wrong = if totp(&secret) == "000000" { "111111" } else { "000000" };
+
+    // Each token allows a few guesses; the password gets a fresh one.
+    let mut statuses = Vec::new();
+    'tokens: for _ in 0..4 {
+        let r = server.login("guessed");
+        statuses.push(r.status);
+        if r.status != 200 {
+            break;
+        }
+        let mfa_token = r.body["mfa_token"].as_str().unwrap().to_string();
+        for _ in 0..3 {
+            let r = server.post_anon("/auth/login/2fa", json!({"mfa_token": mfa_token, "code": wrong}));
+            statuses.push(r.status);
+            if r.status == 429 {
+                break 'tokens;
+            }
+        }
+    }
+    assert_eq!(statuses.last(), Some(&429), "{:?}", statuses);
+
+    // Locked out: even the right password is refused.
+    let r = server.login("guessed");
+    assert_eq!(r.status, 429, "{}", r.body);
+}
@@ -0,0 +1,25 @@
+//! Users from before organizations existed get a personal organization,
+//! with a wallet, at startup.
+
+mod common;
+
+use common::Server;
+
+#[test]
+fn legacy_user_without_billing_gets_a_wallet() {
+    let mut server = Server::start();
+    let (_, uid) = server.signup("legacy");
+    server.sql(&format!("DELETE FROM billing WHERE user_id = {}", uid));
+    server.sql(&format!("DELETE FROM organizations WHERE personal_user_id = {}", uid));
+
+    server.restart();
+
+    let org = server.sql_value(&format!("SELECT id FROM organizations WHERE personal_user_id = {}", uid));
+    assert!(org.is_some(), "{}", server.log());
+    let wallets = server.sql_value(&format!(
+        "SELECT COUNT(*) FROM billing WHERE user_id = {} AND org_id = {} AND amount_in_wallet = 0",
+        uid,
+        org.unwrap()
+    ));
+    assert_eq!(wallets.as_deref(), Some("1"));
+}
@@ -0,0 +1,17 @@
+//! With Postgres configured, SQLite hands out ids from a range Postgres
+//! never reaches, so rows created on either side during a failover can't
+//! collide.
+
+mod common;
+
+use common::Server;
+
+#[test]
+fn sqlite_ids_start_above_the_postgres_range() {
+    // Nothing listens on port 1; the mirror stays offline.
+    let server = Server::with_env(&[("POSTGRES_DATABASE_URL", "postgres://orsta@127.0.0.1:1/orsta")]);
+    let (_, uid) = server.signup("member");
+    assert!(uid >= 1_000_000_000, "user id {}", uid);
+    let instance = server.sql_value(&format!("SELECT MIN(id) FROM instances WHERE user_id = {}", uid));
+    assert!(instance.unwrap().parse::<i64>().unwrap() >= 1_000_000_000);
+}
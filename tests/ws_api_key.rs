@@ -0,0 +1,138 @@
+//! WebSockets opened with an API key close once the key stops working.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::{
+    self, Message, WebSocket, client::IntoClientRequest, http::HeaderValue, protocol::frame::coding::CloseCode,
+};
+
+type Socket = WebSocket<TcpStream>;
+
+/// Sign up `name`, pay for API access and create a key that may open the
+/// WebSocket. Returns the session token, the key's id and the key.
+fn key_owner(server: &Server, name: &str) -> (String, i64, String) {
+    let (token, _) = server.signup(name);
+    server.enable_api_keys(name, &token);
+    let r = server.post("/api-keys", json!({"name": "socket", "scopes": ["messages:send"]}), &token);
+    assert_eq!(r.status, 201, "{}", r.body);
+    (token, r.body["id"].as_i64().unwrap(), r.body["key"].as_str().unwrap().to_string())
+}
+
+fn open(server: &Server, key: &str) -> Socket {
+    let mut request = format!("ws://127.0.0.1:{}/ws", server.port).into_client_request().unwrap();
+    request.headers_mut().insert("X-Api-Key", HeaderValue::from_str(key).unwrap());
+    let stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let (mut socket, _) = tungstenite::client(request, stream).expect("WebSocket upgrade");
+    match socket.read().unwrap() {
+        Message::Text(t) => assert!(t.contains("\"connected\""), "{}", t),
+        other => panic!("unexpected {:?}", other),
+    }
+    socket
+}
+
+/// Read until the server closes the socket or `secs` pass; the close code,
+/// if it did.
+fn closed_within(socket: &mut Socket, secs: u64) -> Option<CloseCode> {
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    while Instant::now() < deadline {
+        match socket.read() {
+            Ok(Message::Close(frame)) => return frame.map(|f| f.code),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+            Err(e) => panic!("socket failed: {}", e),
+        }
+    }
+    None
+}
+
+#[test]
+fn revoking_the_key_closes_its_sockets() {
+    let server = Server::start();
+    let (token, key_id, key) = key_owner(&server, "integrator");
+    let mut socket = open(&server, &key);
+    assert_eq!(closed_within(&mut socket, 1), None);
+
+    let r = server.request("DELETE", &format!("/api-keys/{}", key_id), None, &[("Authorization", &format!("Bearer {}", token))]);
+    assert!(r.status < 300, "{}", r.body);
+    assert_eq!(closed_within(&mut socket, 5), Some(CloseCode::Policy));
+}
+
+#[test]
+fn expiry_closes_the_socket() {
+    let server = Server::start();
+    let (token, _) = server.signup("integrator");
+    server.enable_api_keys("integrator", &token);
+    let expires_at = chrono::Utc::now().timestamp() + 3;
+    let r = server.post(
+        "/api-keys",
+        json!({"name": "short", "scopes": ["messages:send"], "expires_at": expires_at}),
+        &token,
+    );
+    assert_eq!(r.status, 201, "{}", r.body);
+    let mut socket = open(&server, r.body["key"].as_str().unwrap());
+
+    assert_eq!(closed_within(&mut socket, 10), Some(CloseCode::Policy));
+    assert!(chrono::Utc::now().timestamp() >= expires_at);
+}
+
+#[test]
+fn replaced_value_closes_when_the_grace_period_ends() {
+    let server = Server::start();
+    let (token, key_id, old_key) = key_owner(&server, "integrator");
+    let mut before = open(&server, &old_key);
+
+    let r = server.post(&format!("/api-keys/{}/rotate", key_id), json!({"grace_secs": 3}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    let grace_ends = r.body["previous_expires_at"].as_i64().unwrap();
+    // Opened with the old value during the grace period.
+    let mut during = open(&server, &old_key);
+    let mut current = open(&server, r.body["key"].as_str().unwrap());
+
+    assert_eq!(closed_within(&mut before, 10), Some(CloseCode::Policy));
+    assert_eq!(closed_within(&mut during, 2), Some(CloseCode::Policy));
+    assert!(chrono::Utc::now().timestamp() >= grace_ends);
+    assert_eq!(closed_within(&mut current, 2), None);
+}
+
+#[test]
+fn rotation_without_grace_closes_at_once() {
+    let server = Server::start();
+    let (token, key_id, old_key) = key_owner(&server, "integrator");
+    let mut socket = open(&server, &old_key);
+
+    let r = server.post(&format!("/api-keys/{}/rotate", key_id), json!({"grace_secs": 0}), &token);
+    assert_eq!(r.status, 200, "{}", r.body);
+    assert_eq!(closed_within(&mut socket, 2), Some(CloseCode::Policy));
+}
+
+#[test]
+fn suspension_and_forced_deactivation_close_key_sockets() {
+    let server = Server::start();
+    server.signup("operator");
+    let admin = server.promote("operator", "admin");
+    let (_, suspended_id) = server.signup("suspended");
+    let (_, deactivated_id) = server.signup("deactivated");
+    let mut sockets = Vec::new();
+    for name in ["suspended", "deactivated"] {
+        let token = server.login(name).body["token"].as_str().unwrap().to_string();
+        server.enable_api_keys(name, &token);
+        let r = server.post("/api-keys", json!({"name": "socket", "scopes": ["messages:send"]}), &token);
+        assert_eq!(r.status, 201, "{}", r.body);
+        sockets.push(open(&server, r.body["key"].as_str().unwrap()));
+    }
+
+    let r = server.post(&format!("/admin/users/{}/suspend", suspended_id), json!({"reason": "abuse"}), &admin);
+    assert_eq!(r.status, 200, "{}", r.body);
+    assert_eq!(closed_within(&mut sockets[0], 5), Some(CloseCode::Policy));
+    assert_eq!(closed_within(&mut sockets[1], 1), None);
+
+    let r = server.post(&format!("/admin/users/{}/deactivate-api-key", deactivated_id), json!({}), &admin);
+    assert_eq!(r.status, 200, "{}", r.body);
+    assert_eq!(closed_within(&mut sockets[1], 5), Some(CloseCode::Policy));
+}